@@ -0,0 +1,89 @@
+//! Selection of the amdgpu firmware by GPU generation.
+//!
+//! amdgpu lists the firmware of every GPU it drives, several hundred MiB of it, so the module
+//! scan keeps the whole `amdgpu/` directory. Its files are named after the IP block version they
+//! are loaded for, such as `dcn_3_1_4_dmcub.bin` or `vcn_4_0_0.bin`, or after the ASIC, such as
+//! `navi10_sdma.bin` or `green_sardine_asd.bin`, which is enough to only keep the files of the
+//! GPUs an image targets.
+
+use crate::firmware::firmware_name;
+use std::path::Path;
+
+/// Directory of the amdgpu firmware, relative to a firmware layer.
+pub const AMDGPU_DIR: &str = "amdgpu";
+
+/// Returns whether the firmware file at `path` is an amdgpu firmware, in the `amdgpu/` directory
+/// of any firmware layer.
+pub fn is_amdgpu_firmware(path: &Path) -> bool {
+    path.parent()
+        .and_then(Path::file_name)
+        .is_some_and(|dir| dir == AMDGPU_DIR)
+}
+
+/// Returns whether the amdgpu firmware file named `file_name` belongs to `generation`: an IP
+/// block and the leading digits of its version, such as `dcn31` for `dcn_3_1_4_dmcub.bin` or
+/// `gc11` for `gc_11_0_0_me.bin`, or an ASIC name, such as `navi10` or `green_sardine`.
+pub fn matches_generation(file_name: &str, generation: &str) -> bool {
+    let generation = generation.trim().to_ascii_lowercase();
+    if generation.is_empty() {
+        return false;
+    }
+    let name = firmware_name(Path::new(file_name));
+    let stem = name.strip_suffix(".bin").unwrap_or(&name);
+    if stem == generation || stem.starts_with(&format!("{}_", generation)) {
+        return true;
+    }
+
+    let digits_start = generation.find(|c: char| c.is_ascii_digit()).unwrap_or(generation.len());
+    let (block, version) = generation.split_at(digits_start);
+    if block.is_empty() || version.is_empty() || !version.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+    let mut components = stem.split('_');
+    if components.next() != Some(block) {
+        return false;
+    }
+    // The version components are concatenated until they spell the requested version.
+    let mut spelled = String::new();
+    for component in components.take_while(|c| !c.is_empty() && c.chars().all(|c| c.is_ascii_digit())) {
+        spelled.push_str(component);
+        if spelled == version {
+            return true;
+        }
+        if spelled.len() >= version.len() {
+            break;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_generation() {
+        assert!(matches_generation("dcn_3_1_4_dmcub.bin", "dcn31"));
+        assert!(matches_generation("dcn_3_1_4_dmcub.bin.zst", "DCN314"));
+        assert!(matches_generation("dcn_3_1_4_dmcub.bin", "dcn3"));
+        assert!(matches_generation("vcn_4_0_0.bin", "vcn4"));
+        assert!(matches_generation("gc_11_0_0_me.bin.xz", "gc11"));
+        assert!(!matches_generation("gc_11_0_0_me.bin", "gc1"));
+        assert!(!matches_generation("dcn_3_2_0_dmcub.bin", "dcn31"));
+        assert!(!matches_generation("vcn_4_0_0.bin", "dcn4"));
+
+        assert!(matches_generation("navi10_sdma.bin", "navi10"));
+        assert!(!matches_generation("navi12_sdma.bin", "navi1"));
+        assert!(matches_generation("green_sardine_asd.bin", "green_sardine"));
+        assert!(!matches_generation("green_sardine_asd.bin", "sardine"));
+        assert!(!matches_generation("vcn_4_0_0.bin", ""));
+    }
+
+    #[test]
+    fn test_is_amdgpu_firmware() {
+        assert!(is_amdgpu_firmware(Path::new("amdgpu/vcn_4_0_0.bin")));
+        assert!(is_amdgpu_firmware(Path::new("updates/amdgpu/vcn_4_0_0.bin")));
+        assert!(!is_amdgpu_firmware(Path::new("radeon/vcn_4_0_0.bin")));
+        assert!(!is_amdgpu_firmware(Path::new("amdgpu")));
+    }
+}
@@ -0,0 +1,175 @@
+//! Crash-safe replacement of files inside the image tree.
+//!
+//! New content is written to a temporary file next to its destination and renamed over it once
+//! complete, so an interrupted pass never leaves a half-written module or firmware file behind.
+//! Temporary files left over by a crash are recognized by their name and removed on the next run.
+
+use crate::attributes;
+use crate::error::JanitorError;
+use log::{debug, info};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use walkdir::WalkDir;
+
+/// Marker in the name of the temporary files.
+const TEMP_MARKER: &str = ".ij-tmp-";
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the temporary path used to write `path`, unique per process and call.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(
+        ".{}{}{}-{}",
+        name,
+        TEMP_MARKER,
+        std::process::id(),
+        id
+    ))
+}
+
+/// Returns the id of the process which created the temporary file at `path`, if it is one.
+fn temp_owner(path: &Path) -> Option<u32> {
+    let name = path.file_name()?.to_str()?;
+    if !name.starts_with('.') {
+        return None;
+    }
+    let (_, suffix) = name.rsplit_once(TEMP_MARKER)?;
+    let (pid, id) = suffix.split_once('-')?;
+    id.parse::<usize>().ok()?;
+    pid.parse().ok()
+}
+
+/// Writes `path` through `write`, atomically replacing any previous file.
+///
+/// The mode, ownership and extended attributes of a replaced file are kept. On failure the
+/// temporary file is removed and the previous content, if any, is left untouched.
+pub fn write_atomic<F>(path: &Path, write: F) -> Result<(), JanitorError>
+where
+    F: FnOnce(&mut fs::File) -> io::Result<()>,
+{
+    write_atomic_like(path, path, write)
+}
+
+/// Like [`write_atomic`], giving `path` the attributes of `original`, if it exists, instead of
+/// the ones of the replaced file.
+pub fn write_atomic_like<F>(path: &Path, original: &Path, write: F) -> Result<(), JanitorError>
+where
+    F: FnOnce(&mut fs::File) -> io::Result<()>,
+{
+    let temp = temp_path(path);
+    let result = (|| {
+        let mut file = fs::File::create(&temp)?;
+        write(&mut file)?;
+        file.flush()?;
+        if fs::metadata(original).is_ok() {
+            attributes::copy_attributes(original, &temp)?;
+        }
+        file.sync_all()?;
+        Ok::<_, JanitorError>(fs::rename(&temp, path)?)
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Atomically replaces `path` with a hardlink to `original`, which must be on the same
+/// filesystem. On failure `path` is left untouched.
+pub fn link_atomic(original: &Path, path: &Path) -> Result<(), JanitorError> {
+    let temp = temp_path(path);
+    let result = fs::hard_link(original, &temp).and_then(|_| fs::rename(&temp, path));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Removes the temporary files left below `dir` by interrupted runs, returning their paths.
+///
+/// Files of processes still running are left alone, they may belong to a concurrent run.
+/// In a dry run the orphans are only reported.
+pub fn remove_orphans(dir: &Path, delete: bool) -> Result<Vec<PathBuf>, JanitorError> {
+    let mut orphans = Vec::new();
+    for entry in WalkDir::new(dir).into_iter().filter_map(Result::ok) {
+        let path = entry.path();
+        let Some(pid) = temp_owner(path) else {
+            continue;
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        if pid != std::process::id() && Path::new(&format!("/proc/{}", pid)).exists() {
+            debug!(
+                "Temporary file {} is in use by process {}",
+                path.display(),
+                pid
+            );
+            continue;
+        }
+        if delete {
+            info!("Removing leftover temporary file {}", path.display());
+            fs::remove_file(path)?;
+        } else {
+            info!("Would remove leftover temporary file {}", path.display());
+        }
+        orphans.push(path.to_path_buf());
+    }
+    Ok(orphans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_write_atomic() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("a.ko.zst");
+        fs::write(&path, "old").unwrap();
+
+        write_atomic(&path, |f| f.write_all(b"new")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+
+        // A failed write keeps the previous content and leaves no temporary file.
+        let result = write_atomic(&path, |f| {
+            f.write_all(b"partial")?;
+            Err(io::Error::other("compression failed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_remove_orphans() {
+        let temp_dir = tempdir().unwrap();
+        // No process can have this id, the file is left over by a crash.
+        let orphan = temp_dir.path().join(".a.ko.zst.ij-tmp-4294967295-0");
+        let unrelated = temp_dir.path().join(".hidden");
+        fs::write(&orphan, "partial").unwrap();
+        fs::write(&unrelated, "").unwrap();
+
+        assert_eq!(
+            remove_orphans(temp_dir.path(), false).unwrap(),
+            vec![orphan.clone()]
+        );
+        assert!(orphan.exists());
+
+        assert_eq!(
+            remove_orphans(temp_dir.path(), true).unwrap(),
+            vec![orphan.clone()]
+        );
+        assert!(!orphan.exists());
+        assert!(unrelated.exists());
+    }
+}
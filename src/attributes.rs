@@ -0,0 +1,105 @@
+//! File attributes kept when a file of the image is rewritten.
+//!
+//! Images sealed with IMA or running with SELinux break when a rewritten module or firmware file
+//! loses its `security.*` extended attributes, so every operation replacing a file (module
+//! recompression and stripping, firmware deduplication, restores) carries the mode, ownership and
+//! extended attributes of the original over.
+
+use crate::error::JanitorError;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+/// Mode, ownership and extended attributes of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attributes {
+    /// Permission bits, including the setuid, setgid and sticky ones.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Extended attributes by name, e.g. `security.selinux` or `security.ima`.
+    pub xattrs: BTreeMap<OsString, Vec<u8>>,
+}
+
+impl Attributes {
+    /// Reads the attributes of `path`, without following a final symlink.
+    pub fn read(path: &Path) -> Result<Self, JanitorError> {
+        let metadata = fs::symlink_metadata(path)?;
+        Ok(Attributes {
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            xattrs: read_xattrs(path)?,
+        })
+    }
+
+    /// Gives `path` these attributes. Extended attributes `path` has but these do not are left
+    /// alone.
+    pub fn apply(&self, path: &Path) -> Result<(), JanitorError> {
+        let metadata = fs::symlink_metadata(path)?;
+        // Changing the owner clears the setuid bits and the file capabilities, so it comes first.
+        if (metadata.uid(), metadata.gid()) != (self.uid, self.gid) {
+            std::os::unix::fs::lchown(path, Some(self.uid), Some(self.gid))?;
+        }
+        if !metadata.file_type().is_symlink() {
+            fs::set_permissions(path, fs::Permissions::from_mode(self.mode))?;
+        }
+        for (name, value) in &self.xattrs {
+            xattr::set(path, name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the extended attributes of `path`, none on filesystems not supporting them.
+pub fn read_xattrs(path: &Path) -> Result<BTreeMap<OsString, Vec<u8>>, JanitorError> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut xattrs = BTreeMap::new();
+    for name in names {
+        if let Some(value) = xattr::get(path, &name)? {
+            xattrs.insert(name, value);
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Copies the attributes of `from` to `to`.
+pub fn copy_attributes(from: &Path, to: &Path) -> Result<(), JanitorError> {
+    Attributes::read(from)?.apply(to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_copy_attributes() {
+        let temp_dir = tempdir().unwrap();
+        let from = temp_dir.path().join("from.bin");
+        let to = temp_dir.path().join("to.bin");
+        fs::write(&from, "from").unwrap();
+        fs::write(&to, "to").unwrap();
+        fs::set_permissions(&from, fs::Permissions::from_mode(0o640)).unwrap();
+        // user.* attributes are the ones every user can set, tmpfs may not support them.
+        let supported = xattr::set(&from, "user.label", b"firmware_t").is_ok();
+
+        copy_attributes(&from, &to).unwrap();
+        let attributes = Attributes::read(&to).unwrap();
+        assert_eq!(attributes.mode, 0o640);
+        assert_eq!(attributes, Attributes::read(&from).unwrap());
+        if supported {
+            assert_eq!(
+                attributes.xattrs.get(&OsString::from("user.label")),
+                Some(&b"firmware_t".to_vec())
+            );
+        }
+    }
+}
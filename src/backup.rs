@@ -0,0 +1,155 @@
+//! Compressed tar archives of the deleted files, to undo a cleanup.
+
+use crate::attributes;
+use crate::error::JanitorError;
+use log::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+type Encoder = zstd::stream::AutoFinishEncoder<'static, fs::File>;
+
+/// A zstd compressed tar archive the files are copied to before being deleted.
+///
+/// Entries are named after the absolute path of the files, without the leading `/`, so the
+/// archive can be restored below any root. The archive is finalized when dropped, so it stays
+/// readable when a run is interrupted. Each archive written to a file is a zstd frame of its own
+/// following the previous ones, which the restore reads through.
+pub struct Backup {
+    builder: tar::Builder<Encoder>,
+}
+
+impl Backup {
+    /// Creates the archive at `path`, appending to an existing one so the passes of a run can
+    /// share it.
+    pub fn create(path: &Path) -> Result<Self, JanitorError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        let encoder = zstd::Encoder::new(file, 0)?.auto_finish();
+        let mut builder = tar::Builder::new(encoder);
+        builder.follow_symlinks(false);
+        Ok(Backup { builder })
+    }
+
+    /// Adds the file, symlink or directory at `path`, with its mode, ownership and extended
+    /// attributes. Directories are added without content.
+    pub fn add(&mut self, path: &Path) -> Result<(), JanitorError> {
+        let absolute = std::path::absolute(path)?;
+        let name = absolute.strip_prefix("/").unwrap_or(&absolute);
+        // Extended attributes are stored as PAX records, as GNU tar does with --xattrs.
+        let xattrs: Vec<(String, Vec<u8>)> = attributes::read_xattrs(path)?
+            .into_iter()
+            .map(|(key, value)| (format!("SCHILY.xattr.{}", key.to_string_lossy()), value))
+            .collect();
+        self.builder
+            .append_pax_extensions(xattrs.iter().map(|(key, value)| (key.as_str(), value.as_slice())))?;
+        self.builder.append_path_with_name(path, name)?;
+        Ok(())
+    }
+
+    /// Writes the end of the archive, reporting errors which would be ignored on drop.
+    pub fn finish(self) -> Result<(), JanitorError> {
+        self.builder.into_inner()?;
+        Ok(())
+    }
+}
+
+/// Extracts the archive at `backup` below `root`, returning the restored paths.
+///
+/// Existing files are overwritten, and permissions, ownership, extended attributes and
+/// modification times are restored.
+pub fn restore(backup: &Path, root: &Path) -> Result<Vec<PathBuf>, JanitorError> {
+    restore_entries(backup, root, |_| true)
+}
+
+/// Like [`restore`], only extracting the entries whose name (the original absolute path without
+/// the leading `/`) is accepted by `select`.
+pub fn restore_entries<F>(backup: &Path, root: &Path, select: F) -> Result<Vec<PathBuf>, JanitorError>
+where
+    F: Fn(&Path) -> bool,
+{
+    let decoder = zstd::Decoder::new(fs::File::open(backup)?)?;
+    fs::create_dir_all(root)?;
+    let mut archive = tar::Archive::new(decoder);
+    // The end of the archive of a pass is followed by the archive of the next one.
+    archive.set_ignore_zeros(true);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive.set_unpack_xattrs(true);
+    archive.set_overwrite(true);
+
+    let mut restored = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        if !select(&name) {
+            continue;
+        }
+        let path = root.join(name);
+        if entry.unpack_in(root)? {
+            info!("Restored {}", path.display());
+            restored.push(path);
+        }
+    }
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_backup_and_restore() {
+        let temp_dir = tempdir().unwrap();
+        let source = temp_dir.path().join("source");
+        fs::create_dir_all(source.join("dir")).unwrap();
+        fs::write(source.join("dir/file.bin"), "data").unwrap();
+        symlink("file.bin", source.join("dir/link.bin")).unwrap();
+
+        let archive = temp_dir.path().join("cache/backup.tar.zst");
+        let mut backup = Backup::create(&archive).unwrap();
+        backup.add(&source.join("dir/file.bin")).unwrap();
+        backup.add(&source.join("dir/link.bin")).unwrap();
+        backup.add(&source.join("dir")).unwrap();
+        backup.finish().unwrap();
+
+        let root = temp_dir.path().join("root");
+        let restored = restore(&archive, &root).unwrap();
+        assert_eq!(restored.len(), 3);
+
+        let restored_dir = root.join(source.strip_prefix("/").unwrap()).join("dir");
+        assert_eq!(
+            fs::read_to_string(restored_dir.join("file.bin")).unwrap(),
+            "data"
+        );
+        assert_eq!(
+            fs::read_link(restored_dir.join("link.bin")).unwrap(),
+            Path::new("file.bin")
+        );
+    }
+
+    #[test]
+    fn test_backup_appended_by_passes() {
+        let temp_dir = tempdir().unwrap();
+        let source = temp_dir.path().join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("driver.ko"), "driver").unwrap();
+        fs::write(source.join("firmware.bin"), "firmware").unwrap();
+
+        let archive = temp_dir.path().join("backup.tar.zst");
+        for name in ["driver.ko", "firmware.bin"] {
+            let mut backup = Backup::create(&archive).unwrap();
+            backup.add(&source.join(name)).unwrap();
+            backup.finish().unwrap();
+        }
+
+        let root = temp_dir.path().join("root");
+        let restored = restore(&archive, &root).unwrap();
+        assert_eq!(restored.len(), 2);
+        let restored_dir = root.join(source.strip_prefix("/").unwrap());
+        assert_eq!(fs::read_to_string(restored_dir.join("firmware.bin")).unwrap(), "firmware");
+    }
+}
@@ -0,0 +1,147 @@
+use crate::error::JanitorError;
+use log::warn;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// The files (with their sizes) and directories found below a root at one point in time.
+#[derive(Debug, Default)]
+pub struct TreeSnapshot {
+    files: BTreeMap<PathBuf, u64>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl TreeSnapshot {
+    /// Walks `root` without following symlinks and records every entry.
+    pub fn capture(root: &Path) -> Result<Self, JanitorError> {
+        let mut snapshot = TreeSnapshot::default();
+        for entry in WalkDir::new(root) {
+            let entry = entry?;
+            let metadata = entry.path().symlink_metadata()?;
+            if metadata.is_dir() {
+                snapshot.dirs.insert(entry.path().to_path_buf());
+            } else {
+                snapshot
+                    .files
+                    .insert(entry.path().to_path_buf(), metadata.len());
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Verified difference between the filesystem before and after a cleanup run.
+#[derive(Debug, Default, Serialize)]
+pub struct ChangeReport {
+    pub files_removed: Vec<PathBuf>,
+    pub dirs_removed: Vec<PathBuf>,
+    /// Bytes freed, measured from the sizes recorded before the run.
+    pub bytes_freed: u64,
+    /// Files the cleanup planned to delete that are still present.
+    pub planned_not_removed: Vec<PathBuf>,
+    /// Files that disappeared although the cleanup did not plan to delete them.
+    pub removed_not_planned: Vec<PathBuf>,
+}
+
+impl ChangeReport {
+    /// Compares two snapshots of the same root against the list of planned deletions.
+    pub fn new(before: &TreeSnapshot, after: &TreeSnapshot, planned: &[PathBuf]) -> Self {
+        let planned: BTreeSet<&PathBuf> = planned.iter().collect();
+        let mut report = ChangeReport::default();
+
+        for (path, size) in &before.files {
+            if !after.files.contains_key(path) {
+                report.files_removed.push(path.clone());
+                report.bytes_freed += size;
+                if !planned.contains(path) {
+                    report.removed_not_planned.push(path.clone());
+                }
+            }
+        }
+        report.dirs_removed = before.dirs.difference(&after.dirs).cloned().collect();
+        report.planned_not_removed = planned
+            .into_iter()
+            .filter(|p| after.files.contains_key(*p))
+            .cloned()
+            .collect();
+        report
+    }
+
+    /// True if what happened on disk differs from the plan.
+    pub fn has_divergence(&self) -> bool {
+        !self.planned_not_removed.is_empty() || !self.removed_not_planned.is_empty()
+    }
+}
+
+/// Re-walks `root` after a run, compares it with `before` and writes the result as JSON to `path`.
+pub fn write_changed_report(
+    path: &Path,
+    root: &Path,
+    before: &TreeSnapshot,
+    planned: &[PathBuf],
+) -> Result<ChangeReport, JanitorError> {
+    let after = TreeSnapshot::capture(root)?;
+    let report = ChangeReport::new(before, &after, planned);
+    if report.has_divergence() {
+        warn!(
+            "Filesystem changes diverge from the plan: {} planned files not removed, {} unplanned files removed",
+            report.planned_not_removed.len(),
+            report.removed_not_planned.len()
+        );
+    }
+    fs::write(path, serde_json::to_string_pretty(&report)?)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_change_report_measures_removed_files() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let dir = root.join("dir");
+        let planned = dir.join("planned.bin");
+        let kept = root.join("kept.bin");
+        fs::create_dir(&dir).unwrap();
+        fs::write(&planned, "12345").unwrap();
+        fs::write(&kept, "1").unwrap();
+
+        let before = TreeSnapshot::capture(root).unwrap();
+        fs::remove_file(&planned).unwrap();
+        fs::remove_dir(&dir).unwrap();
+        let after = TreeSnapshot::capture(root).unwrap();
+
+        let report = ChangeReport::new(&before, &after, std::slice::from_ref(&planned));
+        assert_eq!(report.files_removed, vec![planned]);
+        assert_eq!(report.dirs_removed, vec![dir]);
+        assert_eq!(report.bytes_freed, 5);
+        assert!(!report.has_divergence());
+    }
+
+    #[test]
+    fn test_change_report_detects_divergence() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let planned = root.join("planned.bin");
+        let unplanned = root.join("unplanned.bin");
+        fs::write(&planned, "data").unwrap();
+        fs::write(&unplanned, "data").unwrap();
+
+        let before = TreeSnapshot::capture(root).unwrap();
+        fs::remove_file(&unplanned).unwrap();
+        let report_path = root.join("report.json");
+        let report =
+            write_changed_report(&report_path, root, &before, std::slice::from_ref(&planned))
+                .unwrap();
+
+        assert_eq!(report.planned_not_removed, vec![planned]);
+        assert_eq!(report.removed_not_planned, vec![unplanned]);
+        assert!(report.has_divergence());
+        assert!(report_path.exists());
+    }
+}
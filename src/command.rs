@@ -1,18 +1,90 @@
 use crate::error::JanitorError;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 pub trait CommandRunner {
     fn run(&self, command: &str, args: &[&str]) -> Result<String, JanitorError>;
 }
 
-pub struct SystemCommandRunner;
+/// Commands reading the kernel, package or initramfs configuration of the system they run on,
+/// which are run inside the target root in chroot mode.
+const IN_ROOT_COMMANDS: &[&str] = &[
+    "modinfo",
+    "depmod",
+    "rpm",
+    "dpkg-query",
+    "dracut",
+    "update-initramfs",
+    "mkinitfs",
+];
+
+/// How commands are run inside the target root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChrootMode {
+    /// `chroot`, sharing the host kernel interfaces mounted in the root, if any.
+    Chroot,
+    /// `systemd-nspawn`, which also mounts /proc, /sys and /dev in the container.
+    Nspawn,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SystemCommandRunner {
+    chroot: Option<(PathBuf, ChrootMode)>,
+}
+
+impl SystemCommandRunner {
+    /// Runs the commands of [`IN_ROOT_COMMANDS`] inside `root` with `mode`, so the tooling and
+    /// databases of the image are used instead of the ones of the host. Their arguments naming
+    /// paths below `root` are rewritten to the paths seen inside it. Other commands, e.g. the
+    /// image tools, still run on the host.
+    pub fn in_root(root: &Path, mode: ChrootMode) -> Self {
+        SystemCommandRunner {
+            chroot: Some((root.to_path_buf(), mode)),
+        }
+    }
+
+    /// Returns the program and arguments actually executed to run `command`.
+    fn command_line(&self, command: &str, args: &[&str]) -> Vec<String> {
+        let Some((root, mode)) = self
+            .chroot
+            .as_ref()
+            .filter(|_| IN_ROOT_COMMANDS.contains(&command))
+        else {
+            return std::iter::once(command)
+                .chain(args.iter().copied())
+                .map(String::from)
+                .collect();
+        };
+        let mut line: Vec<String> = match mode {
+            ChrootMode::Chroot => vec!["chroot".to_string(), root.display().to_string()],
+            ChrootMode::Nspawn => vec![
+                "systemd-nspawn".to_string(),
+                "--quiet".to_string(),
+                format!("--directory={}", root.display()),
+                "--".to_string(),
+            ],
+        };
+        line.push(command.to_string());
+        line.extend(
+            args.iter()
+                .map(|arg| match Path::new(arg).strip_prefix(root) {
+                    Ok(inside) => Path::new("/").join(inside).display().to_string(),
+                    Err(_) => arg.to_string(),
+                }),
+        );
+        line
+    }
+}
 
 impl CommandRunner for SystemCommandRunner {
     fn run(&self, command: &str, args: &[&str]) -> Result<String, JanitorError> {
-        let output = Command::new(command)
-            .args(args)
+        let line = self.command_line(command, args);
+        let output = Command::new(&line[0])
+            .args(&line[1..])
             .output()
-            .map_err(|e| JanitorError::Command(format!("Failed to execute '{}': {}", command, e)))?;
+            .map_err(|e| {
+                JanitorError::Command(format!("Failed to execute '{}': {}", line[0], e))
+            })?;
 
         if !output.status.success() {
             return Err(JanitorError::Command(format!(
@@ -25,3 +97,53 @@ impl CommandRunner for SystemCommandRunner {
         Ok(String::from_utf8(output.stdout).unwrap().trim().to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_line_in_root() {
+        let runner = SystemCommandRunner::in_root(Path::new("/images/sle"), ChrootMode::Chroot);
+        assert_eq!(
+            runner.command_line("depmod", &["-a", "-b", "/images/sle/usr", "6.4.0"]),
+            vec![
+                "chroot",
+                "/images/sle",
+                "depmod",
+                "-a",
+                "-b",
+                "/usr",
+                "6.4.0"
+            ]
+        );
+        assert_eq!(
+            runner.command_line("rpm", &["--root", "/images/sle", "-qa"]),
+            vec!["chroot", "/images/sle", "rpm", "--root", "/", "-qa"]
+        );
+        // Image tools run on the host, and paths merely sharing the prefix are not rewritten.
+        assert_eq!(
+            runner.command_line("unsquashfs", &["-s", "/images/sle.squashfs"]),
+            vec!["unsquashfs", "-s", "/images/sle.squashfs"]
+        );
+
+        let runner = SystemCommandRunner::in_root(Path::new("/images/sle"), ChrootMode::Nspawn);
+        assert_eq!(
+            runner.command_line("dracut", &["--force", "--kver", "6.4.0"]),
+            vec![
+                "systemd-nspawn",
+                "--quiet",
+                "--directory=/images/sle",
+                "--",
+                "dracut",
+                "--force",
+                "--kver",
+                "6.4.0"
+            ]
+        );
+        assert_eq!(
+            SystemCommandRunner::default().command_line("depmod", &["-a"]),
+            vec!["depmod", "-a"]
+        );
+    }
+}
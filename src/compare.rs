@@ -0,0 +1,195 @@
+//! Comparison of the module and firmware trees of two image roots.
+//!
+//! Modules are compared between the latest kernel of each image, relative to its kernel
+//! directory, so a kernel update does not show every module as removed and added again.
+
+use crate::error::JanitorError;
+use crate::util;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// A file present in at least one of the trees, with its size in each of them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileChange {
+    /// Path relative to the compared tree.
+    pub path: String,
+    pub size_a: Option<u64>,
+    pub size_b: Option<u64>,
+}
+
+impl FileChange {
+    /// Growth from tree A to tree B, in bytes.
+    pub fn delta(&self) -> i64 {
+        self.size_b.unwrap_or_default() as i64 - self.size_a.unwrap_or_default() as i64
+    }
+}
+
+/// Differences between two versions of a tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TreeDiff {
+    /// The compared directories.
+    pub dir_a: PathBuf,
+    pub dir_b: PathBuf,
+    pub added: Vec<FileChange>,
+    pub removed: Vec<FileChange>,
+    /// Files whose size or symlink target differs.
+    pub changed: Vec<FileChange>,
+    /// Total size of each tree.
+    pub bytes_a: u64,
+    pub bytes_b: u64,
+}
+
+impl TreeDiff {
+    /// Growth from tree A to tree B, in bytes.
+    pub fn delta(&self) -> i64 {
+        self.bytes_b as i64 - self.bytes_a as i64
+    }
+
+    /// Every difference, the largest growth or shrink first.
+    pub fn by_delta(&self) -> Vec<&FileChange> {
+        let mut changes: Vec<_> = self
+            .added
+            .iter()
+            .chain(&self.removed)
+            .chain(&self.changed)
+            .collect();
+        changes.sort_by_key(|c| std::cmp::Reverse(c.delta().unsigned_abs()));
+        changes
+    }
+}
+
+/// Differences between the module and firmware trees of two images.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Comparison {
+    pub modules: TreeDiff,
+    pub firmware: TreeDiff,
+}
+
+/// Compares the latest kernel module tree and the firmware tree of the images at `root_a` and
+/// `root_b`. A tree missing from an image counts as empty.
+pub fn compare_roots(root_a: &Path, root_b: &Path) -> Result<Comparison, JanitorError> {
+    let kernel_dir = |root: &Path| {
+        let module_dir = util::find_in_root(root, util::MODULE_DIRS);
+        util::find_kernel_dir(&module_dir).unwrap_or(module_dir)
+    };
+    Ok(Comparison {
+        modules: compare_trees(&kernel_dir(root_a), &kernel_dir(root_b))?,
+        firmware: compare_trees(
+            &util::find_in_root(root_a, util::FIRMWARE_DIRS),
+            &util::find_in_root(root_b, util::FIRMWARE_DIRS),
+        )?,
+    })
+}
+
+/// A file of a tree: its size and, for symlinks, its target.
+type Entry = (u64, Option<PathBuf>);
+
+/// Lists the files below `dir`, keyed by path relative to it.
+fn list_files(dir: &Path) -> Result<BTreeMap<String, Entry>, JanitorError> {
+    let mut files = BTreeMap::new();
+    if !dir.is_dir() {
+        return Ok(files);
+    }
+    for entry in WalkDir::new(dir) {
+        let entry = entry?;
+        let metadata = entry.path().symlink_metadata()?;
+        if metadata.is_dir() {
+            continue;
+        }
+        let target = match metadata.file_type().is_symlink() {
+            true => Some(fs::read_link(entry.path())?),
+            false => None,
+        };
+        let relative = entry.path().strip_prefix(dir).unwrap();
+        files.insert(
+            relative.to_string_lossy().into_owned(),
+            (metadata.len(), target),
+        );
+    }
+    Ok(files)
+}
+
+/// Compares the files below `dir_a` and `dir_b`.
+pub fn compare_trees(dir_a: &Path, dir_b: &Path) -> Result<TreeDiff, JanitorError> {
+    let files_a = list_files(dir_a)?;
+    let files_b = list_files(dir_b)?;
+    let mut diff = TreeDiff {
+        dir_a: dir_a.to_path_buf(),
+        dir_b: dir_b.to_path_buf(),
+        bytes_a: files_a.values().map(|(size, _)| size).sum(),
+        bytes_b: files_b.values().map(|(size, _)| size).sum(),
+        ..Default::default()
+    };
+    for (path, a) in &files_a {
+        let change = FileChange {
+            path: path.clone(),
+            size_a: Some(a.0),
+            size_b: files_b.get(path).map(|b| b.0),
+        };
+        match files_b.get(path) {
+            None => diff.removed.push(change),
+            Some(b) if a != b => diff.changed.push(change),
+            Some(_) => {}
+        }
+    }
+    for (path, b) in &files_b {
+        if !files_a.contains_key(path) {
+            diff.added.push(FileChange {
+                path: path.clone(),
+                size_a: None,
+                size_b: Some(b.0),
+            });
+        }
+    }
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_compare_roots() {
+        let temp_dir = tempdir().unwrap();
+        let root_a = temp_dir.path().join("a");
+        let root_b = temp_dir.path().join("b");
+
+        let modules_a = root_a.join("usr/lib/modules/6.1.0-1/kernel");
+        let modules_b = root_b.join("usr/lib/modules/6.2.0-1/kernel");
+        fs::create_dir_all(&modules_a).unwrap();
+        fs::create_dir_all(&modules_b).unwrap();
+        fs::write(modules_a.join("same.ko"), "1234").unwrap();
+        fs::write(modules_b.join("same.ko"), "1234").unwrap();
+        fs::write(modules_a.join("grown.ko"), "12").unwrap();
+        fs::write(modules_b.join("grown.ko"), "123456").unwrap();
+        fs::write(modules_a.join("dropped.ko"), "1").unwrap();
+
+        let firmware_a = root_a.join("lib/firmware");
+        let firmware_b = root_b.join("usr/lib/firmware");
+        fs::create_dir_all(&firmware_a).unwrap();
+        fs::create_dir_all(&firmware_b).unwrap();
+        fs::write(firmware_b.join("new.bin"), "0123456789").unwrap();
+        symlink("old.bin", firmware_a.join("link.bin")).unwrap();
+        symlink("new.bin", firmware_b.join("link.bin")).unwrap();
+
+        let comparison = compare_roots(&root_a, &root_b).unwrap();
+
+        let modules = &comparison.modules;
+        assert!(modules.added.is_empty());
+        assert_eq!(modules.removed[0].path, "kernel/dropped.ko");
+        assert_eq!(modules.changed.len(), 1);
+        assert_eq!(modules.changed[0].delta(), 4);
+        assert_eq!(modules.delta(), 3);
+
+        let firmware = &comparison.firmware;
+        assert_eq!(firmware.added[0].path, "new.bin");
+        assert_eq!(firmware.changed[0].path, "link.bin");
+        assert_eq!(firmware.by_delta()[0].path, "new.bin");
+        assert_eq!(firmware.delta(), 10);
+    }
+}
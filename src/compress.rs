@@ -0,0 +1,313 @@
+//! Recompression of kernel modules.
+//!
+//! Modules are converted to a single compression, e.g. images standardizing on zstd, and depmod
+//! is run again since its indexes name the module files. The uncompressed payload is kept byte
+//! for byte, so appended module signatures stay valid.
+
+use crate::atomic;
+use crate::command::CommandRunner;
+use crate::error::JanitorError;
+use crate::interrupt;
+use crate::modinfo;
+use crate::util::{self, ScanOptions};
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Compression of a kernel module file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Xz,
+    Zstd,
+    /// gzip, the compression of Alpine kernels.
+    Gzip,
+}
+
+impl Compression {
+    /// Returns the compression of the module at `path`, from its extension.
+    pub fn of(path: &Path) -> Compression {
+        let name = path.to_string_lossy();
+        if name.ends_with(".ko.xz") {
+            Compression::Xz
+        } else if name.ends_with(".ko.zst") {
+            Compression::Zstd
+        } else if name.ends_with(".ko.gz") {
+            Compression::Gzip
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Extension of the module files, after `.ko`.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Xz => ".xz",
+            Compression::Zstd => ".zst",
+            Compression::Gzip => ".gz",
+        }
+    }
+
+    /// Compresses the module content `data`, with the settings of the kernel build: xz with
+    /// CRC32 checks and a 1 MiB dictionary, which the in-kernel decompressor requires, zstd at
+    /// its default level and gzip at its best.
+    pub fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Xz => {
+                let mut options = xz2::stream::LzmaOptions::new_preset(6)?;
+                options.dict_size(1 << 20);
+                let stream = xz2::stream::Stream::new_stream_encoder(
+                    xz2::stream::Filters::new().lzma2(&options),
+                    xz2::stream::Check::Crc32,
+                )?;
+                let mut encoder = xz2::write::XzEncoder::new_stream(Vec::new(), stream);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::stream::encode_all(data, 3),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Returns the path of the module at `path` once compressed with `compression`.
+fn converted_path(path: &Path, compression: Compression) -> PathBuf {
+    let name = path.to_string_lossy();
+    let stem = name
+        .strip_suffix(Compression::of(path).extension())
+        .unwrap_or(&name);
+    PathBuf::from(format!("{}{}", stem, compression.extension()))
+}
+
+/// A module converted to another compression.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Conversion {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// Size of the module file before conversion.
+    pub size_before: u64,
+    /// Size of the module file after conversion.
+    pub size_after: u64,
+}
+
+/// Options of a module recompression run.
+#[derive(Debug, Clone)]
+pub struct CompressOptions {
+    /// Directory with the kernel module trees, `lib/modules` below the base directory given to
+    /// depmod.
+    pub module_dir: PathBuf,
+    /// Really convert the modules and run depmod.
+    pub convert: bool,
+    pub scan: ScanOptions,
+    /// Compression the modules are converted to.
+    pub compression: Compression,
+}
+
+/// Converts the modules of the selected kernels to `options.compression`, then runs depmod on
+/// each kernel with converted modules. Returns the conversions done, or the ones which would be
+/// done in a dry run.
+pub fn compress_modules(
+    options: &CompressOptions,
+    runner: &dyn CommandRunner,
+) -> Result<Vec<Conversion>, JanitorError> {
+    let mut conversions = Vec::new();
+    for kernel_dir in util::find_kernel_dirs(&options.module_dir, &options.scan.kernels)? {
+        atomic::remove_orphans(&kernel_dir, options.convert)?;
+        info!("Recompressing kernel modules in {}", kernel_dir.display());
+        let modules = util::find_kernel_modules(&kernel_dir, &options.scan)?;
+        let converted = modules
+            .par_iter()
+            .filter(|path| Compression::of(path) != options.compression)
+            .map(|path| {
+                interrupt::check()?;
+                convert_module(path, options.compression, options.convert)
+            })
+            .collect::<Result<Vec<_>, JanitorError>>()?;
+        let converted: Vec<Conversion> = converted.into_iter().flatten().collect();
+        if options.convert && !converted.is_empty() {
+            run_depmod(&options.module_dir, &kernel_dir, runner)?;
+        }
+        conversions.extend(converted);
+    }
+
+    let before: u64 = conversions.iter().map(|c| c.size_before).sum();
+    let after: u64 = conversions.iter().map(|c| c.size_after).sum();
+    info!(
+        "{} modules, {} -> {} bytes",
+        conversions.len(),
+        before,
+        after
+    );
+    Ok(conversions)
+}
+
+/// Compresses the module at `path` with `compression`, replacing it if `convert` is set. A module
+/// whose converted file already exists is skipped.
+fn convert_module(
+    path: &Path,
+    compression: Compression,
+    convert: bool,
+) -> Result<Option<Conversion>, JanitorError> {
+    let to = converted_path(path, compression);
+    if to.symlink_metadata().is_ok() {
+        warn!(
+            "Not converting {}, {} already exists",
+            path.display(),
+            to.display()
+        );
+        return Ok(None);
+    }
+    let data = modinfo::read_module(path)?;
+    let compressed = compression
+        .compress(&data)
+        .map_err(|e| JanitorError::ModuleParse(path.to_path_buf(), e.to_string()))?;
+    let metadata = fs::metadata(path)?;
+    if convert {
+        debug!(
+            "Converting {} to {}{}",
+            path.display(),
+            to.display(),
+            if modinfo::is_signed(&data) { ", signature kept" } else { "" }
+        );
+        atomic::write_atomic_like(&to, path, |file| file.write_all(&compressed))?;
+        fs::remove_file(path)?;
+    }
+    Ok(Some(Conversion {
+        from: path.to_path_buf(),
+        to,
+        size_before: metadata.len(),
+        size_after: compressed.len() as u64,
+    }))
+}
+
+/// Runs depmod on the kernel of `kernel_dir`, a directory of `module_dir`.
+fn run_depmod(
+    module_dir: &Path,
+    kernel_dir: &Path,
+    runner: &dyn CommandRunner,
+) -> Result<(), JanitorError> {
+    let base = module_dir
+        .parent()
+        .and_then(Path::parent)
+        .filter(|_| module_dir.ends_with("lib/modules"))
+        .ok_or_else(|| {
+            JanitorError::Command(format!(
+                "cannot run depmod, {} is not a lib/modules directory",
+                module_dir.display()
+            ))
+        })?;
+    let base = base
+        .to_str()
+        .ok_or_else(|| JanitorError::InvalidPath(base.to_path_buf()))?;
+    let version = kernel_dir
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| JanitorError::InvalidPath(kernel_dir.to_path_buf()))?;
+    info!("Running depmod for kernel {}", version);
+    runner.run("depmod", &["-a", "-b", base, version])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use tempfile::tempdir;
+
+    struct MockCommandRunner {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for MockCommandRunner {
+        fn run(&self, command: &str, args: &[&str]) -> Result<String, JanitorError> {
+            self.calls
+                .borrow_mut()
+                .push(format!("{} {}", command, args.join(" ")));
+            Ok(String::new())
+        }
+    }
+
+    #[test]
+    fn test_converted_path() {
+        let path = Path::new("/lib/modules/6.1/a.ko.xz");
+        assert_eq!(
+            converted_path(path, Compression::Zstd),
+            Path::new("/lib/modules/6.1/a.ko.zst")
+        );
+        assert_eq!(
+            converted_path(path, Compression::None),
+            Path::new("/lib/modules/6.1/a.ko")
+        );
+        assert_eq!(
+            converted_path(Path::new("b.ko"), Compression::Xz),
+            Path::new("b.ko.xz")
+        );
+        assert_eq!(
+            converted_path(Path::new("c.ko.gz"), Compression::Zstd),
+            Path::new("c.ko.zst")
+        );
+    }
+
+    #[test]
+    fn test_compress_modules() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("usr/lib/modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        let mut signed = modinfo::build_test_module(&["depends="]);
+        signed.extend_from_slice(b"signature\0\0\0\x09~Module signature appended~\n");
+        fs::write(
+            kernel_dir.join("a.ko.xz"),
+            Compression::Xz.compress(&signed).unwrap(),
+        )
+        .unwrap();
+        fs::write(kernel_dir.join("b.ko"), modinfo::build_test_module(&[])).unwrap();
+        fs::write(
+            kernel_dir.join("c.ko.zst"),
+            Compression::Zstd.compress(b"c").unwrap(),
+        )
+        .unwrap();
+
+        let runner = MockCommandRunner {
+            calls: RefCell::new(Vec::new()),
+        };
+        let mut options = CompressOptions {
+            module_dir: module_dir.clone(),
+            convert: false,
+            scan: ScanOptions::default(),
+            compression: Compression::Zstd,
+        };
+        let conversions = compress_modules(&options, &runner).unwrap();
+        let converted: Vec<_> = conversions.iter().map(|c| c.to.clone()).collect();
+        assert_eq!(
+            converted,
+            vec![kernel_dir.join("a.ko.zst"), kernel_dir.join("b.ko.zst")]
+        );
+        assert!(kernel_dir.join("a.ko.xz").exists());
+        assert!(runner.calls.borrow().is_empty());
+
+        options.convert = true;
+        compress_modules(&options, &runner).unwrap();
+        assert!(!kernel_dir.join("a.ko.xz").exists());
+        assert!(!kernel_dir.join("b.ko").exists());
+        let data = modinfo::read_module(&kernel_dir.join("a.ko.zst")).unwrap();
+        assert_eq!(data, signed);
+        assert!(modinfo::is_signed(&data));
+        let base = temp_dir.path().join("usr");
+        assert_eq!(
+            *runner.calls.borrow(),
+            vec![format!("depmod -a -b {} 6.1.0-test", base.display())]
+        );
+    }
+}
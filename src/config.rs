@@ -1,86 +1,183 @@
 use crate::command::CommandRunner;
 use crate::error::JanitorError;
-use log::{debug, info};
-use regex::Regex;
+use crate::policy::{self, Diagnostic, Precedence, Rules};
+use log::{debug, info, warn};
+use std::fmt;
 use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::info_span;
 
-/// Reads the configuration files and returns two lists of regexes: one for keeping and one for deleting.
-pub fn read_config(
-    paths: &[&str],
-    runner: &dyn CommandRunner,
-) -> Result<(Vec<Regex>, Vec<Regex>), JanitorError> {
-    let mut lines = Vec::<String>::new();
-    for path in paths {
-        info!("Reading config file: {}", path);
-        let content = fs::read_to_string(path)
-            .map_err(|e| JanitorError::ConfigRead(path.to_string(), e))?;
-        lines.extend(content.lines().map(String::from));
-    }
+/// A configuration file, read as is.
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    pub path: PathBuf,
+    pub content: String,
+}
 
-    let lines = lines
-        .into_iter()
-        .filter(|l| !l.is_empty() && !l.starts_with('#'))
-        .collect();
+/// Extensions of the fragments read from a drop-in configuration directory.
+const FRAGMENT_EXTENSIONS: &[&str] = &["list", "conf"];
 
-    let arch = get_arch(runner)?;
-    debug!("Current architecture: {}", arch);
-    let filtered_lines = arch_filter(lines, &arch);
-
-    let (delete_lines, keep_lines): (Vec<_>, Vec<_>) = filtered_lines
-        .into_iter()
-        .partition(|l| l.starts_with('-'));
+/// Reads the configuration file at `path`, followed depth first by the files its
+/// `include FILE` lines name, relative to the directory of the including file. A directory,
+/// given or included, is a drop-in directory: its `*.list` and `*.conf` fragments are read in
+/// sorted order.
+pub fn read_with_includes(path: &Path) -> Result<Vec<ConfigSource>, JanitorError> {
+    let mut sources = Vec::new();
+    read_source(path, &mut Vec::new(), &mut sources)?;
+    Ok(sources)
+}
 
-    let to_keep = keep_lines
-        .into_iter()
-        .map(|l| Regex::new(&l).map_err(JanitorError::Regex))
-        .collect::<Result<Vec<_>, _>>()?;
+/// Reads `path` and its includes into `sources`, `chain` being the files including it.
+fn read_source(
+    path: &Path,
+    chain: &mut Vec<PathBuf>,
+    sources: &mut Vec<ConfigSource>,
+) -> Result<(), JanitorError> {
+    let read_error = |e| JanitorError::ConfigRead(path.display().to_string(), e);
+    if path.is_dir() {
+        for fragment in fragments(path).map_err(read_error)? {
+            read_source(&fragment, chain, sources)?;
+        }
+        return Ok(());
+    }
+    let content = fs::read_to_string(path).map_err(read_error)?;
+    let canonical = fs::canonicalize(path).map_err(read_error)?;
+    if chain.contains(&canonical) {
+        return Err(JanitorError::InvalidConfig(format!(
+            "{}: included again by {}, includes must not form a cycle",
+            path.display(),
+            chain.last().unwrap().display()
+        )));
+    }
+    let targets: Vec<PathBuf> = content
+        .lines()
+        .filter_map(policy::include_target)
+        .map(|target| path.parent().unwrap_or(Path::new("")).join(target))
+        .collect();
+    sources.push(ConfigSource {
+        path: path.to_path_buf(),
+        content,
+    });
 
-    let to_delete = delete_lines
-        .into_iter()
-        .map(|l| Regex::new(l.strip_prefix('-').unwrap()).map_err(JanitorError::Regex))
-        .collect::<Result<Vec<_>, _>>()?;
+    chain.push(canonical);
+    for target in targets {
+        read_source(&target, chain, sources)?;
+    }
+    chain.pop();
+    Ok(())
+}
 
-    Ok((to_keep, to_delete))
+/// Returns the configuration fragments of the drop-in directory `dir`, sorted by name.
+fn fragments(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut fragments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        if FRAGMENT_EXTENSIONS.contains(&extension) && !path.is_dir() {
+            fragments.push(path);
+        }
+    }
+    fragments.sort();
+    Ok(fragments)
 }
 
-fn get_arch(runner: &dyn CommandRunner) -> Result<String, JanitorError> {
-    runner.run("arch", &[])
+/// A problem found by [`lint`] in a configuration file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintFinding {
+    pub path: PathBuf,
+    pub diagnostic: Diagnostic,
 }
 
-fn arch_filter(lines: Vec<String>, arch: &str) -> Vec<String> {
-    let mut filtered = Vec::new();
-    let mut skipping = false;
-    let mut arch_tag: Option<String> = None;
+impl fmt::Display for LintFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.path.display(), self.diagnostic)
+    }
+}
 
-    let start_tag_re = Regex::new(r"^\s*<(\w+)\s*>\s*$").unwrap();
-    let end_tag_re = Regex::new(r"^\s*</\w+\s*>\s*$").unwrap();
+/// Checks the configuration files at `paths`, with their includes and drop-in fragments: the
+/// syntax problems [`policy::validate`] reports, and the rules shadowed by other ones as
+/// warnings. Findings are sorted by file, in reading order, and line.
+pub fn lint(paths: &[&str]) -> Result<Vec<LintFinding>, JanitorError> {
+    let mut sources = Vec::new();
+    for path in paths {
+        sources.extend(read_with_includes(Path::new(path))?);
+    }
 
-    for line in lines {
-        if let Some(captures) = start_tag_re.captures(&line) {
-            let tag = captures.get(1).unwrap().as_str().to_string();
-            skipping = tag != arch;
-            arch_tag = Some(tag);
-            continue;
-        }
+    let mut findings: Vec<(usize, LintFinding)> = Vec::new();
+    let mut rules = Vec::new();
+    for (index, source) in sources.iter().enumerate() {
+        findings.extend(policy::validate(&source.content).into_iter().map(|diagnostic| {
+            let path = source.path.clone();
+            (index, LintFinding { path, diagnostic })
+        }));
+        rules.extend(policy::section_rules(&source.content).into_iter().map(|r| (index, r)));
+    }
+    let section_rules: Vec<_> = rules.iter().map(|(_, rule)| rule.clone()).collect();
+    // Unknown precedences are reported by the validation.
+    let precedence = sources
+        .iter()
+        .flat_map(|source| source.content.lines())
+        .find_map(policy::precedence_directive)
+        .and_then(Precedence::parse)
+        .unwrap_or_default();
+    for (shadowed, shadowing) in policy::shadowed_rules(&section_rules, precedence) {
+        let (index, rule) = &rules[shadowed];
+        let (other_index, other) = &rules[shadowing];
+        let diagnostic = Diagnostic {
+            line: rule.line,
+            column: 1,
+            message: format!(
+                "rule '{}' is shadowed by '{}' at {}:{}, it never decides anything",
+                rule.text,
+                other.text,
+                sources[*other_index].path.display(),
+                other.line
+            ),
+            warning: true,
+        };
+        let path = sources[*index].path.clone();
+        findings.push((*index, LintFinding { path, diagnostic }));
+    }
+    findings.sort_by_key(|(index, finding)| (*index, finding.diagnostic.line, finding.diagnostic.column));
+    Ok(findings.into_iter().map(|(_, finding)| finding).collect())
+}
 
-        if end_tag_re.is_match(&line) {
-            skipping = false;
-            arch_tag = None;
-            continue;
-        }
+/// Reads the configuration files and returns the keep and delete rules for the current architecture.
+pub fn read_config(paths: &[&str], runner: &dyn CommandRunner) -> Result<Rules, JanitorError> {
+    read_config_with(paths, &[], runner)
+}
 
-        if skipping {
-            debug!(
-                "Ignoring {} specific line: {}",
-                arch_tag.as_deref().unwrap_or(""),
-                line
-            );
-        } else {
-            filtered.push(line);
+/// Like [`read_config`], with the rules of `extra_rules` before the ones of the files, e.g. the
+/// keep rules derived from a KIWI description.
+pub fn read_config_with(paths: &[&str], extra_rules: &[String], runner: &dyn CommandRunner) -> Result<Rules, JanitorError> {
+    let _span = info_span!("config").entered();
+    let mut lines = extra_rules.to_vec();
+    let mut errors = Vec::new();
+    for path in paths {
+        for source in read_with_includes(Path::new(path))? {
+            let path = source.path.display();
+            info!("Reading config file: {}", path);
+            for diagnostic in policy::validate(&source.content) {
+                if diagnostic.warning {
+                    warn!("{}:{}", path, diagnostic);
+                } else {
+                    errors.push(format!("{}:{}", path, diagnostic));
+                }
+            }
+            lines.extend(source.content.lines().map(String::from));
         }
     }
+    if !errors.is_empty() {
+        return Err(JanitorError::InvalidConfig(errors.join("\n")));
+    }
+
+    let arch = get_arch(runner)?;
+    debug!("Current architecture: {}", arch);
+    Rules::from_lines(lines, &arch)
+}
 
-    filtered
+fn get_arch(runner: &dyn CommandRunner) -> Result<String, JanitorError> {
+    runner.run("arch", &[])
 }
 
 #[cfg(test)]
@@ -103,37 +200,6 @@ mod tests {
         }
     }
 
-    #[test]
-    fn test_arch_filter() {
-        let lines = vec![
-            "<x86_64>".to_string(),
-            "intel_driver".to_string(),
-            "</x86_64>".to_string(),
-            "<aarch64>".to_string(),
-            "arm_driver".to_string(),
-            "</aarch64>".to_string(),
-            "<ppc64le>".to_string(),
-            "power_driver".to_string(),
-            "</ppc64le>".to_string(),
-            "<s390x>".to_string(),
-            "ibm_driver".to_string(),
-            "</s390x>".to_string(),
-            "common_driver".to_string(),
-        ];
-
-        let x86_64_lines = arch_filter(lines.clone(), "x86_64");
-        assert_eq!(x86_64_lines, vec!["intel_driver", "common_driver"]);
-
-        let aarch64_lines = arch_filter(lines.clone(), "aarch64");
-        assert_eq!(aarch64_lines, vec!["arm_driver", "common_driver"]);
-
-        let ppc64le_lines = arch_filter(lines.clone(), "ppc64le");
-        assert_eq!(ppc64le_lines, vec!["power_driver", "common_driver"]);
-
-        let s390x_lines = arch_filter(lines.clone(), "s390x");
-        assert_eq!(s390x_lines, vec!["ibm_driver", "common_driver"]);
-    }
-
     #[test]
     fn test_read_config_with_arch() {
         let mut commands = HashMap::new();
@@ -148,12 +214,144 @@ mod tests {
         )
         .unwrap();
 
-        let (to_keep, to_delete) =
-            read_config(&[config_path.to_str().unwrap()], &runner).unwrap();
+        let rules = read_config(&[config_path.to_str().unwrap()], &runner).unwrap();
+
+        assert_eq!(rules.keep.len(), 1);
+        assert_eq!(rules.delete.len(), 1);
+        assert!(rules.keep[0].is_match("keep_me"));
+        assert!(rules.delete[0].is_match("delete_me"));
+    }
+
+    #[test]
+    fn test_read_config_reports_locations() {
+        let mut commands = HashMap::new();
+        commands.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { commands };
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config_path = temp_dir.path().join("module.list");
+        fs::write(&config_path, "<x86_46>\nkeep_me\n</x86_46>\n-kernel/(sound\n").unwrap();
+        let path = config_path.to_str().unwrap();
+
+        match read_config(&[path], &runner) {
+            Err(JanitorError::InvalidConfig(message)) => assert_eq!(
+                message,
+                format!(
+                    "{0}:1:2: error: unknown architecture 'x86_46', did you mean 'x86_64'?\n\
+                     {0}:4:2: error: invalid regular expression: unclosed group",
+                    path
+                )
+            ),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_read_config_includes() {
+        let mut commands = HashMap::new();
+        commands.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { commands };
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(temp_dir.path().join("common")).unwrap();
+        fs::write(temp_dir.path().join("common/base.list"), "kernel/fs/.*\n-kernel/sound/.*\n").unwrap();
+        let flavor = temp_dir.path().join("server.list");
+        fs::write(&flavor, "include common/base.list\nkernel/drivers/scsi/.*\n").unwrap();
+
+        let sources = read_with_includes(&flavor).unwrap();
+        let paths: Vec<_> = sources.iter().map(|s| s.path.clone()).collect();
+        assert_eq!(paths, vec![flavor.clone(), temp_dir.path().join("common/base.list")]);
+
+        let rules = read_config(&[flavor.to_str().unwrap()], &runner).unwrap();
+        assert_eq!(rules.keep.len(), 2);
+        assert_eq!(rules.delete.len(), 1);
+        assert!(rules.keep.iter().any(|r| r.is_match("kernel/fs/ext4/ext4.ko")));
+    }
+
+    #[test]
+    fn test_read_config_include_cycle() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let a = temp_dir.path().join("a.list");
+        fs::write(&a, "include b.list\n").unwrap();
+        fs::write(temp_dir.path().join("b.list"), "include ./a.list\n").unwrap();
+
+        assert!(matches!(read_with_includes(&a), Err(JanitorError::InvalidConfig(_))));
+        fs::write(&a, "include missing.list\n").unwrap();
+        assert!(matches!(read_with_includes(&a), Err(JanitorError::ConfigRead(_, _))));
+    }
+
+    #[test]
+    fn test_read_config_drop_in_directory() {
+        let mut commands = HashMap::new();
+        commands.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { commands };
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let drop_in = temp_dir.path().join("module.list.d");
+        fs::create_dir_all(drop_in.join("nested.conf")).unwrap();
+        fs::write(drop_in.join("50-storage.list"), "include ../base.list\nkernel/drivers/nvme/.*\n").unwrap();
+        fs::write(drop_in.join("10-net.conf"), "kernel/drivers/net/.*\n").unwrap();
+        fs::write(drop_in.join("README"), "not a rule (\n").unwrap();
+        fs::write(temp_dir.path().join("base.list"), "kernel/fs/.*\n").unwrap();
+
+        let paths: Vec<_> = read_with_includes(&drop_in)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.path)
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                drop_in.join("10-net.conf"),
+                drop_in.join("50-storage.list"),
+                drop_in.join("../base.list"),
+            ]
+        );
+
+        let rules = read_config(&[drop_in.to_str().unwrap()], &runner).unwrap();
+        assert_eq!(rules.keep.len(), 3);
+    }
+
+    #[test]
+    fn test_lint() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let base = temp_dir.path().join("base.list");
+        fs::write(&base, "kernel/drivers/net/.*\n-kernel/drivers/net/wireless/.*\n<x86_64>\nkernel/(sound\n").unwrap();
+        let extra = temp_dir.path().join("extra.list");
+        fs::write(
+            &extra,
+            "kernel/drivers/net/ethernet/intel/e1000e.ko\nkernel/drivers/net/wireless/ath/.*\n\
+             -kernel/drivers/net/wireless/iwlwifi.ko\nkernel/fs/.*\nkernel/fs/.*\n",
+        )
+        .unwrap();
 
-        assert_eq!(to_keep.len(), 1);
-        assert_eq!(to_delete.len(), 1);
-        assert!(to_keep[0].is_match("keep_me"));
-        assert!(to_delete[0].is_match("delete_me"));
+        let findings: Vec<String> = lint(&[base.to_str().unwrap(), extra.to_str().unwrap()])
+            .unwrap()
+            .iter()
+            .map(|f| f.to_string())
+            .collect();
+        let (base, extra) = (base.display(), extra.display());
+        assert_eq!(
+            findings,
+            vec![
+                format!("{}:3:1: error: section <x86_64> is never closed", base),
+                format!("{}:4:1: error: invalid regular expression: unclosed group", base),
+                format!(
+                    "{}:1:1: warning: rule 'kernel/drivers/net/ethernet/intel/e1000e.ko' is shadowed by \
+                     'kernel/drivers/net/.*' at {}:1, it never decides anything",
+                    extra, base
+                ),
+                format!(
+                    "{}:3:1: warning: rule '-kernel/drivers/net/wireless/iwlwifi.ko' is shadowed by \
+                     '-kernel/drivers/net/wireless/.*' at {}:2, it never decides anything",
+                    extra, base
+                ),
+                format!(
+                    "{}:5:1: warning: rule 'kernel/fs/.*' is shadowed by 'kernel/fs/.*' at {}:4, \
+                     it never decides anything",
+                    extra, extra
+                ),
+            ]
+        );
     }
 }
@@ -0,0 +1,146 @@
+//! Hardlink deduplication of the firmware tree.
+//!
+//! linux-firmware ships many byte-identical blobs under different names. Replacing the copies
+//! with hardlinks to a single file keeps every name loadable while storing the content once.
+
+use crate::atomic;
+use crate::attributes::Attributes;
+use crate::error::JanitorError;
+use crate::interrupt;
+use crate::journal;
+use log::{debug, info};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// A file whose content is identical to another one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Duplicate {
+    pub path: PathBuf,
+    /// The file `path` is (or would be) a hardlink to, the first of the identical files.
+    pub original: PathBuf,
+    pub size: u64,
+}
+
+/// Finds the regular files of `fw_dir` with identical content and, if `link` is set, replaces
+/// each copy with a hardlink to the first of them. Files already hardlinked together are not
+/// reported. Symlinks are left alone, and so are the copies whose mode, ownership or extended
+/// attributes (e.g. SELinux labels) differ from the first file, as a hardlink would lose them.
+pub fn dedupe_firmware(fw_dir: &Path, link: bool) -> Result<Vec<Duplicate>, JanitorError> {
+    atomic::remove_orphans(fw_dir, link)?;
+
+    // Only files of the same size can be identical, the others are never hashed.
+    let mut by_size: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+    for entry in WalkDir::new(fw_dir).sort_by_file_name().into_iter().filter_map(Result::ok) {
+        if entry.file_type().is_file() {
+            let size = entry.metadata().map_err(std::io::Error::from)?.len();
+            if size > 0 {
+                by_size.entry(size).or_default().push(entry.into_path());
+            }
+        }
+    }
+
+    let mut duplicates = Vec::new();
+    for (size, paths) in by_size.into_iter().filter(|(_, paths)| paths.len() > 1) {
+        let mut by_hash: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+        for path in paths {
+            interrupt::check()?;
+            by_hash.entry(journal::hash_file(&path)?).or_default().push(path);
+        }
+        for paths in by_hash.into_values() {
+            let original = &paths[0];
+            let original_inode = inode(original)?;
+            let original_attributes = Attributes::read(original)?;
+            for path in &paths[1..] {
+                if inode(path)? == original_inode {
+                    debug!("{} is already a hardlink to {}", path.display(), original.display());
+                    continue;
+                }
+                if Attributes::read(path)? != original_attributes {
+                    info!(
+                        "Not linking {} to {}, their attributes differ",
+                        path.display(),
+                        original.display()
+                    );
+                    continue;
+                }
+                if link {
+                    info!("Linking {} to {}", path.display(), original.display());
+                    atomic::link_atomic(original, path)?;
+                } else {
+                    debug!("Found duplicate {} of {}", path.display(), original.display());
+                }
+                duplicates.push(Duplicate {
+                    path: path.clone(),
+                    original: original.clone(),
+                    size,
+                });
+            }
+        }
+    }
+
+    let savings: u64 = duplicates.iter().map(|d| d.size).sum();
+    info!("Potential savings: {} ({} MiB)", savings, savings >> 20);
+    Ok(duplicates)
+}
+
+/// Returns the device and inode of `path`, identifying hardlinks to the same file.
+fn inode(path: &Path) -> Result<(u64, u64), JanitorError> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_dedupe_firmware() {
+        let temp_dir = tempdir().unwrap();
+        let fw_dir = temp_dir.path();
+        fs::create_dir_all(fw_dir.join("a")).unwrap();
+        fs::create_dir_all(fw_dir.join("b")).unwrap();
+        fs::write(fw_dir.join("a/fw.bin"), "blob").unwrap();
+        fs::write(fw_dir.join("b/fw.bin"), "blob").unwrap();
+        fs::write(fw_dir.join("b/other.bin"), "blab").unwrap();
+        fs::write(fw_dir.join("b/empty.bin"), "").unwrap();
+        fs::write(fw_dir.join("b/empty2.bin"), "").unwrap();
+        symlink("fw.bin", fw_dir.join("b/link.bin")).unwrap();
+
+        let expected = vec![Duplicate {
+            path: fw_dir.join("b/fw.bin"),
+            original: fw_dir.join("a/fw.bin"),
+            size: 4,
+        }];
+        assert_eq!(dedupe_firmware(fw_dir, false).unwrap(), expected);
+        assert_ne!(inode(&fw_dir.join("a/fw.bin")).unwrap(), inode(&fw_dir.join("b/fw.bin")).unwrap());
+
+        assert_eq!(dedupe_firmware(fw_dir, true).unwrap(), expected);
+        assert_eq!(inode(&fw_dir.join("a/fw.bin")).unwrap(), inode(&fw_dir.join("b/fw.bin")).unwrap());
+        assert_eq!(fs::read_to_string(fw_dir.join("b/fw.bin")).unwrap(), "blob");
+        assert!(fs::symlink_metadata(fw_dir.join("b/link.bin")).unwrap().file_type().is_symlink());
+
+        // Once linked, nothing is left to deduplicate.
+        assert!(dedupe_firmware(fw_dir, true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dedupe_keeps_different_attributes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempdir().unwrap();
+        let fw_dir = temp_dir.path();
+        fs::write(fw_dir.join("a.bin"), "blob").unwrap();
+        fs::write(fw_dir.join("b.bin"), "blob").unwrap();
+        fs::set_permissions(fw_dir.join("b.bin"), fs::Permissions::from_mode(0o600)).unwrap();
+
+        // A hardlink would give b.bin the mode of a.bin.
+        assert!(dedupe_firmware(fw_dir, true).unwrap().is_empty());
+        assert_ne!(inode(&fw_dir.join("a.bin")).unwrap(), inode(&fw_dir.join("b.bin")).unwrap());
+    }
+}
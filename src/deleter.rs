@@ -0,0 +1,432 @@
+use crate::backup::Backup;
+use crate::clock::{Clock, SystemClock};
+use crate::error::JanitorError;
+use crate::interrupt;
+use crate::janitor_fs::{JanitorFs, RealFs};
+use crate::journal::{Journal, JournalEntry};
+use crate::quarantine::Quarantine;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Removes files on behalf of the cleanup passes and records what was removed.
+///
+/// In a dry run nothing is touched on disk, but the files that would have been
+/// removed are still recorded.
+pub struct Deleter {
+    delete: bool,
+    files: Vec<PathBuf>,
+    bytes: u64,
+    reasons: BTreeMap<String, usize>,
+    interrupted: fn() -> bool,
+    backup: Option<Backup>,
+    journal: Option<Journal>,
+    quarantine: Option<Quarantine>,
+    clock: Arc<dyn Clock>,
+    fs: Arc<dyn JanitorFs>,
+}
+
+impl Deleter {
+    pub fn new(delete: bool) -> Self {
+        Deleter {
+            delete,
+            files: Vec::new(),
+            bytes: 0,
+            reasons: BTreeMap::new(),
+            interrupted: interrupt::is_interrupted,
+            backup: None,
+            journal: None,
+            quarantine: None,
+            clock: Arc::new(SystemClock),
+            fs: Arc::new(RealFs),
+        }
+    }
+
+    /// Copies every removed file and directory to a backup archive at `path` before deleting
+    /// it. Nothing is written in a dry run.
+    pub fn with_backup(mut self, path: &Path) -> Result<Self, JanitorError> {
+        if self.delete {
+            self.backup = Some(Backup::create(path)?);
+        }
+        Ok(self)
+    }
+
+    /// Records every deletion in the journal at `path`. Nothing is written in a dry run.
+    pub fn with_journal(mut self, path: &Path) -> Result<Self, JanitorError> {
+        if self.delete {
+            self.journal = Some(Journal::create(path)?);
+        }
+        Ok(self)
+    }
+
+    /// Timestamps the journal entries with `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Moves the removed files to a run directory of the quarantine directory `dir` instead of
+    /// unlinking them, see [`Quarantine`]. The run is timestamped with the clock set so far.
+    /// Nothing is moved in a dry run.
+    pub fn with_quarantine(mut self, dir: &Path) -> Result<Self, JanitorError> {
+        if self.delete {
+            self.quarantine = Some(Quarantine::create(dir, self.clock.as_ref())?);
+        }
+        Ok(self)
+    }
+
+    /// Stops once `interrupted` returns true instead of on termination signals.
+    pub fn with_interrupt(mut self, interrupted: fn() -> bool) -> Self {
+        self.interrupted = interrupted;
+        self
+    }
+
+    /// Removes the files through `fs` instead of the host filesystem.
+    pub fn with_fs(mut self, fs: Arc<dyn JanitorFs>) -> Self {
+        self.fs = fs;
+        self
+    }
+
+    /// Completes the run, finalizing the backup archive, and returns the removed files.
+    pub fn finish(self) -> Result<Vec<PathBuf>, JanitorError> {
+        if let Some(backup) = self.backup {
+            backup.finish()?;
+        }
+        Ok(self.files)
+    }
+
+    /// Whether files are really deleted.
+    pub fn is_deleting(&self) -> bool {
+        self.delete
+    }
+
+    /// Files deleted so far (or that would be deleted in a dry run).
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Size of the files deleted so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Number of files deleted so far for each reason.
+    pub fn reasons(&self) -> &BTreeMap<String, usize> {
+        &self.reasons
+    }
+
+    /// Removes `path`, whose size is `size`, for `reason`. Stops with `JanitorError::Interrupted`
+    /// once a termination signal was received, so the current file is always fully processed.
+    pub fn remove_file(&mut self, path: &Path, size: u64, reason: &str) -> Result<(), JanitorError> {
+        self.check_interrupted()?;
+        if self.delete {
+            let entry = self.save(path, reason)?;
+            let quarantined = unlink(self.fs.as_ref(), self.quarantine.as_ref(), path)?;
+            self.journal(entry.map(|entry| JournalEntry { quarantined, ..entry }))?;
+        }
+        self.account(path, size, reason);
+        Ok(())
+    }
+
+    /// Removes `files`, each given with its size and reason, in parallel on the rayon thread
+    /// pool, whose size `--jobs` bounds. They are backed up first, in order, and the ones removed
+    /// are journaled afterwards; the journal and then the directories of the removed files are
+    /// synced once for the whole batch, also when a removal failed. Stops with
+    /// `JanitorError::Interrupted` once a termination signal was received: the files being
+    /// removed are fully processed, the others are left.
+    pub fn remove_files(&mut self, files: &[(PathBuf, u64, &str)]) -> Result<(), JanitorError> {
+        self.check_interrupted()?;
+        if !self.delete {
+            for (path, size, reason) in files {
+                self.account(path, *size, reason);
+            }
+            return Ok(());
+        }
+        // The entries are described before the files are gone, and recorded once they are.
+        let mut entries = Vec::new();
+        if self.journal.is_some() {
+            let clock = self.clock.as_ref();
+            entries = files
+                .par_iter()
+                .map(|(path, _, reason)| JournalEntry::describe(path, reason, clock))
+                .collect::<Result<Vec<_>, _>>()?;
+        }
+        if let Some(backup) = &mut self.backup {
+            for (path, _, _) in files {
+                backup.add(path)?;
+            }
+        }
+        let (interrupted, fs, quarantine) = (self.interrupted, &self.fs, &self.quarantine);
+        // None for the files left once interrupted.
+        let results: Vec<Option<Result<Option<PathBuf>, JanitorError>>> = files
+            .par_iter()
+            .map(|(path, _, _)| (!interrupted()).then(|| unlink(fs.as_ref(), quarantine.as_ref(), path)))
+            .collect();
+        let mut dirs = BTreeSet::new();
+        let mut error = None;
+        // The quarantine location of the files removed, None for the others.
+        let mut removed = vec![None; files.len()];
+        for (index, ((path, size, reason), result)) in files.iter().zip(results).enumerate() {
+            match result {
+                Some(Ok(quarantined)) => {
+                    dirs.extend(path.parent().map(Path::to_path_buf));
+                    self.account(path, *size, reason);
+                    removed[index] = Some(quarantined);
+                }
+                Some(Err(e)) => {
+                    error.get_or_insert(e);
+                }
+                None => {}
+            }
+        }
+        if let Some(journal) = &mut self.journal {
+            let entries: Vec<JournalEntry> = entries
+                .into_iter()
+                .zip(removed)
+                .filter_map(|(entry, quarantined)| Some(JournalEntry { quarantined: quarantined?, ..entry }))
+                .collect();
+            journal.record_all(&entries)?;
+        }
+        for dir in &dirs {
+            self.fs.sync_dir(dir)?;
+        }
+        match error {
+            Some(e) => Err(e),
+            None => self.check_interrupted(),
+        }
+    }
+
+    /// Records the removal of `path`, whose size is `size`, for `reason`.
+    fn account(&mut self, path: &Path, size: u64, reason: &str) {
+        self.files.push(path.to_path_buf());
+        self.bytes += size;
+        *self.reasons.entry(reason.to_string()).or_default() += 1;
+    }
+
+    /// Removes the empty directory `path`, for `reason`.
+    pub fn remove_dir(&mut self, path: &Path, reason: &str) -> Result<(), JanitorError> {
+        self.check_interrupted()?;
+        if self.delete {
+            let entry = self.save(path, reason)?;
+            self.fs.remove_dir(path)?;
+            self.journal(entry)?;
+        }
+        Ok(())
+    }
+
+    /// Copies `path` to the backup before its deletion, and returns its journal entry if a
+    /// journal is written.
+    fn save(&mut self, path: &Path, reason: &str) -> Result<Option<JournalEntry>, JanitorError> {
+        let entry = match self.journal {
+            Some(_) => Some(JournalEntry::describe(path, reason, self.clock.as_ref())?),
+            None => None,
+        };
+        if let Some(backup) = &mut self.backup {
+            backup.add(path)?;
+        }
+        Ok(entry)
+    }
+
+    /// Records `entry`, returned by [`Self::save`], once its path is deleted.
+    fn journal(&mut self, entry: Option<JournalEntry>) -> Result<(), JanitorError> {
+        if let (Some(journal), Some(entry)) = (&mut self.journal, entry) {
+            journal.record(&entry)?;
+        }
+        Ok(())
+    }
+
+    /// Stops with `JanitorError::Interrupted`, listing the files deleted so far, once a
+    /// termination signal was received. To be called by the passes between units of work.
+    pub fn check_interrupted(&self) -> Result<(), JanitorError> {
+        if !(self.interrupted)() {
+            return Ok(());
+        }
+        Err(self.complete_interrupted(JanitorError::Interrupted {
+            deleted: Vec::new(),
+            bytes: 0,
+        }))
+    }
+
+    /// Completes a `JanitorError::Interrupted` raised outside the deleter, e.g. by a module scan,
+    /// with the files deleted so far. Other errors are returned as they are.
+    pub fn complete_interrupted(&self, error: JanitorError) -> JanitorError {
+        match error {
+            JanitorError::Interrupted { .. } if self.delete => JanitorError::Interrupted {
+                deleted: self.files.clone(),
+                bytes: self.bytes,
+            },
+            error => error,
+        }
+    }
+}
+
+/// Unlinks `path` through `fs`, or moves it to `quarantine` if set. Returns where it was moved.
+fn unlink(fs: &dyn JanitorFs, quarantine: Option<&Quarantine>, path: &Path) -> Result<Option<PathBuf>, JanitorError> {
+    match quarantine {
+        Some(quarantine) => Ok(Some(quarantine.move_file(path)?)),
+        None => Ok(fs.remove_file(path).map(|()| None)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::clock::FixedClock;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tempfile::tempdir;
+
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    #[test]
+    fn test_dry_run_keeps_files() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("file");
+        fs::write(&path, "data").unwrap();
+
+        let mut deleter = Deleter::new(false);
+        deleter.remove_file(&path, 4, "test").unwrap();
+
+        assert!(path.exists());
+        assert_eq!(deleter.files(), &[path]);
+        assert_eq!(deleter.bytes(), 4);
+    }
+
+    #[test]
+    fn test_interrupted_reports_deleted_files() {
+        let temp_dir = tempdir().unwrap();
+        let first = temp_dir.path().join("first");
+        let second = temp_dir.path().join("second");
+        fs::write(&first, "data").unwrap();
+        fs::write(&second, "data").unwrap();
+
+        let mut deleter = Deleter {
+            interrupted: || STOP.load(Ordering::SeqCst),
+            ..Deleter::new(true)
+        };
+        deleter.remove_file(&first, 4, "test").unwrap();
+        STOP.store(true, Ordering::SeqCst);
+        let result = deleter.remove_file(&second, 4, "test");
+
+        match result {
+            Err(JanitorError::Interrupted { deleted, bytes }) => {
+                assert_eq!(deleted, vec![first.clone()]);
+                assert_eq!(bytes, 4);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(!first.exists());
+        assert!(second.exists());
+    }
+
+    #[test]
+    fn test_backup_before_delete() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().join("dir");
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("file");
+        fs::write(&path, "data").unwrap();
+        let archive = temp_dir.path().join("backup.tar.zst");
+
+        let mut deleter = Deleter::new(true).with_backup(&archive).unwrap();
+        deleter.remove_file(&path, 4, "test").unwrap();
+        deleter.remove_dir(&dir, "test").unwrap();
+        assert_eq!(deleter.finish().unwrap(), vec![path.clone()]);
+        assert!(!dir.exists());
+
+        let restored = crate::backup::restore(&archive, Path::new("/")).unwrap();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+    }
+
+    #[test]
+    fn test_journal_uses_clock() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("file");
+        fs::write(&path, "data").unwrap();
+        let journal = temp_dir.path().join("journal.jsonl");
+
+        let mut deleter = Deleter::new(true)
+            .with_journal(&journal)
+            .unwrap()
+            .with_clock(Arc::new(FixedClock::from_unix_seconds(1_700_000_000)));
+        deleter.remove_file(&path, 4, "test").unwrap();
+
+        let entries = crate::journal::read_journal(&journal).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].timestamp, 1_700_000_000);
+    }
+
+    #[test]
+    fn test_quarantine_instead_of_delete() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("file");
+        fs::write(&path, "data").unwrap();
+        let quarantine = temp_dir.path().join("quarantine");
+
+        let mut deleter = Deleter::new(true)
+            .with_clock(Arc::new(FixedClock::from_unix_seconds(1_700_000_000)))
+            .with_quarantine(&quarantine)
+            .unwrap();
+        deleter.remove_file(&path, 4, "test").unwrap();
+
+        assert!(!path.exists());
+        let moved = quarantine.join("1700000000").join(path.strip_prefix("/").unwrap());
+        assert_eq!(fs::read_to_string(moved).unwrap(), "data");
+    }
+
+    #[test]
+    fn test_remove_files_in_parallel() {
+        let temp_dir = tempdir().unwrap();
+        let mut files = Vec::new();
+        for dir in ["a", "b"] {
+            fs::create_dir(temp_dir.path().join(dir)).unwrap();
+            for i in 0..25 {
+                let path = temp_dir.path().join(dir).join(format!("{}.bin", i));
+                fs::write(&path, "data").unwrap();
+                files.push((path, 4, if dir == "a" { "unused" } else { "dropped" }));
+            }
+        }
+        let journal = temp_dir.path().join("journal.jsonl");
+
+        let mut dry_run = Deleter::new(false);
+        dry_run.remove_files(&files).unwrap();
+        assert_eq!(dry_run.files().len(), 50);
+        assert!(files.iter().all(|(path, _, _)| path.exists()));
+
+        let mut deleter = Deleter::new(true).with_journal(&journal).unwrap();
+        deleter.remove_files(&files).unwrap();
+        let paths: Vec<PathBuf> = files.iter().map(|(path, _, _)| path.clone()).collect();
+        assert_eq!(deleter.files(), paths.as_slice());
+        assert_eq!(deleter.bytes(), 200);
+        assert_eq!(deleter.reasons()["unused"], 25);
+        assert!(paths.iter().all(|path| !path.exists()));
+        assert_eq!(crate::journal::read_journal(&journal).unwrap().len(), 50);
+    }
+
+    #[test]
+    fn test_remove_files_journals_completed_removals() {
+        let temp_dir = tempdir().unwrap();
+        let first = temp_dir.path().join("first");
+        let second = temp_dir.path().join("second");
+        let dir = temp_dir.path().join("dir");
+        fs::write(&first, "data").unwrap();
+        fs::write(&second, "data").unwrap();
+        fs::create_dir(&dir).unwrap();
+        let journal = temp_dir.path().join("journal.jsonl");
+
+        // Unlinking a directory fails, the files around it are still removed.
+        let files = [(first.clone(), 4, "test"), (dir.clone(), 0, "test"), (second.clone(), 4, "test")];
+        let mut deleter = Deleter::new(true).with_journal(&journal).unwrap();
+        assert!(deleter.remove_files(&files).is_err());
+        assert_eq!(deleter.files(), &[first.clone(), second.clone()]);
+        assert!(dir.exists());
+        let journaled: Vec<PathBuf> = crate::journal::read_journal(&journal)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(journaled, vec![first, second]);
+    }
+}
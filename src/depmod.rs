@@ -0,0 +1,229 @@
+use crate::error::JanitorError;
+use crate::kmod_index;
+use crate::modprobe::ModprobeConfig;
+use crate::policy::Alias;
+use crate::util;
+use log::debug;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Dependencies between modules as recorded by depmod, keyed by module name.
+pub type DependencyMap = HashMap<String, Vec<String>>;
+
+/// Reads the module dependency graph generated by depmod in `kernel_dir`.
+///
+/// `modules.dep` is preferred, `modules.dep.bin` is used when only the binary index is present.
+/// Returns `None` if the kernel directory has no dependency information at all.
+pub fn read_dependencies(kernel_dir: &Path) -> Result<Option<DependencyMap>, JanitorError> {
+    let text_path = kernel_dir.join("modules.dep");
+    if text_path.exists() {
+        debug!("Reading module dependencies from {}", text_path.display());
+        let content = fs::read_to_string(&text_path)?;
+        return Ok(Some(content.lines().filter_map(parse_dep_line).collect()));
+    }
+
+    let bin_path = kernel_dir.join("modules.dep.bin");
+    if bin_path.exists() {
+        debug!("Reading module dependencies from {}", bin_path.display());
+        let entries = kmod_index::read_index(&bin_path)?;
+        return Ok(Some(
+            entries
+                .iter()
+                .filter_map(|e| parse_dep_line(&e.value))
+                .collect(),
+        ));
+    }
+
+    Ok(None)
+}
+
+/// Reads the module aliases generated by depmod in `kernel_dir`, from `modules.alias` or,
+/// when only the binary index is present, from `modules.alias.bin`.
+pub fn read_aliases(kernel_dir: &Path) -> Result<Vec<Alias>, JanitorError> {
+    let text_path = kernel_dir.join("modules.alias");
+    if text_path.exists() {
+        debug!("Reading module aliases from {}", text_path.display());
+        let content = fs::read_to_string(&text_path)?;
+        return Ok(content.lines().filter_map(parse_alias_line).collect());
+    }
+
+    let bin_path = kernel_dir.join("modules.alias.bin");
+    if bin_path.exists() {
+        debug!("Reading module aliases from {}", bin_path.display());
+        let entries = kmod_index::read_index(&bin_path)?;
+        return Ok(entries
+            .into_iter()
+            .map(|e| Alias {
+                pattern: e.key,
+                module: e.value,
+            })
+            .collect());
+    }
+
+    Err(JanitorError::MissingIndex(text_path))
+}
+
+/// Parses an `alias <pattern> <module>` line.
+fn parse_alias_line(line: &str) -> Option<Alias> {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("alias"), Some(pattern), Some(module)) => Some(Alias {
+            pattern: pattern.to_string(),
+            module: module.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+/// Reads the soft dependencies depmod extracted from the modules into `modules.softdep`.
+pub fn read_softdeps(kernel_dir: &Path) -> Result<ModprobeConfig, JanitorError> {
+    let mut config = ModprobeConfig::default();
+    let path = kernel_dir.join("modules.softdep");
+    if path.exists() {
+        debug!("Reading module soft dependencies from {}", path.display());
+        config.parse(&fs::read_to_string(&path)?);
+    }
+    Ok(config)
+}
+
+/// Parses a `path: dep_path dep_path...` line into the module name and the names of its dependencies.
+fn parse_dep_line(line: &str) -> Option<(String, Vec<String>)> {
+    let (module, deps) = line.split_once(':')?;
+    let module = module.trim();
+    if module.is_empty() {
+        return None;
+    }
+    let deps = deps
+        .split_whitespace()
+        .map(|d| util::module_name(Path::new(d)))
+        .collect();
+    Some((util::module_name(Path::new(module)), deps))
+}
+
+/// Returns the binary indexes (`modules.*.bin`) depmod generated in `kernel_dir`. kmod only
+/// needs them for speed, depmod regenerates them from the modules.
+pub fn binary_indexes(kernel_dir: &Path) -> Result<Vec<PathBuf>, JanitorError> {
+    let mut indexes = Vec::new();
+    for entry in fs::read_dir(kernel_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with("modules.") && name.ends_with(".bin") && entry.file_type()?.is_file() {
+            indexes.push(entry.path());
+        }
+    }
+    indexes.sort();
+    Ok(indexes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_read_modules_dep() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(
+            temp_dir.path().join("modules.dep"),
+            "kernel/drivers/net/e1000e.ko.zst: kernel/drivers/ptp/ptp.ko.zst kernel/drivers/pps/pps_core.ko.zst\n\
+             kernel/drivers/ptp/ptp.ko.zst: kernel/drivers/pps/pps_core.ko.zst\n\
+             kernel/drivers/pps/pps_core.ko.zst:\n",
+        )
+        .unwrap();
+
+        let deps = read_dependencies(temp_dir.path()).unwrap().unwrap();
+        assert_eq!(deps.len(), 3);
+        assert_eq!(deps["e1000e"], vec!["ptp", "pps_core"]);
+        assert_eq!(deps["ptp"], vec!["pps_core"]);
+        assert!(deps["pps_core"].is_empty());
+    }
+
+    #[test]
+    fn test_read_modules_dep_bin() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(
+            temp_dir.path().join("modules.dep.bin"),
+            kmod_index::build_test_index(&[
+                (
+                    "snd_hda_intel",
+                    "kernel/sound/snd-hda-intel.ko.xz: kernel/sound/snd-hda-codec.ko.xz",
+                ),
+                ("usbcore", "kernel/drivers/usb/usbcore.ko.xz:"),
+            ]),
+        )
+        .unwrap();
+
+        let deps = read_dependencies(temp_dir.path()).unwrap().unwrap();
+        assert_eq!(deps["snd_hda_intel"], vec!["snd_hda_codec"]);
+        assert!(deps["usbcore"].is_empty());
+    }
+
+    #[test]
+    fn test_read_modules_softdep() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(
+            temp_dir.path().join("modules.softdep"),
+            "# Soft dependencies extracted from modules themselves.\nsoftdep btrfs pre: crc32c\n",
+        )
+        .unwrap();
+
+        let softdeps = read_softdeps(temp_dir.path()).unwrap().softdeps;
+        assert_eq!(softdeps["btrfs"].pre, vec!["crc32c"]);
+    }
+
+    #[test]
+    fn test_read_modules_alias() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(
+            temp_dir.path().join("modules.alias"),
+            "# Aliases extracted from modules themselves.\nalias pci:v00008086d000010D3sv*sd*bc*sc*i* e1000e\nalias fs-btrfs btrfs\n",
+        )
+        .unwrap();
+
+        let aliases = read_aliases(temp_dir.path()).unwrap();
+        assert_eq!(aliases.len(), 2);
+        assert_eq!(aliases[0].pattern, "pci:v00008086d000010D3sv*sd*bc*sc*i*");
+        assert_eq!(aliases[0].module, "e1000e");
+    }
+
+    #[test]
+    fn test_read_modules_alias_bin() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(
+            temp_dir.path().join("modules.alias.bin"),
+            kmod_index::build_test_index(&[("fs-btrfs", "btrfs")]),
+        )
+        .unwrap();
+
+        let aliases = read_aliases(temp_dir.path()).unwrap();
+        assert_eq!(
+            aliases,
+            vec![Alias {
+                pattern: "fs-btrfs".to_string(),
+                module: "btrfs".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_read_dependencies_missing() {
+        let temp_dir = tempdir().unwrap();
+        assert!(read_dependencies(temp_dir.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_binary_indexes() {
+        let temp_dir = tempdir().unwrap();
+        for name in ["modules.dep", "modules.dep.bin", "modules.alias.bin", "modules.builtin.modinfo"] {
+            fs::write(temp_dir.path().join(name), "").unwrap();
+        }
+        assert_eq!(
+            binary_indexes(temp_dir.path()).unwrap(),
+            vec![
+                temp_dir.path().join("modules.alias.bin"),
+                temp_dir.path().join("modules.dep.bin"),
+            ]
+        );
+    }
+}
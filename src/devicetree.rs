@@ -0,0 +1,326 @@
+//! Device tree of ARM boards.
+//!
+//! On boards described by a device tree, most devices sit on no discoverable bus: the kernel
+//! creates a platform device for each enabled node, whose modalias is built from the node name,
+//! type and `compatible` strings, and binds it to the drivers declaring a matching `of:` alias.
+//! Reading the nodes from the flattened device tree (DTB) of a board, or from the
+//! `/proc/device-tree` of a running one, gives the modaliases of its platform devices.
+
+use crate::error::JanitorError;
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Magic number starting a flattened device tree.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// A device tree node with a `compatible` property.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Node {
+    /// Node name without its unit address, e.g. `serial` for `serial@2020000`.
+    pub name: String,
+    pub device_type: Option<String>,
+    pub compatible: Vec<String>,
+}
+
+impl Node {
+    /// Returns the modalias of the platform device of the node, as the kernel builds it, e.g.
+    /// `of:NserialT(null)Cfsl,imx6q-uartCfsl,imx21-uart`.
+    pub fn modalias(&self) -> String {
+        let mut modalias = format!(
+            "of:N{}T{}",
+            self.name,
+            // Recent kernels print a missing type this way.
+            self.device_type.as_deref().unwrap_or("(null)")
+        );
+        for compatible in &self.compatible {
+            modalias.push('C');
+            modalias.push_str(compatible);
+        }
+        modalias
+    }
+}
+
+/// Properties of the node being parsed.
+struct NodeProperties {
+    node: Node,
+    disabled: bool,
+}
+
+impl NodeProperties {
+    fn new(full_name: &str) -> Self {
+        let name = full_name.split('@').next().unwrap_or_default();
+        NodeProperties {
+            node: Node {
+                name: name.to_string(),
+                ..Default::default()
+            },
+            disabled: false,
+        }
+    }
+
+    fn set(&mut self, name: &str, value: &[u8]) {
+        match name {
+            "compatible" => self.node.compatible = strings(value),
+            "device_type" => self.node.device_type = strings(value).into_iter().next(),
+            "status" => {
+                self.disabled = !matches!(
+                    strings(value).first().map(String::as_str),
+                    Some("okay" | "ok")
+                )
+            }
+            _ => {}
+        }
+    }
+
+    /// The node, unless it has no platform device.
+    fn into_node(self) -> Option<Node> {
+        if self.disabled || self.node.compatible.is_empty() {
+            None
+        } else {
+            Some(self.node)
+        }
+    }
+}
+
+/// Returns the NUL-separated strings of a property value.
+fn strings(value: &[u8]) -> Vec<String> {
+    value
+        .split(|b| *b == 0)
+        .filter(|s| !s.is_empty())
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+/// Returns the enabled nodes with a `compatible` property of the flattened device tree `data`.
+pub fn parse_dtb(data: &[u8]) -> Result<Vec<Node>, String> {
+    let word = |offset: usize| -> Result<u32, String> {
+        data.get(offset..offset + 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(|| format!("truncated at offset {}", offset))
+    };
+    if word(0)? != FDT_MAGIC {
+        return Err("not a flattened device tree".to_string());
+    }
+    let structs = word(8)? as usize;
+    let strings_block = word(12)? as usize;
+    let string_at = |offset: usize| -> Result<&[u8], String> {
+        let rest = data
+            .get(offset..)
+            .ok_or_else(|| format!("string out of bounds at offset {}", offset))?;
+        Ok(rest.split(|b| *b == 0).next().unwrap_or_default())
+    };
+    let aligned = |offset: usize| (offset + 3) & !3;
+
+    let mut nodes = Vec::new();
+    let mut stack: Vec<NodeProperties> = Vec::new();
+    let mut offset = structs;
+    loop {
+        let token = word(offset)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = string_at(offset)?;
+                offset = aligned(offset + name.len() + 1);
+                stack.push(NodeProperties::new(&String::from_utf8_lossy(name)));
+            }
+            FDT_END_NODE => {
+                let properties = stack.pop().ok_or("unbalanced end of node")?;
+                nodes.extend(properties.into_node());
+            }
+            FDT_PROP => {
+                let len = word(offset)? as usize;
+                let name = string_at(strings_block + word(offset + 4)? as usize)?;
+                let value = data
+                    .get(offset + 8..offset + 8 + len)
+                    .ok_or_else(|| format!("property out of bounds at offset {}", offset))?;
+                if let Some(properties) = stack.last_mut() {
+                    properties.set(&String::from_utf8_lossy(name), value);
+                }
+                offset = aligned(offset + 8 + len);
+            }
+            FDT_NOP => {}
+            FDT_END => break,
+            token => return Err(format!("unknown token {} at offset {}", token, offset - 4)),
+        }
+    }
+    Ok(nodes)
+}
+
+/// Reads the nodes of the DTB file at `path`, see [`parse_dtb`].
+pub fn read_dtb(path: &Path) -> Result<Vec<Node>, JanitorError> {
+    let data = fs::read(path)?;
+    parse_dtb(&data).map_err(|e| JanitorError::DeviceTreeParse(path.to_path_buf(), e))
+}
+
+/// Reads the enabled nodes with a `compatible` property of the device tree exposed by a running
+/// kernel at `dir`, e.g. `/proc/device-tree`, where each node is a directory and each property
+/// a file.
+pub fn read_device_tree_dir(dir: &Path) -> Result<Vec<Node>, JanitorError> {
+    let mut nodes = Vec::new();
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+        let name = match entry.depth() {
+            0 => String::new(),
+            _ => entry.file_name().to_string_lossy().into_owned(),
+        };
+        let mut properties = NodeProperties::new(&name);
+        for property in ["compatible", "device_type", "status"] {
+            if let Ok(value) = fs::read(entry.path().join(property)) {
+                properties.set(property, &value);
+            }
+        }
+        nodes.extend(properties.into_node());
+    }
+    Ok(nodes)
+}
+
+/// Returns the modaliases of `nodes`.
+pub fn modaliases(nodes: &[Node]) -> Vec<String> {
+    nodes.iter().map(Node::modalias).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::{self, Alias};
+    use tempfile::tempdir;
+
+    /// A node name, its properties and its depth.
+    type TestNode<'a> = (&'a str, &'a [(&'a str, &'a str)], usize);
+
+    /// Builds a flattened device tree of `nodes`, in depth-first order.
+    fn build_dtb(nodes: &[TestNode]) -> Vec<u8> {
+        let mut structs = Vec::new();
+        let mut strings = Vec::new();
+        let pad = |v: &mut Vec<u8>| v.resize((v.len() + 3) & !3, 0);
+        let mut depth = 0;
+        for (name, properties, node_depth) in nodes {
+            while depth > *node_depth {
+                structs.extend(FDT_END_NODE.to_be_bytes());
+                depth -= 1;
+            }
+            structs.extend(FDT_BEGIN_NODE.to_be_bytes());
+            structs.extend(name.as_bytes());
+            structs.push(0);
+            pad(&mut structs);
+            for (property, value) in *properties {
+                let value = format!("{}\0", value.replace('|', "\0"));
+                structs.extend(FDT_PROP.to_be_bytes());
+                structs.extend((value.len() as u32).to_be_bytes());
+                structs.extend((strings.len() as u32).to_be_bytes());
+                structs.extend(value.as_bytes());
+                pad(&mut structs);
+                strings.extend(property.as_bytes());
+                strings.push(0);
+            }
+            depth = node_depth + 1;
+        }
+        for _ in 0..depth {
+            structs.extend(FDT_END_NODE.to_be_bytes());
+        }
+        structs.extend(FDT_NOP.to_be_bytes());
+        structs.extend(FDT_END.to_be_bytes());
+
+        let header_len = 40;
+        let mut dtb = Vec::new();
+        for value in [
+            FDT_MAGIC,
+            (header_len + structs.len() + strings.len()) as u32,
+            header_len as u32,
+            (header_len + structs.len()) as u32,
+            header_len as u32,
+            17,
+            16,
+            0,
+            strings.len() as u32,
+            structs.len() as u32,
+        ] {
+            dtb.extend(value.to_be_bytes());
+        }
+        dtb.extend(structs);
+        dtb.extend(strings);
+        dtb
+    }
+
+    #[test]
+    fn test_parse_dtb() {
+        let dtb = build_dtb(&[
+            ("", &[("compatible", "fsl,imx6q-sabresd|fsl,imx6q")], 0),
+            ("soc", &[("compatible", "simple-bus")], 1),
+            (
+                "serial@2020000",
+                &[
+                    ("compatible", "fsl,imx6q-uart|fsl,imx21-uart"),
+                    ("status", "okay"),
+                ],
+                2,
+            ),
+            (
+                "i2c@21a0000",
+                &[("compatible", "fsl,imx21-i2c"), ("status", "disabled")],
+                2,
+            ),
+            ("memory@10000000", &[("device_type", "memory")], 1),
+        ]);
+        let nodes = parse_dtb(&dtb).unwrap();
+        let modaliases = modaliases(&nodes);
+        assert_eq!(
+            modaliases,
+            vec![
+                "of:NserialT(null)Cfsl,imx6q-uartCfsl,imx21-uart",
+                "of:NsocT(null)Csimple-bus",
+                "of:NT(null)Cfsl,imx6q-sabresdCfsl,imx6q",
+            ]
+        );
+
+        let aliases = [
+            ("of:N*T*Cfsl,imx21-uartC*", "imx"),
+            ("of:N*T*Cfsl,imx21-uart", "imx"),
+            ("of:N*T*Cfsl,imx21-i2c", "i2c-imx"),
+        ]
+        .map(|(pattern, module)| Alias {
+            pattern: pattern.to_string(),
+            module: module.to_string(),
+        });
+        assert_eq!(
+            policy::match_modaliases(&aliases, &modaliases),
+            ["imx".to_string()].into()
+        );
+
+        assert!(parse_dtb(b"not a dtb").is_err());
+        assert!(parse_dtb(&dtb[..60]).is_err());
+    }
+
+    #[test]
+    fn test_read_device_tree_dir() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().join("device-tree");
+        let serial = dir.join("soc/serial@7e201000");
+        fs::create_dir_all(&serial).unwrap();
+        fs::write(
+            dir.join("compatible"),
+            "raspberrypi,4-model-b\0brcm,bcm2711\0",
+        )
+        .unwrap();
+        fs::write(serial.join("compatible"), "arm,pl011\0arm,primecell\0").unwrap();
+        fs::write(serial.join("status"), "okay\0").unwrap();
+
+        let nodes = read_device_tree_dir(&dir).unwrap();
+        assert_eq!(
+            modaliases(&nodes),
+            vec![
+                "of:NT(null)Craspberrypi,4-model-bCbrcm,bcm2711",
+                "of:NserialT(null)Carm,pl011Carm,primecell",
+            ]
+        );
+    }
+}
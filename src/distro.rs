@@ -0,0 +1,157 @@
+//! Distribution specifics of an image.
+//!
+//! Most of a cleanup only depends on the kernel and firmware trees, but regenerating the initramfs
+//! and naming the firmware packages differ between the SUSE, Fedora, Debian and Alpine families.
+//! The family is read from the `os-release` file of the image.
+
+use crate::command::CommandRunner;
+use crate::error::JanitorError;
+use log::info;
+use std::fs;
+use std::path::Path;
+
+/// Family of the distribution installed in an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Distro {
+    /// openSUSE and SLE.
+    Suse,
+    /// Fedora, RHEL and their rebuilds.
+    Fedora,
+    Debian,
+    /// Ubuntu, which ships `linux-firmware` as a single package.
+    Ubuntu,
+    Alpine,
+    Other,
+}
+
+impl Distro {
+    /// Reads the distribution of the image at `root` from `etc/os-release`, or
+    /// `usr/lib/os-release` which it usually links to.
+    pub fn detect(root: &Path) -> Distro {
+        let content = ["etc/os-release", "usr/lib/os-release"]
+            .iter()
+            .find_map(|path| fs::read_to_string(root.join(path)).ok())
+            .unwrap_or_default();
+        Distro::from_os_release(&content)
+    }
+
+    /// Returns the distribution family named by the `ID` and `ID_LIKE` fields of `os-release`.
+    pub fn from_os_release(content: &str) -> Distro {
+        let mut ids = Vec::new();
+        for line in content.lines() {
+            if let Some(("ID" | "ID_LIKE", value)) = line.split_once('=') {
+                ids.extend(value.trim_matches(['"', '\'']).split_whitespace().map(str::to_string));
+            }
+        }
+        ids.iter()
+            .find_map(|id| match id.as_str() {
+                "ubuntu" => Some(Distro::Ubuntu),
+                "alpine" => Some(Distro::Alpine),
+                "debian" => Some(Distro::Debian),
+                "suse" | "opensuse" | "sles" | "sle-micro" => Some(Distro::Suse),
+                "fedora" | "rhel" | "centos" => Some(Distro::Fedora),
+                id if id.starts_with("opensuse") => Some(Distro::Suse),
+                _ => None,
+            })
+            .unwrap_or(Distro::Other)
+    }
+
+    /// The packages providing firmware, to name in hints.
+    pub fn firmware_packages(&self) -> &'static str {
+        match self {
+            Distro::Suse => "kernel-firmware-* (or kernel-firmware-all)",
+            Distro::Fedora | Distro::Ubuntu => "linux-firmware",
+            Distro::Alpine => "linux-firmware-* (or linux-firmware)",
+            Distro::Debian => "firmware-linux-free, firmware-misc-nonfree or the other firmware-*",
+            Distro::Other => "the linux-firmware packages",
+        }
+    }
+
+    /// Returns the command regenerating the initramfs of the kernel `version`: update-initramfs
+    /// on the Debian family, mkinitfs on Alpine, dracut elsewhere.
+    pub fn initramfs_command(&self, version: &str) -> Vec<String> {
+        let command: &[&str] = match self {
+            Distro::Debian | Distro::Ubuntu => &["update-initramfs", "-u", "-k"],
+            Distro::Alpine => &["mkinitfs"],
+            _ => &["dracut", "--force", "--kver"],
+        };
+        let mut command: Vec<String> = command.iter().map(|s| s.to_string()).collect();
+        command.push(version.to_string());
+        command
+    }
+}
+
+/// Regenerates the initramfs of the kernel `version` of the image at `root`, in a chroot unless
+/// the image is the running system.
+pub fn regenerate_initramfs(
+    root: &Path,
+    version: &str,
+    runner: &dyn CommandRunner,
+) -> Result<(), JanitorError> {
+    let mut command = Distro::detect(root).initramfs_command(version);
+    if root != Path::new("/") {
+        let root = root
+            .to_str()
+            .ok_or_else(|| JanitorError::InvalidPath(root.to_path_buf()))?;
+        command.splice(0..0, ["chroot".to_string(), root.to_string()]);
+    }
+    info!("Regenerating the initramfs: {}", command.join(" "));
+    let args: Vec<&str> = command[1..].iter().map(String::as_str).collect();
+    runner.run(&command[0], &args)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use tempfile::tempdir;
+
+    struct MockCommandRunner {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for MockCommandRunner {
+        fn run(&self, command: &str, args: &[&str]) -> Result<String, JanitorError> {
+            self.calls
+                .borrow_mut()
+                .push(format!("{} {}", command, args.join(" ")));
+            Ok(String::new())
+        }
+    }
+
+    #[test]
+    fn test_from_os_release() {
+        assert_eq!(Distro::from_os_release("ID=ubuntu\nID_LIKE=debian\n"), Distro::Ubuntu);
+        assert_eq!(Distro::from_os_release("ID=debian\n"), Distro::Debian);
+        assert_eq!(
+            Distro::from_os_release("ID=\"opensuse-tumbleweed\"\nID_LIKE=\"opensuse suse\"\n"),
+            Distro::Suse
+        );
+        assert_eq!(Distro::from_os_release("ID=\"rocky\"\nID_LIKE=\"rhel centos fedora\"\n"), Distro::Fedora);
+        assert_eq!(Distro::from_os_release("NAME=\"Alpine Linux\"\nID=alpine\n"), Distro::Alpine);
+        assert_eq!(Distro::Alpine.initramfs_command("6.6.31-0-lts"), vec!["mkinitfs", "6.6.31-0-lts"]);
+        assert_eq!(Distro::from_os_release(""), Distro::Other);
+    }
+
+    #[test]
+    fn test_regenerate_initramfs() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/os-release"), "ID=ubuntu\nID_LIKE=debian\n").unwrap();
+        let runner = MockCommandRunner {
+            calls: RefCell::new(Vec::new()),
+        };
+        regenerate_initramfs(root, "6.8.0-31-generic", &runner).unwrap();
+        fs::write(root.join("etc/os-release"), "ID=sles\n").unwrap();
+        regenerate_initramfs(root, "6.4.0-150600", &runner).unwrap();
+        assert_eq!(
+            *runner.calls.borrow(),
+            vec![
+                format!("chroot {} update-initramfs -u -k 6.8.0-31-generic", root.display()),
+                format!("chroot {} dracut --force --kver 6.4.0-150600", root.display()),
+            ]
+        );
+    }
+}
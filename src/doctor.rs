@@ -0,0 +1,428 @@
+//! Diagnostics for the usual misconfigurations of an image build.
+
+use crate::command::CommandRunner;
+use crate::config;
+use crate::depmod;
+use crate::distro::Distro;
+use crate::error::JanitorError;
+use crate::modinfo;
+use crate::policy::{self, RuleMatch, Rules};
+use crate::util::{self, ScanOptions};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How bad a finding is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The cleanup runs, but probably not as intended.
+    Warning,
+    /// The cleanup fails or would remove far too much.
+    Error,
+}
+
+/// A problem found by a check, with a hint on how to fix it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Short name of the check which produced the finding.
+    pub check: &'static str,
+    pub message: String,
+    pub hint: String,
+}
+
+impl Finding {
+    fn new(severity: Severity, check: &'static str, message: String, hint: &str) -> Self {
+        Finding {
+            severity,
+            check,
+            message,
+            hint: hint.to_string(),
+        }
+    }
+}
+
+/// Inputs checked by the doctor, as they would be given to the cleanup commands.
+#[derive(Debug, Clone, Default)]
+pub struct DoctorOptions {
+    /// Module list configuration files.
+    pub config_paths: Vec<String>,
+    /// Directory with the kernel module trees.
+    pub module_dir: PathBuf,
+    /// Directory with the firmware files.
+    pub firmware_dir: PathBuf,
+    pub scan: ScanOptions,
+    /// Root directory of the image, none for the running system.
+    pub root: Option<PathBuf>,
+}
+
+/// Runs every check and returns the findings, an empty list meaning nothing looks wrong.
+pub fn diagnose(
+    options: &DoctorOptions,
+    runner: &dyn CommandRunner,
+) -> Result<Vec<Finding>, JanitorError> {
+    let mut findings = Vec::new();
+    let distro = Distro::detect(options.root.as_deref().unwrap_or(Path::new("/")));
+    if let Some(root) = &options.root {
+        check_usrmerge(root, &mut findings);
+    }
+    check_firmware_dir(&options.firmware_dir, distro, &mut findings);
+
+    let arch = match runner.run("arch", &[]) {
+        Ok(arch) => Some(arch),
+        Err(e) => {
+            findings.push(Finding::new(
+                Severity::Error,
+                "arch",
+                format!("Could not detect the architecture: {}", e),
+                "The 'arch' command from coreutils must be available in the build environment.",
+            ));
+            None
+        }
+    };
+    let config_lines = read_config_lines(&options.config_paths, &mut findings);
+    if let (Some(arch), Some(lines)) = (&arch, &config_lines) {
+        check_arch_sections(arch, lines, &mut findings);
+    }
+
+    let kernel_dirs = match util::find_kernel_dirs(&options.module_dir, &options.scan.kernels) {
+        Ok(kernel_dirs) => kernel_dirs,
+        Err(e) => {
+            findings.push(Finding::new(
+                Severity::Error,
+                "module-dir",
+                e.to_string(),
+                "--module-dir must point to the directory holding one directory per kernel \
+                 version (e.g. <image>/lib/modules), and a kernel package must be installed.",
+            ));
+            return Ok(findings);
+        }
+    };
+    for kernel_dir in &kernel_dirs {
+        let modules = util::find_kernel_modules(kernel_dir, &options.scan)?;
+        if modules.is_empty() {
+            findings.push(Finding::new(
+                Severity::Error,
+                "module-dir",
+                format!("No kernel module found in {}", kernel_dir.display()),
+                "Check that the kernel modules are installed in the image and that --scan-exclude \
+                 does not exclude the whole tree.",
+            ));
+            continue;
+        }
+
+        check_module_metadata(kernel_dir, &modules, &mut findings)?;
+        if let (Some(arch), Some(lines)) = (&arch, &config_lines) {
+            check_config_matches(kernel_dir, &modules, lines.clone(), arch, &mut findings)?;
+        }
+    }
+    Ok(findings)
+}
+
+fn read_config_lines(paths: &[String], findings: &mut Vec<Finding>) -> Option<Vec<String>> {
+    let mut lines = Vec::new();
+    let mut complete = true;
+    for path in paths {
+        match config::read_with_includes(Path::new(path)) {
+            Ok(sources) => {
+                for source in sources {
+                    let path = source.path.display();
+                    for diagnostic in policy::validate(&source.content) {
+                        let severity = if diagnostic.warning {
+                            Severity::Warning
+                        } else {
+                            complete = false;
+                            Severity::Error
+                        };
+                        findings.push(Finding::new(
+                            severity,
+                            "config",
+                            format!(
+                                "{}:{}:{}: {}",
+                                path, diagnostic.line, diagnostic.column, diagnostic.message
+                            ),
+                            "Rules are regular expressions, or globs prefixed with 'glob:', one per \
+                             line, optionally prefixed with '-' to delete; architecture specific \
+                             rules go between <arch> and </arch>, kernel flavor specific ones \
+                             between <flavor:NAME> and </flavor:NAME>.",
+                        ));
+                    }
+                    lines.extend(source.content.lines().map(String::from));
+                }
+            }
+            Err(e) => {
+                findings.push(Finding::new(
+                    Severity::Error,
+                    "config",
+                    e.to_string(),
+                    "Config file paths are relative to the current directory, pass absolute \
+                     paths with --config-files when running from elsewhere. Included files are \
+                     relative to the including one, and must not include it back.",
+                ));
+                complete = false;
+            }
+        }
+    }
+    complete.then_some(lines)
+}
+
+fn check_arch_sections(arch: &str, lines: &[String], findings: &mut Vec<Finding>) {
+    let tags = policy::arch_tags(lines);
+    if !tags.is_empty() && !tags.contains(arch) {
+        findings.push(Finding::new(
+            Severity::Warning,
+            "arch",
+            format!(
+                "Detected architecture {} has no section in the config files (sections: {})",
+                arch,
+                tags.into_iter().collect::<Vec<_>>().join(", ")
+            ),
+            "The architecture is the one of the build host. When building for another \
+             architecture, run the tool in an emulated environment of the target.",
+        ));
+    }
+}
+
+fn check_module_metadata(
+    kernel_dir: &Path,
+    modules: &[PathBuf],
+    findings: &mut Vec<Finding>,
+) -> Result<(), JanitorError> {
+    let unreadable: Vec<&PathBuf> = modules
+        .iter()
+        .filter(|path| modinfo::read_modinfo(path).is_err())
+        .collect();
+    if unreadable.is_empty() {
+        return Ok(());
+    }
+
+    // Without depmod output, the dependencies are read from the module metadata.
+    let has_dependencies = depmod::read_dependencies(kernel_dir)?.is_some();
+    let (severity, hint) = if has_dependencies {
+        (
+            Severity::Warning,
+            "Dependencies are read from modules.dep, but firmware requirements of these \
+             modules are ignored by fw-cleanup.",
+        )
+    } else {
+        (
+            Severity::Error,
+            "Run depmod for this kernel so dependencies are read from modules.dep, and check \
+             that the modules are not corrupted.",
+        )
+    };
+    findings.push(Finding::new(
+        severity,
+        "module-metadata",
+        format!(
+            "Could not read the metadata of {} of {} modules (e.g. {})",
+            unreadable.len(),
+            modules.len(),
+            unreadable[0].display()
+        ),
+        hint,
+    ));
+    Ok(())
+}
+
+fn check_config_matches(
+    kernel_dir: &Path,
+    modules: &[PathBuf],
+    lines: Vec<String>,
+    arch: &str,
+    findings: &mut Vec<Finding>,
+) -> Result<(), JanitorError> {
+    let release = kernel_dir.file_name().unwrap_or_default().to_string_lossy();
+    let rules = Rules::from_lines(lines, arch)?.for_kernel(&release)?;
+    let kept = modules
+        .iter()
+        .filter_map(|path| path.strip_prefix(kernel_dir).ok()?.to_str())
+        .filter(|path| rules.matches(path) == RuleMatch::Keep)
+        .count();
+    if kept == 0 {
+        findings.push(Finding::new(
+            Severity::Error,
+            "config",
+            format!(
+                "The config files keep none of the {} modules, driver-cleanup would delete all of them",
+                modules.len()
+            ),
+            "Patterns are regular expressions matched against paths relative to the kernel \
+             directory (e.g. 'kernel/drivers/net/.*').",
+        ));
+    }
+    Ok(())
+}
+
+/// Warns about a `lib` of the image linking to the absolute `/usr/lib`, as usrmerged Debian
+/// images may: seen from outside the image, paths through it lead to the build host.
+fn check_usrmerge(root: &Path, findings: &mut Vec<Finding>) {
+    let Ok(target) = fs::read_link(root.join("lib")) else {
+        return;
+    };
+    if target.is_absolute() {
+        findings.push(Finding::new(
+            Severity::Warning,
+            "usrmerge",
+            format!(
+                "{} links to {}, outside of the image",
+                root.join("lib").display(),
+                target.display()
+            ),
+            &format!(
+                "Pass --module-dir {} and --firmware-dir {}, or make the link relative.",
+                root.join("usr/lib/modules").display(),
+                root.join("usr/lib/firmware").display()
+            ),
+        ));
+    }
+}
+
+fn check_firmware_dir(firmware_dir: &Path, distro: Distro, findings: &mut Vec<Finding>) {
+    match fs::symlink_metadata(firmware_dir) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            let target = fs::read_link(firmware_dir)
+                .map(|t| t.display().to_string())
+                .unwrap_or_default();
+            findings.push(Finding::new(
+                Severity::Warning,
+                "firmware-dir",
+                format!(
+                    "Firmware directory {} is a symlink to {}",
+                    firmware_dir.display(),
+                    target
+                ),
+                "fw-cleanup would delete files in the symlink target, which may be outside of \
+                 the image. Pass the real directory with --firmware-dir.",
+            ));
+        }
+        Ok(_) => {}
+        Err(e) => findings.push(Finding::new(
+            Severity::Warning,
+            "firmware-dir",
+            format!(
+                "Firmware directory {} is not accessible: {}",
+                firmware_dir.display(),
+                e
+            ),
+            &format!(
+                "Pass the firmware directory of the image with --firmware-dir, and check that {} \
+                 is installed.",
+                distro.firmware_packages()
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    struct MockCommandRunner {
+        responses: HashMap<String, String>,
+    }
+
+    impl CommandRunner for MockCommandRunner {
+        fn run(&self, command: &str, _args: &[&str]) -> Result<String, JanitorError> {
+            self.responses
+                .get(command)
+                .cloned()
+                .ok_or_else(|| JanitorError::Command(format!("Not mocked: {}", command)))
+        }
+    }
+
+    fn runner() -> MockCommandRunner {
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        MockCommandRunner { responses }
+    }
+
+    fn checks(findings: &[Finding]) -> Vec<&str> {
+        findings.iter().map(|f| f.check).collect()
+    }
+
+    #[test]
+    fn test_diagnose_healthy_tree() {
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path().join("modules/6.1.0-test");
+        fs::create_dir_all(kernel_dir.join("kernel")).unwrap();
+        fs::create_dir_all(temp_dir.path().join("firmware")).unwrap();
+        fs::write(
+            kernel_dir.join("kernel/a.ko"),
+            modinfo::build_test_module(&["depends="]),
+        )
+        .unwrap();
+        let config_path = temp_dir.path().join("module.list");
+        fs::write(&config_path, "<x86_64>\nkernel/a.ko\n</x86_64>\n").unwrap();
+
+        let options = DoctorOptions {
+            config_paths: vec![config_path.to_str().unwrap().to_string()],
+            module_dir: temp_dir.path().join("modules"),
+            firmware_dir: temp_dir.path().join("firmware"),
+            ..Default::default()
+        };
+        assert!(diagnose(&options, &runner()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_diagnose_misconfigurations() {
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path().join("modules/6.1.0-test");
+        fs::create_dir_all(kernel_dir.join("kernel")).unwrap();
+        fs::create_dir_all(temp_dir.path().join("elsewhere")).unwrap();
+        symlink(
+            temp_dir.path().join("elsewhere"),
+            temp_dir.path().join("firmware"),
+        )
+        .unwrap();
+        fs::write(kernel_dir.join("kernel/a.ko"), "not an elf").unwrap();
+        let config_path = temp_dir.path().join("module.list");
+        fs::write(&config_path, "<aarch64>\nkernel/a.ko\n</aarch64>\n").unwrap();
+
+        let options = DoctorOptions {
+            config_paths: vec![config_path.to_str().unwrap().to_string()],
+            module_dir: temp_dir.path().join("modules"),
+            firmware_dir: temp_dir.path().join("firmware"),
+            ..Default::default()
+        };
+        let findings = diagnose(&options, &runner()).unwrap();
+        assert_eq!(
+            checks(&findings),
+            vec!["firmware-dir", "arch", "module-metadata", "config"]
+        );
+        assert_eq!(findings[2].severity, Severity::Error);
+    }
+
+    #[test]
+    fn test_diagnose_empty_module_dir() {
+        let temp_dir = tempdir().unwrap();
+        let options = DoctorOptions {
+            module_dir: temp_dir.path().to_path_buf(),
+            firmware_dir: temp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let findings = diagnose(&options, &runner()).unwrap();
+        assert_eq!(checks(&findings), vec!["module-dir"]);
+    }
+
+    #[test]
+    fn test_diagnose_debian_usrmerge() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("usr/lib")).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/os-release"), "ID=debian\n").unwrap();
+        symlink("/usr/lib", root.join("lib")).unwrap();
+        let options = DoctorOptions {
+            module_dir: root.join("usr/lib/modules"),
+            firmware_dir: root.join("usr/lib/firmware"),
+            root: Some(root.to_path_buf()),
+            ..Default::default()
+        };
+        let findings = diagnose(&options, &runner()).unwrap();
+        assert_eq!(checks(&findings), vec!["usrmerge", "firmware-dir", "module-dir"]);
+        assert!(findings[1].hint.contains("firmware-misc-nonfree"));
+    }
+}
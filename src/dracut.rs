@@ -0,0 +1,145 @@
+//! Drivers the dracut configuration of an image embeds in the initramfs.
+//!
+//! dracut fails to build an initramfs when a driver listed in `add_drivers` or `force_drivers`
+//! is missing from the module tree, e.g. at the next kernel update of a cleaned image. These
+//! drivers are kept by name like the modules loaded at boot, unless `omit_drivers` excludes them
+//! from the initramfs too.
+
+use crate::error::JanitorError;
+use crate::modprobe;
+use log::debug;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+/// Main dracut configuration file, relative to the image root, read before the directories.
+const DRACUT_CONF: &str = "etc/dracut.conf";
+
+/// Directories holding dracut configuration, relative to the image root, by decreasing priority.
+const DRACUT_CONF_DIRS: &[&str] = &["etc/dracut.conf.d", "usr/lib/dracut/dracut.conf.d"];
+
+/// The drivers named by the dracut configuration of an image.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DracutConfig {
+    /// Drivers of `add_drivers` and `force_drivers`.
+    pub add: BTreeSet<String>,
+    /// Drivers of `omit_drivers`.
+    pub omit: BTreeSet<String>,
+}
+
+impl DracutConfig {
+    /// Loads the dracut configuration found below `root`, in the order dracut reads it.
+    pub fn load(root: &Path) -> Result<Self, JanitorError> {
+        let mut config = DracutConfig::default();
+        let conf = root.join(DRACUT_CONF);
+        let mut paths = if conf.is_file() {
+            vec![conf]
+        } else {
+            Vec::new()
+        };
+        paths.extend(modprobe::config_files(root, DRACUT_CONF_DIRS)?);
+        for path in paths {
+            debug!("Reading dracut configuration {}", path.display());
+            config.parse(&fs::read_to_string(&path)?);
+        }
+        Ok(config)
+    }
+
+    /// Parses the content of a dracut configuration file: shell assignments such as
+    /// `add_drivers+=" nvme ahci "`. A plain assignment replaces the drivers set by the files
+    /// read before, `+=` adds to them.
+    pub fn parse(&mut self, content: &str) {
+        for line in content.lines() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let (key, append) = match key.strip_suffix('+') {
+                Some(key) => (key.trim(), true),
+                None => (key.trim(), false),
+            };
+            let set = match key {
+                "add_drivers" | "force_drivers" => &mut self.add,
+                "omit_drivers" => &mut self.omit,
+                _ => continue,
+            };
+            if !append {
+                set.clear();
+            }
+            set.extend(shell_words(value).map(|name| name.replace('-', "_")));
+        }
+    }
+
+    /// Returns the names of the drivers dracut embeds in the initramfs.
+    pub fn drivers(&self) -> BTreeSet<String> {
+        self.add.difference(&self.omit).cloned().collect()
+    }
+}
+
+/// Returns the words of the quoted shell value `value`, up to a trailing comment.
+fn shell_words(value: &str) -> impl Iterator<Item = &str> {
+    let value = value.trim();
+    let value = match value.chars().next() {
+        Some(quote @ ('"' | '\'')) => {
+            let rest = &value[1..];
+            rest.find(quote).map_or(rest, |end| &rest[..end])
+        }
+        _ => value.split('#').next().unwrap_or_default(),
+    };
+    value.split_whitespace()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_load_dracut_config() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("etc/dracut.conf.d")).unwrap();
+        fs::create_dir_all(root.join("usr/lib/dracut/dracut.conf.d")).unwrap();
+        fs::write(
+            root.join("etc/dracut.conf"),
+            "add_drivers+=\" virtio-blk \"\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("usr/lib/dracut/dracut.conf.d/10-storage.conf"),
+            "# storage\nadd_drivers+=\" nvme ahci \"\nforce_drivers+='dm-crypt'\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("usr/lib/dracut/dracut.conf.d/20-net.conf"),
+            "add_drivers+=\" e1000e \"\n",
+        )
+        .unwrap();
+        // Overrides the file of the same name of /usr/lib.
+        fs::write(
+            root.join("etc/dracut.conf.d/20-net.conf"),
+            "add_drivers+=\" ixgbe \" # uplink\nomit_drivers+=\" ahci \"\nhostonly=\"yes\"\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("etc/dracut.conf.d/30-reset.conf.bak"),
+            "add_drivers=\"\"\n",
+        )
+        .unwrap();
+
+        let config = DracutConfig::load(root).unwrap();
+        assert_eq!(
+            config.drivers(),
+            BTreeSet::from(["dm_crypt", "ixgbe", "nvme", "virtio_blk"].map(String::from))
+        );
+        assert_eq!(config.omit, BTreeSet::from(["ahci".to_string()]));
+
+        // A plain assignment drops the drivers set before.
+        let mut config = DracutConfig::default();
+        config.parse("add_drivers+=\" nvme \"\nadd_drivers=\"ahci\"\n");
+        assert_eq!(config.add, BTreeSet::from(["ahci".to_string()]));
+    }
+}
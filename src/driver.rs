@@ -1,128 +1,706 @@
+use crate::atomic;
+use crate::clock::Clock;
 use crate::command::CommandRunner;
 use crate::config;
+use crate::deleter::Deleter;
+use crate::depmod::{self, DependencyMap};
+use crate::dracut::DracutConfig;
 use crate::error::JanitorError;
-use crate::util;
+use crate::explain::{Action, Explanation, FileType};
+use crate::hardware::{self, HardwareId};
+use crate::interrupt;
+use crate::janitor_fs::{FileKind, JanitorFs, RealFs};
+use crate::kernel_graph::{KernelGraph, KernelModules};
+use crate::kiwi;
+use crate::modprobe::{ModprobeConfig, SoftDeps};
+use crate::policy::{self, Evaluation, Module, Reason, Rules};
+use crate::profile::{self, Profile};
+use crate::strip;
+use crate::summary::CleanupSummary;
+use crate::util::{self, ScanOptions};
 use log::{debug, info, warn};
-use std::collections::{HashMap, HashSet};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
+use std::io::Write;
+use path_clean::PathClean;
 use std::path::{Path, PathBuf};
-use walkdir::WalkDir;
+use std::sync::Arc;
+use tracing::info_span;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-struct Driver {
-    name: String,
-    path: PathBuf,
-    deps: Vec<String>,
-}
-
-impl Driver {
-    fn from_file(path: &Path, runner: &dyn CommandRunner) -> Result<Self, JanitorError> {
-        let deps_str = match runner.run("/usr/sbin/modinfo", &["-F", "depends", path.to_str().unwrap()]) {
-            Ok(s) => s,
-            Err(e) => {
-                warn!("modinfo for {} failed: {}", path.display(), e);
-                String::new()
+/// Reads the metadata of the module at `path` from its `.modinfo` section. A module whose
+/// metadata cannot be read has no dependencies, and the error is returned along.
+fn module_from_file(
+    path: &Path,
+    kernel: &KernelModules,
+) -> Result<(Module, Option<String>), JanitorError> {
+    let mut error = None;
+    let (deps, softdeps) = match kernel.modinfo(path) {
+        Ok(info) => {
+            let deps = info.depends().iter().map(|d| d.replace('-', "_")).collect();
+            let mut softdeps = SoftDeps::default();
+            for spec in info.get_all("softdep") {
+                softdeps.merge(SoftDeps::parse(spec));
             }
-        };
+            (deps, softdeps.all().cloned().collect())
+        }
+        Err(e) => {
+            warn!("Reading modinfo of {} failed: {}", path.display(), e);
+            error = Some(format!("Reading modinfo of {} failed: {}", path.display(), e));
+            (Vec::new(), Vec::new())
+        }
+    };
 
-        let deps = deps_str
-            .trim()
-            .split(',')
-            .filter(|s| !s.is_empty())
-            .map(String::from)
-            .collect();
+    let module = Module {
+        name: util::module_name(path),
+        path: relative_path(path, &kernel.kernel_dir)?,
+        deps,
+        softdeps,
+    };
+    Ok((module, error))
+}
 
-        let name = path
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .split('.')
-            .next()
-            .unwrap()
-            .to_string();
+/// Builds the module metadata from the dependency information generated by depmod.
+fn module_from_dependency_map(
+    path: &Path,
+    kernel_dir: &Path,
+    dependencies: &DependencyMap,
+    softdeps: &ModprobeConfig,
+) -> Result<Module, JanitorError> {
+    let name = util::module_name(path);
+    let deps = dependencies.get(&name).cloned().unwrap_or_default();
+    let softdeps = softdeps
+        .softdeps
+        .get(&name)
+        .map(|s| s.all().cloned().collect())
+        .unwrap_or_default();
+    Ok(Module {
+        name,
+        path: relative_path(path, kernel_dir)?,
+        deps,
+        softdeps,
+    })
+}
 
-        Ok(Driver { name, path: path.to_path_buf(), deps })
-    }
+fn relative_path(path: &Path, kernel_dir: &Path) -> Result<String, JanitorError> {
+    path.strip_prefix(kernel_dir)
+        .ok()
+        .and_then(|p| p.to_str())
+        .map(String::from)
+        .ok_or_else(|| JanitorError::InvalidPath(path.to_path_buf()))
 }
 
+/// Options of a driver cleanup run.
+#[derive(Debug, Clone, Default)]
+pub struct DriverOptions {
+    /// Module list configuration files.
+    pub config_paths: Vec<String>,
+    /// Rules read before the ones of the configuration files, e.g. the keep rules of the
+    /// `<drivers>` sections of a KIWI description, see [`kiwi::keep_rule`].
+    pub extra_rules: Vec<String>,
+    /// Directory with the kernel module trees.
+    pub module_dir: PathBuf,
+    /// Root of the image being cleaned, system configuration is read below it.
+    pub root: PathBuf,
+    /// Really delete the files.
+    pub delete: bool,
+    pub scan: ScanOptions,
+    /// Modaliases of the target hardware. When set, only the modules matching them through
+    /// `modules.alias` (and their dependencies) are kept, and the configuration files are not used.
+    pub modaliases: Option<Vec<String>>,
+    /// PCI and USB IDs of the target hardware. When set, only the modules having an alias a device
+    /// with one of these IDs can match (and their dependencies) are kept, and the configuration
+    /// files are not used.
+    pub hardware_ids: Option<Vec<HardwareId>>,
+    /// Modaliases of the device tree nodes of the target board, see [`crate::devicetree`]. The
+    /// modules matching them through `modules.alias` are kept in addition.
+    pub device_tree: Vec<String>,
+    /// Hardware profile whose loaded modules and device modaliases are kept in addition.
+    pub profile: Option<Profile>,
+    /// Archive the deleted modules are saved to before being deleted.
+    pub backup: Option<PathBuf>,
+    /// Journal the deletions are recorded in.
+    pub journal: Option<PathBuf>,
+    /// Clock timestamping the journal entries, the system clock if unset.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Quarantine directory the deleted files are moved to instead of being unlinked, see
+    /// [`crate::quarantine`].
+    pub quarantine: Option<PathBuf>,
+    /// Report the decision taken for every module, and its reason, is appended to.
+    pub explain: Option<PathBuf>,
+    /// File the dependency graph of the modules is written to in the DOT language, one digraph
+    /// per kernel.
+    pub dot: Option<PathBuf>,
+    /// Also remove the binary module indexes, for images where depmod is guaranteed to run
+    /// again (e.g. when regenerating the initrd or on first boot).
+    pub drop_binary_indexes: bool,
+    /// Also strip the debug sections of the kept modules, signed ones excepted.
+    pub strip_debug: bool,
+    /// Also remove the files of the kernel directories only needed to build modules, see
+    /// [`devel_files`].
+    pub remove_devel_files: bool,
+    /// Modules smaller than this size, in bytes, are kept instead of being deleted.
+    pub min_size: u64,
+    /// Size, in bytes, each kernel module tree is to fit in. When set, only the largest unused
+    /// modules are deleted until the tree fits, the other ones are kept.
+    pub target_size: Option<u64>,
+    /// Delete the modules blacklisted in modprobe.d even when the configuration keeps them, see
+    /// [`policy::drop_blacklisted`].
+    pub delete_blacklisted: bool,
+    /// File the modules kept are written to, as a KIWI `<drivers>` section.
+    pub kiwi_drivers: Option<PathBuf>,
+    /// Filesystem the modules are measured and deleted through, the host one if unset. The
+    /// modules themselves are still read from the host filesystem.
+    pub fs: Option<Arc<dyn JanitorFs>>,
+    /// Tells whether the run is to stop, [`interrupt::is_interrupted`] if unset.
+    pub interrupted: Option<fn() -> bool>,
+}
+
+/// Reason the binary module indexes are deleted for.
+const INDEX_REASON: &str = "binary index, regenerated by depmod";
+
+/// Reason the dangling `weak-updates` links are deleted for.
+const DANGLING_REASON: &str = "dangling weak-updates link";
+
+/// Reason the kernel development files are deleted for.
+const DEVEL_REASON: &str = "only needed to build modules";
+
+/// Removes the kernel modules not kept by the configuration, returning a summary with the
+/// deleted paths (or the ones that would be deleted in a dry run).
+///
+/// Each selected kernel is evaluated on its own module tree.
 pub fn cleanup_drivers(
-    config_paths: &[&str],
-    module_dir: &Path,
-    delete: bool,
+    options: &DriverOptions,
+    graph: &KernelGraph,
     runner: &dyn CommandRunner,
-) -> Result<(), JanitorError> {
-    let (to_keep_re, to_delete_re) = config::read_config(config_paths, runner)?;
-    let kernel_dir = util::find_kernel_dir(module_dir)?;
-    info!("Scanning kernel modules in {}", kernel_dir.display());
+) -> Result<CleanupSummary, JanitorError> {
+    let kernel_dirs = util::find_kernel_dirs(&options.module_dir, &options.scan.kernels)?;
 
-    let mut driver_map = HashMap::new();
-    for entry in WalkDir::new(&kernel_dir) {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_file()
-            && (
-                path.extension().is_some_and(|e| e == "ko") ||
-                path.to_str().is_some_and(|s| s.ends_with(".ko.xz")) ||
-                path.to_str().is_some_and(|s| s.ends_with(".ko.zst"))
-            )
-        {
-            let driver = Driver::from_file(path, runner)?;
-            driver_map.insert(driver.name.clone(), driver);
+    // Soft dependencies configured in the image are honored like the ones of the modules.
+    let modprobe_config = ModprobeConfig::load(&options.root)?;
+    // So are the drivers dracut embeds, it cannot build an initramfs without them.
+    let dracut_drivers = DracutConfig::load(&options.root)?.drivers();
+    if !dracut_drivers.is_empty() {
+        info!("Keeping the {} drivers dracut is configured to embed", dracut_drivers.len());
+    }
+    let rules = match (&options.modaliases, &options.hardware_ids) {
+        (None, None) => {
+            let config_paths: Vec<&str> = options.config_paths.iter().map(String::as_str).collect();
+            Some(config::read_config_with(&config_paths, &options.extra_rules, runner)?)
         }
+        _ => None,
+    };
+
+    let fs = options.fs.clone().unwrap_or_else(|| Arc::new(RealFs));
+    let interrupted = options.interrupted.unwrap_or(interrupt::is_interrupted);
+    let mut deleter = Deleter::new(options.delete)
+        .with_fs(Arc::clone(&fs))
+        .with_interrupt(interrupted);
+    if let Some(backup) = &options.backup {
+        deleter = deleter.with_backup(backup)?;
+    }
+    if let Some(journal) = &options.journal {
+        deleter = deleter.with_journal(journal)?;
+    }
+    if let Some(clock) = &options.clock {
+        deleter = deleter.with_clock(Arc::clone(clock));
+    }
+    if let Some(quarantine) = &options.quarantine {
+        deleter = deleter.with_quarantine(quarantine)?;
     }
+    let mut explanation = options.explain.as_deref().map(Explanation::create).transpose()?;
+    let mut dot = String::new();
+    let mut rule_usage = BTreeMap::new();
 
-    let mut to_keep: HashSet<Driver> = HashSet::new();
+    // Deleting a loaded module on a live system, e.g. a storage or network driver of a builder
+    // host, can take it down: they are kept whatever the configuration says.
+    let (running_kernel, loaded) = if options.root == Path::new("/") {
+        (profile::running_kernel(&options.root), profile::loaded_modules(&options.root)?)
+    } else {
+        (None, BTreeSet::new())
+    };
+    // The weak-updates links of every installed kernel, selected or not, keep their targets.
+    let links = weak_update_links(fs.as_ref(), &options.module_dir)?;
+    let targets: BTreeMap<&Path, &Path> = links
+        .iter()
+        .filter_map(|(link, target)| Some((target.as_deref()?, link.as_path())))
+        .collect();
+    let (mut examined, mut kept, mut summary_errors) = (0, 0, Vec::new());
+    let mut kept_modules = BTreeSet::new();
+    for kernel_dir in &kernel_dirs {
+        atomic::remove_orphans(kernel_dir, options.delete)?;
+        let name = kernel_dir.file_name().unwrap_or_default().to_string_lossy();
+        let kernel_rules = rules.as_ref().map(|r| r.for_kernel(&name)).transpose()?;
+        let (modules, mut evaluation, errors) = evaluate_kernel(
+            kernel_dir,
+            options,
+            graph,
+            &modprobe_config,
+            &dracut_drivers,
+            kernel_rules.as_ref(),
+        )
+        .map_err(|e| deleter.complete_interrupted(e))?;
+        examined += modules.len();
+        summary_errors.extend(errors);
+        if running_kernel.as_deref() == Some(&*name) {
+            for path in keep_loaded(&mut evaluation, &modules, &loaded) {
+                warn!("Not deleting {}, the module is loaded on the running system", path);
+            }
+        }
+        if options.min_size > 0 {
+            let kept = keep_small(fs.as_ref(), &modules, &mut evaluation, kernel_dir, options.min_size)?;
+            debug!("Keeping {} modules smaller than {} bytes", kept.len(), options.min_size);
+        }
+        for path in keep_link_targets(&modules, &mut evaluation, kernel_dir, &targets) {
+            info!("Not deleting {}, a weak-updates link points to it", path);
+        }
+        if let Some(target_size) = options.target_size {
+            let kept = keep_within_budget(fs.as_ref(), &modules, &mut evaluation, kernel_dir, kernel_rules.as_ref(), target_size)?;
+            for path in &kept {
+                info!("Keeping unused {}, {} fits in {} bytes without deleting it", path, name, target_size);
+            }
+        }
+        // Only a delete rule overrides the drivers kept for dracut.
+        for module in modules.iter().filter(|m| dracut_drivers.contains(&m.name)) {
+            if evaluation.delete.contains(&module.path) {
+                warn!(
+                    "Deleting {}, which dracut is configured to embed: the initramfs of kernel {} \
+                     cannot be built anymore",
+                    module.path, name
+                );
+            }
+        }
+        if let Some(kernel_rules) = &kernel_rules {
+            let paths: Vec<&str> = evaluation.reasons.keys().map(String::as_str).collect();
+            kernel_rules.record_usage(&paths, &mut rule_usage);
+        }
+        if options.dot.is_some() {
+            dot.push_str(&policy::to_dot(&name, &modules, &evaluation));
+        }
+
+        info!("Found {} drivers to delete", evaluation.delete.len());
+        debug!("Drivers to delete: {:?}", evaluation.delete);
 
-    for driver in driver_map.values() {
-        let kernel_path = driver.path.strip_prefix(&kernel_dir).unwrap().to_str()
-            .ok_or_else(|| JanitorError::InvalidPath(driver.path.clone()))?;
+        if let Some(explanation) = &mut explanation {
+            for (relative, module_reason) in &evaluation.reasons {
+                let action = match evaluation.keep.contains(relative) {
+                    true => Action::Keep,
+                    false => Action::Delete,
+                };
+                explanation.record(
+                    &kernel_dir.join(relative),
+                    FileType::Module,
+                    action,
+                    &module_reason.to_string(),
+                )?;
+            }
+        }
+
+        kept += evaluation.keep.len();
+        kept_modules.extend(evaluation.keep.iter().cloned());
+
+        let _span = info_span!("deletion", kernel = %kernel_dir.display()).entered();
+        // Later passes sharing the graph only see the modules kept.
+        let kernel = graph.kernel(kernel_dir, &options.scan)?;
+        let reasons: Vec<String> = evaluation
+            .delete
+            .iter()
+            .map(|relative| evaluation.reasons.get(relative).unwrap_or(&Reason::Unmatched).to_string())
+            .collect();
+        let mut batch = Vec::new();
+        for (relative, reason) in evaluation.delete.iter().zip(&reasons) {
+            let path = kernel_dir.join(relative);
+            kernel.remove(&path);
+            if options.delete {
+                info!("Deleting {}", path.display());
+            }
+            let size = fs.metadata(&path)?.len;
+            batch.push((path, size, reason.as_str()));
+        }
+        deleter.remove_files(&batch)?;
+
+        // The weak-updates links are not scanned as modules, only the dangling ones are deleted.
+        for (link, _) in links
+            .iter()
+            .filter(|(link, target)| link.starts_with(kernel_dir) && target.is_none())
+        {
+            if let Some(explanation) = &mut explanation {
+                explanation.record(link, FileType::Module, Action::Delete, DANGLING_REASON)?;
+            }
+            deleter.remove_file(link, fs.symlink_metadata(link)?.len, DANGLING_REASON)?;
+        }
 
-        if to_delete_re.iter().any(|r| r.is_match(kernel_path)) {
-            debug!("Marked for deletion by config: {}", driver.path.display());
-        } else if to_keep_re.iter().any(|r| r.is_match(kernel_path)) {
-            debug!("Marked for keeping by config: {}", driver.path.display());
-            to_keep.insert(driver.clone());
+        if options.strip_debug {
+            let mut size = 0;
+            for relative in &evaluation.keep {
+                deleter.check_interrupted()?;
+                size += strip::strip_module(&kernel_dir.join(relative), options.delete, runner)?;
+            }
+            info!(
+                "{} {} bytes of debug sections from the kept modules of {}",
+                if options.delete { "Stripped" } else { "Would strip" },
+                size,
+                kernel_dir.display()
+            );
         }
+
+        if options.drop_binary_indexes {
+            let indexes = depmod::binary_indexes(kernel_dir)?;
+            let mut size = 0;
+            for path in &indexes {
+                if let Some(explanation) = &mut explanation {
+                    explanation.record(path, FileType::Index, Action::Delete, INDEX_REASON)?;
+                }
+                let index_size = fs.metadata(path)?.len;
+                deleter.remove_file(path, index_size, INDEX_REASON)?;
+                size += index_size;
+            }
+            info!(
+                "Dropping {} binary indexes of {} ({} bytes), depmod must run before modules are loaded",
+                indexes.len(),
+                kernel_dir.display(),
+                size
+            );
+        }
+
+        if options.remove_devel_files {
+            let files = devel_files(fs.as_ref(), kernel_dir);
+            let mut size = 0;
+            for path in &files {
+                if let Some(explanation) = &mut explanation {
+                    explanation.record(path, FileType::Devel, Action::Delete, DEVEL_REASON)?;
+                }
+                // The build and source symlinks usually dangle in an image without the sources.
+                let file_size = fs.symlink_metadata(path)?.len;
+                deleter.remove_file(path, file_size, DEVEL_REASON)?;
+                size += file_size;
+            }
+            info!(
+                "{} {} development files of {} ({} bytes)",
+                if options.delete { "Deleting" } else { "Would delete" },
+                files.len(),
+                kernel_dir.display(),
+                size
+            );
+        }
+    }
+
+    // Rules for drivers renamed or dropped upstream accumulate silently otherwise.
+    for (rule, _) in rule_usage.iter().filter(|(_, used)| !**used) {
+        warn!("Config rule '{}' matches no module, it may be stale", rule);
+    }
+
+    if let Some(explanation) = explanation {
+        explanation.finish()?;
     }
+    if let Some(path) = &options.dot {
+        fs::write(path, dot)?;
+    }
+    if let Some(path) = &options.kiwi_drivers {
+        info!("Writing the modules kept as a KIWI <drivers> section to {}", path.display());
+        let fragment = kiwi::drivers_fragment(kept_modules.iter().map(String::as_str));
+        atomic::write_atomic(path, |file| file.write_all(fragment.as_bytes()))?;
+    }
+    Ok(CleanupSummary {
+        examined,
+        kept,
+        bytes_reclaimed: deleter.bytes(),
+        reasons: deleter.reasons().clone(),
+        errors: summary_errors,
+        firmware_groups: BTreeMap::new(),
+        deleted: deleter.finish()?,
+    })
+}
 
-    info!("Checking driver dependencies...");
-    let mut worklist: Vec<Driver> = to_keep.iter().cloned().collect();
-    while let Some(driver) = worklist.pop() {
-        for dep_name in &driver.deps {
-            if let Some(dep_driver) = driver_map.get(dep_name) {
-                // If the dependency was not already in to_keep, add it and
-                // put it on the worklist to process its dependencies.
-                if to_keep.insert(dep_driver.clone()) {
-                    info!("Keep dependant driver {}", dep_driver.path.display());
-                    worklist.push(dep_driver.clone());
+/// Returns the files of `kernel_dir` only needed to build modules against the kernel: the
+/// `build` and `source` symlinks to its sources and headers, the `*.symvers` symbol versions and
+/// the `.*.cmd` command files kbuild leaves next to modules built in place.
+pub fn devel_files(fs: &dyn JanitorFs, kernel_dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs
+        .walk(kernel_dir)
+        .into_iter()
+        .filter(|entry| {
+            let name = entry.path.file_name().unwrap_or_default().to_string_lossy();
+            match entry.kind {
+                FileKind::Symlink => {
+                    entry.path.parent() == Some(kernel_dir) && (name == "build" || name == "source")
                 }
+                FileKind::File => {
+                    name.ends_with(".symvers") || (name.starts_with('.') && name.ends_with(".cmd"))
+                }
+                FileKind::Dir => false,
+            }
+        })
+        .map(|entry| entry.path)
+        .collect();
+    files.sort();
+    files
+}
+
+/// Returns the links found in the `weak-updates` directories of the kernels of `module_dir`,
+/// sorted, with the module they point to below `module_dir`, or `None` when it does not exist.
+///
+/// SUSE and RHEL make the modules built for one kernel, e.g. by a KMP or kABI tracking package,
+/// available to the compatible kernels through such links, usually absolute ones like
+/// `/lib/modules/<version>/weak-updates/foo.ko -> /lib/modules/<other>/extra/foo.ko`. Absolute
+/// targets are resolved in `module_dir` rather than on the host.
+pub fn weak_update_links(
+    fs: &dyn JanitorFs,
+    module_dir: &Path,
+) -> Result<Vec<(PathBuf, Option<PathBuf>)>, JanitorError> {
+    let mut links = Vec::new();
+    for kernel_dir in fs.read_dir(module_dir)? {
+        let weak_updates = kernel_dir.join("weak-updates");
+        if !fs.symlink_metadata(&weak_updates).is_ok_and(|m| m.is_dir()) {
+            continue;
+        }
+        for entry in fs.walk(&weak_updates) {
+            if entry.kind != FileKind::Symlink {
+                continue;
             }
+            let target = fs.read_link(&entry.path)?;
+            let target = match util::MODULE_DIRS
+                .iter()
+                .find_map(|dir| target.strip_prefix(Path::new("/").join(dir)).ok())
+            {
+                Some(relative) => module_dir.join(relative),
+                None => entry.path.parent().unwrap_or(&weak_updates).join(target).clean(),
+            };
+            let exists = fs.symlink_metadata(&target).is_ok();
+            links.push((entry.path, exists.then_some(target)));
+        }
+    }
+    links.sort();
+    Ok(links)
+}
+
+/// Moves the modules of `evaluation` to delete which are the target of a link of `targets`,
+/// mapping each target to the link, and the modules they depend on, to the ones to keep. Returns
+/// the paths of the targets.
+fn keep_link_targets(
+    modules: &[Module],
+    evaluation: &mut Evaluation,
+    kernel_dir: &Path,
+    targets: &BTreeMap<&Path, &Path>,
+) -> Vec<String> {
+    let rescued: Vec<(String, Reason)> = evaluation
+        .delete
+        .iter()
+        .filter_map(|path| {
+            let link = targets.get(kernel_dir.join(path).as_path())?;
+            Some((path.clone(), Reason::WeakUpdateTarget(link.display().to_string())))
+        })
+        .collect();
+    let kept = rescued.iter().map(|(path, _)| path.clone()).collect();
+    policy::keep_also(modules, evaluation, rescued);
+    kept
+}
+
+/// Moves the modules of `evaluation` to delete which are called one of `loaded`, and the modules
+/// they depend on, to the ones to keep. Returns the paths of the loaded ones.
+fn keep_loaded(
+    evaluation: &mut Evaluation,
+    modules: &[Module],
+    loaded: &BTreeSet<String>,
+) -> Vec<String> {
+    let kept: Vec<String> = modules
+        .iter()
+        .filter(|m| loaded.contains(&m.name) && evaluation.delete.contains(&m.path))
+        .map(|m| m.path.clone())
+        .collect();
+    let rescued = kept.iter().map(|path| (path.clone(), Reason::Loaded)).collect();
+    policy::keep_also(modules, evaluation, rescued);
+    kept
+}
+
+/// Moves the modules of `evaluation` to delete smaller than `min_size` bytes, and the modules they
+/// depend on, to the ones to keep. Returns the paths of the small ones.
+fn keep_small(
+    fs: &dyn JanitorFs,
+    modules: &[Module],
+    evaluation: &mut Evaluation,
+    kernel_dir: &Path,
+    min_size: u64,
+) -> Result<Vec<String>, JanitorError> {
+    let mut kept = Vec::new();
+    for path in &evaluation.delete {
+        if fs.metadata(&kernel_dir.join(path))?.len < min_size {
+            kept.push(path.clone());
         }
     }
+    let rescued = kept.iter().map(|path| (path.clone(), Reason::BelowMinSize(min_size))).collect();
+    policy::keep_also(modules, evaluation, rescued);
+    Ok(kept)
+}
 
-    let to_delete: Vec<_> = driver_map.values()
-        .filter(|d| !to_keep.contains(d))
+/// Moves the unused modules of `evaluation` to delete which the tree of `kernel_dir` does not need
+/// to lose to fit in `target_size` bytes to the ones to keep, see
+/// [`policy::select_within_budget`]. The modules deleted by a delete rule with a priority of
+/// `rules` are only deleted as needed too, the others deleted by a rule or as blacklisted always
+/// go. The modules kept pull the modules they depend on along. Returns the paths of the modules
+/// kept within the budget.
+fn keep_within_budget(
+    fs: &dyn JanitorFs,
+    modules: &[Module],
+    evaluation: &mut Evaluation,
+    kernel_dir: &Path,
+    rules: Option<&Rules>,
+    target_size: u64,
+) -> Result<Vec<String>, JanitorError> {
+    let mut total: u64 = fs
+        .walk(kernel_dir)
+        .iter()
+        .filter(|entry| entry.kind == FileKind::File)
+        .filter_map(|entry| fs.metadata(&entry.path).ok())
+        .map(|metadata| metadata.len)
+        .sum();
+    let mut candidates = Vec::new();
+    for path in &evaluation.delete {
+        let size = fs.metadata(&kernel_dir.join(path))?.len;
+        let priority = rules.map_or(0, |rules| rules.delete_priority(path));
+        match evaluation.reasons.get(path) {
+            Some(Reason::Unmatched) => candidates.push((path.clone(), size, 0)),
+            Some(Reason::DeleteRule(_)) if priority > 0 => candidates.push((path.clone(), size, priority)),
+            _ => total = total.saturating_sub(size),
+        }
+    }
+    let deleted = policy::select_within_budget(&candidates, total, target_size);
+    let kept: Vec<String> = candidates
+        .iter()
+        .filter(|(path, _, _)| !deleted.contains(path))
+        .map(|(path, _, _)| path.clone())
         .collect();
+    let rescued = kept.iter().map(|path| (path.clone(), Reason::WithinSizeBudget(target_size))).collect();
+    policy::keep_also(modules, evaluation, rescued);
+    // The dependencies of the modules kept may have been selected for deletion.
+    let removed: u64 = candidates
+        .iter()
+        .filter(|(path, _, _)| evaluation.delete.contains(path))
+        .map(|(_, size, _)| size)
+        .sum();
+    if total.saturating_sub(removed) > target_size {
+        warn!("{} does not fit in {} bytes even without its unused modules", kernel_dir.display(), target_size);
+    }
+    Ok(kept)
+}
+
+/// Evaluates the policy over the modules of `kernel_dir`, using `rules` unless only the
+/// modaliases or hardware IDs select the modules to keep. Returns the modules along with the evaluation and the
+/// errors met reading them.
+fn evaluate_kernel(
+    kernel_dir: &Path,
+    options: &DriverOptions,
+    graph: &KernelGraph,
+    modprobe_config: &ModprobeConfig,
+    dracut_drivers: &BTreeSet<String>,
+    rules: Option<&Rules>,
+) -> Result<(Vec<Module>, Evaluation, Vec<String>), JanitorError> {
+    let scan = info_span!("module_scan", kernel = %kernel_dir.display()).entered();
+    info!("Scanning kernel modules in {}", kernel_dir.display());
+
+    // Prefer the dependency graph generated by depmod, it is what modprobe uses at runtime.
+    let dependencies = depmod::read_dependencies(kernel_dir)?;
+    if dependencies.is_none() {
+        info!("No modules.dep found, reading dependencies from module metadata");
+    }
+    let module_softdeps = depmod::read_softdeps(kernel_dir)?;
 
-    info!("Found {} drivers to delete", to_delete.len());
-    debug!("Drivers to delete: {:?}", to_delete.iter().map(|d| &d.path).collect::<Vec<_>>());
+    let interrupted = options.interrupted.unwrap_or(interrupt::is_interrupted);
+    let kernel = graph.kernel(kernel_dir, &options.scan)?;
+    let (mut modules, errors): (Vec<Module>, Vec<Option<String>>) = kernel
+        .paths()
+        .par_iter()
+        .map(|path| {
+            if interrupted() {
+                return Err(JanitorError::Interrupted {
+                    deleted: Vec::new(),
+                    bytes: 0,
+                });
+            }
+            match &dependencies {
+                Some(dependencies) => {
+                    module_from_dependency_map(path, kernel_dir, dependencies, &module_softdeps)
+                        .map(|module| (module, None))
+                }
+                None => module_from_file(path, &kernel),
+            }
+        })
+        .collect::<Result<Vec<_>, JanitorError>>()?
+        .into_iter()
+        .unzip();
+
+    for module in &mut modules {
+        if let Some(softdeps) = modprobe_config.softdeps.get(&module.name) {
+            module.softdeps.extend(softdeps.all().cloned());
+        }
+    }
+    drop(scan);
+    let _span = info_span!("dependency_resolution", kernel = %kernel_dir.display()).entered();
 
-    if delete {
-        for driver in to_delete {
-            info!("Deleting {}", driver.path.display());
-            fs::remove_file(&driver.path)?;
+    // Modules kept by name, whatever the configuration says about them.
+    let mut names = BTreeSet::new();
+    let mut modaliases = options.modaliases.clone().unwrap_or_default();
+    if !options.device_tree.is_empty() {
+        info!("Keeping the drivers of {} device tree nodes", options.device_tree.len());
+        modaliases.extend(options.device_tree.iter().cloned());
+    }
+    if let Some(profile) = &options.profile {
+        info!("Keeping the {} modules loaded on the profiled machine", profile.modules.len());
+        names.extend(profile.modules.iter().cloned());
+        modaliases.extend(profile.modaliases.iter().cloned());
+    }
+    if !modaliases.is_empty() {
+        info!("Matching {} modaliases against module aliases...", modaliases.len());
+        let aliases = depmod::read_aliases(kernel_dir)?;
+        names.extend(policy::match_modaliases(&aliases, &modaliases));
+    }
+    if let Some(ids) = &options.hardware_ids {
+        info!("Matching {} hardware IDs against module aliases...", ids.len());
+        let aliases = depmod::read_aliases(kernel_dir)?;
+        names.extend(hardware::match_hardware_ids(&aliases, ids));
+    }
+    // The modules the image loads at boot may be named by a module alias, e.g. fs-btrfs, which
+    // only the alias index of a kernel tree prepared by depmod resolves.
+    let boot = modprobe_config.boot_modules();
+    if !boot.is_empty() {
+        info!("Keeping the {} modules configured to load at boot", boot.len());
+        names.extend(boot.iter().map(|name| name.replace('-', "_")));
+        let boot: Vec<String> = boot.into_iter().collect();
+        match depmod::read_aliases(kernel_dir) {
+            Ok(aliases) => names.extend(policy::match_modaliases(&aliases, &boot)),
+            Err(JanitorError::MissingIndex(_)) => {}
+            Err(e) => return Err(e),
         }
     }
+    names.extend(dracut_drivers.iter().cloned());
 
-    Ok(())
+    let evaluation = match rules {
+        Some(rules) => {
+            info!("Checking driver dependencies...");
+            policy::evaluate_with_names(&modules, rules, &names)
+        }
+        None => policy::evaluate_names(&modules, &names),
+    };
+    let blacklist = &modprobe_config.blacklist;
+    let evaluation = if options.delete_blacklisted && !blacklist.is_empty() {
+        info!("Deleting the {} modules blacklisted in modprobe.d", blacklist.len());
+        policy::drop_blacklisted(&modules, evaluation, blacklist)
+    } else {
+        evaluation
+    };
+    Ok((modules, evaluation, errors.into_iter().flatten().collect()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::command::CommandRunner;
+    use crate::firmware::{self, FirmwareOptions};
+    use crate::modinfo;
+    use crate::util::KernelSelection;
     use std::collections::HashMap;
+    use std::os::unix::fs::symlink;
+    use std::sync::atomic::{AtomicBool, Ordering};
     use tempfile::tempdir;
 
     struct MockCommandRunner {
@@ -140,6 +718,16 @@ mod tests {
         }
     }
 
+    fn options(config_path: &Path, module_dir: &Path, root: &Path, delete: bool) -> DriverOptions {
+        DriverOptions {
+            config_paths: vec![config_path.to_str().unwrap().to_string()],
+            module_dir: module_dir.to_path_buf(),
+            root: root.to_path_buf(),
+            delete,
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn test_cleanup_drivers() {
         let temp_dir = tempdir().unwrap();
@@ -152,47 +740,734 @@ mod tests {
         let mod_c_path = kernel_dir.join("c.ko");
         let mod_d_path = kernel_dir.join("d.ko");
 
-        fs::write(&mod_a_path, "").unwrap();
-        fs::write(&mod_b_path, "").unwrap();
-        fs::write(&mod_c_path, "").unwrap();
-        fs::write(&mod_d_path, "").unwrap();
+        fs::write(&mod_a_path, modinfo::build_test_module(&["depends=b"])).unwrap();
+        fs::write(&mod_b_path, modinfo::build_test_module(&["depends=c"])).unwrap();
+        fs::write(&mod_c_path, modinfo::build_test_module(&["depends="])).unwrap();
+        fs::write(&mod_d_path, modinfo::build_test_module(&["depends="])).unwrap();
 
         let config_path = temp_dir.path().join("test.conf");
         fs::write(&config_path, "a.ko").unwrap();
 
         let mut responses = HashMap::new();
-        responses.insert(
-            format!("/usr/sbin/modinfo -F depends {}", mod_a_path.display()),
-            "b".to_string(),
-        );
-        responses.insert(
-            format!("/usr/sbin/modinfo -F depends {}", mod_b_path.display()),
-            "c".to_string(),
-        );
-        responses.insert(
-            format!("/usr/sbin/modinfo -F depends {}", mod_c_path.display()),
-            "".to_string(),
-        );
-        responses.insert(
-            format!("/usr/sbin/modinfo -F depends {}", mod_d_path.display()),
-            "".to_string(),
-        );
         responses.insert("arch".to_string(), "x86_64".to_string());
 
         let runner = MockCommandRunner { responses };
 
         // Test dry run
-        cleanup_drivers(&[config_path.to_str().unwrap()], module_dir, false, &runner).unwrap();
+        let summary =
+            cleanup_drivers(&options(&config_path, module_dir, temp_dir.path(), false), &KernelGraph::new(), &runner).unwrap();
+        assert_eq!(
+            summary,
+            CleanupSummary {
+                examined: 4,
+                kept: 3,
+                deleted: vec![mod_d_path.clone()],
+                bytes_reclaimed: fs::metadata(&mod_d_path).unwrap().len(),
+                reasons: BTreeMap::from([("not kept by any rule nor needed by a kept module".to_string(), 1)]),
+                errors: Vec::new(),
+                firmware_groups: BTreeMap::new(),
+            }
+        );
         assert!(mod_a_path.exists());
         assert!(mod_b_path.exists());
         assert!(mod_c_path.exists());
         assert!(mod_d_path.exists());
 
         // Test delete
-        cleanup_drivers(&[config_path.to_str().unwrap()], module_dir, true, &runner).unwrap();
+        cleanup_drivers(&options(&config_path, module_dir, temp_dir.path(), true), &KernelGraph::new(), &runner).unwrap();
         assert!(mod_a_path.exists());
         assert!(mod_b_path.exists());
         assert!(mod_c_path.exists());
         assert!(!mod_d_path.exists());
     }
+
+    #[test]
+    fn test_cleanup_drivers_with_modules_dep() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path();
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(kernel_dir.join("kernel")).unwrap();
+
+        // The module metadata is empty, dependencies only come from modules.dep.
+        let mod_a_path = kernel_dir.join("kernel/a.ko.zst");
+        let mod_b_path = kernel_dir.join("kernel/dash-b.ko.zst");
+        let mod_c_path = kernel_dir.join("kernel/c.ko.zst");
+        for path in [&mod_a_path, &mod_b_path, &mod_c_path] {
+            fs::write(path, "").unwrap();
+        }
+        fs::write(
+            kernel_dir.join("modules.dep"),
+            "kernel/a.ko.zst: kernel/dash-b.ko.zst\nkernel/dash-b.ko.zst:\nkernel/c.ko.zst:\n",
+        )
+        .unwrap();
+
+        let config_path = temp_dir.path().join("test.conf");
+        fs::write(&config_path, "a.ko").unwrap();
+
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+
+        cleanup_drivers(&options(&config_path, module_dir, temp_dir.path(), true), &KernelGraph::new(), &runner).unwrap();
+        assert!(mod_a_path.exists());
+        assert!(mod_b_path.exists());
+        assert!(!mod_c_path.exists());
+    }
+
+    #[test]
+    fn test_cleanup_drivers_keeps_softdeps() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let module_dir = root.join("lib/modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        fs::create_dir_all(root.join("etc/modprobe.d")).unwrap();
+
+        let mod_a_path = kernel_dir.join("a.ko");
+        let mod_pre_path = kernel_dir.join("pre.ko");
+        let mod_post_path = kernel_dir.join("post.ko");
+        let mod_conf_path = kernel_dir.join("conf.ko");
+        let mod_unused_path = kernel_dir.join("unused.ko");
+        fs::write(&mod_a_path, modinfo::build_test_module(&["softdep=pre: pre post: post"])).unwrap();
+        for path in [&mod_pre_path, &mod_post_path, &mod_conf_path, &mod_unused_path] {
+            fs::write(path, modinfo::build_test_module(&["depends="])).unwrap();
+        }
+        fs::write(root.join("etc/modprobe.d/a.conf"), "softdep a post: conf\nalias boot-nic boot\n").unwrap();
+        // Modules loaded at boot are kept with their soft dependencies, also through an alias.
+        fs::create_dir_all(root.join("etc/modules-load.d")).unwrap();
+        fs::write(root.join("etc/modules-load.d/nic.conf"), "boot-nic\n").unwrap();
+        let mod_boot_path = kernel_dir.join("boot.ko");
+        fs::write(&mod_boot_path, modinfo::build_test_module(&["softdep=pre: boot_pre"])).unwrap();
+        let mod_boot_pre_path = kernel_dir.join("boot_pre.ko");
+        fs::write(&mod_boot_pre_path, modinfo::build_test_module(&["depends="])).unwrap();
+
+        let config_path = root.join("test.conf");
+        fs::write(&config_path, "a.ko").unwrap();
+
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+
+        cleanup_drivers(&options(&config_path, &module_dir, root, true), &KernelGraph::new(), &runner).unwrap();
+        assert!(mod_a_path.exists());
+        assert!(mod_pre_path.exists());
+        assert!(mod_post_path.exists());
+        assert!(mod_conf_path.exists());
+        assert!(mod_boot_path.exists());
+        assert!(mod_boot_pre_path.exists());
+        assert!(!mod_unused_path.exists());
+    }
+
+    #[test]
+    fn test_cleanup_drivers_keeps_dracut_drivers() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let module_dir = root.join("lib/modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        fs::create_dir_all(root.join("etc/dracut.conf.d")).unwrap();
+        fs::write(
+            root.join("etc/dracut.conf.d/90-storage.conf"),
+            "add_drivers+=\" nvme \"\nforce_drivers+=\" dm-crypt ahci \"\nomit_drivers+=\" ahci \"\n",
+        )
+        .unwrap();
+
+        let mod_nvme_path = kernel_dir.join("nvme.ko");
+        let mod_core_path = kernel_dir.join("nvme_core.ko");
+        let mod_crypt_path = kernel_dir.join("dm_crypt.ko");
+        let mod_ahci_path = kernel_dir.join("ahci.ko");
+        fs::write(&mod_nvme_path, modinfo::build_test_module(&["depends=nvme_core"])).unwrap();
+        for path in [&mod_core_path, &mod_crypt_path, &mod_ahci_path] {
+            fs::write(path, modinfo::build_test_module(&["depends="])).unwrap();
+        }
+
+        // A delete rule still wins, with a warning.
+        let config_path = root.join("test.conf");
+        fs::write(&config_path, "-dm_crypt.ko").unwrap();
+
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+
+        cleanup_drivers(&options(&config_path, &module_dir, root, true), &KernelGraph::new(), &runner).unwrap();
+        assert!(mod_nvme_path.exists());
+        assert!(mod_core_path.exists());
+        assert!(!mod_crypt_path.exists());
+        assert!(!mod_ahci_path.exists());
+    }
+
+    #[test]
+    fn test_cleanup_drivers_with_modaliases() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path();
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(kernel_dir.join("kernel")).unwrap();
+
+        let e1000e = kernel_dir.join("kernel/e1000e.ko");
+        let ptp = kernel_dir.join("kernel/ptp.ko");
+        let igb = kernel_dir.join("kernel/igb.ko");
+        for path in [&e1000e, &ptp, &igb] {
+            fs::write(path, "").unwrap();
+        }
+        fs::write(
+            kernel_dir.join("modules.dep"),
+            "kernel/e1000e.ko: kernel/ptp.ko\nkernel/ptp.ko:\nkernel/igb.ko:\n",
+        )
+        .unwrap();
+        fs::write(
+            kernel_dir.join("modules.alias"),
+            "alias pci:v00008086d000010D3sv*sd*bc*sc*i* e1000e\nalias pci:v00008086d000010C9sv*sd*bc*sc*i* igb\n",
+        )
+        .unwrap();
+
+        // No configuration file and no arch command: the config files are not used in this mode.
+        let runner = MockCommandRunner { responses: HashMap::new() };
+        let options = DriverOptions {
+            module_dir: module_dir.to_path_buf(),
+            root: module_dir.to_path_buf(),
+            delete: true,
+            modaliases: Some(vec!["pci:v00008086d000010D3sv00008086sd0000A01Fbc02sc00i00".to_string()]),
+            ..Default::default()
+        };
+        cleanup_drivers(&options, &KernelGraph::new(), &runner).unwrap();
+        assert!(e1000e.exists());
+        assert!(ptp.exists());
+        assert!(!igb.exists());
+    }
+
+    #[test]
+    fn test_cleanup_drivers_with_hardware_ids() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path();
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(kernel_dir.join("kernel")).unwrap();
+
+        let e1000e = kernel_dir.join("kernel/e1000e.ko");
+        let ptp = kernel_dir.join("kernel/ptp.ko");
+        let igb = kernel_dir.join("kernel/igb.ko");
+        let ahci = kernel_dir.join("kernel/ahci.ko");
+        for path in [&e1000e, &ptp, &igb, &ahci] {
+            fs::write(path, "").unwrap();
+        }
+        fs::write(
+            kernel_dir.join("modules.dep"),
+            "kernel/e1000e.ko: kernel/ptp.ko\nkernel/ptp.ko:\nkernel/igb.ko: kernel/ptp.ko\nkernel/ahci.ko:\n",
+        )
+        .unwrap();
+        fs::write(
+            kernel_dir.join("modules.alias"),
+            "alias pci:v00008086d000010D3sv*sd*bc*sc*i* e1000e\nalias pci:v00008086d000010C9sv*sd*bc*sc*i* igb\n\
+             alias pci:v*d*sv*sd*bc01sc06i01* ahci\n",
+        )
+        .unwrap();
+
+        let runner = MockCommandRunner { responses: HashMap::new() };
+        let options = DriverOptions {
+            module_dir: module_dir.to_path_buf(),
+            root: module_dir.to_path_buf(),
+            delete: true,
+            hardware_ids: Some(vec![HardwareId::parse("pci 8086:10c9").unwrap()]),
+            ..Default::default()
+        };
+        let summary = cleanup_drivers(&options, &KernelGraph::new(), &runner).unwrap();
+        assert!(igb.exists());
+        assert!(ptp.exists());
+        // The class drivers can match any device.
+        assert!(ahci.exists());
+        assert!(!e1000e.exists());
+        assert_eq!(
+            summary.reasons,
+            BTreeMap::from([("not kept by any rule nor needed by a kept module".to_string(), 1)])
+        );
+    }
+
+    #[test]
+    fn test_cleanup_drivers_with_profile() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path();
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(kernel_dir.join("kernel")).unwrap();
+
+        let paths: Vec<_> = ["a", "loaded", "e1000e", "unused"]
+            .iter()
+            .map(|name| kernel_dir.join(format!("kernel/{}.ko", name)))
+            .collect();
+        for path in &paths {
+            fs::write(path, "").unwrap();
+        }
+        fs::write(
+            kernel_dir.join("modules.dep"),
+            "kernel/a.ko:\nkernel/loaded.ko:\nkernel/e1000e.ko:\nkernel/unused.ko:\n",
+        )
+        .unwrap();
+        fs::write(kernel_dir.join("modules.alias"), "alias pci:v00008086d000010D3* e1000e\n").unwrap();
+
+        let config_path = temp_dir.path().join("test.conf");
+        fs::write(&config_path, "a.ko").unwrap();
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+
+        let profile = Profile {
+            modules: ["loaded".to_string()].into(),
+            modaliases: ["pci:v00008086d000010D3sv00008086sd0000A01Fbc02sc00i00".to_string()].into(),
+            ..Default::default()
+        };
+        let options = DriverOptions {
+            profile: Some(profile),
+            ..options(&config_path, module_dir, temp_dir.path(), true)
+        };
+        cleanup_drivers(&options, &KernelGraph::new(), &runner).unwrap();
+        assert!(paths[0].exists());
+        assert!(paths[1].exists());
+        assert!(paths[2].exists());
+        assert!(!paths[3].exists());
+    }
+
+    #[test]
+    fn test_cleanup_drivers_all_kernels() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("modules");
+        let mut paths = Vec::new();
+        for version in ["6.1.0-1", "6.1.0-2"] {
+            let kernel_dir = module_dir.join(version);
+            fs::create_dir_all(&kernel_dir).unwrap();
+            for name in ["a", "d"] {
+                let path = kernel_dir.join(format!("{}.ko", name));
+                fs::write(&path, modinfo::build_test_module(&["depends="])).unwrap();
+                paths.push(path);
+            }
+        }
+
+        let config_path = temp_dir.path().join("test.conf");
+        fs::write(&config_path, "a.ko").unwrap();
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+
+        let mut options = options(&config_path, &module_dir, temp_dir.path(), true);
+        options.scan.kernels = KernelSelection::All;
+        let deleted = cleanup_drivers(&options, &KernelGraph::new(), &runner).unwrap().deleted;
+        assert_eq!(deleted, vec![paths[1].clone(), paths[3].clone()]);
+        assert!(paths[0].exists());
+        assert!(paths[2].exists());
+    }
+
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    /// Strips modules, and raises the stop flag as a termination signal would.
+    struct InterruptingRunner;
+
+    impl CommandRunner for InterruptingRunner {
+        fn run(&self, command: &str, args: &[&str]) -> Result<String, JanitorError> {
+            match command {
+                "arch" => Ok("x86_64".to_string()),
+                "strip" => {
+                    fs::write(args[1], modinfo::build_test_module(&["depends="]))?;
+                    STOP.store(true, Ordering::SeqCst);
+                    Ok(String::new())
+                }
+                _ => Err(JanitorError::Command(format!("Not mocked: {}", command))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_cleanup_drivers_interrupted() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("modules");
+        let mut paths = Vec::new();
+        for version in ["6.1.0-1", "6.1.0-2"] {
+            let kernel_dir = module_dir.join(version);
+            fs::create_dir_all(&kernel_dir).unwrap();
+            let kept = modinfo::build_test_elf(&[(".modinfo", b"depends=\0"), (".debug_info", b"debug")]);
+            fs::write(kernel_dir.join("a.ko"), kept).unwrap();
+            let path = kernel_dir.join("d.ko");
+            fs::write(&path, modinfo::build_test_module(&["depends="])).unwrap();
+            paths.push(path);
+        }
+        let config_path = temp_dir.path().join("test.conf");
+        fs::write(&config_path, "a.ko").unwrap();
+
+        // The signal arrives while stripping the first kernel, the scan of the second one stops.
+        let mut options = options(&config_path, &module_dir, temp_dir.path(), true);
+        options.scan.kernels = KernelSelection::All;
+        options.strip_debug = true;
+        options.interrupted = Some(|| STOP.load(Ordering::SeqCst));
+        match cleanup_drivers(&options, &KernelGraph::new(), &InterruptingRunner) {
+            Err(JanitorError::Interrupted { deleted, bytes }) => {
+                assert_eq!(deleted, vec![paths[0].clone()]);
+                assert_eq!(bytes, modinfo::build_test_module(&["depends="]).len() as u64);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(!paths[0].exists());
+        assert!(paths[1].exists());
+    }
+
+    #[test]
+    fn test_cleanup_drivers_weak_updates() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("modules");
+        let old_dir = module_dir.join("6.1.0-1");
+        let new_dir = module_dir.join("6.1.0-2");
+        fs::create_dir_all(old_dir.join("extra")).unwrap();
+        fs::create_dir_all(new_dir.join("weak-updates")).unwrap();
+        for path in [old_dir.join("extra/helper.ko"), old_dir.join("extra/old.ko"), new_dir.join("a.ko")] {
+            fs::write(path, modinfo::build_test_module(&["depends="])).unwrap();
+        }
+        fs::write(old_dir.join("extra/kmp.ko"), modinfo::build_test_module(&["depends=helper"])).unwrap();
+        // Absolute targets are resolved in the module directory, not on the host.
+        symlink("/lib/modules/6.1.0-1/extra/kmp.ko", new_dir.join("weak-updates/kmp.ko")).unwrap();
+        symlink("../../6.1.0-1/extra/gone.ko", new_dir.join("weak-updates/gone.ko")).unwrap();
+
+        let config_path = temp_dir.path().join("test.conf");
+        fs::write(&config_path, "a.ko").unwrap();
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+
+        let mut options = options(&config_path, &module_dir, temp_dir.path(), true);
+        options.scan.kernels = KernelSelection::All;
+        let deleted = cleanup_drivers(&options, &KernelGraph::new(), &runner).unwrap().deleted;
+        assert_eq!(deleted, vec![old_dir.join("extra/old.ko"), new_dir.join("weak-updates/gone.ko")]);
+        assert!(old_dir.join("extra/kmp.ko").exists());
+        assert!(old_dir.join("extra/helper.ko").exists());
+        assert!(fs::symlink_metadata(new_dir.join("weak-updates/kmp.ko")).is_ok());
+    }
+
+    #[test]
+    fn test_cleanup_drivers_flavor_sections() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("modules");
+        for version in ["6.4.0-150600.23-default", "6.4.0-150600.23-rt"] {
+            let kernel_dir = module_dir.join(version);
+            fs::create_dir_all(&kernel_dir).unwrap();
+            for name in ["a", "rt_only"] {
+                fs::write(kernel_dir.join(format!("{}.ko", name)), modinfo::build_test_module(&["depends="])).unwrap();
+            }
+        }
+
+        let config_path = temp_dir.path().join("test.conf");
+        fs::write(&config_path, "a.ko\n<flavor:rt>\nrt_only.ko\n</flavor:rt>\n").unwrap();
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+
+        let mut options = options(&config_path, &module_dir, temp_dir.path(), false);
+        options.scan.kernels = KernelSelection::All;
+        let deleted = cleanup_drivers(&options, &KernelGraph::new(), &runner).unwrap().deleted;
+        assert_eq!(deleted, vec![module_dir.join("6.4.0-150600.23-default/rt_only.ko")]);
+    }
+
+    #[test]
+    fn test_firmware_cleanup_sees_removed_drivers() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        let fw_dir = temp_dir.path().join("firmware");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        fs::create_dir_all(&fw_dir).unwrap();
+        for name in ["a", "d"] {
+            let field = format!("firmware={}.bin", name);
+            fs::write(kernel_dir.join(format!("{}.ko", name)), modinfo::build_test_module(&[&field])).unwrap();
+            fs::write(fw_dir.join(format!("{}.bin", name)), "fw").unwrap();
+        }
+
+        let config_path = temp_dir.path().join("test.conf");
+        fs::write(&config_path, "a.ko").unwrap();
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+
+        // Dry runs: the firmware of d.ko is unused once d.ko is gone, although it is still on disk.
+        let graph = KernelGraph::new();
+        let options = options(&config_path, &module_dir, temp_dir.path(), false);
+        assert_eq!(cleanup_drivers(&options, &graph, &runner).unwrap().deleted, vec![kernel_dir.join("d.ko")]);
+        let firmware_options = FirmwareOptions {
+            module_dir: module_dir.clone(),
+            firmware_dir: fw_dir.clone(),
+            ..Default::default()
+        };
+        let deleted = firmware::cleanup_firmware(&firmware_options, &graph).unwrap().deleted;
+        assert_eq!(deleted, vec![fw_dir.join("d.bin")]);
+    }
+
+    #[test]
+    fn test_cleanup_drivers_explain() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        fs::write(kernel_dir.join("a.ko"), modinfo::build_test_module(&["depends=b"])).unwrap();
+        fs::write(kernel_dir.join("b.ko"), modinfo::build_test_module(&["depends="])).unwrap();
+        fs::write(kernel_dir.join("d.ko"), modinfo::build_test_module(&["depends="])).unwrap();
+
+        let config_path = temp_dir.path().join("test.conf");
+        fs::write(&config_path, "a.ko").unwrap();
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+        let report = temp_dir.path().join("explain.jsonl");
+
+        let options = DriverOptions {
+            explain: Some(report.clone()),
+            ..options(&config_path, &module_dir, temp_dir.path(), false)
+        };
+        cleanup_drivers(&options, &KernelGraph::new(), &runner).unwrap();
+
+        let decisions: Vec<_> = crate::explain::read_explanation(&report)
+            .unwrap()
+            .into_iter()
+            .map(|d| (d.path, d.action, d.reason))
+            .collect();
+        assert_eq!(
+            decisions,
+            vec![
+                (kernel_dir.join("a.ko"), Action::Keep, "matched keep rule 'a.ko'".to_string()),
+                (kernel_dir.join("b.ko"), Action::Keep, "dependency of a".to_string()),
+                (
+                    kernel_dir.join("d.ko"),
+                    Action::Delete,
+                    "not kept by any rule nor needed by a kept module".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cleanup_drivers_dot() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        fs::write(kernel_dir.join("a.ko"), modinfo::build_test_module(&["depends=b"])).unwrap();
+        fs::write(kernel_dir.join("b.ko"), modinfo::build_test_module(&["depends="])).unwrap();
+
+        let config_path = temp_dir.path().join("test.conf");
+        fs::write(&config_path, "a.ko").unwrap();
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+        let dot_path = temp_dir.path().join("deps.dot");
+
+        let options = DriverOptions {
+            dot: Some(dot_path.clone()),
+            ..options(&config_path, &module_dir, temp_dir.path(), false)
+        };
+        cleanup_drivers(&options, &KernelGraph::new(), &runner).unwrap();
+
+        let dot = fs::read_to_string(&dot_path).unwrap();
+        assert!(dot.starts_with("digraph \"6.1.0-test\" {"));
+        assert!(dot.contains("\"a\" -> \"b\";"));
+    }
+
+    #[test]
+    fn test_cleanup_drivers_drop_binary_indexes() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        fs::write(kernel_dir.join("a.ko"), modinfo::build_test_module(&["depends="])).unwrap();
+        fs::write(kernel_dir.join("modules.dep"), "a.ko:\n").unwrap();
+        fs::write(kernel_dir.join("modules.dep.bin"), "index").unwrap();
+
+        let config_path = temp_dir.path().join("test.conf");
+        fs::write(&config_path, "a.ko").unwrap();
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+
+        let options = DriverOptions {
+            drop_binary_indexes: true,
+            ..options(&config_path, &module_dir, temp_dir.path(), true)
+        };
+        let deleted = cleanup_drivers(&options, &KernelGraph::new(), &runner).unwrap().deleted;
+        assert_eq!(deleted, vec![kernel_dir.join("modules.dep.bin")]);
+        assert!(kernel_dir.join("modules.dep").exists());
+        assert!(kernel_dir.join("a.ko").exists());
+    }
+
+    #[test]
+    fn test_cleanup_drivers_remove_devel_files() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(kernel_dir.join("extra")).unwrap();
+        fs::write(kernel_dir.join("a.ko"), modinfo::build_test_module(&["depends="])).unwrap();
+        std::os::unix::fs::symlink("/usr/src/linux-6.1.0-obj", kernel_dir.join("build")).unwrap();
+        std::os::unix::fs::symlink("/usr/src/linux-6.1.0", kernel_dir.join("source")).unwrap();
+        fs::write(kernel_dir.join("Module.symvers"), "0x0\tprintk\tvmlinux\tEXPORT_SYMBOL\n").unwrap();
+        fs::write(kernel_dir.join("extra/.a.ko.cmd"), "cmd_a.ko := ld -r").unwrap();
+        // Only the symlinks of the kernel directory itself are the kernel sources.
+        std::os::unix::fs::symlink("a.ko", kernel_dir.join("extra/build")).unwrap();
+
+        let config_path = temp_dir.path().join("test.conf");
+        fs::write(&config_path, "a.ko").unwrap();
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+
+        let options = DriverOptions {
+            remove_devel_files: true,
+            explain: Some(temp_dir.path().join("explain.jsonl")),
+            ..options(&config_path, &module_dir, temp_dir.path(), true)
+        };
+        let deleted = cleanup_drivers(&options, &KernelGraph::new(), &runner).unwrap().deleted;
+        assert_eq!(
+            deleted,
+            vec![
+                kernel_dir.join("Module.symvers"),
+                kernel_dir.join("build"),
+                kernel_dir.join("extra/.a.ko.cmd"),
+                kernel_dir.join("source"),
+            ]
+        );
+        assert!(fs::symlink_metadata(kernel_dir.join("build")).is_err());
+        assert!(fs::symlink_metadata(kernel_dir.join("extra/build")).is_ok());
+        assert!(kernel_dir.join("a.ko").exists());
+    }
+
+    #[test]
+    fn test_keep_loaded() {
+        let module = |name: &str, deps: &[&str]| Module {
+            name: name.to_string(),
+            path: format!("kernel/{}.ko", name),
+            deps: deps.iter().map(|d| d.to_string()).collect(),
+            softdeps: Vec::new(),
+        };
+        let modules = vec![module("nvme", &["nvme-core"]), module("nvme-core", &[]), module("e1000e", &[]), module("igb", &[])];
+        let rules = Rules::parse("-kernel/.*\n", "x86_64").unwrap();
+        let mut evaluation = policy::evaluate(&modules, &rules);
+        let loaded = BTreeSet::from(["nvme".to_string(), "ext4".to_string()]);
+
+        assert_eq!(keep_loaded(&mut evaluation, &modules, &loaded), vec!["kernel/nvme.ko"]);
+        assert!(evaluation.keep.contains("kernel/nvme.ko"));
+        assert!(!evaluation.delete.contains("kernel/nvme.ko"));
+        assert_eq!(evaluation.reasons["kernel/nvme.ko"], Reason::Loaded);
+        assert_eq!(evaluation.reasons["kernel/nvme-core.ko"], Reason::Dependency("nvme".to_string()));
+        assert!(evaluation.delete.contains("kernel/igb.ko"));
+    }
+
+    #[test]
+    fn test_cleanup_drivers_min_size() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        for name in ["big.ko", "helper.ko"] {
+            let mut big = modinfo::build_test_module(&["depends="]);
+            big.resize(4096, 0);
+            fs::write(kernel_dir.join(name), big).unwrap();
+        }
+        // The small module is kept with the large one it depends on.
+        fs::write(kernel_dir.join("small.ko"), modinfo::build_test_module(&["depends=helper"])).unwrap();
+        let config_path = temp_dir.path().join("test.conf");
+        fs::write(&config_path, "none.ko").unwrap();
+
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+        let options = DriverOptions {
+            min_size: 1024,
+            ..options(&config_path, &module_dir, temp_dir.path(), true)
+        };
+        let deleted = cleanup_drivers(&options, &KernelGraph::new(), &runner).unwrap().deleted;
+        assert_eq!(deleted, vec![kernel_dir.join("big.ko")]);
+        assert!(kernel_dir.join("small.ko").exists());
+        assert!(kernel_dir.join("helper.ko").exists());
+    }
+
+    #[test]
+    fn test_cleanup_drivers_target_size() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        for (name, size) in [("big.ko", 16384), ("medium.ko", 8192), ("rule.ko", 1024)] {
+            let mut module = modinfo::build_test_module(&["depends="]);
+            module.resize(size, 0);
+            fs::write(kernel_dir.join(name), module).unwrap();
+        }
+        fs::write(kernel_dir.join("small.ko"), modinfo::build_test_module(&["depends="])).unwrap();
+        let config_path = temp_dir.path().join("test.conf");
+        fs::write(&config_path, "-rule.ko").unwrap();
+
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+        let options = DriverOptions {
+            target_size: Some(10000),
+            ..options(&config_path, &module_dir, temp_dir.path(), true)
+        };
+        let summary = cleanup_drivers(&options, &KernelGraph::new(), &runner).unwrap();
+        assert_eq!(summary.deleted, vec![kernel_dir.join("big.ko"), kernel_dir.join("rule.ko")]);
+        // Each module is counted under its own reason.
+        assert_eq!(
+            summary.reasons,
+            BTreeMap::from([
+                ("matched delete rule '-rule.ko'".to_string(), 1),
+                ("not kept by any rule nor needed by a kept module".to_string(), 1),
+            ])
+        );
+        assert!(kernel_dir.join("medium.ko").exists());
+        assert!(kernel_dir.join("small.ko").exists());
+    }
+
+    #[test]
+    fn test_cleanup_drivers_target_size_dependencies() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        // Deleting big.ko is enough to fit, but medium.ko kept within the budget depends on it.
+        let modules = [("big.ko", "depends=", 32768), ("medium.ko", "depends=big", 8192), ("small.ko", "depends=", 4096)];
+        for (name, depends, size) in modules {
+            let mut module = modinfo::build_test_module(&[depends]);
+            module.resize(size, 0);
+            fs::write(kernel_dir.join(name), module).unwrap();
+        }
+        let config_path = temp_dir.path().join("test.conf");
+        fs::write(&config_path, "none.ko").unwrap();
+
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+        let options = DriverOptions {
+            target_size: Some(15000),
+            ..options(&config_path, &module_dir, temp_dir.path(), true)
+        };
+        let deleted = cleanup_drivers(&options, &KernelGraph::new(), &runner).unwrap().deleted;
+        assert!(deleted.is_empty());
+        assert!(kernel_dir.join("big.ko").exists());
+    }
+
+    #[test]
+    fn test_cleanup_drivers_kiwi() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        for name in ["kernel/drivers/ata/ahci.ko", "kernel/fs/squashfs/squashfs.ko", "kernel/sound/snd.ko"] {
+            fs::create_dir_all(kernel_dir.join(name).parent().unwrap()).unwrap();
+            fs::write(kernel_dir.join(name), modinfo::build_test_module(&["depends="])).unwrap();
+        }
+        let config_path = temp_dir.path().join("test.conf");
+        fs::write(&config_path, "kernel/fs/.*").unwrap();
+        let fragment = temp_dir.path().join("drivers.xml");
+
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+        let options = DriverOptions {
+            extra_rules: vec![kiwi::keep_rule("drivers/ata/*")],
+            kiwi_drivers: Some(fragment.clone()),
+            ..options(&config_path, &module_dir, temp_dir.path(), false)
+        };
+        let deleted = cleanup_drivers(&options, &KernelGraph::new(), &runner).unwrap().deleted;
+        assert_eq!(deleted, vec![kernel_dir.join("kernel/sound/snd.ko")]);
+        assert_eq!(
+            fs::read_to_string(&fragment).unwrap(),
+            "<drivers>\n    <file name=\"drivers/ata/ahci.ko\"/>\n    <file name=\"fs/squashfs/squashfs.ko\"/>\n</drivers>\n"
+        );
+    }
 }
@@ -0,0 +1,187 @@
+//! Read-only analysis of erofs images.
+//!
+//! The image is extracted with `fsck.erofs` from erofs-utils into a work directory, and the
+//! driver and firmware cleanups are run there as dry runs. The result tells how much the image
+//! would shrink and lists the files to exclude when rebuilding it.
+
+use crate::command::CommandRunner;
+use crate::driver::{self, DriverOptions};
+use crate::error::JanitorError;
+use crate::firmware::{self, FirmwareOptions};
+use crate::kernel_graph::KernelGraph;
+use crate::util::{self, ScanOptions};
+use log::info;
+use serde::Serialize;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Options of an erofs image inspection.
+#[derive(Debug, Clone, Default)]
+pub struct InspectOptions {
+    /// The erofs image.
+    pub image: PathBuf,
+    /// Empty or missing directory the image is extracted to, removed afterwards, a new temporary
+    /// directory if unset.
+    pub work_dir: Option<PathBuf>,
+    /// Module list configuration files.
+    pub config_paths: Vec<String>,
+    pub scan: ScanOptions,
+}
+
+/// Files of an image the cleanups would remove.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SavingsReport {
+    /// Absolute paths inside the image.
+    pub files: Vec<String>,
+    /// Total size of these files.
+    pub bytes: u64,
+}
+
+impl SavingsReport {
+    /// Writes the files one per line, as expected by `mkfs.erofs --exclude-path` and
+    /// `mksquashfs -ef`.
+    pub fn write_exclude_list(&self, path: &Path) -> Result<(), JanitorError> {
+        let mut file = fs::File::create(path)?;
+        for excluded in &self.files {
+            writeln!(file, "{}", excluded)?;
+        }
+        Ok(())
+    }
+}
+
+/// Extracts the image and computes what the driver and firmware cleanups would remove from it.
+pub fn inspect_erofs(
+    options: &InspectOptions,
+    runner: &dyn CommandRunner,
+) -> Result<SavingsReport, JanitorError> {
+    util::with_work_dir(options.work_dir.as_deref(), "image-janitor-erofs-", |root| {
+        extract_and_analyze(options, root, runner)
+    })
+}
+
+fn extract_and_analyze(
+    options: &InspectOptions,
+    root: &Path,
+    runner: &dyn CommandRunner,
+) -> Result<SavingsReport, JanitorError> {
+    info!(
+        "Extracting {} to {}",
+        options.image.display(),
+        root.display()
+    );
+    let extract = format!("--extract={}", root.display());
+    let image = options.image.to_string_lossy();
+    runner.run("fsck.erofs", &[&extract, "--no-preserve", &image])?;
+
+    let module_dir = util::find_in_root(root, util::MODULE_DIRS);
+    let firmware_dir = util::find_in_root(root, util::FIRMWARE_DIRS);
+
+    // Both cleanups scan the same module trees.
+    let graph = KernelGraph::new();
+    let mut removed = driver::cleanup_drivers(
+        &DriverOptions {
+            config_paths: options.config_paths.clone(),
+            module_dir: module_dir.clone(),
+            root: root.to_path_buf(),
+            scan: options.scan.clone(),
+            ..Default::default()
+        },
+        &graph,
+        runner,
+    )?;
+    if firmware_dir.is_dir() {
+        removed.merge(firmware::cleanup_firmware(
+            &FirmwareOptions {
+                module_dir,
+                firmware_dir,
+                root: Some(root.to_path_buf()),
+                scan: options.scan.clone(),
+                ..Default::default()
+            },
+            &graph,
+        )?);
+    }
+
+    let mut report = SavingsReport::default();
+    for path in removed.deleted {
+        report.bytes += fs::symlink_metadata(&path)?.len();
+        let relative = path
+            .strip_prefix(root)
+            .map_err(|_| JanitorError::InvalidPath(path.clone()))?;
+        report.files.push(format!("/{}", relative.display()));
+    }
+    report.files.sort();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modinfo;
+    use tempfile::tempdir;
+
+    /// Simulates fsck.erofs by writing a small image tree to the extraction directory.
+    struct ExtractingRunner;
+
+    impl CommandRunner for ExtractingRunner {
+        fn run(&self, command: &str, args: &[&str]) -> Result<String, JanitorError> {
+            match command {
+                "arch" => Ok("x86_64".to_string()),
+                "fsck.erofs" => {
+                    let root = Path::new(args[0].strip_prefix("--extract=").unwrap());
+                    let kernel_dir = root.join("usr/lib/modules/6.1.0-test");
+                    let fw_dir = root.join("usr/lib/firmware");
+                    fs::create_dir_all(&kernel_dir).unwrap();
+                    fs::create_dir_all(&fw_dir).unwrap();
+                    fs::write(
+                        kernel_dir.join("a.ko"),
+                        modinfo::build_test_module(&["firmware=a.bin"]),
+                    )
+                    .unwrap();
+                    fs::write(kernel_dir.join("b.ko"), modinfo::build_test_module(&[])).unwrap();
+                    fs::write(fw_dir.join("a.bin"), "a").unwrap();
+                    fs::write(fw_dir.join("unused.bin"), "12345").unwrap();
+                    Ok(String::new())
+                }
+                _ => Err(JanitorError::Command(format!("Not mocked: {}", command))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_inspect_erofs() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("module.list");
+        fs::write(&config_path, "a.ko").unwrap();
+        let work_dir = temp_dir.path().join("work");
+
+        let options = InspectOptions {
+            image: temp_dir.path().join("root.erofs"),
+            work_dir: Some(work_dir.clone()),
+            config_paths: vec![config_path.to_str().unwrap().to_string()],
+            ..Default::default()
+        };
+        let report = inspect_erofs(&options, &ExtractingRunner).unwrap();
+
+        assert_eq!(
+            report.files,
+            vec![
+                "/usr/lib/firmware/unused.bin",
+                "/usr/lib/modules/6.1.0-test/b.ko"
+            ]
+        );
+        assert_eq!(
+            report.bytes,
+            5 + modinfo::build_test_module(&[]).len() as u64
+        );
+        assert!(!work_dir.exists());
+
+        let exclude_path = temp_dir.path().join("exclude.txt");
+        report.write_exclude_list(&exclude_path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&exclude_path).unwrap(),
+            "/usr/lib/firmware/unused.bin\n/usr/lib/modules/6.1.0-test/b.ko\n"
+        );
+    }
+}
@@ -9,9 +9,13 @@ pub enum JanitorError {
     #[error("Regex error")]
     Regex(#[from] regex::Error),
 
+    #[cfg(feature = "native")]
     #[error("Walkdir error")]
     Walkdir(#[from] walkdir::Error),
 
+    #[error("JSON error")]
+    Json(#[from] serde_json::Error),
+
     #[error("Command failed: {0}")]
     Command(String),
 
@@ -23,4 +27,44 @@ pub enum JanitorError {
 
     #[error("Could not read config file '{0}': {1}")]
     ConfigRead(String, std::io::Error),
+
+    #[error("Invalid configuration:\n{0}")]
+    InvalidConfig(String),
+
+    #[error("Could not parse kernel module '{0}': {1}")]
+    ModuleParse(PathBuf, String),
+
+    #[error("Could not parse index file '{0}': {1}")]
+    IndexParse(PathBuf, String),
+
+    #[error("Module index not found: {0}")]
+    MissingIndex(PathBuf),
+
+    #[error("Could not parse device tree '{0}': {1}")]
+    DeviceTreeParse(PathBuf, String),
+
+    #[error("Restored file '{0}' does not match the journal")]
+    UndoMismatch(PathBuf),
+
+    #[error("The journal '{0}' records deletions only a backup archive can undo")]
+    MissingBackup(PathBuf),
+
+    #[error("File '{0}' does not match the plan")]
+    PlanMismatch(PathBuf),
+
+    #[error("Invalid container image: {0}")]
+    InvalidImage(String),
+
+    #[error("Work directory '{0}' is not empty")]
+    WorkDirNotEmpty(PathBuf),
+
+    #[error("Subcommand name '{0}' is already taken")]
+    DuplicateSubcommand(String),
+
+    #[cfg(feature = "remote")]
+    #[error("HTTP request failed: {0}")]
+    Remote(String),
+
+    #[error("Interrupted after deleting {} files", .deleted.len())]
+    Interrupted { deleted: Vec<PathBuf>, bytes: u64 },
 }
@@ -0,0 +1,262 @@
+//! Explanation of the decisions of a cleanup run.
+//!
+//! The report is a JSON lines file with one record per scanned file, kept or deleted, and the
+//! reason of the decision. Unlike the journal, it is written in dry runs too, to audit a
+//! configuration before applying it. The decisions can also be converted to CSV, to load them in a
+//! spreadsheet.
+
+use crate::error::JanitorError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// What a cleanup does with a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Keep,
+    Delete,
+}
+
+/// The type of a file considered by a cleanup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileType {
+    #[default]
+    Module,
+    Firmware,
+    /// A binary module index written by depmod.
+    Index,
+    /// A file only needed to build modules against the kernel.
+    Devel,
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileType::Module => write!(f, "module"),
+            FileType::Firmware => write!(f, "firmware"),
+            FileType::Index => write!(f, "index"),
+            FileType::Devel => write!(f, "devel"),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Keep => write!(f, "keep"),
+            Action::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+/// The decision taken for one file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Decision {
+    pub path: PathBuf,
+    #[serde(default, rename = "type")]
+    pub file_type: FileType,
+    /// Size of the file when the decision was taken, before it is deleted.
+    #[serde(default)]
+    pub size: u64,
+    pub action: Action,
+    pub reason: String,
+    /// Package owning the deleted file, when package owners were looked up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+}
+
+/// An explanation report being written.
+pub struct Explanation {
+    writer: BufWriter<fs::File>,
+}
+
+impl Explanation {
+    /// Creates the report at `path`, appending to an existing one so the passes of a run can
+    /// share it.
+    pub fn create(path: &Path) -> Result<Self, JanitorError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Explanation {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Records that `path`, of type `file_type`, is kept or deleted, as `action` says, for
+    /// `reason`. Must be called before the file is deleted, to record its size, the size of the
+    /// target for symlinks and of the link itself for dangling ones.
+    pub fn record(
+        &mut self,
+        path: &Path,
+        file_type: FileType,
+        action: Action,
+        reason: &str,
+    ) -> Result<(), JanitorError> {
+        let decision = Decision {
+            path: path.to_path_buf(),
+            file_type,
+            size: fs::metadata(path).or_else(|_| fs::symlink_metadata(path))?.len(),
+            action,
+            reason: reason.to_string(),
+            package: None,
+        };
+        let mut line = serde_json::to_string(&decision)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Flushes the report.
+    pub fn finish(mut self) -> Result<(), JanitorError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the decisions of the report at `path`.
+pub fn read_explanation(path: &Path) -> Result<Vec<Decision>, JanitorError> {
+    let file = fs::File::open(path)?;
+    let mut decisions = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            decisions.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(decisions)
+}
+
+/// Replaces the report at `path` with `decisions`.
+pub fn write_explanation(path: &Path, decisions: &[Decision]) -> Result<(), JanitorError> {
+    let mut writer = BufWriter::new(fs::File::create(path)?);
+    for decision in decisions {
+        serde_json::to_writer(&mut writer, decision)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes `decisions` as CSV to `out`, with a header and one row per file: path, type, size,
+/// decision and reason, plus the owning package if any decision has one.
+pub fn write_csv(decisions: &[Decision], out: &mut dyn Write) -> Result<(), JanitorError> {
+    let packages = decisions.iter().any(|d| d.package.is_some());
+    writeln!(
+        out,
+        "path,type,size,decision,reason{}",
+        if packages { ",package" } else { "" }
+    )?;
+    for decision in decisions {
+        write!(
+            out,
+            "{},{},{},{},{}",
+            csv_field(&decision.path.to_string_lossy()),
+            decision.file_type,
+            decision.size,
+            decision.action,
+            csv_field(&decision.reason)
+        )?;
+        if packages {
+            write!(out, ",{}", csv_field(decision.package.as_deref().unwrap_or_default()))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Quotes `value` as RFC 4180 requires when it holds a separator, a quote or a line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_record_and_write_csv() {
+        let temp_dir = tempdir().unwrap();
+        let module = temp_dir.path().join("a.ko");
+        fs::write(&module, "1234").unwrap();
+        let firmware = temp_dir.path().join("fw, \"new\".bin");
+        fs::write(&firmware, "").unwrap();
+        let report = temp_dir.path().join("explain.jsonl");
+
+        let mut explanation = Explanation::create(&report).unwrap();
+        explanation
+            .record(&module, FileType::Module, Action::Keep, "matched keep rule 'a.ko'")
+            .unwrap();
+        explanation
+            .record(&firmware, FileType::Firmware, Action::Delete, "not required by any module")
+            .unwrap();
+        explanation.finish().unwrap();
+
+        let decisions = read_explanation(&report).unwrap();
+        assert_eq!(decisions[0].size, 4);
+        let mut csv = Vec::new();
+        write_csv(&decisions, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            format!(
+                "path,type,size,decision,reason\n\
+                 {},module,4,keep,matched keep rule 'a.ko'\n\
+                 \"{}\",firmware,0,delete,not required by any module\n",
+                module.display(),
+                firmware.display().to_string().replace('"', "\"\"")
+            )
+        );
+    }
+
+    #[test]
+    fn test_write_explanation_with_packages() {
+        let temp_dir = tempdir().unwrap();
+        let report = temp_dir.path().join("explain.jsonl");
+        let mut decisions = vec![
+            Decision {
+                path: PathBuf::from("/a.ko"),
+                file_type: FileType::Module,
+                size: 1,
+                action: Action::Delete,
+                reason: "unused".to_string(),
+                package: Some("kernel-default".to_string()),
+            },
+            Decision {
+                path: PathBuf::from("/b.ko"),
+                file_type: FileType::Module,
+                size: 2,
+                action: Action::Keep,
+                reason: "required".to_string(),
+                package: None,
+            },
+        ];
+        write_explanation(&report, &decisions).unwrap();
+        assert_eq!(read_explanation(&report).unwrap(), decisions);
+        assert!(!fs::read_to_string(&report).unwrap().lines().nth(1).unwrap().contains("package"));
+
+        let mut csv = Vec::new();
+        write_csv(&decisions, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "path,type,size,decision,reason,package\n\
+             /a.ko,module,1,delete,unused,kernel-default\n\
+             /b.ko,module,2,keep,required,\n"
+        );
+        decisions[0].package = None;
+        let mut csv = Vec::new();
+        write_csv(&decisions, &mut csv).unwrap();
+        assert!(String::from_utf8(csv).unwrap().starts_with("path,type,size,decision,reason\n"));
+    }
+}
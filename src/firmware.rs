@@ -1,41 +1,127 @@
-use crate::command::CommandRunner;
+use crate::amdgpu;
+use crate::atomic;
+use crate::clock::Clock;
+use crate::deleter::Deleter;
+use crate::driver;
 use crate::error::JanitorError;
-use crate::util;
-use log::{debug, info};
+use crate::explain::{Action, Explanation, FileType};
+use crate::iwlwifi;
+use crate::janitor_fs::{FileKind, JanitorFs, RealFs};
+use crate::kernel_graph::KernelGraph;
+use crate::manifest::{self, KeptEntry, ListFormat};
+use crate::microcode::{self, MicrocodePolicy};
+use crate::modinfo;
+use crate::policy::{self, Reason, RuleMatch, Rules};
+use crate::profile::Profile;
+use crate::sof;
+use crate::summary::{CleanupSummary, GroupSummary, TOP_LEVEL_GROUP};
+use crate::util::{self, ScanOptions};
+use glob::Pattern;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
 use path_clean::PathClean;
-use std::collections::HashSet;
-use std::fs;
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use walkdir::WalkDir;
-
-fn find_kernel_modules(kernel_dir: &Path) -> Result<Vec<PathBuf>, JanitorError> {
-    let mut modules = Vec::new();
-    for entry in WalkDir::new(kernel_dir) {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_file()
-            && (path.extension().is_some_and(|e| e == "ko")
-                || path.to_str().is_some_and(|s| s.ends_with(".ko.xz"))
-                || path.to_str().is_some_and(|s| s.ends_with(".ko.zst")))
-        {
-            modules.push(path.to_path_buf());
-        }
-    }
-    Ok(modules)
+use std::sync::Arc;
+use tracing::info_span;
+
+/// Returns the directories the kernel looks firmware up in, by order of preference: the updates
+/// for kernel `release`, the updates, the firmware of kernel `release` and `fw_dir` itself.
+fn firmware_layers(fw_dir: &Path, release: Option<&str>) -> Vec<PathBuf> {
+    let updates = fw_dir.join("updates");
+    let mut layers = Vec::new();
+    if let Some(release) = release {
+        layers.push(updates.join(release));
+    }
+    layers.push(updates);
+    if let Some(release) = release {
+        layers.push(fw_dir.join(release));
+    }
+    layers.push(fw_dir.to_path_buf());
+    layers
 }
 
-fn get_firmware_deps_for_module(
-    module_path: &Path,
-    runner: &dyn CommandRunner,
-) -> Result<Vec<String>, JanitorError> {
-    let firmware_list = runner.run(
-        "/usr/sbin/modinfo",
-        &["-F", "firmware", module_path.to_str().unwrap()],
-    )?;
-    Ok(firmware_list.lines().map(String::from).collect())
+lazy_static! {
+    /// A printf-style placeholder, such as `%02d` or `%s`, or an escaped `%`.
+    static ref PLACEHOLDER: Regex = Regex::new(r"%(?:%|[-+ #0]*\d*(?:\.\d+)?(?:hh|h|ll|l|z|j|t)?[diouxXcsp])").unwrap();
+}
+
+/// Returns the firmware name `fw_name` with its printf-style placeholders replaced by `*`
+/// wildcards: some drivers declare the format string of the names they build at runtime, such
+/// as `foo_%02d.bin`.
+fn expand_placeholders(fw_name: &str) -> Cow<'_, str> {
+    if !fw_name.contains('%') {
+        return Cow::Borrowed(fw_name);
+    }
+    let mut expanded = String::new();
+    let mut last = 0;
+    for placeholder in PLACEHOLDER.find_iter(fw_name) {
+        expanded.push_str(&fw_name[last..placeholder.start()]);
+        if placeholder.as_str() == "%%" {
+            expanded.push('%');
+        } else if !expanded.ends_with('*') {
+            // Adjacent wildcards would make a recursive one.
+            expanded.push('*');
+        }
+        last = placeholder.end();
+    }
+    expanded.push_str(&fw_name[last..]);
+    if expanded != fw_name {
+        debug!("Firmware name {} expanded to {}", fw_name, expanded);
+    }
+    Cow::Owned(expanded)
 }
 
+/// Returns the files of `fw_dir` the kernel `release` (any kernel if unset) loads for the firmware
+/// name `fw_name`, possibly a wildcard pattern or a format string, following the layered lookup of
+/// the kernel: a firmware found in a layer of [`firmware_layers`] hides the ones of the following
+/// layers.
 fn find_firmware_files_from_name(
+    fs: &dyn JanitorFs,
+    fw_name: &str,
+    fw_dir: &Path,
+    release: Option<&str>,
+) -> Result<Vec<PathBuf>, JanitorError> {
+    let fw_name = expand_placeholders(fw_name);
+    let fw_name = fw_name.as_ref();
+    let mut found = Vec::new();
+    let mut names = HashSet::new();
+    for layer in firmware_layers(fw_dir, release) {
+        if !fs.metadata(&layer).is_ok_and(|m| m.is_dir()) {
+            continue;
+        }
+        let files: Vec<_> = find_firmware_files_in_layer(fs, fw_name, &layer)?
+            .into_iter()
+            .map(|path| (firmware_name(path.strip_prefix(&layer).unwrap()), path))
+            .filter(|(name, _)| !names.contains(name))
+            .collect();
+        for (name, path) in files {
+            if layer != fw_dir {
+                debug!("Firmware {} is provided by {}", name, path.display());
+            }
+            names.insert(name);
+            found.push(path);
+        }
+    }
+    Ok(found)
+}
+
+/// Returns `relative_path`, the path of a firmware file in a layer, without its compression
+/// extension: the name the kernel requests it by.
+pub(crate) fn firmware_name(relative_path: &Path) -> String {
+    let relative_path = relative_path.to_string_lossy();
+    relative_path
+        .strip_suffix(".xz")
+        .or_else(|| relative_path.strip_suffix(".zst"))
+        .unwrap_or(&relative_path)
+        .to_string()
+}
+
+/// Returns the files matching the firmware name `fw_name` in the single directory `fw_dir`.
+fn find_firmware_files_in_layer(
+    fs: &dyn JanitorFs,
     fw_name: &str,
     fw_dir: &Path,
 ) -> Result<Vec<PathBuf>, JanitorError> {
@@ -47,73 +133,370 @@ fn find_firmware_files_from_name(
             PathBuf::from(format!("{}.xz", pattern)),
             PathBuf::from(format!("{}.zst", pattern)),
         ];
+        // A symlink counts as present even if its target is missing: the target may be
+        // provided by a firmware overlay.
         Ok(paths_to_check
             .into_iter()
-            .filter(|p| p.exists())
+            .filter(|p| fs.symlink_metadata(p).is_ok())
             .collect())
     } else {
-        let mut results = HashSet::new();
-        for ext in ["", ".xz", ".zst"] {
-            let pattern_with_ext = format!("{}{}", pattern, ext);
-            results.extend(
-                glob::glob(&pattern_with_ext)
-                    .expect("Failed to read glob pattern")
-                    .filter_map(Result::ok),
-            );
+        find_firmware_files_from_pattern(fs, fw_name, fw_dir)
+    }
+}
+
+/// Returns the files whose path relative to `fw_dir`, without compression extension, matches
+/// the wildcard firmware name `fw_name`.
+///
+/// The pattern is anchored to the firmware directory and wildcards never cross a `/` or match
+/// a leading dot, so `brcm/*` keeps neither the subdirectories of `brcm` nor hidden files.
+fn find_firmware_files_from_pattern(
+    fs: &dyn JanitorFs,
+    fw_name: &str,
+    fw_dir: &Path,
+) -> Result<Vec<PathBuf>, JanitorError> {
+    let Ok(pattern) = glob::Pattern::new(fw_name) else {
+        warn!("Ignoring invalid firmware pattern {}", fw_name);
+        return Ok(Vec::new());
+    };
+    let match_options = glob::MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: true,
+    };
+
+    // Only the directories matching the directory part of the pattern are listed, one
+    // component at a time, following symlinks.
+    let mut dirs = vec![fw_dir.to_path_buf()];
+    if let Some((dir_pattern, _)) = fw_name.rsplit_once('/') {
+        for component in dir_pattern.split('/') {
+            let Ok(component_pattern) = glob::Pattern::new(component) else {
+                return Ok(Vec::new());
+            };
+            let mut matching = Vec::new();
+            for dir in &dirs {
+                let candidates = if component.contains(['*', '?', '[']) {
+                    fs.read_dir(dir)?
+                        .into_iter()
+                        .filter(|p| {
+                            let name = p.file_name().unwrap_or_default().to_string_lossy();
+                            component_pattern.matches_with(&name, match_options)
+                        })
+                        .collect()
+                } else {
+                    vec![dir.join(component)]
+                };
+                matching.extend(
+                    candidates
+                        .into_iter()
+                        .filter(|p| fs.metadata(p).is_ok_and(|m| m.is_dir())),
+                );
+            }
+            dirs = matching;
         }
-        Ok(results.into_iter().collect())
     }
+
+    let mut results = Vec::new();
+    for dir in dirs {
+        for path in fs.read_dir(&dir)? {
+            if fs.symlink_metadata(&path)?.is_dir() {
+                continue;
+            }
+            let Ok(relative_path) = path.strip_prefix(fw_dir) else {
+                continue;
+            };
+            if pattern.matches_with(&firmware_name(relative_path), match_options) {
+                results.push(path);
+            }
+        }
+    }
+    results.sort();
+    Ok(results)
+}
+
+/// Returns the overlay providing `path` of the firmware directory, if any.
+fn find_in_overlays<'a>(
+    fs: &dyn JanitorFs,
+    path: &Path,
+    fw_dir: &Path,
+    overlays: &'a [PathBuf],
+) -> Option<&'a PathBuf> {
+    let relative_path = path.strip_prefix(fw_dir).ok()?;
+    overlays
+        .iter()
+        .find(|overlay| fs.symlink_metadata(&overlay.join(relative_path)).is_ok())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn get_required_firmware(
+    fs: &dyn JanitorFs,
+    graph: &KernelGraph,
     kernel_dir: &Path,
     fw_dir: &Path,
-    runner: &dyn CommandRunner,
-) -> Result<HashSet<PathBuf>, JanitorError> {
-    let mut required = HashSet::new();
-    let kernel_modules = find_kernel_modules(kernel_dir)?;
-
-    for module_path in kernel_modules {
-        let firmware_names = get_firmware_deps_for_module(&module_path, runner)?;
-        for fw_name in firmware_names {
-            let firmware_files = find_firmware_files_from_name(&fw_name, fw_dir)?;
-            for fw_file in firmware_files {
-                let symlinks = resolve_symlinks(&fw_file, fw_dir)?;
-                required.extend(symlinks);
+    root: &Path,
+    overlays: &[PathBuf],
+    follow_external: bool,
+    iwlwifi_fallback: usize,
+    scan_options: &ScanOptions,
+    errors: &mut Vec<String>,
+) -> Result<HashMap<PathBuf, String>, JanitorError> {
+    let mut required = HashMap::new();
+    let kernel = graph.kernel(kernel_dir, scan_options)?;
+    let release = kernel_dir.file_name().and_then(|n| n.to_str());
+
+    // The out-of-tree modules of extra/ and updates/ (DKMS included) are part of the tree, the
+    // modules of other kernels linked from weak-updates are loaded by this one too.
+    let mut module_paths = kernel.paths();
+    if let Some(module_dir) = kernel_dir.parent() {
+        let links = driver::weak_update_links(fs, module_dir)?;
+        module_paths.extend(
+            links
+                .into_iter()
+                .filter(|(link, _)| link.starts_with(kernel_dir))
+                .filter_map(|(_, target)| target)
+                .filter(|target| util::is_kernel_module(target)),
+        );
+    }
+    let firmware_deps = module_paths
+        .iter()
+        .filter_map(|module_path| {
+            let name = util::module_name(module_path);
+            let reason = format!("required by module {}", name);
+            match kernel.modinfo(module_path) {
+                Ok(info) => Some((name, reason, info.firmware())),
+                Err(e) => {
+                    warn!("Reading modinfo of {} failed: {}", module_path.display(), e);
+                    errors.push(format!("Reading modinfo of {} failed: {}", module_path.display(), e));
+                    None
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Drivers built into the kernel have no module file, their metadata is collected separately.
+    let builtin_firmware = modinfo::read_builtin_modinfo(kernel_dir)?
+        .into_iter()
+        .map(|(name, info)| {
+            let reason = format!("required by built-in driver {}", name);
+            (name, reason, info.firmware())
+        })
+        .collect::<Vec<_>>();
+
+    let mut group_dirs = HashSet::new();
+    for (name, reason, fw_names) in firmware_deps.into_iter().chain(builtin_firmware) {
+        for fw_name in fw_names {
+            for loaded_name in loaded_firmware_names(fs, &fw_name, fw_dir, release, overlays, iwlwifi_fallback)? {
+                require_firmware(fs, &loaded_name, &reason, fw_dir, root, release, overlays, follow_external, &mut required)?;
+            }
+            if let Some(dir) = firmware_group_dir(&name, &fw_name) {
+                if group_dirs.insert(dir.clone()) {
+                    debug!("Keeping the firmware tree {} of {}", dir, name);
+                    require_firmware_tree(fs, &fw_dir.join(dir), &reason, fw_dir, root, overlays, follow_external, &mut required)?;
+                }
             }
         }
     }
     Ok(required)
 }
 
-fn resolve_symlinks(path: &Path, base_dir: &Path) -> Result<Vec<PathBuf>, JanitorError> {
+/// Returns the names of the firmware kernel `release` loads when `fw_name` is requested: the newest
+/// API versions available, keeping `iwlwifi_fallback` older ones, for an iwlwifi ucode (see
+/// [`iwlwifi::select_api_versions`]), `fw_name` itself otherwise.
+fn loaded_firmware_names(
+    fs: &dyn JanitorFs,
+    fw_name: &str,
+    fw_dir: &Path,
+    release: Option<&str>,
+    overlays: &[PathBuf],
+    iwlwifi_fallback: usize,
+) -> Result<Vec<String>, JanitorError> {
+    let Some(pattern) = iwlwifi::api_pattern(fw_name) else {
+        return Ok(vec![fw_name.to_string()]);
+    };
+    let mut available = Vec::new();
+    for dir in std::iter::once(fw_dir).chain(overlays.iter().map(PathBuf::as_path)) {
+        for path in find_firmware_files_from_name(fs, &pattern, dir, release)? {
+            available.push(iwlwifi::name_of(&pattern, &path));
+        }
+    }
+    let names = iwlwifi::select_api_versions(fw_name, &available, iwlwifi_fallback);
+    if names.first().is_some_and(|name| name != fw_name) {
+        debug!("Firmware {} is loaded as {}", fw_name, names.join(", "));
+    }
+    Ok(names)
+}
+
+/// Firmware trees some drivers load files from without listing them all in their modinfo: when
+/// one of `modules` requires a firmware file below a directory matching `dir`, the whole tree of
+/// that directory is required.
+struct FirmwareGroup {
+    modules: &'static [&'static str],
+    dir: &'static str,
+}
+
+/// nouveau lists the GSP firmware of `nvidia/<chip>/gsp/` for one version only and loads the
+/// other files of the chip by name, and the chip directories link to each other. The nvidia
+/// driver loads the GSP firmware of `nvidia/<version>/`.
+const FIRMWARE_GROUPS: &[FirmwareGroup] = &[FirmwareGroup {
+    modules: &["nouveau", "nvidia"],
+    dir: "nvidia/*",
+}];
+
+/// Returns the directory of the firmware group of `module` containing the firmware `fw_name`,
+/// relative to the firmware directory, if any.
+fn firmware_group_dir(module: &str, fw_name: &str) -> Option<String> {
+    let match_options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    FIRMWARE_GROUPS
+        .iter()
+        .filter(|group| group.modules.contains(&module))
+        .find_map(|group| {
+            let depth = group.dir.split('/').count();
+            let components: Vec<&str> = fw_name.split('/').collect();
+            // The firmware must be inside the directory, whose name must be known.
+            let dir = components.get(..depth).filter(|_| components.len() > depth)?.join("/");
+            let pattern = Pattern::new(group.dir).ok()?;
+            (!dir.contains(['*', '?', '[']) && pattern.matches_with(&dir, match_options))
+                .then_some(dir)
+        })
+}
+
+/// Adds the files of the tree at `dir` to `required` with `reason`, following the symlinks like
+/// [`require_firmware`] does, into other directories too.
+#[allow(clippy::too_many_arguments)]
+fn require_firmware_tree(
+    fs: &dyn JanitorFs,
+    dir: &Path,
+    reason: &str,
+    fw_dir: &Path,
+    root: &Path,
+    overlays: &[PathBuf],
+    follow_external: bool,
+    required: &mut HashMap<PathBuf, String>,
+) -> Result<(), JanitorError> {
+    if fs.symlink_metadata(dir).is_err() {
+        return Ok(());
+    }
+    let mut pending = vec![dir.to_path_buf()];
+    let mut visited = HashSet::new();
+    while let Some(path) = pending.pop() {
+        let chain = resolve_symlinks(fs, &path, fw_dir, root, overlays, follow_external)?;
+        let target = chain.last().cloned().unwrap_or(path);
+        for path in chain {
+            if !fs.symlink_metadata(&path).is_ok_and(|m| m.is_dir()) {
+                required.entry(path).or_insert_with(|| reason.to_string());
+            }
+        }
+        if fs.metadata(&target).is_ok_and(|m| m.is_dir()) && visited.insert(target.clone()) {
+            pending.extend(fs.read_dir(&target)?);
+        }
+    }
+    Ok(())
+}
+
+/// Adds the files loaded for the firmware name `fw_name` by kernel `release`, and the symlink
+/// chains leading to them, to `required`, with `reason` unless they are already required.
+#[allow(clippy::too_many_arguments)]
+fn require_firmware(
+    fs: &dyn JanitorFs,
+    fw_name: &str,
+    reason: &str,
+    fw_dir: &Path,
+    root: &Path,
+    release: Option<&str>,
+    overlays: &[PathBuf],
+    follow_external: bool,
+    required: &mut HashMap<PathBuf, String>,
+) -> Result<(), JanitorError> {
+    let firmware_files = find_firmware_files_from_name(fs, fw_name, fw_dir, release)?;
+    for fw_file in firmware_files {
+        for path in resolve_symlinks(fs, &fw_file, fw_dir, root, overlays, follow_external)? {
+            required.entry(path).or_insert_with(|| reason.to_string());
+        }
+    }
+    // Files only provided by an overlay are required too, even if there is nothing to
+    // keep for them in the firmware directory itself.
+    for overlay in overlays {
+        for fw_file in find_firmware_files_from_name(fs, fw_name, overlay, release)? {
+            if let Ok(relative_path) = fw_file.strip_prefix(overlay) {
+                debug!("Firmware {} provided by overlay {}", relative_path.display(), overlay.display());
+                required
+                    .entry(fw_dir.join(relative_path))
+                    .or_insert_with(|| reason.to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns `path` and the symlink chain it starts, up to the first target missing, provided by
+/// an overlay or, unless `follow_external` is set, outside of `base_dir`.
+fn resolve_symlinks(
+    fs: &dyn JanitorFs,
+    path: &Path,
+    base_dir: &Path,
+    root: &Path,
+    overlays: &[PathBuf],
+    follow_external: bool,
+) -> Result<Vec<PathBuf>, JanitorError> {
     let mut paths_to_keep = vec![path.to_path_buf()];
     let mut current_path = path.to_path_buf();
 
     // Limit the number of symlink hops to avoid infinite loops.
     for _ in 0..10 {
-        if !fs::symlink_metadata(&current_path)?.file_type().is_symlink() {
+        if !fs.symlink_metadata(&current_path)?.is_symlink() {
             // Not a symlink, so we're at the end of the chain.
             break;
         }
 
-        let target = fs::read_link(&current_path)?;
+        let target = fs.read_link(&current_path)?;
         // The target of a symlink can be a relative path. We need to resolve it
-        // relative to the directory containing the symlink.
+        // relative to the directory containing the symlink. An absolute one points into the
+        // image, below its root, not into the host.
         let parent_dir = current_path.parent().unwrap_or_else(|| Path::new(""));
-        current_path = parent_dir.join(target).clean();
+        current_path = match target.strip_prefix("/") {
+            Ok(relative_target) => root.join(relative_target),
+            Err(_) => parent_dir.join(target),
+        }
+        .clean();
 
-        // If the resolved path is not within the base directory, we stop.
+        // If the resolved path is not within the base directory, we stop unless asked to
+        // follow the chain, e.g. through vendor directories.
         if !current_path.starts_with(base_dir) {
-            debug!(
-                "Symlink target {} is outside the firmware directory.",
+            if !follow_external {
+                info!(
+                    "Not following symlink target {} outside the firmware directory",
+                    current_path.display()
+                );
+                return Ok(paths_to_keep);
+            }
+            if fs.symlink_metadata(&current_path).is_err() {
+                debug!("Broken symlink found: {}", current_path.display());
+                return Ok(paths_to_keep);
+            }
+            info!(
+                "Following symlink {} outside the firmware directory to {}",
+                path.display(),
                 current_path.display()
             );
+            paths_to_keep.push(current_path.clone());
+            continue;
+        }
+
+        // A target missing from the base directory may be provided by an overlay.
+        if let Some(overlay) = find_in_overlays(fs, &current_path, base_dir, overlays) {
+            debug!(
+                "Symlink target {} is provided by overlay {}",
+                current_path.display(),
+                overlay.display()
+            );
+            paths_to_keep.push(current_path.clone());
             return Ok(paths_to_keep);
         }
 
         // If the path doesn't exist, it's a broken link.
-        if !current_path.exists() {
+        if !fs.exists(&current_path) {
             debug!("Broken symlink found: {}", current_path.display());
             return Ok(paths_to_keep);
         }
@@ -129,55 +512,259 @@ fn resolve_symlinks(path: &Path, base_dir: &Path) -> Result<Vec<PathBuf>, Janito
     Ok(paths_to_keep)
 }
 
+const UNUSED_REASON: &str = "not required by any module";
+
+/// Applies the firmware config `rules` to the files of `fw_dir`: the files matching a keep rule,
+/// and the targets of the matching symlinks, are added to `required_fw_abs`, and the ones matching
+/// a delete rule are removed from it. Returns the dropped paths, relative to `fw_dir`, with the
+/// reason.
+#[allow(clippy::too_many_arguments)]
+fn apply_rules(
+    fs: &dyn JanitorFs,
+    fw_dir: &Path,
+    root: &Path,
+    rules: &Rules,
+    overlays: &[PathBuf],
+    follow_external: bool,
+    required_fw_abs: &mut HashMap<PathBuf, String>,
+) -> Result<HashMap<PathBuf, String>, JanitorError> {
+    let mut dropped = HashMap::new();
+    for entry in fs.walk(fw_dir) {
+        if entry.kind == FileKind::Dir {
+            continue;
+        }
+        let relative_path = entry.path.strip_prefix(fw_dir).unwrap();
+        match rules.matching_rule_text(&relative_path.to_string_lossy()) {
+            (RuleMatch::Keep, Some(rule)) => {
+                let reason = Reason::KeepRule(rule).to_string();
+                for path in resolve_symlinks(fs, &entry.path, fw_dir, root, overlays, follow_external)? {
+                    required_fw_abs.entry(path).or_insert_with(|| reason.clone());
+                }
+            }
+            (RuleMatch::Delete, Some(rule)) => {
+                debug!("Dropping {} as configured", relative_path.display());
+                let reason = Reason::DeleteRule(rule).to_string();
+                dropped.insert(relative_path.to_path_buf(), reason);
+            }
+            _ => {}
+        }
+    }
+    for path in dropped.keys() {
+        required_fw_abs.remove(&fw_dir.join(path));
+    }
+    Ok(dropped)
+}
+
+/// Removes the files of `fw_dir` for which `is_pruned` returns true, given their path relative to
+/// `fw_dir`, from `required_fw_abs`. Returns them, relative to `fw_dir`, with `reason`.
+fn prune_firmware(
+    fs: &dyn JanitorFs,
+    fw_dir: &Path,
+    reason: &str,
+    is_pruned: impl Fn(&Path) -> bool,
+    required_fw_abs: &mut HashMap<PathBuf, String>,
+) -> HashMap<PathBuf, String> {
+    let mut dropped = HashMap::new();
+    for entry in fs.walk(fw_dir) {
+        let relative_path = entry.path.strip_prefix(fw_dir).unwrap();
+        if entry.kind != FileKind::Dir && is_pruned(relative_path) {
+            debug!("Dropping {}: {}", relative_path.display(), reason);
+            required_fw_abs.remove(&entry.path);
+            dropped.insert(relative_path.to_path_buf(), reason.to_string());
+        }
+    }
+    dropped
+}
+
+/// Removes the files of `fw_dir` missing from `required_fw`, which maps the paths relative to
+/// `fw_dir` to the reason they are kept, recording every decision in `explanation` if given. The
+/// files of `dropped` are deleted for the reason it maps them to, files smaller than `min_size`
+/// are kept. Returns the number of files examined, the size of the unused ones and the files by
+/// group.
 fn remove_unused_files(
+    fs: &dyn JanitorFs,
     fw_dir: &Path,
-    required_fw: &HashSet<PathBuf>,
-    delete: bool,
-) -> Result<u64, JanitorError> {
+    required_fw: &HashMap<PathBuf, String>,
+    dropped: &HashMap<PathBuf, String>,
+    min_size: u64,
+    deleter: &mut Deleter,
+    mut explanation: Option<&mut Explanation>,
+) -> Result<(usize, u64, BTreeMap<String, GroupSummary>), JanitorError> {
     info!("Scanning for unused firmware files...");
     let mut unused_size = 0;
-
-    for entry in WalkDir::new(fw_dir).into_iter().filter_map(Result::ok) {
-        let path = entry.path();
-        if path.is_file() {
+    let mut examined = 0;
+    let mut groups: BTreeMap<String, GroupSummary> = BTreeMap::new();
+    let mut unused = Vec::new();
+
+    for entry in fs.walk(fw_dir) {
+        let path = entry.path.as_path();
+        if fs.metadata(path).is_ok_and(|m| m.is_file()) {
+            examined += 1;
             let relative_path = path.strip_prefix(fw_dir).unwrap().to_path_buf();
-            if !required_fw.contains(&relative_path) {
-                unused_size += fs::metadata(path)?.len();
-                if delete {
+            let size = fs.metadata(path)?.len;
+            let group = groups.entry(firmware_group(&relative_path)).or_default();
+            if let Some(reason) = required_fw.get(&relative_path) {
+                group.add(size, false);
+                if let Some(explanation) = explanation.as_deref_mut() {
+                    explanation.record(path, FileType::Firmware, Action::Keep, reason)?;
+                }
+            } else if size < min_size {
+                group.add(size, false);
+                if let Some(explanation) = explanation.as_deref_mut() {
+                    let reason = Reason::BelowMinSize(min_size).to_string();
+                    explanation.record(path, FileType::Firmware, Action::Keep, &reason)?;
+                }
+            } else {
+                group.add(size, true);
+                let reason = dropped.get(&relative_path).map_or(UNUSED_REASON, String::as_str);
+                if let Some(explanation) = explanation.as_deref_mut() {
+                    explanation.record(path, FileType::Firmware, Action::Delete, reason)?;
+                }
+                unused_size += size;
+                if deleter.is_deleting() {
                     info!("Deleting unused firmware {}", path.display());
-                    fs::remove_file(path)?;
                 } else {
                     debug!("Found unused firmware {}", path.display());
                 }
+                unused.push((path.to_path_buf(), size, reason));
             }
         }
     }
-    Ok(unused_size)
+    deleter.remove_files(&unused)?;
+    Ok((examined, unused_size, groups))
+}
+
+/// Adds the unused firmware files the firmware directory does not need to lose to fit in
+/// `target_size` bytes to `required_fw`, see [`policy::select_within_budget`]. The files dropped
+/// by a delete rule with a priority of `rules` are only deleted as needed too, the other dropped
+/// files always go and the files smaller than `min_size` always stay. Returns the paths of the
+/// files kept, relative to `fw_dir`.
+#[allow(clippy::too_many_arguments)]
+fn keep_within_budget(
+    fs: &dyn JanitorFs,
+    fw_dir: &Path,
+    required_fw: &mut HashMap<PathBuf, String>,
+    dropped: &HashMap<PathBuf, String>,
+    rules: Option<&Rules>,
+    min_size: u64,
+    target_size: u64,
+) -> Result<Vec<PathBuf>, JanitorError> {
+    let mut total = 0;
+    let mut candidates = Vec::new();
+    // Symlinks take no space, only the files they point to do.
+    for entry in fs.walk(fw_dir).into_iter().filter(|entry| entry.kind == FileKind::File) {
+        let size = fs.metadata(&entry.path)?.len;
+        let relative_path = entry.path.strip_prefix(fw_dir).unwrap().to_path_buf();
+        let priority = rules.map_or(0, |rules| rules.delete_priority(&relative_path.to_string_lossy()));
+        if required_fw.contains_key(&relative_path) || size < min_size {
+            total += size;
+        } else if !dropped.contains_key(&relative_path) || priority > 0 {
+            total += size;
+            candidates.push((relative_path, size, priority));
+        }
+    }
+    let deleted = policy::select_within_budget(&candidates, total, target_size);
+    let reclaimed: u64 = candidates
+        .iter()
+        .filter(|(path, _, _)| deleted.contains(path))
+        .map(|(_, size, _)| size)
+        .sum();
+    if total - reclaimed > target_size {
+        warn!("{} does not fit in {} bytes even without its unused firmware", fw_dir.display(), target_size);
+    }
+    let reason = Reason::WithinSizeBudget(target_size).to_string();
+    let mut kept = Vec::new();
+    for (path, _, _) in candidates {
+        if !deleted.contains(&path) {
+            required_fw.insert(path.clone(), reason.clone());
+            kept.push(path);
+        }
+    }
+    Ok(kept)
 }
 
-fn remove_dangling_symlinks(fw_dir: &Path) -> Result<(), JanitorError> {
+/// Writes the files of `fw_dir` missing from `deleted` to `path` in `format`, sorted by path, the
+/// firmware directory being installed at `install_dir`. Symlinks are listed along with the files
+/// they point to, unless these are deleted.
+fn write_kept_list(
+    fs: &dyn JanitorFs,
+    fw_dir: &Path,
+    deleted: &[PathBuf],
+    path: &Path,
+    install_dir: &Path,
+    format: ListFormat,
+) -> Result<(), JanitorError> {
+    let deleted: HashSet<&PathBuf> = deleted.iter().collect();
+    let target_deleted = |link: &Path| match fs.read_link(link) {
+        Ok(target) => deleted.contains(&link.parent().unwrap_or(fw_dir).join(target).clean()),
+        Err(_) => false,
+    };
+    let mut kept: Vec<KeptEntry> = fs
+        .walk(fw_dir)
+        .into_iter()
+        .filter(|entry| fs.metadata(&entry.path).is_ok_and(|m| m.is_file()) && !deleted.contains(&entry.path))
+        .filter(|entry| !target_deleted(&entry.path))
+        .map(|entry| KeptEntry {
+            relative: entry.path.strip_prefix(fw_dir).unwrap().to_path_buf(),
+            target: match entry.kind {
+                FileKind::Symlink => fs.read_link(&entry.path).ok(),
+                _ => None,
+            },
+        })
+        .collect();
+    kept.sort_by(|a, b| a.relative.cmp(&b.relative));
+    info!("Writing the {} firmware files kept to {}", kept.len(), path.display());
+    manifest::write_list(path, &kept, install_dir, format)
+}
+
+/// Returns the group of the firmware file at `relative_path` in the firmware directory: its
+/// top-level directory, usually named after the vendor or the driver.
+fn firmware_group(relative_path: &Path) -> String {
+    let mut components = relative_path.components();
+    match (components.next(), components.next()) {
+        (Some(first), Some(_)) => first.as_os_str().to_string_lossy().into_owned(),
+        _ => TOP_LEVEL_GROUP.to_string(),
+    }
+}
+
+fn remove_dangling_symlinks(
+    fs: &dyn JanitorFs,
+    fw_dir: &Path,
+    overlays: &[PathBuf],
+    deleter: &mut Deleter,
+) -> Result<(), JanitorError> {
     info!("Removing dangling symlinks...");
-    for entry in WalkDir::new(fw_dir).into_iter().filter_map(Result::ok) {
-        let path = entry.path();
-        if path.is_symlink() {
-            // fs::metadata follows symlinks, so it will return an error for a dangling one.
-            if fs::metadata(path).is_err() {
+    for entry in fs.walk(fw_dir) {
+        let path = entry.path.as_path();
+        if entry.kind == FileKind::Symlink {
+            // metadata follows symlinks, so it will return an error for a dangling one.
+            if fs.metadata(path).is_err() {
+                let target = fs.read_link(path)?;
+                let target = path.parent().unwrap_or(fw_dir).join(target).clean();
+                if find_in_overlays(fs, &target, fw_dir, overlays).is_some() {
+                    debug!("Keeping symlink {} to overlay firmware", path.display());
+                    continue;
+                }
                 info!("Deleting dangling symlink {}", path.display());
-                fs::remove_file(path)?;
+                deleter.remove_file(path, 0, "dangling symlink")?;
             }
         }
     }
     Ok(())
 }
 
-fn remove_empty_directories(fw_dir: &Path) -> Result<(), JanitorError> {
+fn remove_empty_directories(
+    fs: &dyn JanitorFs,
+    fw_dir: &Path,
+    deleter: &mut Deleter,
+) -> Result<(), JanitorError> {
     info!("Removing empty directories...");
     // We need to walk from the deepest directories up to ensure parent directories become empty.
-    let mut dirs_to_check: Vec<PathBuf> = WalkDir::new(fw_dir)
+    let mut dirs_to_check: Vec<PathBuf> = fs
+        .walk(fw_dir)
         .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.path().is_dir())
-        .map(|e| e.path().to_path_buf())
+        .filter(|e| e.kind == FileKind::Dir)
+        .map(|e| e.path)
         .collect();
 
     // Sort by depth, deepest first.
@@ -185,63 +772,413 @@ fn remove_empty_directories(fw_dir: &Path) -> Result<(), JanitorError> {
 
     for dir_path in dirs_to_check {
         // Only remove if it's empty and not the root firmware directory itself.
-        if dir_path != fw_dir && fs::read_dir(&dir_path)?.next().is_none() {
+        if dir_path != fw_dir && fs.read_dir(&dir_path)?.is_empty() {
             info!("Deleting empty directory {}", dir_path.display());
-            fs::remove_dir(dir_path)?;
+            deleter.remove_dir(&dir_path, "empty directory")?;
         }
     }
     Ok(())
 }
 
-pub fn cleanup_firmware(
-    module_dir: &Path,
+/// Files kept in every firmware directory although no module requires them: license texts the
+/// distribution must ship and the wireless regulatory database.
+pub const PROTECTED_FILES: &[&str] = &["WHENCE", "LICENSE.*", "LICENCE.*", "regulatory.db*"];
+
+/// Returns the files below `fw_dir`, relative to it, matching one of the built-in protected
+/// patterns or `extra`. Patterns without a `/` are matched against the file name in any
+/// directory, the others against the path relative to `fw_dir`.
+fn protected_files(
+    fs: &dyn JanitorFs,
     fw_dir: &Path,
-    delete: bool,
-    runner: &dyn CommandRunner,
-) -> Result<(), JanitorError> {
-    let kernel_dir = util::find_kernel_dir(module_dir)?;
-    info!("Scanning kernel modules in {}", kernel_dir.display());
+    extra: &[Pattern],
+) -> Result<Vec<PathBuf>, JanitorError> {
+    let builtin = PROTECTED_FILES
+        .iter()
+        .map(|p| Pattern::new(p).expect("invalid built-in pattern"));
+    let patterns: Vec<Pattern> = builtin.chain(extra.iter().cloned()).collect();
+
+    let mut protected = Vec::new();
+    for entry in fs.walk(fw_dir) {
+        if entry.kind == FileKind::Dir {
+            continue;
+        }
+        let relative_path = entry.path.strip_prefix(fw_dir).unwrap();
+        let file_name = relative_path.file_name().unwrap_or_default().to_string_lossy();
+        let is_protected = patterns.iter().any(|p| {
+            if p.as_str().contains('/') {
+                p.matches_path(relative_path)
+            } else {
+                p.matches(&file_name)
+            }
+        });
+        if is_protected {
+            debug!("Protected firmware directory file {}", relative_path.display());
+            protected.push(relative_path.to_path_buf());
+        }
+    }
+    Ok(protected)
+}
+
+/// Returns the files below `fw_dir` whose path relative to it matches one of `patterns`.
+/// Wildcards match across directories, so `ath10k/*` selects the whole subtree.
+fn kept_files(
+    fs: &dyn JanitorFs,
+    fw_dir: &Path,
+    patterns: &[Pattern],
+) -> Result<Vec<PathBuf>, JanitorError> {
+    if patterns.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut kept = Vec::new();
+    for entry in fs.walk(fw_dir) {
+        let relative_path = entry.path.strip_prefix(fw_dir).unwrap();
+        if entry.kind != FileKind::Dir && patterns.iter().any(|p| p.matches_path(relative_path)) {
+            debug!("Keeping {} as requested", relative_path.display());
+            kept.push(entry.path.clone());
+        }
+    }
+    Ok(kept)
+}
+
+/// Options of a firmware cleanup run.
+#[derive(Debug, Clone, Default)]
+pub struct FirmwareOptions {
+    /// Directory with the kernel module trees.
+    pub module_dir: PathBuf,
+    /// Directories of out-of-tree modules installed outside of the kernel module trees, e.g. by
+    /// vendor packages, whose firmware is kept too whatever the kernel selected.
+    pub extra_module_dirs: Vec<PathBuf>,
+    /// Directory with the firmware files.
+    pub firmware_dir: PathBuf,
+    /// Really delete the files.
+    pub delete: bool,
+    pub scan: ScanOptions,
+    /// Firmware trees merged into the image later. Their files count as present when resolving
+    /// requirements, but they are never deleted.
+    pub overlays: Vec<PathBuf>,
+    /// Hardware profile whose loaded firmware files are kept in addition.
+    pub profile: Option<Profile>,
+    /// Firmware names a reference system failed to load according to its kernel log, see
+    /// [`crate::profile::read_firmware_failures`], kept in addition.
+    pub failed_firmware: BTreeSet<String>,
+    /// Files never deleted, in addition to [`PROTECTED_FILES`].
+    pub protect: Vec<Pattern>,
+    /// Firmware kept whatever the modules require, matched against paths relative to the
+    /// firmware directory. Symlinks are followed, so their targets are kept too.
+    pub keep: Vec<Pattern>,
+    /// Rules of the firmware config files, matched against paths relative to the firmware
+    /// directory: keep rules act like [`FirmwareOptions::keep`], and delete rules drop files even
+    /// if a module requires them. Protected files are never dropped.
+    pub rules: Option<Rules>,
+    /// Firmware files smaller than this size, in bytes, are kept instead of being deleted.
+    pub min_size: u64,
+    /// Size, in bytes, the firmware directory is to fit in. When set, only the largest unused
+    /// files are deleted until it fits, the other ones are kept.
+    pub target_size: Option<u64>,
+    /// GPU generations whose amdgpu firmware is kept, IP blocks with their version such as
+    /// `dcn31` or ASIC names such as `navi10`, see [`amdgpu::matches_generation`]. The amdgpu
+    /// firmware of the other generations is dropped even if the module requires it, unless kept
+    /// by the profile, a keep pattern or a keep rule. All of it is kept if empty.
+    pub amdgpu_generations: Vec<String>,
+    /// Number of older API versions of the iwlwifi ucode kept as fallback besides the newest one
+    /// the kernel loads, see [`iwlwifi::select_api_versions`].
+    pub iwlwifi_fallback_versions: usize,
+    /// Intel platforms whose Sound Open Firmware (`intel/sof*`) is kept, such as `tgl`, see
+    /// [`sof::is_other_platform`]. The firmware and topologies of the other platforms are dropped
+    /// like [`FirmwareOptions::amdgpu_generations`]. All of them are kept if empty.
+    pub sof_platforms: Vec<String>,
+    /// What to do with the CPU microcode of [`microcode::MICROCODE_DIRS`], which no module requires.
+    pub microcode: MicrocodePolicy,
+    /// Follow the symlinks leaving the firmware directory, e.g. into vendor directories, so the
+    /// firmware their chains lead back to is kept. The external targets are reported, never
+    /// deleted.
+    pub follow_external_symlinks: bool,
+    /// Root directory of the image the firmware directory belongs to, absolute symlink targets
+    /// are resolved below it. The host root if unset.
+    pub root: Option<PathBuf>,
+    /// Archive the deleted files are saved to before being deleted.
+    pub backup: Option<PathBuf>,
+    /// Journal the deletions are recorded in.
+    pub journal: Option<PathBuf>,
+    /// Clock timestamping the journal entries, the system clock if unset.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Quarantine directory the deleted files are moved to instead of being unlinked, see
+    /// [`crate::quarantine`].
+    pub quarantine: Option<PathBuf>,
+    /// Report the decision taken for every firmware file, and its reason, is appended to.
+    pub explain: Option<PathBuf>,
+    /// File the paths of the firmware files left, relative to the firmware directory, are
+    /// written to, one per line, e.g. for packaging scripts or rsync. In a dry run, the files
+    /// which would be left.
+    pub emit_required: Option<PathBuf>,
+    /// Format of the `emit_required` list.
+    pub emit_format: ListFormat,
+    /// Where the firmware directory is installed in the image, for the absolute paths of the
+    /// `emit_required` list, the firmware directory itself if unset.
+    pub install_dir: Option<PathBuf>,
+    /// Filesystem the firmware directory and the overlays are read and cleaned through, the
+    /// host one if unset.
+    pub fs: Option<Arc<dyn JanitorFs>>,
+}
+
+/// Removes the firmware files no module of the selected kernels requires, returning a summary with
+/// the deleted paths (or the ones that would be deleted in a dry run).
+pub fn cleanup_firmware(
+    options: &FirmwareOptions,
+    graph: &KernelGraph,
+) -> Result<CleanupSummary, JanitorError> {
+    let fw_dir = options.firmware_dir.as_path();
+    let delete = options.delete;
+    let fs = options.fs.clone().unwrap_or_else(|| Arc::new(RealFs));
+    let fs = fs.as_ref();
+    let root = options.root.as_deref().unwrap_or(Path::new("/"));
+    // Atomic writes, whose leftovers are cleaned here, only happen on the host filesystem.
+    if options.fs.is_none() {
+        atomic::remove_orphans(fw_dir, delete)?;
+    }
 
-    let required_fw_abs = get_required_firmware(&kernel_dir, fw_dir, runner)?;
-    let required_fw: HashSet<_> = required_fw_abs.into_iter()
-        .map(|p| p.strip_prefix(fw_dir).unwrap().to_path_buf())
+    let resolution = info_span!("firmware_resolution").entered();
+    // Firmware needed by any of the selected kernels is kept.
+    let mut required_fw_abs = HashMap::new();
+    let mut errors = Vec::new();
+    for kernel_dir in util::find_kernel_dirs(&options.module_dir, &options.scan.kernels)? {
+        info!("Scanning kernel modules in {}", kernel_dir.display());
+        let required = get_required_firmware(
+            fs,
+            graph,
+            &kernel_dir,
+            fw_dir,
+            root,
+            &options.overlays,
+            options.follow_external_symlinks,
+            options.iwlwifi_fallback_versions,
+            &options.scan,
+            &mut errors,
+        )?;
+        for (path, reason) in required {
+            required_fw_abs.entry(path).or_insert(reason);
+        }
+    }
+    for module_dir in &options.extra_module_dirs {
+        info!("Scanning out-of-tree modules in {}", module_dir.display());
+        let modules = graph.kernel(module_dir, &ScanOptions::default())?;
+        for module_path in modules.paths() {
+            let reason = format!("required by out-of-tree module {}", util::module_name(&module_path));
+            let info = match modules.modinfo(&module_path) {
+                Ok(info) => info,
+                Err(e) => {
+                    warn!("Reading modinfo of {} failed: {}", module_path.display(), e);
+                    errors.push(format!("Reading modinfo of {} failed: {}", module_path.display(), e));
+                    continue;
+                }
+            };
+            for fw_name in info.firmware() {
+                let overlays = &options.overlays;
+                for loaded_name in loaded_firmware_names(fs, &fw_name, fw_dir, None, overlays, options.iwlwifi_fallback_versions)? {
+                    require_firmware(
+                        fs,
+                        &loaded_name,
+                        &reason,
+                        fw_dir,
+                        root,
+                        None,
+                        overlays,
+                        options.follow_external_symlinks,
+                        &mut required_fw_abs,
+                    )?;
+                }
+            }
+        }
+    }
+    let mut dropped = HashMap::new();
+    let generations = &options.amdgpu_generations;
+    if !generations.is_empty() {
+        let reason = format!("amdgpu firmware of none of the generations {}", generations.join(","));
+        let is_pruned = |path: &Path| {
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            amdgpu::is_amdgpu_firmware(path) && !generations.iter().any(|g| amdgpu::matches_generation(&file_name, g))
+        };
+        dropped.extend(prune_firmware(fs, fw_dir, &reason, is_pruned, &mut required_fw_abs));
+    }
+    let platforms = &options.sof_platforms;
+    if !platforms.is_empty() {
+        let reason = format!("Sound Open Firmware of none of the platforms {}", platforms.join(","));
+        let is_pruned = |path: &Path| sof::is_sof_firmware(path) && sof::is_other_platform(path, platforms);
+        dropped.extend(prune_firmware(fs, fw_dir, &reason, is_pruned, &mut required_fw_abs));
+    }
+    match &options.microcode {
+        MicrocodePolicy::Keep => {
+            for entry in fs.walk(fw_dir) {
+                let relative_path = entry.path.strip_prefix(fw_dir).unwrap();
+                if entry.kind != FileKind::Dir && microcode::is_microcode(relative_path) {
+                    required_fw_abs.entry(entry.path.clone()).or_insert_with(|| "CPU microcode".to_string());
+                }
+            }
+        }
+        MicrocodePolicy::Delete => {
+            dropped.extend(prune_firmware(fs, fw_dir, "CPU microcode", microcode::is_microcode, &mut required_fw_abs));
+        }
+        MicrocodePolicy::MatchCpu(cpu) => {
+            let is_pruned = |path: &Path| microcode::is_microcode(path) && !cpu.loads(path);
+            dropped.extend(prune_firmware(fs, fw_dir, "microcode of another CPU", is_pruned, &mut required_fw_abs));
+            for entry in fs.walk(fw_dir) {
+                let relative_path = entry.path.strip_prefix(fw_dir).unwrap();
+                if entry.kind != FileKind::Dir && microcode::is_microcode(relative_path) && cpu.loads(relative_path) {
+                    required_fw_abs.entry(entry.path.clone()).or_insert_with(|| "microcode of the CPU".to_string());
+                }
+            }
+        }
+    }
+    if let Some(profile) = &options.profile {
+        info!("Keeping the {} firmware files loaded on the profiled machine", profile.firmware.len());
+        for fw_name in &profile.firmware {
+            let reason = "loaded on the profiled machine";
+            require_firmware(
+                fs,
+                fw_name,
+                reason,
+                fw_dir,
+                root,
+                None,
+                &options.overlays,
+                options.follow_external_symlinks,
+                &mut required_fw_abs,
+            )?;
+        }
+    }
+    if !options.failed_firmware.is_empty() {
+        info!("Keeping the {} firmware files the reference system failed to load", options.failed_firmware.len());
+        for fw_name in &options.failed_firmware {
+            require_firmware(
+                fs,
+                fw_name,
+                "failed to load on the reference system",
+                fw_dir,
+                root,
+                None,
+                &options.overlays,
+                options.follow_external_symlinks,
+                &mut required_fw_abs,
+            )?;
+        }
+    }
+    for path in kept_files(fs, fw_dir, &options.keep)? {
+        for path in resolve_symlinks(fs, &path, fw_dir, root, &options.overlays, options.follow_external_symlinks)? {
+            required_fw_abs.entry(path).or_insert_with(|| "matched a keep pattern".to_string());
+        }
+    }
+    if let Some(rules) = &options.rules {
+        dropped.extend(apply_rules(
+            fs,
+            fw_dir,
+            root,
+            rules,
+            &options.overlays,
+            options.follow_external_symlinks,
+            &mut required_fw_abs,
+        )?);
+    }
+    // Targets of symlinks leaving the firmware directory are never deleted, only reported.
+    let (external, required_fw_abs): (Vec<_>, Vec<_>) = required_fw_abs
+        .into_iter()
+        .partition(|(path, _)| !path.starts_with(fw_dir));
+    let mut required_fw: HashMap<_, _> = required_fw_abs.into_iter()
+        .map(|(p, reason)| (p.strip_prefix(fw_dir).unwrap().to_path_buf(), reason))
         .collect();
+    for path in protected_files(fs, fw_dir, &options.protect)? {
+        required_fw.entry(path).or_insert_with(|| "protected file".to_string());
+    }
+    if let Some(target_size) = options.target_size {
+        let kept = keep_within_budget(
+            fs,
+            fw_dir,
+            &mut required_fw,
+            &dropped,
+            options.rules.as_ref(),
+            options.min_size,
+            target_size,
+        )?;
+        for path in &kept {
+            info!(
+                "Keeping unused firmware {}, {} fits in {} bytes without deleting it",
+                path.display(),
+                fw_dir.display(),
+                target_size
+            );
+        }
+    }
+    drop(resolution);
 
-    let unused_size = remove_unused_files(fw_dir, &required_fw, delete)?;
+    let _span = info_span!("deletion").entered();
+
+    let mut deleter = Deleter::new(delete);
+    if let Some(fs) = &options.fs {
+        deleter = deleter.with_fs(Arc::clone(fs));
+    }
+    if let Some(backup) = &options.backup {
+        deleter = deleter.with_backup(backup)?;
+    }
+    if let Some(journal) = &options.journal {
+        deleter = deleter.with_journal(journal)?;
+    }
+    if let Some(clock) = &options.clock {
+        deleter = deleter.with_clock(Arc::clone(clock));
+    }
+    if let Some(quarantine) = &options.quarantine {
+        deleter = deleter.with_quarantine(quarantine)?;
+    }
+    let mut explanation = options.explain.as_deref().map(Explanation::create).transpose()?;
+    for (path, reason) in &external {
+        info!("Keeping external firmware {} ({})", path.display(), reason);
+        if let Some(explanation) = &mut explanation {
+            explanation.record(path, FileType::Firmware, Action::Keep, reason)?;
+        }
+    }
+    let (examined, unused_size, firmware_groups) = remove_unused_files(
+        fs,
+        fw_dir,
+        &required_fw,
+        &dropped,
+        options.min_size,
+        &mut deleter,
+        explanation.as_mut(),
+    )?;
+    if let Some(explanation) = explanation {
+        explanation.finish()?;
+    }
+    let kept = examined + external.len() - deleter.files().len();
+    if let Some(path) = &options.emit_required {
+        let install_dir = options.install_dir.as_deref().unwrap_or(fw_dir);
+        write_kept_list(fs, fw_dir, deleter.files(), path, install_dir, options.emit_format)?;
+    }
 
     if delete {
-        remove_dangling_symlinks(fw_dir)?;
-        remove_empty_directories(fw_dir)?;
+        remove_dangling_symlinks(fs, fw_dir, &options.overlays, &mut deleter)?;
+        remove_empty_directories(fs, fw_dir, &mut deleter)?;
     }
 
     info!("Potential savings: {} ({} MiB)", unused_size, unused_size >> 20);
 
-    Ok(())
+    Ok(CleanupSummary {
+        examined: examined + external.len(),
+        kept,
+        bytes_reclaimed: deleter.bytes(),
+        reasons: deleter.reasons().clone(),
+        errors,
+        firmware_groups,
+        deleted: deleter.finish()?,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::command::CommandRunner;
-    use std::collections::HashMap;
+    use crate::janitor_fs::MemoryFs;
+    use crate::util::KernelSelection;
+    use std::fs;
     use std::os::unix::fs::symlink;
     use tempfile::tempdir;
 
-    struct MockCommandRunner {
-        responses: HashMap<String, String>,
-    }
-
-    impl CommandRunner for MockCommandRunner {
-        fn run(&self, command: &str, args: &[&str]) -> Result<String, JanitorError> {
-            let key = if args.is_empty() {
-                command.to_string()
-            } else {
-                format!("{} {}", command, args.join(" "))
-            };
-            self.responses.get(&key).cloned().ok_or(JanitorError::Command(format!("Not mocked: {}", key)))
-        }
-    }
-
     #[test]
     fn test_get_required_firmware() {
         let temp_dir = tempdir().unwrap();
@@ -251,20 +1188,13 @@ mod tests {
         fs::create_dir_all(&fw_dir).unwrap();
 
         let mod1_path = kernel_dir.join("mod1.ko");
-        fs::write(&mod1_path, "").unwrap();
+        fs::write(&mod1_path, modinfo::build_test_module(&["firmware=fw1.bin"])).unwrap();
         let fw1_path = fw_dir.join("fw1.bin");
         fs::write(&fw1_path, "").unwrap();
 
-        let mut responses = HashMap::new();
-        responses.insert(
-            format!("/usr/sbin/modinfo -F firmware {}", mod1_path.display()),
-            "fw1.bin".to_string(),
-        );
-        let runner = MockCommandRunner { responses };
-
-        let required_fw = get_required_firmware(&kernel_dir, &fw_dir, &runner).unwrap();
+        let required_fw = get_required_firmware(&RealFs, &KernelGraph::new(), &kernel_dir, &fw_dir, Path::new("/"), &[], false, 0, &ScanOptions::default(), &mut Vec::new()).unwrap();
         assert_eq!(required_fw.len(), 1);
-        assert!(required_fw.contains(&fw1_path));
+        assert!(required_fw.contains_key(&fw1_path));
     }
 
     #[test]
@@ -276,7 +1206,7 @@ mod tests {
         fs::create_dir_all(&fw_dir).unwrap();
 
         let mod1_path = kernel_dir.join("mod1.ko");
-        fs::write(&mod1_path, "").unwrap();
+        fs::write(&mod1_path, modinfo::build_test_module(&["firmware=brcm/brcmfmac*-sdio.bin"])).unwrap();
 
         let fw_file1 = fw_dir.join("brcm/brcmfmac43430-sdio.bin");
         let fw_file2 = fw_dir.join("brcm/brcmfmac43430-sdio.txt");
@@ -284,17 +1214,81 @@ mod tests {
         fs::write(&fw_file1, "").unwrap();
         fs::write(&fw_file2, "").unwrap();
 
-        let mut responses = HashMap::new();
-        responses.insert(
-            format!("/usr/sbin/modinfo -F firmware {}", mod1_path.display()),
-            "brcm/brcmfmac*-sdio.bin".to_string(),
+        let required_fw = get_required_firmware(&RealFs, &KernelGraph::new(), &kernel_dir, &fw_dir, Path::new("/"), &[], false, 0, &ScanOptions::default(), &mut Vec::new()).unwrap();
+        assert_eq!(required_fw.len(), 1);
+        assert!(required_fw.contains_key(&fw_file1));
+        assert!(!required_fw.contains_key(&fw_file2));
+    }
+
+    #[test]
+    fn test_get_required_firmware_with_placeholders() {
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path().join("lib/modules/6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(fw_dir.join("vendor")).unwrap();
+        let module = modinfo::build_test_module(&["firmware=vendor/foo_%02d.bin"]);
+        fs::write(kernel_dir.join("foo.ko"), module).unwrap();
+        for file in ["vendor/foo_01.bin", "vendor/foo_02.bin.xz", "vendor/bar_01.bin"] {
+            fs::write(fw_dir.join(file), "").unwrap();
+        }
+
+        let required = get_required_firmware(&RealFs, &KernelGraph::new(), &kernel_dir, &fw_dir, Path::new("/"), &[], false, 0, &ScanOptions::default(), &mut Vec::new()).unwrap();
+        assert_eq!(required.len(), 2);
+        assert!(required.contains_key(&fw_dir.join("vendor/foo_01.bin")));
+        assert!(required.contains_key(&fw_dir.join("vendor/foo_02.bin.xz")));
+
+        assert_eq!(expand_placeholders("foo_%02d.bin"), "foo_*.bin");
+        assert_eq!(expand_placeholders("%s/fw-%d.%d%x.bin"), "*/fw-*.*.bin");
+        assert_eq!(expand_placeholders("fw_%lu_%%.bin"), "fw_*_%.bin");
+        assert_eq!(expand_placeholders("fw-100%.bin"), "fw-100%.bin");
+        assert_eq!(expand_placeholders("plain.bin"), "plain.bin");
+    }
+
+    #[test]
+    fn test_get_required_firmware_groups() {
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path().join("lib/modules/6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        let module = modinfo::build_test_module(&["firmware=nvidia/tu104/gsp/gsp-535.113.01.bin"]);
+        fs::write(kernel_dir.join("nouveau.ko"), module).unwrap();
+
+        let files = [
+            "nvidia/tu102/gsp/gsp-535.113.01.bin",
+            "nvidia/tu102/gsp/gsp-570.144.bin",
+            "nvidia/tu102/gsp/booter_load-570.144.bin",
+            "nvidia/tu102/sec2/sig.bin",
+            "nvidia/tu104/acr/bl.bin",
+            "nvidia/ga100/gsp/gsp-570.144.bin",
+        ];
+        for file in files {
+            fs::create_dir_all(fw_dir.join(file).parent().unwrap()).unwrap();
+            fs::write(fw_dir.join(file), "").unwrap();
+        }
+        // The chips sharing firmware link to the directory of another one.
+        symlink("../tu102/gsp", fw_dir.join("nvidia/tu104/gsp")).unwrap();
+
+        let required = get_required_firmware(&RealFs, &KernelGraph::new(), &kernel_dir, &fw_dir, Path::new("/"), &[], false, 0, &ScanOptions::default(), &mut Vec::new()).unwrap();
+        let mut required: Vec<_> = required.keys().map(|p| p.strip_prefix(&fw_dir).unwrap().to_path_buf()).collect();
+        required.sort();
+        assert_eq!(
+            required,
+            [
+                "nvidia/tu102/gsp/booter_load-570.144.bin",
+                "nvidia/tu102/gsp/gsp-535.113.01.bin",
+                "nvidia/tu102/gsp/gsp-570.144.bin",
+                "nvidia/tu104/acr/bl.bin",
+                "nvidia/tu104/gsp",
+                "nvidia/tu104/gsp/gsp-535.113.01.bin",
+            ]
+            .map(PathBuf::from)
         );
-        let runner = MockCommandRunner { responses };
 
-        let required_fw = get_required_firmware(&kernel_dir, &fw_dir, &runner).unwrap();
-        assert_eq!(required_fw.len(), 1);
-        assert!(required_fw.contains(&fw_file1));
-        assert!(!required_fw.contains(&fw_file2));
+        assert_eq!(firmware_group_dir("nvidia", "nvidia/570.144/gsp_tu10x.bin").as_deref(), Some("nvidia/570.144"));
+        assert_eq!(firmware_group_dir("nouveau", "nvidia/gsp.bin"), None);
+        assert_eq!(firmware_group_dir("nouveau", "nvidia/*/gsp/gsp.bin"), None);
+        assert_eq!(firmware_group_dir("amdgpu", "nvidia/tu102/gsp/gsp.bin"), None);
     }
 
     #[test]
@@ -303,7 +1297,7 @@ mod tests {
         let file_path = temp_dir.path().join("file.bin");
         fs::write(&file_path, "data").unwrap();
 
-        let resolved = resolve_symlinks(&file_path, temp_dir.path()).unwrap();
+        let resolved = resolve_symlinks(&RealFs, &file_path, temp_dir.path(), Path::new("/"), &[], false).unwrap();
         assert_eq!(resolved, vec![file_path]);
     }
 
@@ -321,7 +1315,7 @@ mod tests {
         symlink(&link1_path, &link2_path).unwrap();
         symlink(&link2_path, &link3_path).unwrap();
 
-        let resolved = resolve_symlinks(&link3_path, base_dir).unwrap();
+        let resolved = resolve_symlinks(&RealFs, &link3_path, base_dir, Path::new("/"), &[], false).unwrap();
 
         // The new implementation returns the starting link and all intermediate links/targets.
         assert_eq!(resolved.len(), 4);
@@ -339,7 +1333,7 @@ mod tests {
 
         symlink("non_existent_file", &link_path).unwrap();
 
-        let resolved = resolve_symlinks(&link_path, base_dir).unwrap();
+        let resolved = resolve_symlinks(&RealFs, &link_path, base_dir, Path::new("/"), &[], false).unwrap();
         // fs::canonicalize fails on broken links, so only the original path is returned.
         assert_eq!(resolved, vec![link_path]);
     }
@@ -354,7 +1348,7 @@ mod tests {
         symlink(&link2_path, &link1_path).unwrap();
         symlink(&link1_path, &link2_path).unwrap();
 
-        let resolved = resolve_symlinks(&link1_path, base_dir).unwrap();
+        let resolved = resolve_symlinks(&RealFs, &link1_path, base_dir, Path::new("/"), &[], false).unwrap();
         // fs::canonicalize fails on link cycles, so only the original path is returned.
         assert_eq!(resolved.len(), 1);
         assert!(resolved.contains(&link1_path));
@@ -371,17 +1365,17 @@ mod tests {
         fs::write(fw_dir.join(&required_file_path), "required_data").unwrap();
         fs::write(fw_dir.join(&unused_file_path), "unused_data").unwrap();
 
-        let mut required_fw = HashSet::new();
-        required_fw.insert(required_file_path.clone());
+        let mut required_fw = HashMap::new();
+        required_fw.insert(required_file_path.clone(), "test".to_string());
 
         // Test without deleting
-        let unused_size = remove_unused_files(fw_dir, &required_fw, false).unwrap();
+        let (_, unused_size, _) = remove_unused_files(&RealFs, fw_dir, &required_fw, &HashMap::new(), 0, &mut Deleter::new(false), None).unwrap();
         assert_eq!(unused_size, 11); // "unused_data".len()
         assert!(fw_dir.join(&unused_file_path).exists());
         assert!(fw_dir.join(&required_file_path).exists());
 
         // Test with deleting
-        let unused_size_del = remove_unused_files(fw_dir, &required_fw, true).unwrap();
+        let (_, unused_size_del, _) = remove_unused_files(&RealFs, fw_dir, &required_fw, &HashMap::new(), 0, &mut Deleter::new(true), None).unwrap();
         assert_eq!(unused_size_del, 11);
         assert!(!fw_dir.join(&unused_file_path).exists());
         assert!(fw_dir.join(&required_file_path).exists());
@@ -402,7 +1396,7 @@ mod tests {
 
         assert!(dangling_symlink.is_symlink());
 
-        remove_dangling_symlinks(fw_dir).unwrap();
+        remove_dangling_symlinks(&RealFs, fw_dir, &[], &mut Deleter::new(true)).unwrap();
 
         assert!(valid_symlink.exists());
         assert!(!dangling_symlink.exists());
@@ -430,7 +1424,7 @@ mod tests {
         assert!(dir_b.exists());
         assert!(dir_d.exists());
 
-        remove_empty_directories(fw_dir).unwrap();
+        remove_empty_directories(&RealFs, fw_dir, &mut Deleter::new(true)).unwrap();
 
         // Assert empty directories are removed
         assert!(!dir_b.exists());
@@ -443,38 +1437,10 @@ mod tests {
 
         // Run again to ensure it handles the case where 'a' is now empty
         fs::remove_dir_all(&dir_c).unwrap();
-        remove_empty_directories(fw_dir).unwrap();
+        remove_empty_directories(&RealFs, fw_dir, &mut Deleter::new(true)).unwrap();
         assert!(!dir_a.exists());
     }
 
-    #[test]
-    fn test_find_kernel_modules() {
-        let temp_dir = tempdir().unwrap();
-        let kernel_dir = temp_dir.path();
-
-        let mod1 = kernel_dir.join("module1.ko");
-        let mod2 = kernel_dir.join("module2.ko.xz");
-        let mod3 = kernel_dir.join("module3.ko.zst");
-        let not_a_mod = kernel_dir.join("not_a_module.txt");
-        let nested_dir = kernel_dir.join("nested");
-        fs::create_dir(&nested_dir).unwrap();
-        let nested_mod = nested_dir.join("nested.ko");
-
-        fs::write(&mod1, "").unwrap();
-        fs::write(&mod2, "").unwrap();
-        fs::write(&mod3, "").unwrap();
-        fs::write(&not_a_mod, "").unwrap();
-        fs::write(&nested_mod, "").unwrap();
-
-        let mut found = find_kernel_modules(kernel_dir).unwrap();
-        found.sort();
-
-        let mut expected = vec![mod1, mod2, mod3, nested_mod];
-        expected.sort();
-
-        assert_eq!(found, expected);
-    }
-
     #[test]
     fn test_find_firmware_files_from_name() {
         let temp_dir = tempdir().unwrap();
@@ -491,16 +1457,16 @@ mod tests {
         fs::write(&other_file, "").unwrap();
 
         // Test exact name matching with compressed variants
-        let mut found1 = find_firmware_files_from_name("iwlwifi-1.bin", fw_dir).unwrap();
+        let mut found1 = find_firmware_files_from_name(&RealFs, "iwlwifi-1.bin", fw_dir, None).unwrap();
         found1.sort();
         assert_eq!(found1, vec![fw1.clone()]);
 
-        let mut found2 = find_firmware_files_from_name("iwlwifi-2.bin", fw_dir).unwrap();
+        let mut found2 = find_firmware_files_from_name(&RealFs, "iwlwifi-2.bin", fw_dir, None).unwrap();
         found2.sort();
         assert_eq!(found2, vec![fw2_xz.clone()]);
 
         // Test glob matching
-        let mut found_glob = find_firmware_files_from_name("iwlwifi-*", fw_dir).unwrap();
+        let mut found_glob = find_firmware_files_from_name(&RealFs, "iwlwifi-*", fw_dir, None).unwrap();
         found_glob.sort();
         let mut expected_glob = vec![fw1.clone(), fw2_xz.clone(), fw3_zst.clone()];
         expected_glob.sort();
@@ -516,7 +1482,7 @@ mod tests {
         fs::create_dir_all(&fw_dir).unwrap();
 
         let mod1_path = kernel_dir.join("mod1.ko");
-        fs::write(&mod1_path, "").unwrap();
+        fs::write(&mod1_path, modinfo::build_test_module(&["firmware=brcm/brcmfmac*-sdio.bin"])).unwrap();
 
         let fw_file1 = fw_dir.join("brcm/brcmfmac43430-sdio.bin.xz");
         let fw_file2 = fw_dir.join("brcm/brcmfmac43430-sdio.txt");
@@ -524,17 +1490,10 @@ mod tests {
         fs::write(&fw_file1, "").unwrap();
         fs::write(&fw_file2, "").unwrap();
 
-        let mut responses = HashMap::new();
-        responses.insert(
-            format!("/usr/sbin/modinfo -F firmware {}", mod1_path.display()),
-            "brcm/brcmfmac*-sdio.bin".to_string(),
-        );
-        let runner = MockCommandRunner { responses };
-
-        let required_fw = get_required_firmware(&kernel_dir, &fw_dir, &runner).unwrap();
+        let required_fw = get_required_firmware(&RealFs, &KernelGraph::new(), &kernel_dir, &fw_dir, Path::new("/"), &[], false, 0, &ScanOptions::default(), &mut Vec::new()).unwrap();
         assert_eq!(required_fw.len(), 1);
-        assert!(required_fw.contains(&fw_file1));
-        assert!(!required_fw.contains(&fw_file2));
+        assert!(required_fw.contains_key(&fw_file1));
+        assert!(!required_fw.contains_key(&fw_file2));
     }
 
     #[test]
@@ -550,10 +1509,771 @@ mod tests {
         fs::write(&file_path, "data").unwrap();
         symlink("../../file.bin", &link_path).unwrap();
 
-        let resolved = resolve_symlinks(&link_path, base_dir).unwrap();
+        let resolved = resolve_symlinks(&RealFs, &link_path, base_dir, Path::new("/"), &[], false).unwrap();
 
         assert_eq!(resolved.len(), 2);
         assert!(resolved.contains(&file_path));
         assert!(resolved.contains(&link_path));
     }
+
+    #[test]
+    fn test_cleanup_firmware_with_overlay() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(fw_dir.join("vendor")).unwrap();
+        let overlay_dir = temp_dir.path().join("overlay");
+        fs::create_dir_all(overlay_dir.join("vendor")).unwrap();
+
+        fs::write(
+            kernel_dir.join("mod1.ko"),
+            modinfo::build_test_module(&["firmware=alias.bin", "firmware=vendor/extra.bin"]),
+        )
+        .unwrap();
+        // alias.bin points to a blob that only the overlay provides.
+        symlink("vendor/blob.bin", fw_dir.join("alias.bin")).unwrap();
+        fs::write(overlay_dir.join("vendor/blob.bin"), "blob").unwrap();
+        fs::write(overlay_dir.join("vendor/extra.bin"), "extra").unwrap();
+        fs::write(fw_dir.join("unused.bin"), "unused").unwrap();
+
+        let options = FirmwareOptions {
+            module_dir,
+            firmware_dir: fw_dir.clone(),
+            delete: true,
+            overlays: vec![overlay_dir.clone()],
+            ..Default::default()
+        };
+        let deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+
+        assert_eq!(deleted, vec![fw_dir.join("unused.bin")]);
+        assert!(fw_dir.join("alias.bin").is_symlink());
+        assert!(overlay_dir.join("vendor/blob.bin").exists());
+        assert!(overlay_dir.join("vendor/extra.bin").exists());
+    }
+
+    #[test]
+    fn test_cleanup_firmware_amdgpu_generations() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(fw_dir.join("amdgpu")).unwrap();
+        let files = ["dcn_3_1_4_dmcub.bin", "dcn_3_2_0_dmcub.bin", "vcn_4_0_0.bin", "navi10_sdma.bin", "renoir_asd.bin"];
+        let fields: Vec<String> = files.iter().map(|f| format!("firmware=amdgpu/{}", f)).collect();
+        let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+        fs::write(kernel_dir.join("amdgpu.ko"), modinfo::build_test_module(&fields)).unwrap();
+        for file in files {
+            fs::write(fw_dir.join("amdgpu").join(format!("{}.zst", file)), "fw").unwrap();
+        }
+
+        let options = FirmwareOptions {
+            module_dir,
+            firmware_dir: fw_dir.clone(),
+            keep: vec![Pattern::new("amdgpu/renoir_*").unwrap()],
+            amdgpu_generations: vec!["dcn31".to_string(), "vcn4".to_string()],
+            ..Default::default()
+        };
+        let mut summary = cleanup_firmware(&options, &KernelGraph::new()).unwrap();
+        summary.deleted.sort();
+        // Explicitly kept firmware stays whatever its generation.
+        assert_eq!(
+            summary.deleted,
+            vec![fw_dir.join("amdgpu/dcn_3_2_0_dmcub.bin.zst"), fw_dir.join("amdgpu/navi10_sdma.bin.zst")]
+        );
+        assert_eq!(summary.reasons.len(), 1);
+    }
+
+    #[test]
+    fn test_cleanup_firmware_sof_platforms() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        let files = [
+            "intel/sof/sof-tgl.ri",
+            "intel/sof/sof-cml.ri",
+            "intel/sof-tplg/sof-tgl-rt711.tplg",
+            "intel/sof-tplg/sof-cml-rt5682.tplg",
+            "intel/sof-tplg/sof-hda-generic.tplg",
+            "intel/sof-ipc4/mtl/sof-mtl.ri",
+        ];
+        let module = modinfo::build_test_module(&["firmware=intel/sof/*", "firmware=intel/sof-tplg/*", "firmware=intel/sof-ipc4/mtl/*"]);
+        fs::write(kernel_dir.join("snd-sof-pci.ko"), module).unwrap();
+        for file in files {
+            fs::create_dir_all(fw_dir.join(file).parent().unwrap()).unwrap();
+            fs::write(fw_dir.join(file), "fw").unwrap();
+        }
+
+        let options = FirmwareOptions {
+            module_dir,
+            firmware_dir: fw_dir.clone(),
+            sof_platforms: vec!["tgl".to_string()],
+            ..Default::default()
+        };
+        let mut deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+        deleted.sort();
+        assert_eq!(
+            deleted,
+            ["intel/sof/sof-cml.ri", "intel/sof-ipc4/mtl/sof-mtl.ri", "intel/sof-tplg/sof-cml-rt5682.tplg"].map(|f| fw_dir.join(f))
+        );
+    }
+
+    #[test]
+    fn test_cleanup_firmware_iwlwifi_api_versions() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(&fw_dir).unwrap();
+        let module = modinfo::build_test_module(&["firmware=iwlwifi-cc-a0-77.ucode", "firmware=iwlwifi-QuZ-a0-hr-b0-74.ucode"]);
+        fs::write(kernel_dir.join("iwlwifi.ko"), module).unwrap();
+        for file in ["iwlwifi-cc-a0-46.ucode", "iwlwifi-cc-a0-72.ucode", "iwlwifi-cc-a0-77.ucode.xz", "iwlwifi-QuZ-a0-hr-b0-59.ucode", "iwlwifi-QuZ-a0-hr-b0-72.ucode"] {
+            fs::write(fw_dir.join(file), "fw").unwrap();
+        }
+
+        let mut options = FirmwareOptions {
+            module_dir,
+            firmware_dir: fw_dir.clone(),
+            ..Default::default()
+        };
+        let mut deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+        deleted.sort();
+        // The newest API version available is kept, even below the one requested.
+        assert_eq!(
+            deleted,
+            ["iwlwifi-QuZ-a0-hr-b0-59.ucode", "iwlwifi-cc-a0-46.ucode", "iwlwifi-cc-a0-72.ucode"].map(|f| fw_dir.join(f))
+        );
+
+        options.iwlwifi_fallback_versions = 1;
+        let deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+        assert_eq!(deleted, vec![fw_dir.join("iwlwifi-cc-a0-46.ucode")]);
+    }
+
+    #[test]
+    fn test_cleanup_firmware_with_profile() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        fs::create_dir_all(module_dir.join("6.1.0-test")).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(fw_dir.join("rtl_nic")).unwrap();
+        fs::write(fw_dir.join("rtl_nic/rtl8168h-2.fw.xz"), "fw").unwrap();
+        fs::write(fw_dir.join("unused.bin"), "unused").unwrap();
+
+        let options = FirmwareOptions {
+            module_dir,
+            firmware_dir: fw_dir.clone(),
+            delete: true,
+            profile: Some(Profile {
+                firmware: ["rtl_nic/rtl8168h-2.fw".to_string()].into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+
+        assert_eq!(deleted, vec![fw_dir.join("unused.bin")]);
+        assert!(fw_dir.join("rtl_nic/rtl8168h-2.fw.xz").exists());
+    }
+
+    #[test]
+    fn test_cleanup_firmware_microcode() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        fs::create_dir_all(module_dir.join("6.1.0-test")).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(fw_dir.join("intel-ucode")).unwrap();
+        fs::create_dir_all(fw_dir.join("amd-ucode")).unwrap();
+        for file in ["intel-ucode/06-8c-01", "intel-ucode/06-97-02", "amd-ucode/microcode_amd_fam19h.bin"] {
+            fs::write(fw_dir.join(file), "ucode").unwrap();
+        }
+
+        let mut options = FirmwareOptions {
+            module_dir,
+            firmware_dir: fw_dir.clone(),
+            ..Default::default()
+        };
+        assert!(cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted.is_empty());
+
+        options.microcode = MicrocodePolicy::Delete;
+        assert_eq!(cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted.len(), 3);
+
+        options.microcode = MicrocodePolicy::MatchCpu(microcode::Cpu {
+            vendor: "GenuineIntel".to_string(),
+            family: 6,
+            model: 0x8c,
+            stepping: 1,
+        });
+        let mut deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+        deleted.sort();
+        assert_eq!(deleted, ["amd-ucode/microcode_amd_fam19h.bin", "intel-ucode/06-97-02"].map(|f| fw_dir.join(f)));
+    }
+
+    #[test]
+    fn test_cleanup_firmware_with_failed_firmware() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        fs::create_dir_all(module_dir.join("6.1.0-test")).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(&fw_dir).unwrap();
+        fs::write(fw_dir.join("iwlwifi-cc-a0-78.ucode"), "fw").unwrap();
+        fs::write(fw_dir.join("unused.bin"), "unused").unwrap();
+
+        let options = FirmwareOptions {
+            module_dir,
+            firmware_dir: fw_dir.clone(),
+            failed_firmware: ["iwlwifi-cc-a0-78.ucode".to_string()].into(),
+            ..Default::default()
+        };
+        let deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+        assert_eq!(deleted, vec![fw_dir.join("unused.bin")]);
+    }
+
+    #[test]
+    fn test_cleanup_firmware_unreadable_module() {
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path().join("lib/modules/6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        fs::write(kernel_dir.join("broken.ko"), "not an ELF file").unwrap();
+        fs::write(kernel_dir.join("mod.ko"), modinfo::build_test_module(&["firmware=used.bin"])).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(&fw_dir).unwrap();
+        fs::write(fw_dir.join("used.bin"), "fw").unwrap();
+        fs::write(fw_dir.join("unused.bin"), "fw").unwrap();
+
+        let options = FirmwareOptions {
+            module_dir: temp_dir.path().join("lib/modules"),
+            firmware_dir: fw_dir.clone(),
+            ..Default::default()
+        };
+        // The module which cannot be read is reported, the others are still processed.
+        let summary = cleanup_firmware(&options, &KernelGraph::new()).unwrap();
+        assert_eq!(summary.deleted, vec![fw_dir.join("unused.bin")]);
+        assert_eq!(summary.errors.len(), 1);
+        assert!(summary.errors[0].contains("broken.ko"));
+    }
+
+    #[test]
+    fn test_cleanup_firmware_all_kernels() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(&fw_dir).unwrap();
+        for (version, fw) in [("6.1.0-1", "old.bin"), ("6.1.0-2", "new.bin")] {
+            let kernel_dir = module_dir.join(version);
+            fs::create_dir_all(&kernel_dir).unwrap();
+            let field = format!("firmware={}", fw);
+            fs::write(kernel_dir.join("mod.ko"), modinfo::build_test_module(&[&field])).unwrap();
+            fs::write(fw_dir.join(fw), "fw").unwrap();
+        }
+
+        let mut options = FirmwareOptions {
+            module_dir,
+            firmware_dir: fw_dir.clone(),
+            ..Default::default()
+        };
+        // Only the latest kernel by default, the firmware of the older one would be removed.
+        assert_eq!(cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted, vec![fw_dir.join("old.bin")]);
+
+        options.scan.kernels = KernelSelection::All;
+        assert!(cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted.is_empty());
+    }
+
+    #[test]
+    fn test_cleanup_firmware_out_of_tree_modules() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        let vendor_dir = temp_dir.path().join("opt/vendor/modules");
+        let old_dir = module_dir.join("6.1.0-1");
+        let kernel_dir = module_dir.join("6.1.0-2");
+        fs::create_dir_all(&fw_dir).unwrap();
+        fs::create_dir_all(&vendor_dir).unwrap();
+        fs::create_dir_all(old_dir.join("extra")).unwrap();
+        fs::create_dir_all(kernel_dir.join("updates/dkms")).unwrap();
+        fs::create_dir_all(kernel_dir.join("weak-updates")).unwrap();
+        for (path, fw) in [
+            (kernel_dir.join("updates/dkms/nic.ko"), "nic.bin"),
+            (old_dir.join("extra/kmp.ko"), "kmp.bin"),
+            (old_dir.join("extra/unlinked.ko"), "unlinked.bin"),
+            (vendor_dir.join("gpu.ko"), "gpu.bin"),
+        ] {
+            let field = format!("firmware={}", fw);
+            fs::write(path, modinfo::build_test_module(&[&field])).unwrap();
+            fs::write(fw_dir.join(fw), "fw").unwrap();
+        }
+        symlink("/lib/modules/6.1.0-1/extra/kmp.ko", kernel_dir.join("weak-updates/kmp.ko")).unwrap();
+
+        let options = FirmwareOptions {
+            module_dir,
+            firmware_dir: fw_dir.clone(),
+            extra_module_dirs: vec![vendor_dir],
+            ..Default::default()
+        };
+        // Only the latest kernel is selected, the modules of the older one are not scanned.
+        assert_eq!(
+            cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted,
+            vec![fw_dir.join("unlinked.bin")]
+        );
+    }
+
+    #[test]
+    fn test_get_required_firmware_builtin() {
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path().join("lib/modules/6.1.0-test");
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        fs::create_dir_all(fw_dir.join("i915")).unwrap();
+        fs::write(
+            kernel_dir.join("modules.builtin.modinfo"),
+            "i915.firmware=i915/kbl_dmc_ver1_04.bin\0",
+        )
+        .unwrap();
+        let fw_path = fw_dir.join("i915/kbl_dmc_ver1_04.bin");
+        fs::write(&fw_path, "fw").unwrap();
+
+        let required_fw = get_required_firmware(&RealFs, &KernelGraph::new(), &kernel_dir, &fw_dir, Path::new("/"), &[], false, 0, &ScanOptions::default(), &mut Vec::new()).unwrap();
+        assert_eq!(required_fw.len(), 1);
+        assert!(required_fw.contains_key(&fw_path));
+    }
+
+    #[test]
+    fn test_find_firmware_files_from_pattern_is_not_greedy() {
+        let temp_dir = tempdir().unwrap();
+        // Glob metacharacters in the firmware directory must not be interpreted.
+        let fw_dir = temp_dir.path().join("fw[1]");
+        fs::create_dir_all(fw_dir.join("brcm/sub")).unwrap();
+        fs::create_dir_all(fw_dir.join("brcm-old")).unwrap();
+
+        let blob = fw_dir.join("brcm/brcmfmac43455-sdio.bin");
+        let blob_zst = fw_dir.join("brcm/brcmfmac4356-pcie.bin.zst");
+        let nested = fw_dir.join("brcm/sub/nested.bin");
+        let hidden = fw_dir.join("brcm/.brcmfmac.bin.ij-tmp-1-0");
+        let sibling = fw_dir.join("brcm-old/brcmfmac43455-sdio.bin");
+        let top = fw_dir.join("brcm.txt");
+        for path in [&blob, &blob_zst, &nested, &hidden, &sibling, &top] {
+            fs::write(path, "").unwrap();
+        }
+
+        let found = find_firmware_files_from_name(&RealFs, "brcm/*", &fw_dir, None).unwrap();
+        assert_eq!(found, vec![blob.clone(), blob_zst.clone()]);
+
+        // Compression extensions are only appended to full names: the pattern must match up to
+        // the end of the name without extension.
+        let found = find_firmware_files_from_name(&RealFs, "brcm/brcmfmac*.bin", &fw_dir, None).unwrap();
+        assert_eq!(found, vec![blob, blob_zst]);
+        assert!(find_firmware_files_from_name(&RealFs, "brcm/*.zst", &fw_dir, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cleanup_firmware_keeps_protected_files() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        fs::create_dir_all(module_dir.join("6.1.0-test")).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(fw_dir.join("vendor")).unwrap();
+        let kept = ["WHENCE", "LICENSE.vendor", "vendor/LICENCE.blob", "regulatory.db.p7s", "vendor/keep.me"];
+        for name in kept {
+            fs::write(fw_dir.join(name), "").unwrap();
+        }
+        fs::write(fw_dir.join("unused.bin"), "").unwrap();
+
+        let options = FirmwareOptions {
+            module_dir,
+            firmware_dir: fw_dir.clone(),
+            delete: true,
+            protect: vec![Pattern::new("vendor/*.me").unwrap()],
+            ..Default::default()
+        };
+        let deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+
+        assert_eq!(deleted, vec![fw_dir.join("unused.bin")]);
+        for name in kept {
+            assert!(fw_dir.join(name).exists());
+        }
+    }
+
+    #[test]
+    fn test_cleanup_firmware_with_keep_patterns() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        fs::create_dir_all(module_dir.join("6.1.0-test")).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(fw_dir.join("ath10k/QCA6174/hw3.0")).unwrap();
+        fs::create_dir_all(fw_dir.join("shared")).unwrap();
+
+        let board = fw_dir.join("ath10k/QCA6174/hw3.0/board-2.bin");
+        fs::write(&board, "").unwrap();
+        fs::write(fw_dir.join("shared/firmware-6.bin"), "").unwrap();
+        symlink(
+            "../../../shared/firmware-6.bin",
+            fw_dir.join("ath10k/QCA6174/hw3.0/firmware-6.bin"),
+        )
+        .unwrap();
+        fs::write(fw_dir.join("shared/unused.bin"), "").unwrap();
+
+        let options = FirmwareOptions {
+            module_dir,
+            firmware_dir: fw_dir.clone(),
+            delete: true,
+            keep: vec![Pattern::new("ath10k/*").unwrap()],
+            ..Default::default()
+        };
+        let deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+
+        assert_eq!(deleted, vec![fw_dir.join("shared/unused.bin")]);
+        assert!(board.exists());
+        assert!(fw_dir.join("ath10k/QCA6174/hw3.0/firmware-6.bin").exists());
+    }
+
+    #[test]
+    fn test_cleanup_firmware_explain() {
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path().join("lib/modules/6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        fs::write(kernel_dir.join("iwlwifi.ko"), modinfo::build_test_module(&["firmware=iwl.bin"])).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(&fw_dir).unwrap();
+        for name in ["iwl.bin", "WHENCE", "unused.bin"] {
+            fs::write(fw_dir.join(name), "").unwrap();
+        }
+        let report = temp_dir.path().join("explain.jsonl");
+
+        let options = FirmwareOptions {
+            module_dir: temp_dir.path().join("lib/modules"),
+            firmware_dir: fw_dir.clone(),
+            explain: Some(report.clone()),
+            ..Default::default()
+        };
+        cleanup_firmware(&options, &KernelGraph::new()).unwrap();
+
+        let mut decisions: Vec<_> = crate::explain::read_explanation(&report)
+            .unwrap()
+            .into_iter()
+            .map(|d| (d.path, d.action, d.reason))
+            .collect();
+        decisions.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            decisions,
+            vec![
+                (fw_dir.join("WHENCE"), Action::Keep, "protected file".to_string()),
+                (fw_dir.join("iwl.bin"), Action::Keep, "required by module iwlwifi".to_string()),
+                (fw_dir.join("unused.bin"), Action::Delete, UNUSED_REASON.to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cleanup_firmware_config_rules() {
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path().join("lib/modules/6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        fs::write(kernel_dir.join("nfp.ko"), modinfo::build_test_module(&["firmware=netronome/nic.nffw"])).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(fw_dir.join("bnx2x")).unwrap();
+        fs::create_dir_all(fw_dir.join("netronome")).unwrap();
+        for name in ["bnx2x/bnx2x-e2.fw", "netronome/nic.nffw", "netronome/LICENSE.netronome", "unused.bin"] {
+            fs::write(fw_dir.join(name), "").unwrap();
+        }
+        let report = temp_dir.path().join("explain.jsonl");
+
+        let options = FirmwareOptions {
+            module_dir: temp_dir.path().join("lib/modules"),
+            firmware_dir: fw_dir.clone(),
+            delete: true,
+            rules: Some(Rules::parse("bnx2x/.*\n-netronome/.*\n", "x86_64").unwrap()),
+            explain: Some(report.clone()),
+            ..Default::default()
+        };
+        let mut deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+        deleted.sort();
+
+        // The required netronome firmware is dropped, but not its protected license.
+        assert_eq!(deleted, vec![fw_dir.join("netronome/nic.nffw"), fw_dir.join("unused.bin")]);
+        assert!(fw_dir.join("bnx2x/bnx2x-e2.fw").exists());
+        assert!(fw_dir.join("netronome/LICENSE.netronome").exists());
+        let decisions = crate::explain::read_explanation(&report).unwrap();
+        let reason = |path: PathBuf| decisions.iter().find(|d| d.path == path).unwrap().reason.clone();
+        assert_eq!(reason(fw_dir.join("bnx2x/bnx2x-e2.fw")), "matched keep rule 'bnx2x/.*'");
+        assert_eq!(reason(fw_dir.join("netronome/nic.nffw")), "matched delete rule '-netronome/.*'");
+    }
+
+    #[test]
+    fn test_cleanup_firmware_updates_layers() {
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path().join("lib/modules/6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        let module = modinfo::build_test_module(&["firmware=a.bin", "firmware=b.bin", "firmware=c.bin"]);
+        fs::write(kernel_dir.join("mod.ko"), module).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(fw_dir.join("updates/6.1.0-test")).unwrap();
+        fs::create_dir_all(fw_dir.join("updates/6.2.0-other")).unwrap();
+        for name in [
+            "updates/6.1.0-test/a.bin",
+            "updates/a.bin",
+            "a.bin",
+            "updates/b.bin.xz",
+            "b.bin",
+            "c.bin",
+            "updates/6.2.0-other/c.bin",
+        ] {
+            fs::write(fw_dir.join(name), "").unwrap();
+        }
+
+        let options = FirmwareOptions {
+            module_dir: temp_dir.path().join("lib/modules"),
+            firmware_dir: fw_dir.clone(),
+            ..Default::default()
+        };
+        let mut deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+        deleted.sort();
+
+        // Only the copy the kernel loads is kept, the ones it hides are unused.
+        let expected: Vec<_> = ["a.bin", "b.bin", "updates/6.2.0-other/c.bin", "updates/a.bin"]
+            .iter()
+            .map(|name| fw_dir.join(name))
+            .collect();
+        assert_eq!(deleted, expected);
+    }
+
+    #[test]
+    fn test_cleanup_firmware_follow_external_symlinks() {
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path().join("lib/modules/6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        fs::write(kernel_dir.join("mod.ko"), modinfo::build_test_module(&["firmware=a.bin"])).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        let vendor_dir = temp_dir.path().join("vendor");
+        fs::create_dir_all(fw_dir.join("real")).unwrap();
+        fs::create_dir_all(&vendor_dir).unwrap();
+        // a.bin -> vendor/a.bin -> real/a.bin, back in the firmware directory.
+        fs::write(fw_dir.join("real/a.bin"), "").unwrap();
+        symlink("../lib/firmware/real/a.bin", vendor_dir.join("a.bin")).unwrap();
+        symlink("../../vendor/a.bin", fw_dir.join("a.bin")).unwrap();
+        let report = temp_dir.path().join("explain.jsonl");
+
+        let mut options = FirmwareOptions {
+            module_dir: temp_dir.path().join("lib/modules"),
+            firmware_dir: fw_dir.clone(),
+            explain: Some(report.clone()),
+            ..Default::default()
+        };
+        let deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+        assert_eq!(deleted, vec![fw_dir.join("real/a.bin")]);
+
+        options.follow_external_symlinks = true;
+        let deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+        assert!(deleted.is_empty());
+        let decisions = crate::explain::read_explanation(&report).unwrap();
+        let vendor = decisions.iter().find(|d| d.path == vendor_dir.join("a.bin")).unwrap();
+        assert_eq!(vendor.action, Action::Keep);
+    }
+
+    #[test]
+    fn test_cleanup_firmware_follow_absolute_symlinks_below_root() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let kernel_dir = root.join("lib/modules/6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        fs::write(kernel_dir.join("mod.ko"), modinfo::build_test_module(&["firmware=a.bin"])).unwrap();
+        let fw_dir = root.join("lib/firmware");
+        let vendor_dir = root.join("vendor");
+        fs::create_dir_all(fw_dir.join("real")).unwrap();
+        fs::create_dir_all(&vendor_dir).unwrap();
+        // a.bin -> /vendor/a.bin -> /lib/firmware/real/a.bin, absolute paths of the image which
+        // do not exist on the host.
+        fs::write(fw_dir.join("real/a.bin"), "").unwrap();
+        symlink("/lib/firmware/real/a.bin", vendor_dir.join("a.bin")).unwrap();
+        symlink("/vendor/a.bin", fw_dir.join("a.bin")).unwrap();
+        let report = root.join("explain.jsonl");
+
+        let options = FirmwareOptions {
+            module_dir: root.join("lib/modules"),
+            firmware_dir: fw_dir.clone(),
+            follow_external_symlinks: true,
+            root: Some(root.to_path_buf()),
+            explain: Some(report.clone()),
+            ..Default::default()
+        };
+        let deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+        assert!(deleted.is_empty());
+        let decisions = crate::explain::read_explanation(&report).unwrap();
+        let vendor = decisions.iter().find(|d| d.path == vendor_dir.join("a.bin")).unwrap();
+        assert_eq!(vendor.action, Action::Keep);
+    }
+
+    #[test]
+    fn test_cleanup_firmware_min_size() {
+        let temp_dir = tempdir().unwrap();
+        fs::create_dir_all(temp_dir.path().join("lib/modules/6.1.0-test")).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(&fw_dir).unwrap();
+        fs::write(fw_dir.join("blob.bin"), vec![0u8; 4096]).unwrap();
+        fs::write(fw_dir.join("nvram.txt"), "boardtype=0x0\n").unwrap();
+
+        let options = FirmwareOptions {
+            module_dir: temp_dir.path().join("lib/modules"),
+            firmware_dir: fw_dir.clone(),
+            delete: true,
+            min_size: 1024,
+            ..Default::default()
+        };
+        let deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+        assert_eq!(deleted, vec![fw_dir.join("blob.bin")]);
+        assert!(fw_dir.join("nvram.txt").exists());
+    }
+
+    #[test]
+    fn test_cleanup_firmware_emit_required() {
+        let temp_dir = tempdir().unwrap();
+        fs::create_dir_all(temp_dir.path().join("lib/modules/6.1.0-test")).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(fw_dir.join("brcm")).unwrap();
+        fs::write(fw_dir.join("blob.bin"), vec![0u8; 4096]).unwrap();
+        fs::write(fw_dir.join("brcm/nvram.txt"), "boardtype=0x0\n").unwrap();
+        std::os::unix::fs::symlink("brcm/nvram.txt", fw_dir.join("nvram.txt")).unwrap();
+        std::os::unix::fs::symlink("blob.bin", fw_dir.join("blob-link.bin")).unwrap();
+        let list = temp_dir.path().join("required.txt");
+
+        let options = FirmwareOptions {
+            module_dir: temp_dir.path().join("lib/modules"),
+            firmware_dir: fw_dir.clone(),
+            min_size: 1024,
+            emit_required: Some(list.clone()),
+            ..Default::default()
+        };
+        cleanup_firmware(&options, &KernelGraph::new()).unwrap();
+        assert_eq!(fs::read_to_string(&list).unwrap(), "brcm/nvram.txt\nnvram.txt\n");
+        assert!(fw_dir.join("blob.bin").exists());
+
+        let options = FirmwareOptions {
+            emit_format: ListFormat::RpmFiles,
+            install_dir: Some(PathBuf::from("/usr/lib/firmware")),
+            ..options
+        };
+        cleanup_firmware(&options, &KernelGraph::new()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&list).unwrap(),
+            "%dir /usr/lib/firmware/brcm\n/usr/lib/firmware/brcm/nvram.txt\n/usr/lib/firmware/nvram.txt\n"
+        );
+    }
+
+    #[test]
+    fn test_cleanup_firmware_target_size() {
+        let temp_dir = tempdir().unwrap();
+        fs::create_dir_all(temp_dir.path().join("lib/modules/6.1.0-test")).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(&fw_dir).unwrap();
+        for (name, size) in [("big.bin", 8192), ("medium.bin", 4096), ("small.bin", 2048)] {
+            fs::write(fw_dir.join(name), vec![0u8; size]).unwrap();
+        }
+        let explain = temp_dir.path().join("explain.jsonl");
+
+        let options = FirmwareOptions {
+            module_dir: temp_dir.path().join("lib/modules"),
+            firmware_dir: fw_dir.clone(),
+            delete: true,
+            target_size: Some(7000),
+            explain: Some(explain.clone()),
+            ..Default::default()
+        };
+        let deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+        assert_eq!(deleted, vec![fw_dir.join("big.bin")]);
+        assert!(fw_dir.join("medium.bin").exists());
+        assert!(fw_dir.join("small.bin").exists());
+        let report = fs::read_to_string(&explain).unwrap();
+        assert!(report.contains("kept within the target size of 7000 bytes"));
+    }
+
+    #[test]
+    fn test_cleanup_firmware_target_size_priorities() {
+        let temp_dir = tempdir().unwrap();
+        fs::create_dir_all(temp_dir.path().join("lib/modules/6.1.0-test")).unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(fw_dir.join("vendor")).unwrap();
+        for (name, size) in [("big.bin", 8192), ("vendor/a.bin", 2048), ("vendor/b.bin", 4096), ("c.bin", 1024)] {
+            fs::write(fw_dir.join(name), vec![0u8; size]).unwrap();
+        }
+
+        // The dropped files of the rules with a priority go first, only as needed; the others
+        // always go.
+        let options = FirmwareOptions {
+            module_dir: temp_dir.path().join("lib/modules"),
+            firmware_dir: fw_dir.clone(),
+            delete: true,
+            target_size: Some(9000),
+            rules: Some(Rules::parse("delete@5 vendor/a.bin\ndelete@1 vendor/b.bin\n-c.bin\n", "x86_64").unwrap()),
+            ..Default::default()
+        };
+        let deleted = cleanup_firmware(&options, &KernelGraph::new()).unwrap().deleted;
+        assert_eq!(deleted, vec![fw_dir.join("c.bin"), fw_dir.join("vendor/a.bin"), fw_dir.join("vendor/b.bin")]);
+        assert!(fw_dir.join("big.bin").exists());
+    }
+
+    #[test]
+    fn test_cleanup_in_memory() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file("/fw/unused.bin", 10)
+            .add_file("/fw/vendor/blob.bin", 5)
+            .add_symlink("/fw/link.bin", "vendor/blob.bin")
+            .add_symlink("/fw/dangling.bin", "missing.bin")
+            .add_symlink("/fw/overlaid.bin", "extra/overlay.bin")
+            .add_file("/overlay/extra/overlay.bin", 1)
+            .add_dir("/fw/empty/sub");
+        let fw_dir = Path::new("/fw");
+        let overlays = [PathBuf::from("/overlay")];
+
+        let resolved = resolve_symlinks(fs.as_ref(), &fw_dir.join("link.bin"), fw_dir, Path::new("/"), &overlays, false).unwrap();
+        assert_eq!(resolved, vec![fw_dir.join("link.bin"), fw_dir.join("vendor/blob.bin")]);
+        let required: HashMap<PathBuf, String> = resolved
+            .iter()
+            .map(|p| (p.strip_prefix(fw_dir).unwrap().to_path_buf(), "required".to_string()))
+            .collect();
+
+        let mut deleter = Deleter::new(true).with_fs(fs.clone());
+        let (_, unused_size, groups) =
+            remove_unused_files(fs.as_ref(), fw_dir, &required, &HashMap::new(), 0, &mut deleter, None).unwrap();
+        assert_eq!(unused_size, 10);
+        // The symlinks are counted with the size of their target, the dangling ones are not.
+        assert_eq!(
+            groups,
+            BTreeMap::from([
+                (
+                    TOP_LEVEL_GROUP.to_string(),
+                    GroupSummary {
+                        kept: 1,
+                        kept_bytes: 5,
+                        deleted: 1,
+                        deleted_bytes: 10
+                    }
+                ),
+                (
+                    "vendor".to_string(),
+                    GroupSummary {
+                        kept: 1,
+                        kept_bytes: 5,
+                        ..Default::default()
+                    }
+                ),
+            ])
+        );
+        remove_dangling_symlinks(fs.as_ref(), fw_dir, &overlays, &mut deleter).unwrap();
+        remove_empty_directories(fs.as_ref(), fw_dir, &mut deleter).unwrap();
+
+        let left: Vec<PathBuf> = fs.walk(fw_dir).into_iter().map(|e| e.path).collect();
+        assert_eq!(
+            left,
+            vec![
+                fw_dir.to_path_buf(),
+                fw_dir.join("link.bin"),
+                fw_dir.join("overlaid.bin"),
+                fw_dir.join("vendor"),
+                fw_dir.join("vendor/blob.bin"),
+            ]
+        );
+    }
 }
@@ -0,0 +1,301 @@
+//! Savings forecast over every cleanup subsystem, without modifying the image.
+//!
+//! Drivers and firmware are forecast by dry runs of their cleanups. Locales, documentation,
+//! caches and old kernels are measured: they are what a product would typically drop next, and
+//! knowing their weight helps deciding which cleanups to enable.
+
+use crate::command::CommandRunner;
+use crate::driver::{self, DriverOptions};
+use crate::error::JanitorError;
+use crate::firmware::{self, FirmwareOptions};
+use crate::kernel_graph::KernelGraph;
+use crate::util::{self, KernelSelection, ScanOptions};
+use log::warn;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Locale directories kept when forecasting the locale cleanup.
+const KEPT_LOCALES: &[&str] = &["C", "en", "en_US"];
+
+/// Documentation directories, relative to the image root.
+const DOC_DIRS: &[&str] = &[
+    "usr/share/doc",
+    "usr/share/man",
+    "usr/share/info",
+    "usr/share/gtk-doc",
+];
+
+/// Cache directories, relative to the image root.
+const CACHE_DIRS: &[&str] = &["var/cache"];
+
+/// Lists the files a subsystem would remove.
+type Analyzer<'a> = Box<dyn Fn() -> Result<Vec<PathBuf>, JanitorError> + 'a>;
+
+/// Options of a forecast.
+#[derive(Debug, Clone, Default)]
+pub struct ForecastOptions {
+    /// Root of the image.
+    pub root: PathBuf,
+    /// Module list configuration files, for the driver cleanup.
+    pub config_paths: Vec<String>,
+    pub scan: ScanOptions,
+}
+
+/// Potential savings of one subsystem.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Forecast {
+    pub subsystem: &'static str,
+    pub files: usize,
+    pub bytes: u64,
+    /// Why the subsystem could not be forecast, if it failed.
+    pub error: Option<String>,
+}
+
+impl Forecast {
+    fn from_paths(subsystem: &'static str, paths: Vec<PathBuf>) -> Result<Self, JanitorError> {
+        let mut forecast = Forecast {
+            subsystem,
+            ..Default::default()
+        };
+        for path in paths {
+            let metadata = fs::symlink_metadata(&path)?;
+            if !metadata.is_dir() {
+                forecast.files += 1;
+                forecast.bytes += metadata.len();
+            }
+        }
+        Ok(forecast)
+    }
+}
+
+/// Forecasts the savings of every subsystem on the image at `options.root`. A subsystem which
+/// fails is reported with its error, the others are still forecast.
+pub fn forecast(options: &ForecastOptions, runner: &dyn CommandRunner) -> Vec<Forecast> {
+    let root = &options.root;
+    let module_dir = util::find_in_root(root, util::MODULE_DIRS);
+    let firmware_dir = util::find_in_root(root, util::FIRMWARE_DIRS);
+    // The driver and firmware forecasts scan the same module trees.
+    let graph = KernelGraph::new();
+
+    let subsystems: Vec<(&'static str, Analyzer)> = vec![
+        (
+            "drivers",
+            Box::new(|| {
+                driver::cleanup_drivers(
+                    &DriverOptions {
+                        config_paths: options.config_paths.clone(),
+                        module_dir: module_dir.clone(),
+                        root: root.clone(),
+                        scan: options.scan.clone(),
+                        ..Default::default()
+                    },
+                    &graph,
+                    runner,
+                )
+                .map(|summary| summary.deleted)
+            }),
+        ),
+        (
+            "firmware",
+            Box::new(|| {
+                firmware::cleanup_firmware(
+                    &FirmwareOptions {
+                        module_dir: module_dir.clone(),
+                        firmware_dir: firmware_dir.clone(),
+                        root: Some(root.clone()),
+                        scan: options.scan.clone(),
+                        ..Default::default()
+                    },
+                    &graph,
+                )
+                .map(|summary| summary.deleted)
+            }),
+        ),
+        ("locales", Box::new(|| unused_locales(root))),
+        ("docs", Box::new(|| files_below(root, DOC_DIRS))),
+        ("caches", Box::new(|| files_below(root, CACHE_DIRS))),
+        ("old-kernels", Box::new(|| old_kernels(root, &module_dir))),
+    ];
+
+    subsystems
+        .into_iter()
+        .map(|(subsystem, analyze)| {
+            match analyze().and_then(|paths| Forecast::from_paths(subsystem, paths)) {
+                Ok(forecast) => forecast,
+                Err(e) => {
+                    warn!("Forecasting {} failed: {}", subsystem, e);
+                    Forecast {
+                        subsystem,
+                        error: Some(e.to_string()),
+                        ..Default::default()
+                    }
+                }
+            }
+        })
+        .collect()
+}
+
+/// Returns the files below the `dirs` of `root`, those missing being skipped.
+fn files_below(root: &Path, dirs: &[&str]) -> Result<Vec<PathBuf>, JanitorError> {
+    let mut files = Vec::new();
+    for dir in dirs.iter().map(|d| root.join(d)).filter(|d| d.is_dir()) {
+        for entry in WalkDir::new(dir) {
+            let entry = entry?;
+            if !entry.file_type().is_dir() {
+                files.push(entry.into_path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Returns the translations of the locales other than [`KEPT_LOCALES`].
+fn unused_locales(root: &Path) -> Result<Vec<PathBuf>, JanitorError> {
+    let locale_dir = root.join("usr/share/locale");
+    if !locale_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&locale_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let language = name.split(['.', '@']).next().unwrap_or_default();
+        if entry.file_type()?.is_dir() && !KEPT_LOCALES.contains(&language) {
+            let relative = entry.path().strip_prefix(root).unwrap().to_path_buf();
+            files.extend(files_below(root, &[&relative.to_string_lossy()])?);
+        }
+    }
+    Ok(files)
+}
+
+/// Returns the module trees and boot files of the kernels other than the latest one.
+fn old_kernels(root: &Path, module_dir: &Path) -> Result<Vec<PathBuf>, JanitorError> {
+    if !module_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let latest = util::find_kernel_dir(module_dir)?;
+    let mut files = Vec::new();
+    for kernel_dir in util::find_kernel_dirs(module_dir, &KernelSelection::All)? {
+        if kernel_dir == latest {
+            continue;
+        }
+        let version = kernel_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        for entry in WalkDir::new(&kernel_dir) {
+            let entry = entry?;
+            if !entry.file_type().is_dir() {
+                files.push(entry.into_path());
+            }
+        }
+        let boot_dir = root.join("boot");
+        if boot_dir.is_dir() {
+            for entry in fs::read_dir(&boot_dir)? {
+                let path = entry?.path();
+                let is_for_version = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.ends_with(&format!("-{}", version)));
+                if is_for_version && !path.is_dir() {
+                    files.push(path);
+                }
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modinfo;
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+
+    struct MockCommandRunner {
+        responses: HashMap<String, String>,
+    }
+
+    impl CommandRunner for MockCommandRunner {
+        fn run(&self, command: &str, _args: &[&str]) -> Result<String, JanitorError> {
+            self.responses
+                .get(command)
+                .cloned()
+                .ok_or_else(|| JanitorError::Command(format!("Not mocked: {}", command)))
+        }
+    }
+
+    fn write(path: &Path, size: usize) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, vec![0u8; size]).unwrap();
+    }
+
+    #[test]
+    fn test_forecast() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let module = modinfo::build_test_module(&[]);
+        for version in ["6.1.0-1", "6.1.0-2"] {
+            let kernel_dir = root.join("usr/lib/modules").join(version);
+            fs::create_dir_all(&kernel_dir).unwrap();
+            fs::write(kernel_dir.join("keep.ko"), &module).unwrap();
+            fs::write(kernel_dir.join("drop.ko"), &module).unwrap();
+            write(&root.join("boot").join(format!("vmlinuz-{}", version)), 100);
+        }
+        write(&root.join("usr/lib/firmware/unused.bin"), 10);
+        write(&root.join("usr/share/locale/de/LC_MESSAGES/app.mo"), 20);
+        write(&root.join("usr/share/locale/en_US/LC_MESSAGES/app.mo"), 20);
+        write(&root.join("usr/share/doc/app/README"), 30);
+        write(&root.join("usr/share/man/man1/app.1.gz"), 5);
+        write(&root.join("var/cache/zypp/solv"), 40);
+
+        let config_path = root.join("module.list");
+        fs::write(&config_path, "keep.ko").unwrap();
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+
+        let options = ForecastOptions {
+            root: root.to_path_buf(),
+            config_paths: vec![config_path.to_str().unwrap().to_string()],
+            ..Default::default()
+        };
+        let forecasts = forecast(&options, &runner);
+        let summary: Vec<_> = forecasts
+            .iter()
+            .map(|f| (f.subsystem, f.files, f.bytes, f.error.is_none()))
+            .collect();
+        let module_size = module.len() as u64;
+        assert_eq!(
+            summary,
+            vec![
+                ("drivers", 1, module_size, true),
+                ("firmware", 1, 10, true),
+                ("locales", 1, 20, true),
+                ("docs", 2, 35, true),
+                ("caches", 1, 40, true),
+                ("old-kernels", 3, 2 * module_size + 100, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forecast_reports_failures() {
+        let temp_dir = tempdir().unwrap();
+        let runner = MockCommandRunner {
+            responses: HashMap::new(),
+        };
+        let options = ForecastOptions {
+            root: temp_dir.path().to_path_buf(),
+            config_paths: vec!["missing.list".to_string()],
+            ..Default::default()
+        };
+        let forecasts = forecast(&options, &runner);
+        assert!(forecasts[0].error.is_some());
+        assert_eq!(forecasts[2].subsystem, "locales");
+        assert_eq!(forecasts[2].error, None);
+    }
+}
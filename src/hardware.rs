@@ -0,0 +1,387 @@
+//! PCI and USB hardware IDs of a fixed bill of materials.
+//!
+//! A vendor:device ID does not give the full modalias of a device: the subsystem IDs, class and
+//! interfaces are unknown. A module alias is matched on the vendor and device fields only, so
+//! every driver which can handle a device with these IDs is kept, including the ones matching a
+//! whole device class, such as `ahci` or `xhci_pci`. The devices listed by `lspci -nn` come
+//! with their class, which narrows the PCI class drivers down.
+
+use crate::command::CommandRunner;
+use crate::depmod;
+use crate::error::JanitorError;
+use crate::policy::Alias;
+use crate::util::{self, ScanOptions};
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use regex::Regex;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+lazy_static! {
+    /// Vendor, device, base class and subclass fields of a PCI alias, e.g.
+    /// `pci:v00008086d000010D3sv*sd*bc*sc*i*`.
+    static ref PCI_ALIAS: Regex = Regex::new(
+        r"^pci:v([0-9A-F*?\[\]!-]+)d([0-9A-F*?\[\]!-]+)(?:sv[^s]*sd[^b]*bc([0-9A-F*?\[\]!-]+)sc([0-9A-F*?\[\]!-]+)i|$)"
+    )
+    .unwrap();
+    /// Vendor and product fields of a USB alias, e.g. `usb:v046DpC52Bd*dc*dsc*dp*ic*isc*ip*in*`.
+    static ref USB_ALIAS: Regex =
+        Regex::new(r"^usb:v([0-9A-F*?\[\]!-]+)p([0-9A-F*?\[\]!-]+)(?:d|$)").unwrap();
+}
+
+/// Bus a hardware ID belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Bus {
+    Pci,
+    Usb,
+}
+
+/// Vendor and device ID of a PCI or USB device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HardwareId {
+    /// Bus of the device, any bus if unset.
+    pub bus: Option<Bus>,
+    pub vendor: u16,
+    pub device: u16,
+    /// Base class and subclass of a PCI device, any class if unset.
+    pub class: Option<u16>,
+}
+
+impl HardwareId {
+    /// Parses an ID written `VVVV:DDDD` in hexadecimal, as printed by `lspci -nn` and `lsusb`,
+    /// optionally prefixed with its bus, e.g. `pci 8086:10d3` or `usb 046d:c52b`.
+    pub fn parse(line: &str) -> Option<Self> {
+        let (bus, id) = match line.split_once(char::is_whitespace) {
+            Some(("pci", id)) => (Some(Bus::Pci), id.trim()),
+            Some(("usb", id)) => (Some(Bus::Usb), id.trim()),
+            Some(_) => return None,
+            None => (None, line),
+        };
+        let (vendor, device) = id.split_once(':')?;
+        let hex = |s: &str| match s.len() {
+            4 => u16::from_str_radix(s, 16).ok(),
+            _ => None,
+        };
+        Some(HardwareId {
+            bus,
+            vendor: hex(vendor)?,
+            device: hex(device)?,
+            class: None,
+        })
+    }
+
+    /// Returns whether a device with this ID can match the modalias `pattern` of a module.
+    pub fn matches(&self, pattern: &str) -> bool {
+        let (regex, bus, width) = match pattern.split_once(':') {
+            Some(("pci", _)) => (&*PCI_ALIAS, Bus::Pci, 8),
+            Some(("usb", _)) => (&*USB_ALIAS, Bus::Usb, 4),
+            _ => return false,
+        };
+        if self.bus.is_some_and(|b| b != bus) {
+            return false;
+        }
+        let Some(fields) = regex.captures(pattern) else {
+            debug!(
+                "Ignoring alias {} without vendor and device fields",
+                pattern
+            );
+            return false;
+        };
+        let field = |index: usize, value: u16, width: usize| {
+            fields.get(index).is_none_or(|field| {
+                glob::Pattern::new(field.as_str())
+                    .is_ok_and(|p| p.matches(&format!("{:0width$X}", value, width = width)))
+            })
+        };
+        let class = self
+            .class
+            .is_none_or(|class| field(3, class >> 8, 2) && field(4, class & 0xff, 2));
+        field(1, self.vendor, width) && field(2, self.device, width) && class
+    }
+}
+
+impl fmt::Display for HardwareId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bus {
+            Some(Bus::Pci) => write!(f, "pci ")?,
+            Some(Bus::Usb) => write!(f, "usb ")?,
+            None => {}
+        }
+        write!(f, "{:04x}:{:04x}", self.vendor, self.device)
+    }
+}
+
+/// A device of the machine, as listed by `lspci -nn` or `lsusb`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub id: HardwareId,
+    /// The line describing the device in the listing.
+    pub description: String,
+}
+
+/// Parses the output of `lspci -nn`, e.g.
+/// `00:1f.6 Ethernet controller [0200]: Intel Corporation I219-LM [8086:15bb] (rev 10)`.
+pub fn parse_lspci(output: &str) -> Vec<Device> {
+    let class_re = Regex::new(r"^\S+ [^\[]*\[([0-9a-f]{4})\]:").unwrap();
+    let id_re = Regex::new(r"\[([0-9a-f]{4}):([0-9a-f]{4})\]").unwrap();
+    output
+        .lines()
+        .filter_map(|line| {
+            let id = id_re.captures_iter(line).last()?;
+            let hex = |s: &str| u16::from_str_radix(s, 16).ok();
+            Some(Device {
+                id: HardwareId {
+                    bus: Some(Bus::Pci),
+                    vendor: hex(&id[1])?,
+                    device: hex(&id[2])?,
+                    class: class_re.captures(line).and_then(|c| hex(&c[1])),
+                },
+                description: line.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Parses the output of `lsusb`, e.g. `Bus 001 Device 002: ID 046d:c52b Logitech, Inc.`.
+pub fn parse_lsusb(output: &str) -> Vec<Device> {
+    let id_re = Regex::new(r" ID ([0-9a-f]{4}):([0-9a-f]{4})").unwrap();
+    output
+        .lines()
+        .filter_map(|line| {
+            let id = id_re.captures(line)?;
+            let hex = |s: &str| u16::from_str_radix(s, 16).ok();
+            Some(Device {
+                id: HardwareId {
+                    bus: Some(Bus::Usb),
+                    vendor: hex(&id[1])?,
+                    device: hex(&id[2])?,
+                    class: None,
+                },
+                description: line.trim().to_string(),
+            })
+        })
+        // The root hubs are handled by the host controller drivers.
+        .filter(|device| device.id.vendor != 0x1d6b)
+        .collect()
+}
+
+/// Returns the PCI and USB devices of the machine the tool runs on. A listing command failing,
+/// e.g. lsusb not being installed, is only a warning if the other one succeeds.
+pub fn list_devices(runner: &dyn CommandRunner) -> Result<Vec<Device>, JanitorError> {
+    let mut devices = Vec::new();
+    let mut failure = None;
+    for (command, args) in [("lspci", &["-nn"][..]), ("lsusb", &[])] {
+        match runner.run(command, args) {
+            Ok(output) if command == "lspci" => devices.extend(parse_lspci(&output)),
+            Ok(output) => devices.extend(parse_lsusb(&output)),
+            Err(e) => {
+                warn!("Cannot list the devices with {}: {}", command, e);
+                failure = Some(e);
+            }
+        }
+    }
+    match failure {
+        Some(e) if devices.is_empty() => Err(e),
+        _ => Ok(devices),
+    }
+}
+
+/// Returns a module list configuration keeping the drivers of `devices` in the kernels of
+/// `kernel_dirs`: for each device, a comment with its description, then a keep rule for each of
+/// the modules having an alias it can match which no previous device needed. The rules leave
+/// the compression suffix of the modules out, and their dependencies are kept by the cleanup.
+pub fn keep_config(
+    devices: &[Device],
+    kernel_dirs: &[PathBuf],
+    scan: &ScanOptions,
+) -> Result<String, JanitorError> {
+    let mut rules: Vec<BTreeSet<String>> = vec![BTreeSet::new(); devices.len()];
+    for kernel_dir in kernel_dirs {
+        let aliases = depmod::read_aliases(kernel_dir)?;
+        let mut paths: HashMap<String, Vec<String>> = HashMap::new();
+        for path in util::find_kernel_modules(kernel_dir, scan)? {
+            let relative = path.strip_prefix(kernel_dir).unwrap().to_string_lossy();
+            let relative = relative
+                .rfind(".ko")
+                .map_or(&*relative, |end| &relative[..end + 3]);
+            paths
+                .entry(util::module_name(&path))
+                .or_default()
+                // Dashes only need escaping in brackets, rules read better without.
+                .push(regex::escape(relative).replace("\\-", "-"));
+        }
+        for (device, rules) in devices.iter().zip(&mut rules) {
+            for name in match_hardware_ids(&aliases, &[device.id]) {
+                rules.extend(paths.get(&name).into_iter().flatten().cloned());
+            }
+        }
+    }
+
+    let mut config = String::from(
+        "# Drivers of the PCI and USB devices of the machine, written by image-janitor.\n",
+    );
+    let mut written = BTreeSet::new();
+    for (device, rules) in devices.iter().zip(rules) {
+        config.push_str(&format!("\n# {}\n", device.description));
+        if rules.is_empty() {
+            config.push_str("# (no driver)\n");
+        }
+        for rule in rules {
+            if written.insert(rule.clone()) {
+                config.push_str(&rule);
+                config.push('\n');
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// Reads a file listing hardware IDs, one per line, see [`HardwareId::parse`].
+pub fn read_hardware_ids(path: &Path) -> Result<Vec<HardwareId>, JanitorError> {
+    util::read_list_file(path)?
+        .iter()
+        .map(|line| {
+            HardwareId::parse(line).ok_or_else(|| {
+                JanitorError::InvalidConfig(format!(
+                    "{}: invalid hardware ID '{}', expected [pci|usb] VVVV:DDDD",
+                    path.display(),
+                    line
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Returns the names of the modules having an alias a device of `ids` can match.
+pub fn match_hardware_ids(aliases: &[Alias], ids: &[HardwareId]) -> BTreeSet<String> {
+    aliases
+        .iter()
+        .filter(|alias| ids.iter().any(|id| id.matches(&alias.pattern)))
+        .map(|alias| alias.module.replace('-', "_"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::{RuleMatch, Rules};
+    use std::fs;
+    use tempfile::tempdir;
+
+    /// Lists a machine with a NIC, a SATA controller, a USB receiver and no lsusb root hub driver.
+    struct MockCommandRunner;
+
+    impl CommandRunner for MockCommandRunner {
+        fn run(&self, command: &str, args: &[&str]) -> Result<String, JanitorError> {
+            match (command, args) {
+                ("lspci", ["-nn"]) => Ok(
+                    "00:17.0 SATA controller [0106]: Intel Corporation Q170/Q150/B150/H170/H110/Z170/CM236 Chipset SATA Controller [AHCI Mode] [8086:a102] (rev 31)\n\
+                     00:1f.6 Ethernet controller [0200]: Intel Corporation Ethernet Connection I219-LM [8086:15b7] (rev 31)\n\
+                     00:1f.3 Audio device [0403]: Intel Corporation Device [8086:a170] (rev 31)\n"
+                        .to_string(),
+                ),
+                ("lsusb", []) => Ok(
+                    "Bus 001 Device 002: ID 046d:c52b Logitech, Inc. Unifying Receiver\n\
+                     Bus 001 Device 001: ID 1d6b:0002 Linux Foundation 2.0 root hub\n"
+                        .to_string(),
+                ),
+                _ => Err(JanitorError::Command(format!(
+                    "Not mocked: {} {:?}",
+                    command, args
+                ))),
+            }
+        }
+    }
+
+    fn alias(pattern: &str, module: &str) -> Alias {
+        Alias {
+            pattern: pattern.to_string(),
+            module: module.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_match_hardware_ids() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("ids.txt");
+        fs::write(&path, "# NIC\npci 8086:10d3\n046d:c52b\n").unwrap();
+        let ids = read_hardware_ids(&path).unwrap();
+        assert_eq!(ids[0].to_string(), "pci 8086:10d3");
+        assert_eq!(ids[1].to_string(), "046d:c52b");
+
+        let aliases = vec![
+            alias("pci:v00008086d000010D3sv*sd*bc*sc*i*", "e1000e"),
+            alias("pci:v00008086d000010D4sv*sd*bc*sc*i*", "e1000"),
+            alias("pci:v*d*sv*sd*bc01sc06i01*", "ahci"),
+            alias("pci:v000010ECd0000816[0-9A-F]sv*sd*bc*sc*i*", "r8169"),
+            alias("usb:v046DpC52Bd*dc*dsc*dp*ic*isc*ip*in*", "hid-logitech-dj"),
+            alias("usb:v8086p*d*dc*dsc*dp*ic*isc*ip*in*", "intel-usb"),
+            alias("acpi*:PNP0C0A:*", "battery"),
+        ];
+        assert_eq!(
+            match_hardware_ids(&aliases, &ids),
+            BTreeSet::from(["ahci", "e1000e", "hid_logitech_dj"].map(String::from))
+        );
+        // The bus-less ID matches PCI devices too.
+        let realtek = HardwareId::parse("10ec:8168").unwrap();
+        assert!(realtek.matches(&aliases[3].pattern));
+        assert!(!HardwareId::parse("usb 10ec:8168")
+            .unwrap()
+            .matches("pci:v000010ECd*"));
+
+        fs::write(&path, "pci 8086:10d3x\n").unwrap();
+        assert!(matches!(
+            read_hardware_ids(&path),
+            Err(JanitorError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_keep_config() {
+        let devices = list_devices(&MockCommandRunner).unwrap();
+        let ids: Vec<String> = devices.iter().map(|d| d.id.to_string()).collect();
+        assert_eq!(ids, vec!["pci 8086:a102", "pci 8086:15b7", "pci 8086:a170", "usb 046d:c52b"]);
+        assert_eq!(devices[0].id.class, Some(0x0106));
+
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path().join("6.4.0-default");
+        let modules = [
+            "kernel/drivers/ata/ahci.ko.zst",
+            "kernel/drivers/nvme/host/nvme.ko.zst",
+            "kernel/drivers/net/ethernet/intel/e1000e/e1000e.ko.zst",
+            "kernel/drivers/hid/hid-logitech-dj.ko.zst",
+        ];
+        for module in modules {
+            fs::create_dir_all(kernel_dir.join(module).parent().unwrap()).unwrap();
+            fs::write(kernel_dir.join(module), "").unwrap();
+        }
+        fs::write(
+            kernel_dir.join("modules.alias"),
+            "alias pci:v*d*sv*sd*bc01sc06i01* ahci\n\
+             alias pci:v*d*sv*sd*bc01sc08i02* nvme\n\
+             alias pci:v00008086d000015B7sv*sd*bc*sc*i* e1000e\n\
+             alias usb:v046DpC52Bd*dc*dsc*dp*ic*isc*ip*in* hid_logitech_dj\n",
+        )
+        .unwrap();
+
+        let config = keep_config(&devices, &[kernel_dir], &ScanOptions::default()).unwrap();
+        assert_eq!(
+            config,
+            "# Drivers of the PCI and USB devices of the machine, written by image-janitor.\n\
+             \n# 00:17.0 SATA controller [0106]: Intel Corporation Q170/Q150/B150/H170/H110/Z170/CM236 Chipset SATA Controller [AHCI Mode] [8086:a102] (rev 31)\n\
+             kernel/drivers/ata/ahci\\.ko\n\
+             \n# 00:1f.6 Ethernet controller [0200]: Intel Corporation Ethernet Connection I219-LM [8086:15b7] (rev 31)\n\
+             kernel/drivers/net/ethernet/intel/e1000e/e1000e\\.ko\n\
+             \n# 00:1f.3 Audio device [0403]: Intel Corporation Device [8086:a170] (rev 31)\n\
+             # (no driver)\n\
+             \n# Bus 001 Device 002: ID 046d:c52b Logitech, Inc. Unifying Receiver\n\
+             kernel/drivers/hid/hid-logitech-dj\\.ko\n"
+        );
+        // The generated configuration keeps exactly these modules.
+        let rules = Rules::parse(&config, "x86_64").unwrap();
+        assert_eq!(rules.matches("kernel/drivers/ata/ahci.ko.zst"), RuleMatch::Keep);
+        assert_eq!(rules.matches("kernel/drivers/hid/hid-logitech-dj.ko"), RuleMatch::Keep);
+        assert_eq!(rules.matches("kernel/drivers/nvme/host/nvme.ko.zst"), RuleMatch::Unmatched);
+    }
+}
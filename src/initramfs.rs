@@ -0,0 +1,198 @@
+//! Consistency of the initramfs images with the cleaned module trees.
+//!
+//! The initramfs of a kernel carries its own copy of the modules needed to mount the root
+//! filesystem. Deleting one of them from the module tree leaves the initramfs unchanged, so the
+//! image keeps booting until the initramfs is regenerated, e.g. by the next kernel update, and
+//! the storage driver silently disappears from it. The initramfs images of each cleaned kernel
+//! are listed to find the deleted modules they still contain.
+
+use crate::command::CommandRunner;
+use crate::distro::Distro;
+use crate::error::JanitorError;
+use crate::util;
+use log::warn;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// An initramfs image containing deleted modules.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StaleInitramfs {
+    /// Version of the kernel the image belongs to.
+    pub version: String,
+    pub image: PathBuf,
+    /// The deleted modules found in the image.
+    pub modules: Vec<PathBuf>,
+}
+
+/// Returns the initramfs images of the kernel `version` installed in the image at `root`.
+pub fn find_images(root: &Path, version: &str) -> Vec<PathBuf> {
+    let mut candidates = vec![
+        // SUSE, Fedora and Debian, then the kernel-install layout.
+        format!("boot/initrd-{}", version),
+        format!("boot/initramfs-{}.img", version),
+        format!("boot/initrd.img-{}", version),
+        format!("usr/lib/modules/{}/initrd", version),
+        format!("lib/modules/{}/initrd", version),
+    ];
+    if Distro::detect(root) == Distro::Alpine {
+        // Alpine names the initramfs after the kernel flavor, e.g. initramfs-lts for 6.6.31-0-lts.
+        if let Some((_, flavor)) = version.rsplit_once('-') {
+            candidates.push(format!("boot/initramfs-{}", flavor));
+        }
+    }
+    // On usrmerged images, lib/modules and usr/lib/modules are the same directory.
+    let mut seen = BTreeSet::new();
+    candidates
+        .iter()
+        .map(|c| root.join(c))
+        .filter(|p| p.is_file())
+        .filter(|p| seen.insert(fs::canonicalize(p).unwrap_or_else(|_| p.clone())))
+        .collect()
+}
+
+/// Returns the names of the kernel modules contained in the initramfs `image`, as listed by
+/// `lsinitramfs` on the Debian family and `lsinitrd` elsewhere.
+pub fn image_modules(
+    image: &Path,
+    distro: Distro,
+    runner: &dyn CommandRunner,
+) -> Result<BTreeSet<String>, JanitorError> {
+    let lister = match distro {
+        Distro::Debian | Distro::Ubuntu => "lsinitramfs",
+        _ => "lsinitrd",
+    };
+    let image = image
+        .to_str()
+        .ok_or_else(|| JanitorError::InvalidPath(image.to_path_buf()))?;
+    let listing = runner.run(lister, &[image])?;
+    Ok(listing
+        .lines()
+        // lsinitrd lists the entries like ls -l, with symlinks as "name -> target".
+        .filter_map(|line| line.split(" -> ").next()?.split_whitespace().last())
+        .map(Path::new)
+        .filter(|path| util::is_kernel_module(path))
+        .map(util::module_name)
+        .collect())
+}
+
+/// Returns the initramfs images of the image at `root` containing modules of `deleted`, the
+/// files deleted below the kernel directories of `module_dir`. Images which cannot be listed
+/// are skipped with a warning.
+pub fn check_initramfs(
+    root: &Path,
+    module_dir: &Path,
+    deleted: &[PathBuf],
+    runner: &dyn CommandRunner,
+) -> Result<Vec<StaleInitramfs>, JanitorError> {
+    let mut by_version: BTreeMap<String, Vec<&PathBuf>> = BTreeMap::new();
+    for path in deleted.iter().filter(|p| util::is_kernel_module(p)) {
+        let version = path
+            .strip_prefix(module_dir)
+            .ok()
+            .and_then(|relative| relative.components().next());
+        if let Some(Component::Normal(version)) = version {
+            by_version
+                .entry(version.to_string_lossy().into_owned())
+                .or_default()
+                .push(path);
+        }
+    }
+
+    let distro = Distro::detect(root);
+    let mut stale = Vec::new();
+    for (version, modules) in by_version {
+        for image in find_images(root, &version) {
+            let contained = match image_modules(&image, distro, runner) {
+                Ok(contained) => contained,
+                Err(e) => {
+                    warn!("Cannot list the initramfs {}: {}", image.display(), e);
+                    continue;
+                }
+            };
+            let modules: Vec<PathBuf> = modules
+                .iter()
+                .filter(|module| contained.contains(&util::module_name(module)))
+                .map(|module| module.to_path_buf())
+                .collect();
+            if !modules.is_empty() {
+                stale.push(StaleInitramfs {
+                    version: version.clone(),
+                    image,
+                    modules,
+                });
+            }
+        }
+    }
+    Ok(stale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Lists a dracut initramfs the way lsinitrd does.
+    struct MockCommandRunner;
+
+    impl CommandRunner for MockCommandRunner {
+        fn run(&self, command: &str, args: &[&str]) -> Result<String, JanitorError> {
+            match (command, args) {
+                ("lsinitrd", [image]) if image.ends_with("initrd-6.4.0-default") => Ok(
+                    "Image: /boot/initrd-6.4.0-default: 12M\n\
+                     ========================================================================\n\
+                     drwxr-xr-x   3 root     root            0 Jan  1 00:00 usr/lib/modules/6.4.0-default/kernel\n\
+                     -rw-r--r--   1 root     root        81234 Jan  1 00:00 usr/lib/modules/6.4.0-default/kernel/drivers/ata/ahci.ko.zst\n\
+                     -rw-r--r--   1 root     root        31234 Jan  1 00:00 usr/lib/modules/6.4.0-default/kernel/drivers/nvme/host/nvme-core.ko.zst\n\
+                     lrwxrwxrwx   1 root     root            7 Jan  1 00:00 lib -> usr/lib"
+                        .to_string(),
+                ),
+                _ => Err(JanitorError::Command(format!(
+                    "Not mocked: {} {:?}",
+                    command, args
+                ))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_initramfs() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let module_dir = root.join("usr/lib/modules");
+        fs::create_dir_all(root.join("boot")).unwrap();
+        fs::write(root.join("boot/initrd-6.4.0-default"), "").unwrap();
+        // An initramfs which cannot be listed is skipped.
+        fs::create_dir_all(module_dir.join("6.4.0-default")).unwrap();
+        fs::write(module_dir.join("6.4.0-default/initrd"), "").unwrap();
+
+        let kernel_dir = module_dir.join("6.4.0-default/kernel/drivers");
+        let deleted = vec![
+            kernel_dir.join("ata/ahci.ko.zst"),
+            kernel_dir.join("ata/pata_acpi.ko.zst"),
+            kernel_dir.join("nvme/host/nvme_core.ko.zst"),
+            root.join("usr/lib/firmware/ahci.bin"),
+        ];
+        let stale = check_initramfs(root, &module_dir, &deleted, &MockCommandRunner).unwrap();
+        assert_eq!(
+            stale,
+            vec![StaleInitramfs {
+                version: "6.4.0-default".to_string(),
+                image: root.join("boot/initrd-6.4.0-default"),
+                modules: vec![
+                    kernel_dir.join("ata/ahci.ko.zst"),
+                    kernel_dir.join("nvme/host/nvme_core.ko.zst"),
+                ],
+            }]
+        );
+
+        // Kernels without an initramfs have nothing to check.
+        let deleted = vec![module_dir.join("6.5.0-default/kernel/ahci.ko.zst")];
+        assert!(
+            check_initramfs(root, &module_dir, &deleted, &MockCommandRunner)
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
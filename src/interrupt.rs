@@ -0,0 +1,39 @@
+use crate::error::JanitorError;
+use lazy_static::lazy_static;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Exit code used when a run was stopped by SIGINT or SIGTERM.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+lazy_static! {
+    static ref INTERRUPTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+}
+
+/// Installs SIGINT/SIGTERM handlers that request a graceful stop instead of killing the process.
+/// A second signal exits right away, for when the run does not reach a point where it stops.
+pub fn install_handlers() -> Result<(), JanitorError> {
+    for signal in [SIGINT, SIGTERM] {
+        // Registered first, so it only fires once the flag was set by a previous signal.
+        signal_hook::flag::register_conditional_shutdown(signal, EXIT_INTERRUPTED, Arc::clone(&INTERRUPTED))?;
+        signal_hook::flag::register(signal, Arc::clone(&INTERRUPTED))?;
+    }
+    Ok(())
+}
+
+/// Returns true once a termination signal has been received.
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Returns an error if a termination signal has been received, to be called between units of work.
+pub fn check() -> Result<(), JanitorError> {
+    if is_interrupted() {
+        return Err(JanitorError::Interrupted {
+            deleted: Vec::new(),
+            bytes: 0,
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,111 @@
+//! API version selection of the iwlwifi firmware.
+//!
+//! iwlwifi requests the ucode of a device family for its newest supported API version, e.g.
+//! `iwlwifi-cc-a0-77.ucode`, then for the previous versions down to the oldest one it supports,
+//! and loads the first one found. linux-firmware ships several API versions of every family, so
+//! only the newest ones not above the requested version are needed.
+
+use std::path::Path;
+
+/// Returns the family and the API version of the iwlwifi ucode named `file_name`, e.g.
+/// `iwlwifi-cc-a0` and 77 for `iwlwifi-cc-a0-77.ucode`.
+pub fn parse_ucode_name(file_name: &str) -> Option<(&str, u32)> {
+    let stem = file_name.strip_suffix(".ucode")?;
+    let (family, version) = stem.rsplit_once('-')?;
+    if !family.starts_with("iwlwifi-") || !version.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some((family, version.parse().ok()?))
+}
+
+/// Returns the wildcard firmware name matching all the API versions of the iwlwifi ucode
+/// `fw_name`, if it is one.
+pub fn api_pattern(fw_name: &str) -> Option<String> {
+    let (dir, file_name) = split_name(fw_name);
+    let (family, _) = parse_ucode_name(file_name)?;
+    Some(format!("{}{}-*.ucode", dir, family))
+}
+
+/// Returns the names, among `available`, of the newest `1 + fallback` API versions of the iwlwifi
+/// ucode requested as `fw_name` not above its version, newest first. Returns `fw_name` alone if
+/// none is available, or if it is not an iwlwifi ucode.
+pub fn select_api_versions(fw_name: &str, available: &[String], fallback: usize) -> Vec<String> {
+    let (dir, file_name) = split_name(fw_name);
+    let Some((family, max_version)) = parse_ucode_name(file_name) else {
+        return vec![fw_name.to_string()];
+    };
+    let mut versions: Vec<(u32, &String)> = available
+        .iter()
+        .filter_map(|name| {
+            let (name_dir, file_name) = split_name(name);
+            let (name_family, version) = parse_ucode_name(file_name)?;
+            (name_dir == dir && name_family == family && version <= max_version).then_some((version, name))
+        })
+        .collect();
+    versions.sort_by(|a, b| b.cmp(a));
+    versions.dedup_by_key(|(version, _)| *version);
+    if versions.is_empty() {
+        return vec![fw_name.to_string()];
+    }
+    versions
+        .into_iter()
+        .take(1 + fallback)
+        .map(|(_, name)| name.clone())
+        .collect()
+}
+
+/// Splits the firmware name `fw_name` into its directory, with a trailing `/` unless empty, and
+/// its file name.
+fn split_name(fw_name: &str) -> (&str, &str) {
+    match fw_name.rfind('/') {
+        Some(index) => fw_name.split_at(index + 1),
+        None => ("", fw_name),
+    }
+}
+
+/// Returns the firmware name of the file `path` found for the wildcard name `pattern` of
+/// [`api_pattern`]: its file name without compression extension, in the directory of `pattern`.
+pub fn name_of(pattern: &str, path: &Path) -> String {
+    let (dir, _) = split_name(pattern);
+    let file_name = crate::firmware::firmware_name(Path::new(path.file_name().unwrap_or_default()));
+    format!("{}{}", dir, file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ucode_name() {
+        assert_eq!(parse_ucode_name("iwlwifi-cc-a0-77.ucode"), Some(("iwlwifi-cc-a0", 77)));
+        assert_eq!(parse_ucode_name("iwlwifi-so-a0-gf-a0-86.ucode"), Some(("iwlwifi-so-a0-gf-a0", 86)));
+        assert_eq!(parse_ucode_name("iwlwifi-so-a0-gf-a0.pnvm"), None);
+        assert_eq!(parse_ucode_name("iwlwifi-cc-a0-*.ucode"), None);
+        assert_eq!(parse_ucode_name("ath10k-77.ucode"), None);
+    }
+
+    #[test]
+    fn test_select_api_versions() {
+        let available: Vec<String> = ["iwlwifi-cc-a0-46.ucode", "iwlwifi-cc-a0-72.ucode", "iwlwifi-cc-a0-77.ucode", "iwlwifi-cc-a0-83.ucode", "iwlwifi-ty-a0-gf-a0-77.ucode"]
+            .map(String::from)
+            .into();
+        assert_eq!(select_api_versions("iwlwifi-cc-a0-77.ucode", &available, 0), ["iwlwifi-cc-a0-77.ucode"]);
+        assert_eq!(
+            select_api_versions("iwlwifi-cc-a0-77.ucode", &available, 1),
+            ["iwlwifi-cc-a0-77.ucode", "iwlwifi-cc-a0-72.ucode"]
+        );
+        // The kernel falls back to the older versions when the requested one is missing.
+        assert_eq!(select_api_versions("iwlwifi-cc-a0-75.ucode", &available, 0), ["iwlwifi-cc-a0-72.ucode"]);
+        assert_eq!(select_api_versions("iwlwifi-cc-a0-40.ucode", &available, 0), ["iwlwifi-cc-a0-40.ucode"]);
+        assert_eq!(select_api_versions("intel/iwlwifi-cc-a0-77.ucode", &available, 0), ["intel/iwlwifi-cc-a0-77.ucode"]);
+        assert_eq!(select_api_versions("rtl_nic/rtl8168h-2.fw", &available, 0), ["rtl_nic/rtl8168h-2.fw"]);
+    }
+
+    #[test]
+    fn test_api_pattern() {
+        assert_eq!(api_pattern("iwlwifi-cc-a0-77.ucode").as_deref(), Some("iwlwifi-cc-a0-*.ucode"));
+        assert_eq!(api_pattern("intel/iwlwifi-cc-a0-77.ucode").as_deref(), Some("intel/iwlwifi-cc-a0-*.ucode"));
+        assert_eq!(api_pattern("iwlwifi-so-a0-gf-a0.pnvm"), None);
+        assert_eq!(name_of("intel/iwlwifi-cc-a0-*.ucode", Path::new("/fw/updates/intel/iwlwifi-cc-a0-72.ucode.zst")), "intel/iwlwifi-cc-a0-72.ucode");
+    }
+}
@@ -0,0 +1,470 @@
+//! Filesystem access of the cleanup engines.
+//!
+//! The driver and firmware cleanups walk, inspect and delete files through [`JanitorFs`] rather
+//! than `std::fs`. [`RealFs`] is the filesystem of the host; [`MemoryFs`] is a tree of sizes and
+//! symlinks built in memory, so the symlink, layering and pruning logic can be tested
+//! exhaustively without temporary directories. Other backends, e.g. archives or remote roots,
+//! only need to implement the trait.
+
+use path_clean::PathClean;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use walkdir::WalkDir;
+
+/// Type of a filesystem entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    /// A regular file, or any other entry which is neither a directory nor a symlink.
+    File,
+    Dir,
+    Symlink,
+}
+
+/// Metadata of a filesystem entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsMetadata {
+    pub kind: FileKind,
+    /// Size in bytes.
+    pub len: u64,
+}
+
+impl FsMetadata {
+    pub fn is_file(&self) -> bool {
+        self.kind == FileKind::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Dir
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind == FileKind::Symlink
+    }
+}
+
+/// An entry found by [`JanitorFs::walk`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsEntry {
+    pub path: PathBuf,
+    /// Type of the entry itself, symlinks are not followed.
+    pub kind: FileKind,
+}
+
+pub trait JanitorFs: Debug + Send + Sync {
+    /// Returns `root` and every entry below it, directories before their content. Symlinks are
+    /// not followed, except `root` itself. Entries which cannot be read are skipped.
+    fn walk(&self, root: &Path) -> Vec<FsEntry>;
+
+    /// Returns the paths of the entries of the directory `path`.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Returns the metadata of `path`, following symlinks.
+    fn metadata(&self, path: &Path) -> io::Result<FsMetadata>;
+
+    /// Returns the metadata of `path`, without following a final symlink.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<FsMetadata>;
+
+    /// Returns the target of the symlink `path`.
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Removes the empty directory `path`.
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Flushes the entries of the directory `path` to disk, so the removals done in it are
+    /// durable. Backends without persistent storage have nothing to do.
+    fn sync_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Whether `path` exists, following symlinks.
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+}
+
+fn kind_of(file_type: fs::FileType) -> FileKind {
+    if file_type.is_dir() {
+        FileKind::Dir
+    } else if file_type.is_symlink() {
+        FileKind::Symlink
+    } else {
+        FileKind::File
+    }
+}
+
+impl From<fs::Metadata> for FsMetadata {
+    fn from(metadata: fs::Metadata) -> Self {
+        FsMetadata {
+            kind: kind_of(metadata.file_type()),
+            len: metadata.len(),
+        }
+    }
+}
+
+/// The filesystem of the host.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFs;
+
+impl JanitorFs for RealFs {
+    fn walk(&self, root: &Path) -> Vec<FsEntry> {
+        WalkDir::new(root)
+            .into_iter()
+            .filter_map(Result::ok)
+            .map(|entry| FsEntry {
+                kind: kind_of(entry.file_type()),
+                path: entry.into_path(),
+            })
+            .collect()
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?.map(|entry| Ok(entry?.path())).collect()
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FsMetadata> {
+        Ok(fs::metadata(path)?.into())
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<FsMetadata> {
+        Ok(fs::symlink_metadata(path)?.into())
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        fs::File::open(path)?.sync_all()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    File(u64),
+    Dir,
+    Symlink(PathBuf),
+}
+
+/// Maximum number of symlinks followed when resolving a path, as the kernel does.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// A filesystem held in memory: files only have a size, symlinks a target. Parent directories
+/// are created along with the entries added.
+#[derive(Debug, Default)]
+pub struct MemoryFs {
+    nodes: Mutex<BTreeMap<PathBuf, Node>>,
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        MemoryFs::default()
+    }
+
+    /// Adds the file `path` of `len` bytes.
+    pub fn add_file(&self, path: impl AsRef<Path>, len: u64) -> &Self {
+        self.add(path.as_ref(), Node::File(len))
+    }
+
+    /// Adds the symlink `path` pointing to `target`.
+    pub fn add_symlink(&self, path: impl AsRef<Path>, target: impl AsRef<Path>) -> &Self {
+        self.add(path.as_ref(), Node::Symlink(target.as_ref().to_path_buf()))
+    }
+
+    /// Adds the directory `path`.
+    pub fn add_dir(&self, path: impl AsRef<Path>) -> &Self {
+        self.add(path.as_ref(), Node::Dir)
+    }
+
+    fn add(&self, path: &Path, node: Node) -> &Self {
+        let mut nodes = self.nodes.lock().unwrap();
+        for ancestor in path
+            .ancestors()
+            .skip(1)
+            .filter(|a| !a.as_os_str().is_empty())
+        {
+            nodes.entry(ancestor.to_path_buf()).or_insert(Node::Dir);
+        }
+        nodes.insert(path.to_path_buf(), node);
+        self
+    }
+
+    /// Returns `path` with the symlinks of its directories, and of its last component if
+    /// `follow` is set, replaced by their targets.
+    fn resolve(nodes: &BTreeMap<PathBuf, Node>, path: &Path, follow: bool) -> io::Result<PathBuf> {
+        let mut components: Vec<OsString> = path
+            .components()
+            .rev()
+            .map(|c| c.as_os_str().to_os_string())
+            .collect();
+        let mut current = PathBuf::new();
+        let mut hops = 0;
+        while let Some(component) = components.pop() {
+            let next = current.join(&component).clean();
+            if let Some(Node::Symlink(target)) = nodes.get(&next) {
+                if follow || !components.is_empty() {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return Err(io::Error::other(format!(
+                            "too many levels of symbolic links in {}",
+                            path.display()
+                        )));
+                    }
+                    components.extend(
+                        current
+                            .join(target)
+                            .clean()
+                            .components()
+                            .rev()
+                            .map(|c| c.as_os_str().to_os_string()),
+                    );
+                    current = PathBuf::new();
+                    continue;
+                }
+            }
+            current = next;
+        }
+        Ok(current)
+    }
+
+    fn node(&self, path: &Path, follow: bool) -> io::Result<(PathBuf, Node)> {
+        let nodes = self.nodes.lock().unwrap();
+        let resolved = MemoryFs::resolve(&nodes, path, follow)?;
+        let node = nodes
+            .get(&resolved)
+            .cloned()
+            .ok_or_else(|| not_found(path))?;
+        Ok((resolved, node))
+    }
+
+    fn metadata_of(node: &Node) -> FsMetadata {
+        match node {
+            Node::File(len) => FsMetadata {
+                kind: FileKind::File,
+                len: *len,
+            },
+            Node::Dir => FsMetadata {
+                kind: FileKind::Dir,
+                len: 0,
+            },
+            Node::Symlink(target) => FsMetadata {
+                kind: FileKind::Symlink,
+                len: target.as_os_str().len() as u64,
+            },
+        }
+    }
+
+    fn children(nodes: &BTreeMap<PathBuf, Node>, dir: &Path) -> Vec<PathBuf> {
+        nodes
+            .keys()
+            .filter(|p| p.parent() == Some(dir))
+            .cloned()
+            .collect()
+    }
+}
+
+impl JanitorFs for MemoryFs {
+    fn walk(&self, root: &Path) -> Vec<FsEntry> {
+        let Ok((resolved, node)) = self.node(root, true) else {
+            return Vec::new();
+        };
+        let mut entries = vec![FsEntry {
+            path: root.to_path_buf(),
+            kind: MemoryFs::metadata_of(&node).kind,
+        }];
+        if node == Node::Dir {
+            let nodes = self.nodes.lock().unwrap();
+            // Paths are ordered component by component, so directories come before their content.
+            entries.extend(
+                nodes
+                    .range(resolved.clone()..)
+                    .skip(1)
+                    .take_while(|(path, _)| path.starts_with(&resolved))
+                    .map(|(path, node)| FsEntry {
+                        path: root.join(path.strip_prefix(&resolved).unwrap()),
+                        kind: MemoryFs::metadata_of(node).kind,
+                    }),
+            );
+        }
+        entries
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let (resolved, node) = self.node(path, true)?;
+        if node != Node::Dir {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", path.display()),
+            ));
+        }
+        let nodes = self.nodes.lock().unwrap();
+        Ok(MemoryFs::children(&nodes, &resolved)
+            .into_iter()
+            .map(|child| path.join(child.file_name().unwrap_or_default()))
+            .collect())
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FsMetadata> {
+        Ok(MemoryFs::metadata_of(&self.node(path, true)?.1))
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<FsMetadata> {
+        Ok(MemoryFs::metadata_of(&self.node(path, false)?.1))
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        match self.node(path, false)? {
+            (_, Node::Symlink(target)) => Ok(target),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a symlink", path.display()),
+            )),
+        }
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let (resolved, node) = self.node(path, false)?;
+        if node == Node::Dir {
+            return Err(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                format!("{} is a directory", path.display()),
+            ));
+        }
+        self.nodes.lock().unwrap().remove(&resolved);
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let (resolved, node) = self.node(path, false)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        if node != Node::Dir {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", path.display()),
+            ));
+        }
+        if !MemoryFs::children(&nodes, &resolved).is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::DirectoryNotEmpty,
+                format!("{} is not empty", path.display()),
+            ));
+        }
+        nodes.remove(&resolved);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, _) = self.node(from, false)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        let to = match to.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => {
+                MemoryFs::resolve(&nodes, parent, true)?.join(to.file_name().unwrap_or_default())
+            }
+            _ => to.to_path_buf(),
+        };
+        let moved: Vec<PathBuf> = nodes
+            .keys()
+            .filter(|p| p.starts_with(&from))
+            .cloned()
+            .collect();
+        for path in moved {
+            let node = nodes.remove(&path).unwrap();
+            nodes.insert(to.join(path.strip_prefix(&from).unwrap()), node);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_fs_symlinks() {
+        let fs = MemoryFs::new();
+        fs.add_file("/fw/real/a.bin", 10)
+            .add_symlink("/fw/vendor", "real")
+            .add_symlink("/fw/link.bin", "vendor/a.bin")
+            .add_symlink("/fw/dangling.bin", "missing.bin")
+            .add_symlink("/fw/loop.bin", "loop.bin");
+
+        assert_eq!(fs.metadata(Path::new("/fw/link.bin")).unwrap().len, 10);
+        assert!(fs
+            .symlink_metadata(Path::new("/fw/link.bin"))
+            .unwrap()
+            .is_symlink());
+        assert!(fs
+            .metadata(Path::new("/fw/vendor/a.bin"))
+            .unwrap()
+            .is_file());
+        assert!(!fs.exists(Path::new("/fw/dangling.bin")));
+        assert!(fs.symlink_metadata(Path::new("/fw/dangling.bin")).is_ok());
+        assert!(fs.metadata(Path::new("/fw/loop.bin")).is_err());
+        assert_eq!(
+            fs.read_link(Path::new("/fw/link.bin")).unwrap(),
+            Path::new("vendor/a.bin")
+        );
+        assert_eq!(
+            fs.read_dir(Path::new("/fw/vendor")).unwrap(),
+            vec![PathBuf::from("/fw/vendor/a.bin")]
+        );
+    }
+
+    #[test]
+    fn test_memory_fs_walk_and_remove() {
+        let fs = MemoryFs::new();
+        fs.add_file("/fw/a/b.bin", 1)
+            .add_file("/fw/a.bin", 2)
+            .add_dir("/fw/empty");
+        let walked: Vec<(String, FileKind)> = fs
+            .walk(Path::new("/fw"))
+            .into_iter()
+            .map(|e| (e.path.display().to_string(), e.kind))
+            .collect();
+        assert_eq!(
+            walked,
+            vec![
+                ("/fw".to_string(), FileKind::Dir),
+                ("/fw/a".to_string(), FileKind::Dir),
+                ("/fw/a/b.bin".to_string(), FileKind::File),
+                ("/fw/a.bin".to_string(), FileKind::File),
+                ("/fw/empty".to_string(), FileKind::Dir),
+            ]
+        );
+
+        assert!(fs.remove_dir(Path::new("/fw/a")).is_err());
+        fs.rename(Path::new("/fw/a"), Path::new("/fw/c")).unwrap();
+        assert!(fs.exists(Path::new("/fw/c/b.bin")));
+        fs.remove_file(Path::new("/fw/c/b.bin")).unwrap();
+        fs.remove_dir(Path::new("/fw/c")).unwrap();
+        assert!(!fs.exists(Path::new("/fw/c")));
+        assert!(fs.remove_file(Path::new("/fw/empty")).is_err());
+    }
+}
@@ -0,0 +1,238 @@
+//! Audit trail of the deletions done by a run, and undo from its backup archive and quarantine.
+//!
+//! The journal is a JSON lines file, one entry per deleted path, flushed as the run goes so it
+//! is complete up to the last deletion even if the run is interrupted. An entry is only written
+//! once its path is deleted, and the entries of a batch of deletions are flushed together.
+
+use crate::backup;
+use crate::clock::Clock;
+use crate::error::JanitorError;
+use crate::quarantine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Kind of a deleted path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Symlink,
+    Dir,
+}
+
+/// One deletion.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Absolute path of the deleted entry.
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    pub size: u64,
+    /// SHA-256 of the content of deleted files.
+    pub sha256: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Why the entry was deleted.
+    pub reason: String,
+    /// Where the entry was moved to when quarantined instead of being unlinked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantined: Option<PathBuf>,
+}
+
+impl JournalEntry {
+    /// Describes `path`, about to be deleted for `reason`, at the time given by `clock`.
+    pub fn describe(path: &Path, reason: &str, clock: &dyn Clock) -> Result<Self, JanitorError> {
+        let metadata = fs::symlink_metadata(path)?;
+        let (kind, sha256) = if metadata.is_dir() {
+            (EntryKind::Dir, None)
+        } else if metadata.file_type().is_symlink() {
+            (EntryKind::Symlink, None)
+        } else {
+            (EntryKind::File, Some(hash_file(path)?))
+        };
+        Ok(JournalEntry {
+            path: std::path::absolute(path)?,
+            kind,
+            size: metadata.len(),
+            sha256,
+            timestamp: clock.unix_seconds(),
+            reason: reason.to_string(),
+            quarantined: None,
+        })
+    }
+}
+
+/// Returns the hex encoded SHA-256 of the content of `path`.
+pub(crate) fn hash_file(path: &Path) -> Result<String, JanitorError> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// A journal being written.
+pub struct Journal {
+    file: fs::File,
+}
+
+impl Journal {
+    /// Creates the journal at `path`, appending to an existing one.
+    pub fn create(path: &Path) -> Result<Self, JanitorError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Journal { file })
+    }
+
+    /// Appends `entry` and flushes it to disk.
+    pub fn record(&mut self, entry: &JournalEntry) -> Result<(), JanitorError> {
+        self.record_all(std::slice::from_ref(entry))
+    }
+
+    /// Appends `entries` and flushes them to disk at once.
+    pub fn record_all(&mut self, entries: &[JournalEntry]) -> Result<(), JanitorError> {
+        let mut lines = String::new();
+        for entry in entries {
+            lines.push_str(&serde_json::to_string(entry)?);
+            lines.push('\n');
+        }
+        self.file.write_all(lines.as_bytes())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Reads the entries of the journal at `path`.
+pub fn read_journal(path: &Path) -> Result<Vec<JournalEntry>, JanitorError> {
+    let file = fs::File::open(path)?;
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+/// Restores the entries of the journal at `journal` below `root`: the quarantined ones from the
+/// quarantine, the others from the archive at `backup`, which is only needed for deleted files
+/// and symlinks. Checks the restored files against their recorded hash. Returns the restored
+/// paths.
+pub fn undo(journal: &Path, backup: Option<&Path>, root: &Path) -> Result<Vec<PathBuf>, JanitorError> {
+    let entries: HashMap<PathBuf, JournalEntry> = read_journal(journal)?
+        .into_iter()
+        .map(|e| (e.path.strip_prefix("/").unwrap_or(&e.path).to_path_buf(), e))
+        .collect();
+
+    let archived = |name: &Path| entries.get(name).is_some_and(|e| e.quarantined.is_none());
+    let mut restored = match backup {
+        Some(backup) => backup::restore_entries(backup, root, archived)?,
+        None => {
+            if entries.values().any(|e| e.quarantined.is_none() && e.kind != EntryKind::Dir) {
+                return Err(JanitorError::MissingBackup(journal.to_path_buf()));
+            }
+            // Only removed directories are left, they have no content to restore.
+            let mut restored = Vec::new();
+            for (name, _) in entries.iter().filter(|(_, e)| e.kind == EntryKind::Dir) {
+                fs::create_dir_all(root.join(name))?;
+                restored.push(root.join(name));
+            }
+            restored
+        }
+    };
+    for (name, entry) in &entries {
+        if let Some(quarantined) = &entry.quarantined {
+            quarantine::restore_file(quarantined, &root.join(name))?;
+            restored.push(root.join(name));
+        }
+    }
+    for (name, entry) in &entries {
+        let path = root.join(name);
+        if let Some(expected) = &entry.sha256 {
+            if !path.exists() || &hash_file(&path)? != expected {
+                return Err(JanitorError::UndoMismatch(path));
+            }
+        }
+    }
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deleter::Deleter;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_journal_and_undo() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().join("fw/vendor");
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("blob.bin");
+        fs::write(&file, "abc").unwrap();
+        let journal_path = temp_dir.path().join("journal.jsonl");
+        let backup_path = temp_dir.path().join("backup.tar.zst");
+
+        let mut deleter = Deleter::new(true)
+            .with_backup(&backup_path)
+            .unwrap()
+            .with_journal(&journal_path)
+            .unwrap();
+        deleter.remove_file(&file, 3, "unused").unwrap();
+        deleter.remove_dir(&dir, "empty directory").unwrap();
+        deleter.finish().unwrap();
+
+        let entries = read_journal(&journal_path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, file);
+        assert_eq!(entries[0].kind, EntryKind::File);
+        assert_eq!(entries[0].reason, "unused");
+        assert_eq!(
+            entries[0].sha256.as_deref(),
+            Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(entries[1].kind, EntryKind::Dir);
+
+        let root = temp_dir.path().join("root");
+        let restored = undo(&journal_path, Some(&backup_path), &root).unwrap();
+        assert_eq!(restored.len(), 2);
+        let restored_file = root.join(file.strip_prefix("/").unwrap());
+        assert_eq!(fs::read_to_string(restored_file).unwrap(), "abc");
+    }
+
+    #[test]
+    fn test_undo_from_quarantine() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().join("fw");
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("blob.bin");
+        fs::write(&file, "abc").unwrap();
+        let journal_path = temp_dir.path().join("journal.jsonl");
+
+        let mut deleter = Deleter::new(true)
+            .with_journal(&journal_path)
+            .unwrap()
+            .with_quarantine(&temp_dir.path().join("quarantine"))
+            .unwrap();
+        deleter.remove_files(&[(file.clone(), 3, "unused")]).unwrap();
+        deleter.finish().unwrap();
+        assert!(!file.exists());
+        assert!(read_journal(&journal_path).unwrap()[0].quarantined.is_some());
+
+        // Without a backup archive, the quarantined files are moved back in place.
+        let restored = undo(&journal_path, None, Path::new("/")).unwrap();
+        assert_eq!(restored, vec![file.clone()]);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "abc");
+    }
+}
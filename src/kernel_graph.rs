@@ -0,0 +1,253 @@
+//! Module metadata shared by the cleanups run within one invocation.
+//!
+//! Listing a kernel tree and extracting the `.modinfo` section of every module, possibly
+//! decompressing it, is the expensive part of both cleanups. A [`KernelGraph`] does it once per
+//! kernel tree and hands the result to every pass asking for it. The modules a pass removes are
+//! forgotten, so the following passes see the tree as it will be shipped. With a
+//! [`ModinfoCache`], the metadata of the modules unchanged since an earlier run is not read again.
+
+use crate::error::JanitorError;
+use crate::interrupt;
+use crate::modinfo::{self, ModInfo};
+use crate::modinfo_cache::ModinfoCache;
+use log::warn;
+use crate::util::{self, ScanOptions};
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// A kernel directory and the scan exclusion patterns applied to it.
+type TreeKey = (PathBuf, Vec<String>);
+
+/// Cache of the module trees scanned so far, keyed by kernel directory and scan exclusions.
+#[derive(Debug, Default)]
+pub struct KernelGraph {
+    kernels: Mutex<HashMap<TreeKey, Arc<KernelModules>>>,
+    cache: Option<Arc<ModinfoCache>>,
+}
+
+impl KernelGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a graph looking up the module metadata in `cache` before reading the modules.
+    pub fn with_cache(cache: ModinfoCache) -> Self {
+        KernelGraph {
+            cache: Some(Arc::new(cache)),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the modules of `kernel_dir`, scanning the tree on the first call only.
+    pub fn kernel(
+        &self,
+        kernel_dir: &Path,
+        scan: &ScanOptions,
+    ) -> Result<Arc<KernelModules>, JanitorError> {
+        let key = (
+            kernel_dir.to_path_buf(),
+            scan.exclude
+                .iter()
+                .map(|p| p.as_str().to_string())
+                .collect(),
+        );
+        if let Some(kernel) = self.kernels.lock().unwrap().get(&key) {
+            return Ok(Arc::clone(kernel));
+        }
+        let kernel = Arc::new(KernelModules {
+            kernel_dir: kernel_dir.to_path_buf(),
+            paths: util::find_kernel_modules(kernel_dir, scan)?,
+            removed: Mutex::default(),
+            modinfo: OnceLock::new(),
+            cache: self.cache.clone(),
+        });
+        self.kernels
+            .lock()
+            .unwrap()
+            .insert(key, Arc::clone(&kernel));
+        Ok(kernel)
+    }
+}
+
+/// The modules of one kernel tree.
+#[derive(Debug)]
+pub struct KernelModules {
+    pub kernel_dir: PathBuf,
+    paths: Vec<PathBuf>,
+    removed: Mutex<HashSet<PathBuf>>,
+    /// Metadata of each module, or why it could not be read.
+    modinfo: OnceLock<HashMap<PathBuf, Result<ModInfo, String>>>,
+    cache: Option<Arc<ModinfoCache>>,
+}
+
+impl KernelModules {
+    /// Returns the paths of the module files not removed by an earlier pass.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let removed = self.removed.lock().unwrap();
+        self.paths
+            .iter()
+            .filter(|path| !removed.contains(*path))
+            .cloned()
+            .collect()
+    }
+
+    /// Records that the module at `path` is removed, in a dry run too.
+    pub fn remove(&self, path: &Path) {
+        self.removed.lock().unwrap().insert(path.to_path_buf());
+    }
+
+    /// Returns the metadata of the module at `path`, reading the metadata of every module on the
+    /// first call. Modules unknown to the tree are read directly.
+    pub fn modinfo(&self, path: &Path) -> Result<ModInfo, JanitorError> {
+        match self.read_all()?.get(path) {
+            Some(Ok(info)) => Ok(info.clone()),
+            Some(Err(e)) => Err(JanitorError::ModuleParse(path.to_path_buf(), e.clone())),
+            None => modinfo::read_modinfo(path),
+        }
+    }
+
+    fn read_all(&self) -> Result<&HashMap<PathBuf, Result<ModInfo, String>>, JanitorError> {
+        if let Some(modinfo) = self.modinfo.get() {
+            return Ok(modinfo);
+        }
+        let modinfo = self
+            .paths
+            .par_iter()
+            .map(|path| {
+                interrupt::check()?;
+                Ok((path.clone(), self.read_modinfo(path)))
+            })
+            .collect::<Result<HashMap<_, _>, JanitorError>>()?;
+        if let Some(cache) = &self.cache {
+            if let Err(e) = cache.save() {
+                warn!("Cannot write the modinfo cache: {}", e);
+            }
+        }
+        // Another thread may have been faster, both read the same files.
+        Ok(self.modinfo.get_or_init(|| modinfo))
+    }
+
+    /// Reads the metadata of the module at `path`, from the cache if the module did not change.
+    /// Modules which cannot be parsed are not cached.
+    fn read_modinfo(&self, path: &Path) -> Result<ModInfo, String> {
+        let metadata = self.cache.as_ref().and_then(|_| path.metadata().ok());
+        if let (Some(cache), Some(metadata)) = (&self.cache, &metadata) {
+            if let Some(info) = cache.get(path, metadata) {
+                return Ok(info);
+            }
+        }
+        let info = modinfo::read_modinfo(path).map_err(|e| match e {
+            JanitorError::ModuleParse(_, message) => message,
+            e => e.to_string(),
+        })?;
+        if let (Some(cache), Some(metadata)) = (&self.cache, &metadata) {
+            cache.insert(path, metadata, &info);
+        }
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_kernel_graph_reads_modules_once() {
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path();
+        let path = kernel_dir.join("a.ko");
+        fs::write(&path, modinfo::build_test_module(&["firmware=a.bin"])).unwrap();
+        fs::write(kernel_dir.join("bogus.ko"), "not an elf").unwrap();
+
+        let graph = KernelGraph::new();
+        let kernel = graph.kernel(kernel_dir, &ScanOptions::default()).unwrap();
+        assert_eq!(kernel.paths().len(), 2);
+        assert_eq!(kernel.modinfo(&path).unwrap().firmware(), vec!["a.bin"]);
+        assert!(matches!(
+            kernel.modinfo(&kernel_dir.join("bogus.ko")),
+            Err(JanitorError::ModuleParse(_, _))
+        ));
+
+        // Later passes get the cached tree and metadata.
+        fs::write(&path, modinfo::build_test_module(&["firmware=b.bin"])).unwrap();
+        fs::write(kernel_dir.join("c.ko"), modinfo::build_test_module(&[])).unwrap();
+        let again = graph.kernel(kernel_dir, &ScanOptions::default()).unwrap();
+        assert!(Arc::ptr_eq(&kernel, &again));
+        assert_eq!(again.modinfo(&path).unwrap().firmware(), vec!["a.bin"]);
+    }
+
+    #[test]
+    fn test_kernel_graph_keyed_by_exclusions() {
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path();
+        fs::create_dir_all(kernel_dir.join("kernel/sound")).unwrap();
+        fs::write(kernel_dir.join("a.ko"), modinfo::build_test_module(&[])).unwrap();
+        fs::write(
+            kernel_dir.join("kernel/sound/b.ko"),
+            modinfo::build_test_module(&[]),
+        )
+        .unwrap();
+
+        let graph = KernelGraph::new();
+        let excluding = ScanOptions {
+            exclude: vec![glob::Pattern::new("kernel/sound").unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(graph.kernel(kernel_dir, &excluding).unwrap().paths().len(), 1);
+        assert_eq!(
+            graph
+                .kernel(kernel_dir, &ScanOptions::default())
+                .unwrap()
+                .paths
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_kernel_modules_remove() {
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path();
+        fs::write(kernel_dir.join("a.ko"), modinfo::build_test_module(&[])).unwrap();
+        fs::write(kernel_dir.join("b.ko"), modinfo::build_test_module(&[])).unwrap();
+
+        let graph = KernelGraph::new();
+        graph
+            .kernel(kernel_dir, &ScanOptions::default())
+            .unwrap()
+            .remove(&kernel_dir.join("a.ko"));
+        let kernel = graph.kernel(kernel_dir, &ScanOptions::default()).unwrap();
+        assert_eq!(kernel.paths(), vec![kernel_dir.join("b.ko")]);
+    }
+
+    #[test]
+    fn test_kernel_graph_modinfo_cache() {
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path().join("6.1.0-test");
+        fs::create_dir_all(&kernel_dir).unwrap();
+        let cache_path = temp_dir.path().join("modinfo.json");
+        let path = kernel_dir.join("a.ko");
+        fs::write(&path, modinfo::build_test_module(&["firmware=a.bin"])).unwrap();
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+
+        let graph = KernelGraph::with_cache(ModinfoCache::open(&cache_path));
+        let kernel = graph.kernel(&kernel_dir, &ScanOptions::default()).unwrap();
+        assert_eq!(kernel.modinfo(&path).unwrap().firmware(), vec!["a.bin"]);
+        assert!(cache_path.exists());
+
+        // Same size and modification time: the next run trusts the cache.
+        fs::write(&path, modinfo::build_test_module(&["firmware=b.bin"])).unwrap();
+        fs::File::options().write(true).open(&path).unwrap().set_modified(mtime).unwrap();
+        let graph = KernelGraph::with_cache(ModinfoCache::open(&cache_path));
+        let kernel = graph.kernel(&kernel_dir, &ScanOptions::default()).unwrap();
+        assert_eq!(kernel.modinfo(&path).unwrap().firmware(), vec!["a.bin"]);
+
+        // Without the cache, the module is read.
+        let kernel = KernelGraph::new().kernel(&kernel_dir, &ScanOptions::default()).unwrap();
+        assert_eq!(kernel.modinfo(&path).unwrap().firmware(), vec!["b.bin"]);
+    }
+}
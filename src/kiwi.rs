@@ -0,0 +1,231 @@
+//! KIWI image descriptions.
+//!
+//! openSUSE live and installation images are built by KIWI, whose description lists the kernel
+//! modules to keep in `<drivers>` sections, for every profile or only for some. [`Description`]
+//! reads them so the driver cleanup keeps the same modules without a copy of the lists to
+//! maintain by hand, and [`drivers_fragment`] writes the modules a run kept back as a `<drivers>`
+//! section.
+//!
+//! The `file` names of a `<drivers>` section are relative to the `kernel` directory of the module
+//! tree, and their `*` matches across directories, like KIWI's own stripping.
+
+use crate::error::JanitorError;
+use crate::util;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+/// A `<drivers>` section of a description.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DriversSection {
+    /// The profiles the section is for, every one if empty.
+    pub profiles: Vec<String>,
+    /// The `name` of its `file` elements.
+    pub files: Vec<String>,
+}
+
+/// The parts of a KIWI image description the driver cleanup reads.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Description {
+    /// The profiles declared, with the profiles each one requires.
+    pub profiles: BTreeMap<String, Vec<String>>,
+    /// The profiles built when none is given, the ones with `import="true"`.
+    pub imported: Vec<String>,
+    pub drivers: Vec<DriversSection>,
+}
+
+/// Splits the comma separated `profiles` attribute of an element.
+fn profile_list(node: roxmltree::Node) -> Vec<String> {
+    node.attribute("profiles")
+        .map(|p| p.split(',').map(str::trim).filter(|p| !p.is_empty()).map(String::from).collect())
+        .unwrap_or_default()
+}
+
+impl Description {
+    /// Reads the description at `path`, usually `config.kiwi` or `config.xml`.
+    pub fn read(path: &Path) -> Result<Self, JanitorError> {
+        let content = fs::read_to_string(path).map_err(|e| JanitorError::ConfigRead(path.display().to_string(), e))?;
+        Self::parse(&content).map_err(|e| JanitorError::InvalidConfig(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Parses the content of a description.
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let document = roxmltree::Document::parse(content).map_err(|e| e.to_string())?;
+        let image = document.root_element();
+        if !image.has_tag_name("image") {
+            return Err(format!("root element <{}> is not <image>", image.tag_name().name()));
+        }
+        let mut description = Description::default();
+        for profile in image
+            .children()
+            .filter(|n| n.has_tag_name("profiles"))
+            .flat_map(|n| n.children())
+            .filter(|n| n.has_tag_name("profile"))
+        {
+            let name = profile.attribute("name").ok_or("<profile> without a name")?;
+            let requires = profile
+                .children()
+                .filter(|n| n.has_tag_name("requires"))
+                .filter_map(|n| n.attribute("profile").map(String::from))
+                .collect();
+            description.profiles.insert(name.to_string(), requires);
+            if profile.attribute("import") == Some("true") {
+                description.imported.push(name.to_string());
+            }
+        }
+        for section in image.children().filter(|n| n.has_tag_name("drivers")) {
+            let files = section
+                .children()
+                .filter(|n| n.has_tag_name("file"))
+                .map(|n| n.attribute("name").map(String::from).ok_or("<file> without a name in <drivers>"))
+                .collect::<Result<_, _>>()?;
+            description.drivers.push(DriversSection {
+                profiles: profile_list(section),
+                files,
+            });
+        }
+        Ok(description)
+    }
+
+    /// Returns the `file` names of the `<drivers>` sections applying to a build of `profiles`, the
+    /// imported ones if empty, and of the profiles they require.
+    pub fn drivers(&self, profiles: &[String]) -> Result<Vec<&str>, JanitorError> {
+        let mut pending: Vec<&str> = match profiles.is_empty() {
+            true => self.imported.iter().map(String::as_str).collect(),
+            false => profiles.iter().map(String::as_str).collect(),
+        };
+        let mut selected = BTreeSet::new();
+        while let Some(profile) = pending.pop() {
+            let requires = self.profiles.get(profile).ok_or_else(|| {
+                JanitorError::InvalidConfig(format!(
+                    "unknown KIWI profile '{}', the description declares {}",
+                    profile,
+                    self.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+                ))
+            })?;
+            if selected.insert(profile) {
+                pending.extend(requires.iter().map(String::as_str));
+            }
+        }
+        Ok(self
+            .drivers
+            .iter()
+            .filter(|s| s.profiles.is_empty() || s.profiles.iter().any(|p| selected.contains(p.as_str())))
+            .flat_map(|s| s.files.iter().map(String::as_str))
+            .collect())
+    }
+}
+
+/// Returns the keep rule of the configuration matching the modules of the `<drivers>` file
+/// `name`: the module itself, compressed or not, or everything below a directory.
+pub fn keep_rule(name: &str) -> String {
+    let name = name.trim().trim_start_matches('/').trim_end_matches('/');
+    let pattern: String = name
+        .split('*')
+        .map(|part| part.split('?').map(regex::escape).collect::<Vec<_>>().join("."))
+        .collect::<Vec<_>>()
+        .join(".*");
+    let suffix = if name.ends_with(".ko") {
+        r"(\.(xz|zst|gz))?"
+    } else if name.ends_with('*') {
+        ""
+    } else {
+        "(/.*)?"
+    };
+    format!("^kernel/{}{}$", pattern, suffix)
+}
+
+/// Escapes `value` for an XML attribute.
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Returns a `<drivers>` section keeping the modules at `relative_paths`, relative to a kernel
+/// directory. Modules outside its `kernel` directory, e.g. in `updates`, are left out, KIWI does
+/// not strip them.
+pub fn drivers_fragment<'a>(relative_paths: impl IntoIterator<Item = &'a str>) -> String {
+    let names: BTreeSet<&str> = relative_paths
+        .into_iter()
+        .filter(|p| util::is_kernel_module(Path::new(p)))
+        .filter_map(|p| p.strip_prefix("kernel/"))
+        .collect();
+    let mut fragment = String::from("<drivers>\n");
+    for name in names {
+        fragment.push_str(&format!("    <file name=\"{}\"/>\n", escape_attribute(name)));
+    }
+    fragment.push_str("</drivers>\n");
+    fragment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    const DESCRIPTION: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<image schemaversion="7.4" name="openSUSE-Tumbleweed-Live">
+    <profiles>
+        <profile name="Base" description="Common drivers" import="true"/>
+        <profile name="KDE" description="KDE live system">
+            <requires profile="Base"/>
+        </profile>
+        <profile name="Rescue" description="Rescue system"/>
+    </profiles>
+    <drivers>
+        <file name="drivers/ata/*"/>
+    </drivers>
+    <drivers profiles="KDE">
+        <file name="drivers/gpu/drm/i915/i915.ko"/>
+    </drivers>
+    <drivers profiles="Base,Rescue">
+        <file name="fs/squashfs"/>
+    </drivers>
+</image>"#;
+
+    #[test]
+    fn test_drivers() {
+        let description = Description::parse(DESCRIPTION).unwrap();
+        assert_eq!(description.imported, ["Base"]);
+        assert_eq!(description.drivers(&[]).unwrap(), ["drivers/ata/*", "fs/squashfs"]);
+        assert_eq!(
+            description.drivers(&["KDE".to_string()]).unwrap(),
+            ["drivers/ata/*", "drivers/gpu/drm/i915/i915.ko", "fs/squashfs"]
+        );
+        assert_eq!(description.drivers(&["Rescue".to_string()]).unwrap(), ["drivers/ata/*", "fs/squashfs"]);
+        assert!(description.drivers(&["GNOME".to_string()]).is_err());
+        assert!(Description::parse("<domain/>").is_err());
+        assert!(Description::parse("<image><drivers><file/></drivers></image>").is_err());
+    }
+
+    #[test]
+    fn test_keep_rule() {
+        let matches = |name: &str, path: &str| Regex::new(&keep_rule(name)).unwrap().is_match(path);
+        assert!(matches("drivers/ata/*", "kernel/drivers/ata/pata/pata_acpi.ko.zst"));
+        assert!(!matches("drivers/ata/*", "kernel/drivers/atafoo/x.ko"));
+        assert!(matches("drivers/gpu/drm/i915/i915.ko", "kernel/drivers/gpu/drm/i915/i915.ko.xz"));
+        assert!(!matches("drivers/gpu/drm/i915/i915.ko", "kernel/drivers/gpu/drm/i915/i915.ko.bak"));
+        assert!(matches("fs/squashfs/", "kernel/fs/squashfs/squashfs.ko"));
+        assert!(!matches("fs/squashfs", "kernel/fs/squashfs2/squashfs2.ko"));
+        assert!(matches("drivers/net/e1000?/*", "kernel/drivers/net/e1000e/e1000e.ko"));
+    }
+
+    #[test]
+    fn test_drivers_fragment() {
+        let paths = [
+            "kernel/fs/squashfs/squashfs.ko.zst",
+            "kernel/drivers/ata/ahci.ko.zst",
+            "updates/vendor.ko",
+            "modules.dep",
+        ];
+        assert_eq!(
+            drivers_fragment(paths),
+            "<drivers>\n    <file name=\"drivers/ata/ahci.ko.zst\"/>\n    <file name=\"fs/squashfs/squashfs.ko.zst\"/>\n</drivers>\n"
+        );
+        let description = Description::parse(&format!("<image>{}</image>", drivers_fragment(paths))).unwrap();
+        assert_eq!(description.drivers(&[]).unwrap(), ["drivers/ata/ahci.ko.zst", "fs/squashfs/squashfs.ko.zst"]);
+    }
+}
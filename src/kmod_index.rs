@@ -0,0 +1,187 @@
+//! Reader for the binary index files (`modules.*.bin`) written by depmod.
+//!
+//! The format is a trie: each node may carry a prefix string, a range of
+//! children indexed by their next character and a list of values. All integers
+//! are big-endian.
+
+use crate::error::JanitorError;
+use std::fs;
+use std::path::Path;
+
+const INDEX_MAGIC: u32 = 0xB007_F457;
+const INDEX_VERSION_MAJOR: u32 = 0x0002;
+
+const NODE_PREFIX: u32 = 0x8000_0000;
+const NODE_VALUES: u32 = 0x4000_0000;
+const NODE_CHILDS: u32 = 0x2000_0000;
+const NODE_MASK: u32 = 0x0FFF_FFFF;
+
+/// A key/value pair stored in a kmod index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub key: String,
+    pub priority: u32,
+    pub value: String,
+}
+
+/// Reads every entry of the kmod index file at `path`.
+pub fn read_index(path: &Path) -> Result<Vec<IndexEntry>, JanitorError> {
+    let data = fs::read(path)?;
+    parse_index(&data).map_err(|e| JanitorError::IndexParse(path.to_path_buf(), e))
+}
+
+/// Parses the content of a kmod index file.
+pub fn parse_index(data: &[u8]) -> Result<Vec<IndexEntry>, String> {
+    let reader = Reader { data };
+    if reader.u32(0)? != INDEX_MAGIC {
+        return Err("bad magic".to_string());
+    }
+    let version = reader.u32(4)?;
+    if version >> 16 != INDEX_VERSION_MAJOR {
+        return Err(format!("unsupported index version {:#x}", version));
+    }
+
+    let mut entries = Vec::new();
+    let mut stack = vec![(reader.u32(8)?, String::new())];
+    while let Some((node, mut key)) = stack.pop() {
+        let mut pos = (node & NODE_MASK) as usize;
+        if node & NODE_PREFIX != 0 {
+            let prefix = reader.string(pos)?;
+            pos += prefix.len() + 1;
+            key.push_str(&prefix);
+        }
+        if node & NODE_CHILDS != 0 {
+            let first = reader.u8(pos)?;
+            let last = reader.u8(pos + 1)?;
+            pos += 2;
+            for ch in first..=last {
+                let child = reader.u32(pos)?;
+                pos += 4;
+                if child != 0 {
+                    let mut child_key = key.clone();
+                    child_key.push(char::from(ch));
+                    stack.push((child, child_key));
+                }
+            }
+        }
+        if node & NODE_VALUES != 0 {
+            let count = reader.u32(pos)?;
+            pos += 4;
+            for _ in 0..count {
+                let priority = reader.u32(pos)?;
+                let value = reader.string(pos + 4)?;
+                pos += 4 + value.len() + 1;
+                entries.push(IndexEntry {
+                    key: key.clone(),
+                    priority,
+                    value,
+                });
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.key.cmp(&b.key).then(a.priority.cmp(&b.priority)));
+    Ok(entries)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn bytes(&self, pos: usize, len: usize) -> Result<&[u8], String> {
+        pos.checked_add(len)
+            .and_then(|end| self.data.get(pos..end))
+            .ok_or_else(|| format!("truncated index at offset {}", pos))
+    }
+
+    fn u8(&self, pos: usize) -> Result<u8, String> {
+        Ok(self.bytes(pos, 1)?[0])
+    }
+
+    fn u32(&self, pos: usize) -> Result<u32, String> {
+        let b = self.bytes(pos, 4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn string(&self, pos: usize) -> Result<String, String> {
+        let rest = self
+            .data
+            .get(pos..)
+            .ok_or_else(|| format!("truncated index at offset {}", pos))?;
+        let end = rest
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(|| format!("unterminated string at offset {}", pos))?;
+        Ok(String::from_utf8_lossy(&rest[..end]).into_owned())
+    }
+}
+
+/// Builds a kmod index holding `entries`, one trie node per key below a root with children.
+/// Keys must be non-empty; keys sharing their first character must be identical.
+#[cfg(test)]
+pub(crate) fn build_test_index(entries: &[(&str, &str)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&INDEX_MAGIC.to_be_bytes());
+    data.extend_from_slice(&0x0002_0001u32.to_be_bytes());
+    data.extend_from_slice(&12u32.to_be_bytes());
+
+    // Root node: one child per distinct first character, each child holding the rest of the key
+    // as prefix and the values.
+    let mut firsts: Vec<u8> = entries.iter().map(|(k, _)| k.as_bytes()[0]).collect();
+    firsts.sort();
+    firsts.dedup();
+    let (first, last) = (firsts[0], firsts[firsts.len() - 1]);
+    let children_table = data.len() + 2;
+    data.push(first);
+    data.push(last);
+    data.resize(children_table + 4 * usize::from(last - first + 1), 0);
+
+    for ch in firsts {
+        let offset = data.len() as u32;
+        let slot = children_table + 4 * usize::from(ch - first);
+        data[slot..slot + 4].copy_from_slice(&(offset | NODE_PREFIX | NODE_VALUES).to_be_bytes());
+        let matching: Vec<_> = entries
+            .iter()
+            .filter(|(k, _)| k.as_bytes()[0] == ch)
+            .collect();
+        data.extend_from_slice(&matching[0].0.as_bytes()[1..]);
+        data.push(0);
+        data.extend_from_slice(&(matching.len() as u32).to_be_bytes());
+        for (priority, (_, value)) in matching.iter().enumerate() {
+            data.extend_from_slice(&(priority as u32).to_be_bytes());
+            data.extend_from_slice(value.as_bytes());
+            data.push(0);
+        }
+    }
+    let root = 12u32 | NODE_CHILDS;
+    data[8..12].copy_from_slice(&root.to_be_bytes());
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_index() {
+        let data = build_test_index(&[
+            ("e1000e", "kernel/drivers/net/e1000e.ko: kernel/net/ptp.ko"),
+            ("ptp", "kernel/net/ptp.ko:"),
+        ]);
+
+        let entries = parse_index(&data).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].key, "e1000e");
+        assert_eq!(
+            entries[0].value,
+            "kernel/drivers/net/e1000e.ko: kernel/net/ptp.ko"
+        );
+        assert_eq!(entries[1].key, "ptp");
+        assert_eq!(entries[1].value, "kernel/net/ptp.ko:");
+    }
+
+    #[test]
+    fn test_parse_index_bad_magic() {
+        assert!(parse_index(&[0u8; 12]).is_err());
+    }
+}
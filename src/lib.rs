@@ -1,6 +1,114 @@
+#[cfg(feature = "native")]
+pub mod amdgpu;
+#[cfg(feature = "native")]
+pub mod atomic;
+#[cfg(feature = "native")]
+pub mod attributes;
+#[cfg(feature = "native")]
+pub mod backup;
+#[cfg(feature = "native")]
+pub mod changes;
+#[cfg(feature = "native")]
+pub mod clock;
+#[cfg(feature = "native")]
+pub mod command;
+#[cfg(feature = "native")]
+pub mod compare;
+#[cfg(feature = "native")]
+pub mod compress;
+#[cfg(feature = "native")]
 pub mod config;
+#[cfg(feature = "native")]
+pub mod dedupe;
+#[cfg(feature = "native")]
+pub mod deleter;
+#[cfg(feature = "native")]
+pub mod devicetree;
+#[cfg(feature = "native")]
+pub mod depmod;
+#[cfg(feature = "native")]
+pub mod distro;
+#[cfg(feature = "native")]
+pub mod doctor;
+#[cfg(feature = "native")]
+pub mod dracut;
+#[cfg(feature = "native")]
 pub mod driver;
+#[cfg(feature = "native")]
+pub mod erofs;
 pub mod error;
+#[cfg(feature = "native")]
+pub mod explain;
+#[cfg(feature = "native")]
 pub mod firmware;
+#[cfg(feature = "native")]
+pub mod forecast;
+#[cfg(feature = "native")]
+pub mod hardware;
+#[cfg(feature = "native")]
+pub mod initramfs;
+#[cfg(feature = "native")]
+pub mod interrupt;
+#[cfg(feature = "native")]
+pub mod iwlwifi;
+#[cfg(feature = "native")]
+pub mod janitor_fs;
+#[cfg(feature = "native")]
+pub mod journal;
+#[cfg(feature = "native")]
+pub mod kernel_graph;
+#[cfg(feature = "native")]
+pub mod kiwi;
+#[cfg(feature = "native")]
+pub mod kmod_index;
+#[cfg(feature = "native")]
+pub mod manifest;
+#[cfg(feature = "native")]
+pub mod microcode;
+#[cfg(feature = "native")]
+pub mod mkosi;
+#[cfg(feature = "native")]
+pub mod modinfo;
+#[cfg(feature = "native")]
+pub mod modinfo_cache;
+#[cfg(feature = "native")]
+pub mod modprobe;
+#[cfg(feature = "native")]
+pub mod oci;
+#[cfg(feature = "native")]
+pub mod owners;
+#[cfg(feature = "native")]
+pub mod plan;
+pub mod policy;
+#[cfg(feature = "native")]
+pub mod profile;
+#[cfg(feature = "native")]
+pub mod quarantine;
+#[cfg(feature = "remote")]
+pub mod remote;
+#[cfg(feature = "native")]
+pub mod sbom;
+#[cfg(feature = "native")]
+pub mod snapshot;
+#[cfg(feature = "native")]
+pub mod sof;
+#[cfg(feature = "native")]
+pub mod squashfs;
+#[cfg(feature = "native")]
+pub mod state;
+#[cfg(feature = "native")]
+pub mod stats;
+#[cfg(feature = "native")]
+pub mod strip;
+#[cfg(feature = "native")]
+pub mod subcommand;
+#[cfg(feature = "native")]
+pub mod summary;
+#[cfg(feature = "testbed")]
+pub mod testbed;
+#[cfg(feature = "native")]
+pub mod trace;
+#[cfg(feature = "native")]
+pub mod usage;
+#[cfg(feature = "native")]
 pub mod util;
-pub mod command;
@@ -1,89 +1,2808 @@
 use anyhow::Result;
-use clap::Parser;
-use env_logger::Env;
-use image_janitor::{command::SystemCommandRunner, driver, firmware};
-use log::info;
-use std::path::PathBuf;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, FromArgMatches, Parser, ValueEnum};
+use glob::Pattern;
+use image_janitor::backup;
+use image_janitor::changes::{self, TreeSnapshot};
+use image_janitor::clock::SystemClock;
+use image_janitor::compare;
+use image_janitor::compress::{self, Compression, CompressOptions};
+use image_janitor::config;
+use image_janitor::dedupe;
+use image_janitor::devicetree;
+use image_janitor::distro;
+use image_janitor::erofs::{self, InspectOptions};
+use image_janitor::error::JanitorError;
+use image_janitor::explain;
+use image_janitor::doctor::{self, DoctorOptions, Severity};
+use image_janitor::driver::DriverOptions;
+use image_janitor::firmware::FirmwareOptions;
+use image_janitor::forecast::{self, ForecastOptions};
+use image_janitor::hardware;
+use image_janitor::initramfs;
+use image_janitor::journal;
+use image_janitor::kernel_graph::KernelGraph;
+use image_janitor::kiwi;
+use image_janitor::manifest::ListFormat;
+use image_janitor::microcode::{Cpu, MicrocodePolicy};
+use image_janitor::mkosi::{self, MkosiConfig, MkosiOptions};
+use image_janitor::modinfo_cache::ModinfoCache;
+use image_janitor::owners::{self, Owners, PackageDb};
+use image_janitor::plan::{self, ApplyOptions, Plan};
+use image_janitor::policy::Rules;
+use image_janitor::profile::{self, Profile};
+use image_janitor::quarantine;
+#[cfg(feature = "remote")]
+use image_janitor::remote;
+use image_janitor::sbom;
+use image_janitor::snapshot::{Btrfs, Snapper, SnapshotProvider};
+use image_janitor::oci::{self, OciOptions};
+use image_janitor::squashfs::{self, SquashfsOptions};
+use image_janitor::state::{self, Input, Run, State};
+use image_janitor::stats::{self, Stats};
+use image_janitor::subcommand::{ArgsSubcommand, Context, Registry, Subcommand};
+use image_janitor::summary::CleanupSummary;
+use image_janitor::trace;
+use image_janitor::usage;
+use image_janitor::util::{self, KernelSelection, ScanOptions};
+use image_janitor::command::{ChrootMode, CommandRunner, SystemCommandRunner};
+use image_janitor::{driver, firmware, interrupt};
+use log::{error, info, warn};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::{self, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::FmtContext;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+use std::io::IsTerminal;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_required = true)]
 struct Cli {
-    #[command(subcommand)]
-    command: Commands,
+    /// Enable verbose logging.
+    #[arg(short, long, default_value_t = false)]
+    verbose: bool,
+
+    /// Format of the logs written to the standard error.
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Root directory of the image being cleaned, system configuration (e.g. modprobe.d) is read below it,
+    /// and the module and firmware directories default to the ones below it.
+    #[arg(long, global = true)]
+    root: Option<PathBuf>,
+
+    /// Maximum number of parallel jobs used for scanning and deleting (defaults to the number of CPUs).
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Allow --delete on the module and firmware directories of the running system when no --root is given.
+    #[arg(long, global = true)]
+    force_live: bool,
+
+    /// Read the metadata of every module instead of reusing the one cached by earlier runs.
+    #[arg(long, global = true)]
+    no_cache: bool,
+
+    /// Snapshot the root (--root, or the running system) with this tool before deleting anything.
+    #[arg(long, global = true, value_enum)]
+    snapshot: Option<SnapshotKind>,
+
+    /// Run depmod, the package managers and the initramfs tools inside --root, with chroot or systemd-nspawn, instead of the host ones.
+    #[arg(long, global = true, value_enum, num_args = 0..=1, default_missing_value = "chroot", requires = "root")]
+    chroot: Option<ChrootKind>,
+}
+
+/// Formats of the logs.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    /// One line per event, prefixed with the phases it belongs to.
+    Text,
+    /// One JSON object per event, with its level, target, fields and the spans of the phases it belongs
+    /// to, for log collectors.
+    Json,
+    /// The message after a ‣ like the logs of mkosi, the default of the mkosi command when run by mkosi.
+    Mkosi,
+}
+
+/// Formats the events like the logs of mkosi: the message and its fields after a `‣`, warnings and
+/// errors naming their level.
+struct MkosiFormat;
+
+impl<S, N> FormatEvent<S, N> for MkosiFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: format::Writer<'_>, event: &Event<'_>) -> std::fmt::Result {
+        write!(writer, "‣ ")?;
+        let level = *event.metadata().level();
+        if level <= Level::WARN {
+            write!(writer, "{}: ", level.as_str().to_lowercase())?;
+        }
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+/// Tools taking the snapshot before a destructive run.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum SnapshotKind {
+    /// A numbered snapper snapshot.
+    Snapper,
+    /// A read-only btrfs snapshot in the .snapshots directory of the root subvolume.
+    Btrfs,
+}
+
+/// Tools running the commands inside the root.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ChrootKind {
+    Chroot,
+    Nspawn,
+}
+
+impl Cli {
+    /// Returns the runner of the external commands, inside --root with --chroot.
+    fn runner(&self) -> SystemCommandRunner {
+        match (&self.root, self.chroot) {
+            (Some(root), Some(ChrootKind::Chroot)) => SystemCommandRunner::in_root(root, ChrootMode::Chroot),
+            (Some(root), Some(ChrootKind::Nspawn)) => SystemCommandRunner::in_root(root, ChrootMode::Nspawn),
+            _ => SystemCommandRunner::default(),
+        }
+    }
+
+    /// Returns the graph the cleanups share, backed by the modinfo cache unless disabled.
+    fn kernel_graph(&self) -> KernelGraph {
+        match ModinfoCache::default_path().filter(|_| !self.no_cache) {
+            Some(path) => KernelGraph::with_cache(ModinfoCache::open(&path)),
+            None => KernelGraph::new(),
+        }
+    }
+}
+
+/// What the firmware cleanup does with the CPU microcode (amd-ucode and intel-ucode).
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum MicrocodeMode {
+    /// Keep all of it.
+    Keep,
+    /// Delete all of it, e.g. for virtual machine images.
+    Delete,
+    /// Keep only the microcode of the CPU of this machine, as read from /proc/cpuinfo.
+    MatchCpu,
+}
+
+impl MicrocodeMode {
+    /// Returns the policy of the firmware cleanup, reading the CPU of this machine if needed.
+    fn policy(self) -> Result<MicrocodePolicy> {
+        Ok(match self {
+            MicrocodeMode::Keep => MicrocodePolicy::Keep,
+            MicrocodeMode::Delete => MicrocodePolicy::Delete,
+            MicrocodeMode::MatchCpu => MicrocodePolicy::MatchCpu(Cpu::read()?),
+        })
+    }
+}
+
+/// How the cleanup commands report their decisions on the standard output, in addition to the logs.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    /// Only the logs.
+    Human,
+    /// One CSV row per scanned file: path, type, size, decision and reason.
+    Csv,
+    /// SPDX JSON listing the firmware files left after the cleanup with their WHENCE licences.
+    Sbom,
+}
+
+/// Options of the cleanup commands reporting their decisions.
+#[derive(clap::Args)]
+struct DecisionArgs {
+    /// Write every scanned file with its keep or delete decision and the reason as JSON lines to this file.
+    #[arg(long)]
+    explain: Option<PathBuf>,
+
+    /// Print the decisions on the standard output in this format, e.g. csv for spreadsheets.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    output: OutputFormat,
+
+    /// Look up the package owning each deleted file in the rpm or dpkg database of the image, and report
+    /// the deletions per package and the packages whose files are all deleted.
+    #[arg(long)]
+    package_owners: bool,
+
+    /// Write a shell script removing the packages whose files are all deleted with the package manager of
+    /// the image, to run instead of deleting their files.
+    #[arg(long, requires = "package_owners")]
+    package_script: Option<PathBuf>,
+}
+
+impl DecisionArgs {
+    /// Returns the report the decisions of a cleanup are recorded in: the explanation report if
+    /// requested, or a temporary one when they are only printed as CSV or annotated with their
+    /// package owners.
+    fn decisions_path(&self) -> Result<Option<PathBuf>> {
+        if self.explain.is_none() && (self.output == OutputFormat::Csv || self.package_owners) {
+            let report = tempfile::Builder::new().prefix("image-janitor-").suffix(".jsonl").tempfile()?;
+            return Ok(Some(report.keep()?.1));
+        }
+        explain_path(&self.explain)
+    }
+
+    /// Prints the decisions recorded in `report` in the requested format, removing the report
+    /// unless it is the explanation the user asked for. With --package-owners, the deleted files
+    /// are annotated with the package owning them in the image, in the explanation report too, and
+    /// the packages whose files below `dirs` are all deleted are suggested for removal.
+    fn print(
+        &self,
+        cli: &Cli,
+        report: &Option<PathBuf>,
+        dirs: &[&Path],
+        runner: &dyn CommandRunner,
+    ) -> Result<()> {
+        let Some(report) = report
+            .as_deref()
+            .filter(|_| self.output == OutputFormat::Csv || self.package_owners)
+        else {
+            return Ok(());
+        };
+        let mut decisions = explain::read_explanation(report)?;
+        if self.explain.is_none() {
+            fs::remove_file(report)?;
+        }
+        if self.package_owners {
+            self.report_owners(cli, &mut decisions, dirs, runner)?;
+            if self.explain.is_some() {
+                explain::write_explanation(report, &decisions)?;
+            }
+        }
+        if self.output == OutputFormat::Csv {
+            explain::write_csv(&decisions, &mut std::io::stdout().lock())?;
+        }
+        Ok(())
+    }
+
+    /// Records the package owning each deleted file in `decisions`, from the package database of
+    /// the image, logs the deletions per package and suggests the removal of the packages whose
+    /// files below `dirs` are all deleted.
+    fn report_owners(
+        &self,
+        cli: &Cli,
+        decisions: &mut [explain::Decision],
+        dirs: &[&Path],
+        runner: &dyn CommandRunner,
+    ) -> Result<()> {
+        let root = cli.root.clone().unwrap_or_else(|| PathBuf::from("/"));
+        let Some(db) = PackageDb::detect(&root) else {
+            warn!("No rpm or dpkg database in {}, package owners unknown", root.display());
+            return Ok(());
+        };
+        let owners = Owners::read(&root, db, runner)?;
+        owners.annotate(decisions);
+        for (package, files, size) in owners::summarize(decisions) {
+            info!(
+                "{}: {} files, {} bytes ({} MiB)",
+                package.as_deref().unwrap_or("(no package)"),
+                files,
+                size,
+                size >> 20
+            );
+        }
+        let removable = owners.removable_packages(decisions, dirs);
+        for package in &removable {
+            info!("Suggested removal of package {}, none of its files is used", package);
+        }
+        if let Some(path) = &self.package_script {
+            owners::write_removal_script(path, &root, db, &removable)?;
+            info!("Package removal script written to {}", path.display());
+        }
+        Ok(())
+    }
+}
+
+/// Options shared by the commands scanning kernel modules.
+#[derive(clap::Args)]
+struct ScanArgs {
+    /// Skip the matching subtrees of the kernel directory while scanning (e.g. 'kernel/drivers/gpu/drm/amd/*').
+    #[arg(long)]
+    scan_exclude: Vec<Pattern>,
+
+    /// Process the kernel with this version instead of the latest one (repeatable).
+    #[arg(long = "kernel-version", conflicts_with = "all_kernels")]
+    kernel_versions: Vec<String>,
+
+    /// Process every installed kernel instead of the latest one.
+    #[arg(long)]
+    all_kernels: bool,
+}
+
+impl ScanArgs {
+    fn to_options(&self) -> ScanOptions {
+        let kernels = if self.all_kernels {
+            KernelSelection::All
+        } else if !self.kernel_versions.is_empty() {
+            KernelSelection::Versions(self.kernel_versions.clone())
+        } else {
+            KernelSelection::Latest
+        };
+        ScanOptions {
+            exclude: self.scan_exclude.clone(),
+            kernels,
+        }
+    }
+
+    /// The options as given on the command line.
+    fn describe(&self) -> Vec<String> {
+        let mut options: Vec<String> = self
+            .scan_exclude
+            .iter()
+            .map(|p| format!("--scan-exclude={}", p))
+            .collect();
+        if self.all_kernels {
+            options.push("--all-kernels".to_string());
+        }
+        options.extend(
+            self.kernel_versions
+                .iter()
+                .map(|v| format!("--kernel-version={}", v)),
+        );
+        options
+    }
+}
+
+/// Options of the commands running both cleanups.
+#[derive(clap::Args)]
+struct CleanupArgs {
+    /// Directory with kernel modules.
+    #[arg(long, default_value = "/lib/modules")]
+    module_dir: PathBuf,
+
+    /// Directory with firmware files.
+    #[arg(long, default_value = "/lib/firmware")]
+    firmware_dir: PathBuf,
+
+    /// Paths to module list configuration files, or drop-in directories of *.list and *.conf fragments.
+    #[arg(long, default_value = "module.list,module.list.extra")]
+    config_files: String,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+
+    /// Keep only the modules matching the modaliases listed in this file (one per line)
+    /// through modules.alias, plus their dependencies, instead of using the config files.
+    #[arg(long)]
+    modalias_file: Option<PathBuf>,
+
+    /// Keep only the modules whose modules.alias entries can match the PCI or USB IDs listed in this
+    /// file (one "[pci|usb] VVVV:DDDD" per line), plus their dependencies, instead of using the config files.
+    #[arg(long, conflicts_with = "modalias_file")]
+    hardware_ids: Option<PathBuf>,
+
+    /// Also keep the platform drivers matching the nodes of this flattened device tree (DTB) of the target
+    /// board through their of: aliases in modules.alias.
+    #[arg(long)]
+    dtb: Option<PathBuf>,
+
+    /// Also keep the modules and firmware loaded on the machine of this hardware profile (see capture-profile).
+    #[arg(long)]
+    profile: Option<PathBuf>,
+
+    /// Also keep the firmware a reference system failed to load according to this kernel log, saved with dmesg
+    /// or journalctl -k (repeatable).
+    #[arg(long = "firmware-log")]
+    firmware_logs: Vec<PathBuf>,
+
+    /// Extra firmware tree merged into the image later: its files satisfy requirements but are never deleted.
+    #[arg(long = "firmware-overlay")]
+    firmware_overlays: Vec<PathBuf>,
+
+    /// Directory of out-of-tree modules installed outside of the module directory, e.g. by vendor packages:
+    /// the firmware they require is kept too (repeatable).
+    #[arg(long = "extra-module-dir")]
+    extra_module_dirs: Vec<PathBuf>,
+
+    /// Never delete the matching firmware files, in addition to WHENCE, LICENSE.*, LICENCE.* and regulatory.db*.
+    #[arg(long)]
+    protect: Vec<Pattern>,
+
+    /// Keep the firmware matching this pattern, relative to the firmware directory, whatever the modules require.
+    #[arg(long)]
+    keep: Vec<Pattern>,
+
+    /// Paths to firmware configuration files, in the module list format with paths relative to the firmware directory.
+    #[arg(long)]
+    firmware_config_files: Option<String>,
+
+    /// Follow firmware symlinks leaving the firmware directory, keeping the firmware their chains lead back to.
+    #[arg(long)]
+    follow_external_symlinks: bool,
+
+    /// Keep only the amdgpu firmware of these GPU generations, IP blocks with their version or ASIC names
+    /// (comma separated, e.g. dcn31,vcn4,navi10), even if the amdgpu module requires the others.
+    #[arg(long, value_delimiter = ',')]
+    amdgpu_generations: Vec<String>,
+
+    /// Keep this many older API versions of the iwlwifi ucode as fallback, besides the newest one the kernel loads.
+    #[arg(long, default_value_t = 0)]
+    iwlwifi_fallback_versions: usize,
+
+    /// Keep only the Sound Open Firmware (intel/sof*) of these Intel platforms (comma separated, e.g. tgl,adl),
+    /// even if the snd-sof drivers require the others.
+    #[arg(long, value_delimiter = ',')]
+    sof_platforms: Vec<String>,
+
+    /// What to do with the CPU microcode, which no module requires.
+    #[arg(long, value_enum, default_value = "keep")]
+    microcode: MicrocodeMode,
+
+    /// Also delete the binary module indexes (modules.*.bin), when depmod is guaranteed to run again,
+    /// e.g. on first boot.
+    #[arg(long)]
+    drop_binary_indexes: bool,
+
+    /// Also strip the debug sections of the kept modules with strip --strip-debug, signed modules excepted.
+    #[arg(long)]
+    strip_debug: bool,
+
+    /// Also delete the build and source symlinks, *.symvers and .cmd files of the kernel directories,
+    /// only needed to build modules.
+    #[arg(long)]
+    remove_devel_files: bool,
+
+    /// Regenerate the initramfs of the cleaned kernels afterwards (with --delete), with update-initramfs on
+    /// Debian and Ubuntu images and dracut on the others, in a chroot when --root is given.
+    #[arg(long, conflicts_with = "drop_binary_indexes")]
+    regenerate_initramfs: bool,
+
+    /// Only regenerate the initramfs images found to contain deleted modules (with --delete).
+    #[arg(long, conflicts_with_all = ["drop_binary_indexes", "regenerate_initramfs"])]
+    regenerate_stale_initramfs: bool,
+
+    /// Only delete the modules and firmware files of at least this many bytes, smaller ones are kept.
+    #[arg(long, default_value_t = 0)]
+    min_size: u64,
+
+    /// Only delete the largest unused modules until each kernel module tree fits in this size, in bytes or with
+    /// a unit (e.g. 200MiB), and keep the others. Modules deleted by a rule or as blacklisted always go.
+    #[arg(long, value_parser = util::parse_size)]
+    module_target_size: Option<u64>,
+
+    /// Only delete the largest unused firmware files until the firmware directory fits in this size, in bytes or
+    /// with a unit (e.g. 800MiB), and keep the others. The firmware dropped by a rule or option always goes.
+    #[arg(long, value_parser = util::parse_size)]
+    firmware_target_size: Option<u64>,
+
+    /// Delete the modules blacklisted in the modprobe.d configuration of the image, and the modules only
+    /// kept for them, even when the configuration keeps them.
+    #[arg(long)]
+    delete_blacklisted: bool,
+}
+
+impl CleanupArgs {
+    /// Runs the driver cleanup, then the firmware cleanup on the modules it keeps.
+    #[allow(clippy::too_many_arguments)]
+    fn run(
+        &self,
+        cli: &Cli,
+        delete: bool,
+        backup: Option<PathBuf>,
+        journal: Option<PathBuf>,
+        quarantine: Option<PathBuf>,
+        explain: Option<PathBuf>,
+        context: &Context,
+    ) -> Result<Vec<PathBuf>> {
+        check_live(cli, delete, &[&self.module_dir, &self.firmware_dir])?;
+        take_snapshot(cli, delete, "cleanup-all", context.runner)?;
+        info!(
+            "Cleanup running. Delete: {}, Module Dir: {}, Firmware Dir: {}",
+            delete,
+            self.module_dir.display(),
+            self.firmware_dir.display()
+        );
+        let profile = self.profile.as_deref().map(Profile::read).transpose()?;
+        let driver_options = DriverOptions {
+            config_paths: self.config_files.split(',').map(String::from).collect(),
+            extra_rules: Vec::new(),
+            module_dir: self.module_dir.clone(),
+            root: cli.root.clone().unwrap_or_else(|| PathBuf::from("/")),
+            delete,
+            scan: self.scan.to_options(),
+            modaliases: self
+                .modalias_file
+                .as_deref()
+                .map(util::read_list_file)
+                .transpose()?,
+            hardware_ids: self
+                .hardware_ids
+                .as_deref()
+                .map(hardware::read_hardware_ids)
+                .transpose()?,
+            device_tree: device_tree_modaliases(&self.dtb)?,
+            profile: profile.clone(),
+            backup: backup.clone(),
+            journal: journal.clone(),
+            clock: None,
+            quarantine: quarantine.clone(),
+            explain: explain.clone(),
+            dot: None,
+            drop_binary_indexes: self.drop_binary_indexes,
+            strip_debug: self.strip_debug,
+            remove_devel_files: self.remove_devel_files,
+            min_size: self.min_size,
+            target_size: self.module_target_size,
+            delete_blacklisted: self.delete_blacklisted,
+            kiwi_drivers: None,
+            fs: None,
+            interrupted: None,
+        };
+        let firmware_options = FirmwareOptions {
+            module_dir: self.module_dir.clone(),
+            extra_module_dirs: self.extra_module_dirs.clone(),
+            firmware_dir: self.firmware_dir.clone(),
+            delete,
+            scan: self.scan.to_options(),
+            overlays: self.firmware_overlays.clone(),
+            profile,
+            failed_firmware: read_firmware_failures(&self.firmware_logs)?,
+            protect: self.protect.clone(),
+            keep: self.keep.clone(),
+            rules: firmware_rules(&self.firmware_config_files, context.runner)?,
+            follow_external_symlinks: self.follow_external_symlinks,
+            root: cli.root.clone(),
+            min_size: self.min_size,
+            target_size: self.firmware_target_size,
+            amdgpu_generations: self.amdgpu_generations.clone(),
+            iwlwifi_fallback_versions: self.iwlwifi_fallback_versions,
+            sof_platforms: self.sof_platforms.clone(),
+            microcode: self.microcode.policy()?,
+            backup,
+            journal,
+            clock: None,
+            quarantine,
+            explain,
+            emit_required: None,
+            emit_format: ListFormat::Paths,
+            install_dir: None,
+            fs: None,
+        };
+        // The modules are scanned once, and the firmware pass only sees the modules kept.
+        let mut summary = driver::cleanup_drivers(&driver_options, context.graph, context.runner)?;
+        let drivers = summary.deleted.len();
+        // The driver deletions already done are reported along if the firmware pass is interrupted.
+        let firmware = firmware::cleanup_firmware(&firmware_options, context.graph).map_err(|e| match e {
+            JanitorError::Interrupted { deleted, bytes } => JanitorError::Interrupted {
+                deleted: summary.deleted.iter().cloned().chain(deleted).collect(),
+                bytes: summary.bytes_reclaimed + bytes,
+            },
+            e => e,
+        })?;
+        summary.merge(firmware);
+        if self.regenerate_initramfs && delete {
+            update_initramfs(cli, &self.module_dir, &driver_options.scan, context.runner)?;
+        } else {
+            check_initramfs(cli, &self.module_dir, &summary.deleted, delete, self.regenerate_stale_initramfs, context.runner)?;
+        }
+        info!(
+            "{} {} drivers and {} firmware files",
+            if delete { "Deleted" } else { "Would delete" },
+            drivers,
+            summary.deleted.len() - drivers
+        );
+        log_summary(&summary, delete);
+        Ok(summary.deleted)
+    }
+}
+
+/// Arguments of the driver-cleanup subcommand.
+#[derive(clap::Args)]
+struct DriverCleanupArgs {
+    /// Really delete the files.
+    #[arg(long)]
+    delete: bool,
+
+    /// Directory with kernel modules.
+    #[arg(long, default_value = "/lib/modules")]
+    module_dir: PathBuf,
+
+    /// Paths to module list configuration files, or drop-in directories of *.list and *.conf fragments.
+    #[arg(long, default_value = "module.list,module.list.extra")]
+    config_files: String,
+
+    /// Write a verified before/after diff of the module directory to this file (with --delete).
+    #[arg(long)]
+    changed_report: Option<PathBuf>,
+
+    /// Save the deleted files to this zstd compressed tar archive first, see the restore command (with --delete).
+    #[arg(long)]
+    backup: Option<PathBuf>,
+
+    /// Record every deletion with its size, hash and reason in this JSON lines journal, see the undo command (with --delete).
+    #[arg(long)]
+    journal: Option<PathBuf>,
+
+    /// Move the deleted files to a timestamped mirror tree below this directory instead of unlinking them,
+    /// see the purge-quarantine command (with --delete).
+    #[arg(long)]
+    quarantine: Option<PathBuf>,
+
+    /// Also delete the binary module indexes (modules.*.bin), when depmod is guaranteed to run again,
+    /// e.g. on first boot. The requirement is recorded with --write-state.
+    #[arg(long)]
+    drop_binary_indexes: bool,
+
+    /// Also strip the debug sections of the kept modules with strip --strip-debug, signed modules excepted.
+    #[arg(long)]
+    strip_debug: bool,
+
+    /// Also delete the build and source symlinks, *.symvers and .cmd files of the kernel directories,
+    /// only needed to build modules.
+    #[arg(long)]
+    remove_devel_files: bool,
+
+    /// Regenerate the initramfs of the cleaned kernels afterwards (with --delete), with update-initramfs on
+    /// Debian and Ubuntu images and dracut on the others, in a chroot when --root is given.
+    #[arg(long, conflicts_with = "drop_binary_indexes")]
+    regenerate_initramfs: bool,
+
+    /// Only regenerate the initramfs images found to contain deleted modules (with --delete).
+    #[arg(long, conflicts_with_all = ["drop_binary_indexes", "regenerate_initramfs"])]
+    regenerate_stale_initramfs: bool,
+
+    /// Only delete the modules of at least this many bytes, smaller ones are kept.
+    #[arg(long, default_value_t = 0)]
+    min_size: u64,
+
+    /// Only delete the largest unused modules until each kernel module tree fits in this size, in bytes or
+    /// with a unit (e.g. 800MiB), and keep the others. Modules deleted by a rule or as blacklisted always go.
+    #[arg(long, value_parser = util::parse_size)]
+    target_size: Option<u64>,
+
+    /// Delete the modules blacklisted in the modprobe.d configuration of the image, and the modules
+    /// only kept for them, even when the configuration keeps them.
+    #[arg(long)]
+    delete_blacklisted: bool,
+
+    /// Write the module dependency graph in the DOT language to this file, kept modules in green.
+    #[arg(long)]
+    graph: Option<PathBuf>,
+
+    #[command(flatten)]
+    decisions: DecisionArgs,
+
+    /// Record the kernel, input hashes and options of this run in <root>/usr/lib/image-janitor/state.json (with --delete).
+    #[arg(long)]
+    write_state: bool,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+
+    /// Keep only the modules matching the modaliases listed in this file (one per line)
+    /// through modules.alias, plus their dependencies, instead of using the config files.
+    #[arg(long)]
+    modalias_file: Option<PathBuf>,
+
+    /// Keep only the modules whose modules.alias entries can match the PCI or USB IDs listed in this
+    /// file (one "[pci|usb] VVVV:DDDD" per line), plus their dependencies, instead of using the config files.
+    #[arg(long, conflicts_with = "modalias_file")]
+    hardware_ids: Option<PathBuf>,
+
+    /// Also keep the platform drivers matching the nodes of this flattened device tree (DTB) of the target
+    /// board through their of: aliases in modules.alias.
+    #[arg(long)]
+    dtb: Option<PathBuf>,
+
+    /// Also keep the modules listed in the <drivers> sections of this KIWI image description, the default
+    /// configuration files being optional then.
+    #[arg(long, conflicts_with_all = ["modalias_file", "hardware_ids"])]
+    kiwi: Option<PathBuf>,
+
+    /// Profiles of the KIWI description being built (comma separated), the imported ones by default.
+    #[arg(long, value_delimiter = ',', requires = "kiwi")]
+    kiwi_profile: Vec<String>,
+
+    /// Write the modules kept to this file as a KIWI <drivers> section.
+    #[arg(long)]
+    emit_kiwi_drivers: Option<PathBuf>,
+
+    /// Also keep the modules loaded on the machine of this hardware profile (see capture-profile).
+    #[arg(long)]
+    profile: Option<PathBuf>,
+}
+
+/// Arguments of the fw-cleanup subcommand.
+#[derive(clap::Args)]
+struct FwCleanupArgs {
+    /// Really delete the files.
+    #[arg(long)]
+    delete: bool,
+
+    /// Directory with kernel modules.
+    #[arg(long, default_value = "/lib/modules")]
+    module_dir: PathBuf,
+
+    /// Directory with firmware files.
+    #[arg(long, default_value = "/lib/firmware")]
+    firmware_dir: PathBuf,
+
+    /// Write a verified before/after diff of the firmware directory to this file (with --delete).
+    #[arg(long)]
+    changed_report: Option<PathBuf>,
+
+    /// Save the deleted files to this zstd compressed tar archive first, see the restore command (with --delete).
+    #[arg(long)]
+    backup: Option<PathBuf>,
+
+    /// Record every deletion with its size, hash and reason in this JSON lines journal, see the undo command (with --delete).
+    #[arg(long)]
+    journal: Option<PathBuf>,
+
+    /// Move the deleted files to a timestamped mirror tree below this directory instead of unlinking them,
+    /// see the purge-quarantine command (with --delete).
+    #[arg(long)]
+    quarantine: Option<PathBuf>,
+
+    #[command(flatten)]
+    decisions: DecisionArgs,
+
+    /// Record the kernel, input hashes and options of this run in <root>/usr/lib/image-janitor/state.json (with --delete).
+    #[arg(long)]
+    write_state: bool,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+
+    /// Extra firmware tree merged into the image later: its files satisfy requirements but are never deleted.
+    #[arg(long = "firmware-overlay")]
+    firmware_overlays: Vec<PathBuf>,
+
+    /// Directory of out-of-tree modules installed outside of the module directory, e.g. by vendor packages:
+    /// the firmware they require is kept too (repeatable).
+    #[arg(long = "extra-module-dir")]
+    extra_module_dirs: Vec<PathBuf>,
+
+    /// Also keep the firmware loaded on the machine of this hardware profile (see capture-profile).
+    #[arg(long)]
+    profile: Option<PathBuf>,
+
+    /// Also keep the firmware a reference system failed to load according to this kernel log, saved with
+    /// dmesg or journalctl -k (repeatable).
+    #[arg(long = "firmware-log")]
+    firmware_logs: Vec<PathBuf>,
+
+    /// Never delete the matching files, in addition to WHENCE, LICENSE.*, LICENCE.* and regulatory.db*
+    /// (repeatable, patterns without '/' match file names in any directory).
+    #[arg(long)]
+    protect: Vec<Pattern>,
+
+    /// Keep the firmware matching this pattern, relative to the firmware directory, whatever the modules
+    /// require (repeatable, e.g. 'ath10k/*' keeps the whole subtree).
+    #[arg(long)]
+    keep: Vec<Pattern>,
+
+    /// Paths to firmware configuration files, in the module list format with paths relative to the firmware
+    /// directory: keep rules keep firmware, delete rules drop it even if a module requires it.
+    #[arg(long)]
+    firmware_config_files: Option<String>,
+
+    /// Follow symlinks leaving the firmware directory, e.g. into /usr/share or vendor directories, keeping
+    /// the firmware their chains lead back to. The external targets are reported, never deleted.
+    #[arg(long)]
+    follow_external_symlinks: bool,
+
+    /// Only delete the firmware files of at least this many bytes, e.g. to keep the small configuration and
+    /// NVRAM files.
+    #[arg(long, default_value_t = 0)]
+    min_size: u64,
+
+    /// Only delete the largest unused firmware files until the firmware directory fits in this size, in bytes
+    /// or with a unit (e.g. 800MiB), and keep the others. The firmware dropped by a rule or option always goes.
+    #[arg(long, value_parser = util::parse_size)]
+    target_size: Option<u64>,
+
+    /// Write the paths of the firmware files left, relative to the firmware directory, to this file, one per
+    /// line. Without --delete, nothing is deleted and the files which would be left are listed.
+    #[arg(long)]
+    emit_required: Option<PathBuf>,
+
+    /// Format of the --emit-required list: relative paths, an rpm spec %files fragment or an install manifest,
+    /// the last two with the paths the firmware is installed at, below --root when it is given.
+    #[arg(long, value_enum, default_value_t = ListFormat::Paths, requires = "emit_required")]
+    emit_format: ListFormat,
+
+    /// Keep only the amdgpu firmware of these GPU generations, IP blocks with their version or ASIC names
+    /// (comma separated, e.g. dcn31,vcn4,navi10), even if the amdgpu module requires the others.
+    #[arg(long, value_delimiter = ',')]
+    amdgpu_generations: Vec<String>,
+
+    /// Keep this many older API versions of the iwlwifi ucode as fallback, besides the newest one the
+    /// kernel loads.
+    #[arg(long, default_value_t = 0)]
+    iwlwifi_fallback_versions: usize,
+
+    /// Keep only the Sound Open Firmware (intel/sof*) of these Intel platforms (comma separated, e.g.
+    /// tgl,adl), even if the snd-sof drivers require the others.
+    #[arg(long, value_delimiter = ',')]
+    sof_platforms: Vec<String>,
+
+    /// What to do with the CPU microcode of amd-ucode and intel-ucode, which no module requires.
+    #[arg(long, value_enum, default_value = "keep")]
+    microcode: MicrocodeMode,
+}
+
+/// Arguments of the cleanup-all subcommand.
+#[derive(clap::Args)]
+struct CleanupAllArgs {
+    /// Really delete the files.
+    #[arg(long)]
+    delete: bool,
+
+    /// Save the deleted modules and firmware files to this zstd compressed tar archive first, see the restore
+    /// command (with --delete).
+    #[arg(long)]
+    backup: Option<PathBuf>,
+
+    /// Record every deletion with its size, hash and reason in this JSON lines journal (with --delete).
+    #[arg(long)]
+    journal: Option<PathBuf>,
+
+    /// Move the deleted files to a timestamped mirror tree below this directory instead of unlinking them,
+    /// see the purge-quarantine command (with --delete).
+    #[arg(long)]
+    quarantine: Option<PathBuf>,
+
+    #[command(flatten)]
+    decisions: DecisionArgs,
+
+    #[command(flatten)]
+    cleanup: CleanupArgs,
+}
+
+/// Arguments of the plan subcommand.
+#[derive(clap::Args)]
+struct PlanArgs {
+    /// File the plan is written to.
+    #[arg(long, required_unless_present = "upload")]
+    output: Option<PathBuf>,
+
+    /// Upload the plan with an HTTP PUT to this URL, authenticated by the token in $IMAGE_JANITOR_TOKEN if set
+    /// (requires the remote feature).
+    #[arg(long)]
+    upload: Option<String>,
+
+    #[command(flatten)]
+    cleanup: CleanupArgs,
+}
+
+/// Arguments of the check subcommand.
+#[derive(clap::Args)]
+struct CheckArgs {
+    /// Fail if the unused drivers and firmware weigh more than this many bytes.
+    #[arg(long)]
+    max_unused_bytes: Option<u64>,
+
+    /// Fail if a file missing from this approved plan (see the plan command) would be deleted.
+    #[arg(long)]
+    expected: Option<PathBuf>,
+
+    #[command(flatten)]
+    cleanup: CleanupArgs,
+}
+
+/// Arguments of the why-keep subcommand.
+#[derive(clap::Args)]
+struct WhyKeepArgs {
+    /// Path of the file, a path suffix such as its file name, or a module name.
+    path: String,
+
+    /// Read the decisions from this explanation report (see --explain) instead of running a dry cleanup.
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    #[command(flatten)]
+    cleanup: CleanupArgs,
+}
+
+/// Arguments of the why-delete subcommand.
+#[derive(clap::Args)]
+struct WhyDeleteArgs {
+    /// Path of the file, a path suffix such as its file name, or a module name.
+    path: String,
+
+    /// Read the decisions from this explanation report (see --explain) instead of running a dry cleanup.
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    #[command(flatten)]
+    cleanup: CleanupArgs,
+}
+
+/// Arguments of the apply subcommand.
+#[derive(clap::Args)]
+struct ApplyArgs {
+    /// Really delete the files.
+    #[arg(long)]
+    delete: bool,
+
+    /// The plan written by the plan command.
+    #[arg(long, required_unless_present = "plan_url", conflicts_with = "plan_url")]
+    plan: Option<PathBuf>,
+
+    /// Download the plan from this URL, authenticated by the token in $IMAGE_JANITOR_TOKEN if set
+    /// (requires the remote feature).
+    #[arg(long)]
+    plan_url: Option<String>,
+
+    /// Save the deleted files to this zstd compressed tar archive first, see the restore command (with --delete).
+    #[arg(long)]
+    backup: Option<PathBuf>,
+
+    /// Record every deletion with its size, hash and reason in this JSON lines journal, see the undo command (with --delete).
+    #[arg(long)]
+    journal: Option<PathBuf>,
+
+    /// Move the deleted files to a timestamped mirror tree below this directory instead of unlinking them,
+    /// see the purge-quarantine command (with --delete).
+    #[arg(long)]
+    quarantine: Option<PathBuf>,
+}
+
+/// Arguments of the doctor subcommand.
+#[derive(clap::Args)]
+struct DoctorArgs {
+    /// Directory with kernel modules.
+    #[arg(long, default_value = "/lib/modules")]
+    module_dir: PathBuf,
+
+    /// Directory with firmware files.
+    #[arg(long, default_value = "/lib/firmware")]
+    firmware_dir: PathBuf,
+
+    /// Paths to module list configuration files, or drop-in directories of *.list and *.conf fragments.
+    #[arg(long, default_value = "module.list,module.list.extra")]
+    config_files: String,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+}
+
+/// Arguments of the inspect-erofs subcommand.
+#[derive(clap::Args)]
+struct InspectErofsArgs {
+    /// The erofs image.
+    #[arg(long)]
+    image: PathBuf,
+
+    /// Paths to module list configuration files, or drop-in directories of *.list and *.conf fragments.
+    #[arg(long, default_value = "module.list,module.list.extra")]
+    config_files: String,
+
+    /// Empty or missing directory the image is extracted to, removed afterwards (defaults to a new directory in the
+    /// system temporary directory).
+    #[arg(long)]
+    work_dir: Option<PathBuf>,
+
+    /// Write the savings report as JSON to this file.
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    /// Write the paths to remove, one per line, to this file (for mkfs.erofs --exclude-path or mksquashfs -ef).
+    #[arg(long)]
+    exclude_list: Option<PathBuf>,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+}
+
+/// Arguments of the mkosi subcommand.
+#[derive(clap::Args)]
+struct MkosiArgs {
+    /// Really delete the files.
+    #[arg(long)]
+    delete: bool,
+
+    /// The mkosi configuration directory (defaults to $SRCDIR when run by mkosi, else the current directory).
+    #[arg(long)]
+    directory: Option<PathBuf>,
+
+    /// Profiles of the build (comma separated), by default $PROFILES when run by mkosi, else the Profiles=
+    /// of the configuration.
+    #[arg(long = "profile", value_delimiter = ',')]
+    profiles: Vec<String>,
+
+    /// Paths to module list configuration files, or drop-in directories of *.list and *.conf fragments, read
+    /// after the KernelModulesInclude= and KernelModulesExclude= of the configuration. Without them, the
+    /// modules these settings do not exclude are kept.
+    #[arg(long)]
+    config_files: Option<String>,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+}
+
+/// Arguments of the squashfs subcommand.
+#[derive(clap::Args)]
+struct SquashfsArgs {
+    /// The squashfs image to clean.
+    #[arg(long = "in")]
+    input: PathBuf,
+
+    /// The cleaned image to write.
+    #[arg(long = "out")]
+    output: PathBuf,
+
+    /// Paths to module list configuration files, or drop-in directories of *.list and *.conf fragments.
+    #[arg(long, default_value = "module.list,module.list.extra")]
+    config_files: String,
+
+    /// Empty or missing directory the image is unpacked to, removed afterwards (defaults to a new directory in the
+    /// system temporary directory).
+    #[arg(long)]
+    work_dir: Option<PathBuf>,
+
+    /// Write the size report as JSON to this file.
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+}
+
+/// Arguments of the oci subcommand.
+#[derive(clap::Args)]
+struct OciArgs {
+    /// The image: an OCI layout directory, or a tarball written by docker save.
+    #[arg(long = "in")]
+    input: PathBuf,
+
+    /// The cleaned image to write, in the format of the input.
+    #[arg(long = "out")]
+    output: PathBuf,
+
+    /// Paths to module list configuration files, or drop-in directories of *.list and *.conf fragments.
+    #[arg(long, default_value = "module.list,module.list.extra")]
+    config_files: String,
+
+    /// Empty or missing directory the image is extracted to, removed afterwards (defaults to a new directory in the
+    /// system temporary directory).
+    #[arg(long)]
+    work_dir: Option<PathBuf>,
+
+    /// Write the removed files and the new digests as JSON to this file.
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+}
+
+/// Arguments of the restore subcommand.
+#[derive(clap::Args)]
+struct RestoreArgs {
+    /// The backup archive.
+    #[arg(long)]
+    backup: PathBuf,
+}
+
+/// Arguments of the undo subcommand.
+#[derive(clap::Args)]
+struct UndoArgs {
+    /// The journal written with --journal.
+    #[arg(long)]
+    journal: PathBuf,
+
+    /// The backup archive written with --backup, needed for the entries not quarantined.
+    #[arg(long)]
+    backup: Option<PathBuf>,
+}
+
+/// Arguments of the purge-quarantine subcommand.
+#[derive(clap::Args)]
+struct PurgeQuarantineArgs {
+    /// Really remove the runs, instead of listing them.
+    #[arg(long)]
+    delete: bool,
+
+    /// The quarantine directory given to the cleanup commands with --quarantine.
+    #[arg(long)]
+    quarantine: PathBuf,
+
+    /// Only remove the runs started at least this long ago, e.g. 30d or 12h.
+    #[arg(long)]
+    older_than: humantime::Duration,
+}
+
+/// Arguments of the capture-profile subcommand.
+#[derive(clap::Args)]
+struct CaptureProfileArgs {
+    /// File the profile is written to.
+    #[arg(long)]
+    output: PathBuf,
+}
+
+/// Arguments of the report subcommand.
+#[derive(clap::Args)]
+struct ReportArgs {
+    /// Directory with kernel modules.
+    #[arg(long, default_value = "/lib/modules")]
+    module_dir: PathBuf,
+
+    /// Directory with firmware files.
+    #[arg(long, default_value = "/lib/firmware")]
+    firmware_dir: PathBuf,
+
+    /// Number of largest files listed per tree.
+    #[arg(long, default_value_t = 20)]
+    top: usize,
+
+    /// Write the full accounting as JSON to this file.
+    #[arg(long)]
+    json: Option<PathBuf>,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+}
+
+/// Arguments of the stats subcommand.
+#[derive(clap::Args)]
+struct StatsArgs {
+    /// Directory with kernel modules.
+    #[arg(long, default_value = "/lib/modules")]
+    module_dir: PathBuf,
+
+    /// Directory with firmware files.
+    #[arg(long, default_value = "/lib/firmware")]
+    firmware_dir: PathBuf,
+
+    /// Print the inventory as JSON instead of text.
+    #[arg(long)]
+    json: bool,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+}
+
+/// Arguments of the list-kernels subcommand.
+#[derive(clap::Args)]
+struct ListKernelsArgs {
+    /// Directory with kernel modules.
+    #[arg(long, default_value = "/lib/modules")]
+    module_dir: PathBuf,
+
+    /// Print the kernels as JSON instead of text.
+    #[arg(long)]
+    json: bool,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+}
+
+/// Arguments of the keep-list subcommand.
+#[derive(clap::Args)]
+struct KeepListArgs {
+    /// File the keep configuration is written to.
+    #[arg(long)]
+    output: PathBuf,
+
+    /// Directory with kernel modules.
+    #[arg(long, default_value = "/lib/modules")]
+    module_dir: PathBuf,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+}
+
+/// Arguments of the compare subcommand.
+#[derive(clap::Args)]
+struct CompareArgs {
+    /// Root of the first (older) image.
+    #[arg(long)]
+    root_a: PathBuf,
+
+    /// Root of the second (newer) image.
+    #[arg(long)]
+    root_b: PathBuf,
+
+    /// Number of largest differences listed per tree.
+    #[arg(long, default_value_t = 10)]
+    top: usize,
+
+    /// Write every difference as JSON to this file.
+    #[arg(long)]
+    report: Option<PathBuf>,
+}
+
+/// Arguments of the diff subcommand.
+#[derive(clap::Args)]
+struct DiffArgs {
+    /// The older plan (see the plan command) or explanation report (see --explain).
+    old: PathBuf,
+
+    /// The newer plan or explanation report.
+    new: PathBuf,
+
+    /// Write every difference as JSON to this file.
+    #[arg(long)]
+    report: Option<PathBuf>,
+}
+
+/// Arguments of the forecast subcommand.
+#[derive(clap::Args)]
+struct ForecastArgs {
+    /// Paths to module list configuration files, or drop-in directories of *.list and *.conf fragments.
+    #[arg(long, default_value = "module.list,module.list.extra")]
+    config_files: String,
+
+    /// Write the forecast as JSON to this file.
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+}
+
+/// Arguments of the config-lint subcommand.
+#[derive(clap::Args)]
+struct ConfigLintArgs {
+    /// Paths to module list configuration files, or drop-in directories of *.list and *.conf fragments.
+    #[arg(long, default_value = "module.list,module.list.extra")]
+    config_files: String,
+}
+
+/// Arguments of the fw-dedupe subcommand.
+#[derive(clap::Args)]
+struct DedupeArgs {
+    /// Really replace the duplicates with hardlinks.
+    #[arg(long)]
+    link: bool,
+
+    /// Directory with firmware files.
+    #[arg(long, default_value = "/lib/firmware")]
+    firmware_dir: PathBuf,
+}
+
+/// Arguments of the module-compress subcommand.
+#[derive(clap::Args)]
+struct CompressArgs {
+    /// Really convert the modules and run depmod.
+    #[arg(long)]
+    convert: bool,
+
+    /// Directory with kernel modules.
+    #[arg(long, default_value = "/lib/modules")]
+    module_dir: PathBuf,
+
+    /// Compression the modules are converted to.
+    #[arg(long, value_enum, default_value_t = Compression::Zstd)]
+    compression: Compression,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+}
+
+/// A built-in subcommand taking the arguments of a clap derived struct, run by a function with
+/// the global options of the command line.
+struct Builtin<A> {
+    name: &'static str,
+    about: &'static str,
+    run: fn(&A, &Cli, &Context) -> Result<()>,
+}
+
+impl<A> Builtin<A> {
+    fn new(name: &'static str, about: &'static str, run: fn(&A, &Cli, &Context) -> Result<()>) -> Self {
+        Builtin { name, about, run }
+    }
+}
+
+impl<A: clap::Args + FromArgMatches> Subcommand for Builtin<A> {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn command(&self) -> clap::Command {
+        A::augment_args(clap::Command::new(self.name)).about(self.about)
+    }
+
+    fn run(&self, matches: &ArgMatches, context: &Context) -> Result<()> {
+        let cli = Cli::from_arg_matches(context.matches)?;
+        (self.run)(&A::from_arg_matches(matches)?, &cli, context)
+    }
+}
+
+/// The subcommands of the tool, the built-in ones first. Downstream subsystems are registered
+/// here too.
+fn registry() -> Result<Registry> {
+    let mut registry = Registry::new();
+    registry.register(Builtin::new(
+        "driver-cleanup",
+        "Cleans up unused kernel drivers",
+        run_driver_cleanup,
+    ))?;
+    registry.register(Builtin::new(
+        "fw-cleanup",
+        "Cleans up unused firmware",
+        run_fw_cleanup,
+    ))?;
+    registry.register(Builtin::new(
+        "cleanup-all",
+        "Cleans up unused kernel drivers, then the firmware only the removed drivers needed",
+        run_cleanup_all,
+    ))?;
+    registry.register(Builtin::new(
+        "plan",
+        "Computes what cleanup-all would remove and saves it as a plan, to apply with the apply command",
+        run_plan,
+    ))?;
+    registry.register(Builtin::new(
+        "check",
+        "Checks what cleanup-all would remove against thresholds, without deleting anything, for CI gates",
+        run_check,
+    ))?;
+    registry.register(Builtin::new(
+        "why-keep",
+        "Prints why a module or firmware file is kept, following the modules requiring it up to the rule keeping them",
+        run_why_keep,
+    ))?;
+    registry.register(Builtin::new(
+        "why-delete",
+        "Prints why a module or firmware file is deleted: the delete rule it matched, or the keep rules closest to matching it when nothing keeps it",
+        run_why_delete,
+    ))?;
+    registry.register(Builtin::new(
+        "apply",
+        "Removes the files listed in a plan below --root, after checking they match the plan",
+        run_apply,
+    ))?;
+    registry.register(Builtin::new(
+        "doctor",
+        "Checks the setup for common misconfigurations and prints hints to fix them",
+        run_doctor,
+    ))?;
+    registry.register(Builtin::new(
+        "inspect-erofs",
+        "Reports what the cleanups would remove from an erofs image, without modifying it",
+        run_inspect_erofs,
+    ))?;
+    registry.register(Builtin::new(
+        "mkosi",
+        "Cleans the image of an mkosi build: the directory image of its configuration, or $BUILDROOT when run as an mkosi postinst or finalize script",
+        run_mkosi,
+    ))?;
+    registry.register(Builtin::new(
+        "squashfs",
+        "Cleans a squashfs image: unpacks it, runs the driver and firmware cleanups inside and packs it again",
+        run_squashfs,
+    ))?;
+    registry.register(Builtin::new(
+        "oci",
+        "Cleans a container image, an OCI layout directory or a docker save archive, by adding a layer hiding the unused drivers and firmware",
+        run_oci,
+    ))?;
+    registry.register(Builtin::new(
+        "restore",
+        "Puts back the files saved by a cleanup run with --backup, below --root",
+        run_restore,
+    ))?;
+    registry.register(Builtin::new(
+        "undo",
+        "Restores the deletions recorded in a journal, from the quarantine or the backup archive of the same run, below --root",
+        run_undo,
+    ))?;
+    registry.register(Builtin::new(
+        "purge-quarantine",
+        "Removes the runs of a quarantine directory older than a given age, reclaiming their space",
+        run_purge_quarantine,
+    ))?;
+    registry.register(Builtin::new(
+        "capture-profile",
+        "Records the modules, device modaliases and firmware used by the running system",
+        run_capture_profile,
+    ))?;
+    registry.register(ArgsSubcommand::new(
+        "report",
+        "Lists the drivers and firmware files by size, with totals per category",
+        run_report,
+    ))?;
+    registry.register(ArgsSubcommand::new(
+        "stats",
+        "Counts the modules per kernel and the firmware per vendor directory, by compression, with their symlinks",
+        run_stats,
+    ))?;
+    registry.register(ArgsSubcommand::new(
+        "list-kernels",
+        "Lists the installed kernels, marking the ones the cleanups would process with the same kernel selection",
+        run_list_kernels,
+    ))?;
+    registry.register(ArgsSubcommand::new(
+        "keep-list",
+        "Writes a keep configuration for the drivers of the PCI and USB devices of this machine, from lspci and lsusb",
+        run_keep_list,
+    ))?;
+    registry.register(ArgsSubcommand::new(
+        "compare",
+        "Compares the module and firmware trees of two image roots",
+        run_compare,
+    ))?;
+    registry.register(ArgsSubcommand::new(
+        "diff",
+        "Compares two plans or explanation reports: the files newly unused, no longer unused and the size deltas",
+        run_diff,
+    ))?;
+    registry.register(ArgsSubcommand::new(
+        "forecast",
+        "Estimates the savings of every cleanup subsystem on the image at --root, without modifying it",
+        run_forecast,
+    ))?;
+    registry.register(ArgsSubcommand::new(
+        "config-lint",
+        "Checks the module list configuration files for errors and shadowed rules",
+        run_config_lint,
+    ))?;
+    registry.register(ArgsSubcommand::new(
+        "fw-dedupe",
+        "Replaces byte-identical firmware files with hardlinks to a single copy",
+        run_fw_dedupe,
+    ))?;
+    registry.register(ArgsSubcommand::new(
+        "module-compress",
+        "Converts the kernel modules to one compression and runs depmod again",
+        run_module_compress,
+    ))?;
+    Ok(registry)
+}
+
+fn run_config_lint(args: &ConfigLintArgs, _context: &Context) -> Result<()> {
+    let paths: Vec<&str> = args.config_files.split(',').collect();
+    let findings = config::lint(&paths)?;
+    for finding in &findings {
+        println!("{}", finding);
+    }
+    let errors = findings.iter().filter(|f| !f.diagnostic.warning).count();
+    if errors > 0 {
+        anyhow::bail!("config-lint found {} errors", errors);
+    }
+    info!("{} warnings", findings.len());
+    Ok(())
+}
+
+fn run_fw_dedupe(args: &DedupeArgs, _context: &Context) -> Result<()> {
+    let duplicates = dedupe::dedupe_firmware(&args.firmware_dir, args.link)?;
+    for duplicate in &duplicates {
+        println!(
+            "{:>12} {} -> {}",
+            duplicate.size,
+            duplicate.path.display(),
+            duplicate.original.display()
+        );
+    }
+    let savings: u64 = duplicates.iter().map(|d| d.size).sum();
+    println!(
+        "{} {} duplicates, {} bytes ({} MiB)",
+        if args.link { "Linked" } else { "Would link" },
+        duplicates.len(),
+        savings,
+        savings >> 20
+    );
+    Ok(())
+}
 
-    /// Enable verbose logging.
-    #[arg(short, long, default_value_t = false)]
-    verbose: bool,
+fn run_module_compress(args: &CompressArgs, context: &Context) -> Result<()> {
+    let options = CompressOptions {
+        module_dir: args.module_dir.clone(),
+        convert: args.convert,
+        scan: args.scan.to_options(),
+        compression: args.compression,
+    };
+    let conversions = compress::compress_modules(&options, context.runner)?;
+    for conversion in &conversions {
+        println!(
+            "{:>12} {:>12} {}",
+            conversion.size_before,
+            conversion.size_after,
+            conversion.to.display()
+        );
+    }
+    let before: u64 = conversions.iter().map(|c| c.size_before).sum();
+    let after: u64 = conversions.iter().map(|c| c.size_after).sum();
+    println!(
+        "{} {} modules, {} -> {} bytes ({:+} MiB)",
+        if args.convert { "Converted" } else { "Would convert" },
+        conversions.len(),
+        before,
+        after,
+        (after as i64 - before as i64) / (1 << 20)
+    );
+    Ok(())
 }
 
-#[derive(clap::Subcommand)]
-enum Commands {
-    /// Cleans up unused kernel drivers.
-    DriverCleanup {
-        /// Really delete the files.
-        #[arg(long)]
-        delete: bool,
+fn run_report(args: &ReportArgs, _context: &Context) -> Result<()> {
+    let drivers = usage::module_usage(&args.module_dir, &args.scan.to_options())?;
+    let firmware = usage::firmware_usage(&args.firmware_dir)?;
+    for (name, usage) in [("Drivers", &drivers), ("Firmware", &firmware)] {
+        println!(
+            "{}: {} files, {} bytes ({} MiB)",
+            name,
+            usage.files.len(),
+            usage.bytes,
+            usage.bytes >> 20
+        );
+        for category in &usage.categories {
+            println!(
+                "  {:>12} {:>6} files  {}",
+                category.bytes, category.files, category.name
+            );
+        }
+        println!("  Largest files:");
+        for file in usage.files.iter().take(args.top) {
+            println!("  {:>12} {}", file.size, file.path.display());
+        }
+    }
+    if let Some(path) = &args.json {
+        let report = serde_json::json!({ "drivers": drivers, "firmware": firmware });
+        fs::write(path, serde_json::to_string_pretty(&report)?)?;
+    }
+    Ok(())
+}
 
-        /// Directory with kernel modules.
-        #[arg(long, default_value = "/lib/modules")]
-        module_dir: PathBuf,
-
-        /// Paths to module list configuration files.
-        #[arg(long, default_value = "module.list,module.list.extra")]
-        config_files: String,
-    },
-    /// Cleans up unused firmware.
-    FwCleanup {
-        /// Really delete the files.
-        #[arg(long)]
-        delete: bool,
+fn run_stats(args: &StatsArgs, _context: &Context) -> Result<()> {
+    let stats = Stats {
+        kernels: stats::kernel_stats(&args.module_dir, &args.scan.to_options())?,
+        firmware: stats::firmware_stats(&args.firmware_dir)?,
+    };
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+    let print_counts = |title: &str, counts: &BTreeMap<String, stats::Count>| {
+        println!("  {}:", title);
+        for (name, count) in counts {
+            println!("  {:>12} {:>6} files  {}", count.bytes, count.files, name);
+        }
+    };
+    for kernel in &stats.kernels {
+        println!(
+            "Kernel {}: {} modules, {} bytes ({} MiB), {} symlinks ({} dangling)",
+            kernel.release,
+            kernel.modules.files,
+            kernel.modules.bytes,
+            kernel.modules.bytes >> 20,
+            kernel.symlinks.total,
+            kernel.symlinks.dangling
+        );
+        print_counts("By compression", &kernel.compression);
+    }
+    let firmware = &stats.firmware;
+    println!(
+        "Firmware: {} files, {} bytes ({} MiB), {} symlinks ({} dangling)",
+        firmware.files.files,
+        firmware.files.bytes,
+        firmware.files.bytes >> 20,
+        firmware.symlinks.total,
+        firmware.symlinks.dangling
+    );
+    print_counts("By vendor", &firmware.vendors);
+    print_counts("By compression", &firmware.compression);
+    Ok(())
+}
+
+fn run_list_kernels(args: &ListKernelsArgs, _context: &Context) -> Result<()> {
+    let kernels = stats::list_kernels(&args.module_dir, &args.scan.to_options())?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&kernels)?);
+        return Ok(());
+    }
+    for kernel in &kernels {
+        println!(
+            "{} {}  flavor {}, {} modules, {} bytes ({} MiB){}",
+            if kernel.selected { "*" } else { " " },
+            kernel.release,
+            kernel.flavor.as_deref().unwrap_or("(none)"),
+            kernel.modules,
+            kernel.bytes,
+            kernel.bytes >> 20,
+            if kernel.modules_dep { "" } else { ", no modules.dep" }
+        );
+    }
+    Ok(())
+}
+
+fn run_keep_list(args: &KeepListArgs, context: &Context) -> Result<()> {
+    let devices = hardware::list_devices(context.runner)?;
+    let scan = args.scan.to_options();
+    let kernel_dirs = util::find_kernel_dirs(&args.module_dir, &scan.kernels)?;
+    let config = hardware::keep_config(&devices, &kernel_dirs, &scan)?;
+    fs::write(&args.output, &config)?;
+    let rules = config.lines().filter(|l| !l.is_empty() && !l.starts_with('#')).count();
+    info!(
+        "Keep configuration written to {}: {} rules for {} devices",
+        args.output.display(),
+        rules,
+        devices.len()
+    );
+    Ok(())
+}
+
+fn run_diff(args: &DiffArgs, _context: &Context) -> Result<()> {
+    let old = read_unused(&args.old)?;
+    let new = read_unused(&args.new)?;
+    let diff = old.diff(&new);
+    println!(
+        "Unused: {} -> {} bytes ({:+} bytes, {:+} MiB)",
+        diff.bytes_old,
+        diff.bytes_new,
+        diff.delta(),
+        diff.delta() / (1 << 20)
+    );
+    let sections = [
+        ("Newly unused", &diff.added),
+        ("No longer unused", &diff.removed),
+        ("Changed size", &diff.changed),
+    ];
+    for (name, changes) in sections {
+        let delta: i64 = changes.iter().map(|c| c.delta()).sum();
+        println!("{}: {} files ({:+} bytes)", name, changes.len(), delta);
+        for change in changes {
+            let size = |s: Option<u64>| s.map_or("-".to_string(), |s| s.to_string());
+            println!(
+                "  {:>+12} {} ({} -> {})",
+                change.delta(),
+                change.path,
+                size(change.size_a),
+                size(change.size_b)
+            );
+        }
+    }
+    if let Some(path) = &args.report {
+        fs::write(path, serde_json::to_string_pretty(&diff)?)?;
+    }
+    Ok(())
+}
+
+/// Reads the files planned for removal by the plan at `path`, or deleted according to the
+/// explanation report at `path`.
+fn read_unused(path: &Path) -> Result<Plan> {
+    match Plan::read(path) {
+        Ok(plan) => Ok(plan),
+        Err(_) => Ok(Plan::from_decisions(&explain::read_explanation(path)?)),
+    }
+}
 
-        /// Directory with kernel modules.
-        #[arg(long, default_value = "/lib/modules")]
-        module_dir: PathBuf,
+fn run_compare(args: &CompareArgs, _context: &Context) -> Result<()> {
+    let comparison = compare::compare_roots(&args.root_a, &args.root_b)?;
+    let trees = [("Modules", &comparison.modules), ("Firmware", &comparison.firmware)];
+    for (name, diff) in trees {
+        println!(
+            "{}: {} -> {} ({:+} bytes, {:+} MiB)",
+            name,
+            diff.dir_a.display(),
+            diff.dir_b.display(),
+            diff.delta(),
+            diff.delta() / (1 << 20)
+        );
+        println!(
+            "  {} added, {} removed, {} changed",
+            diff.added.len(),
+            diff.removed.len(),
+            diff.changed.len()
+        );
+        for change in diff.by_delta().into_iter().take(args.top) {
+            let size = |s: Option<u64>| s.map_or("-".to_string(), |s| s.to_string());
+            println!(
+                "  {:>+12} {} ({} -> {})",
+                change.delta(),
+                change.path,
+                size(change.size_a),
+                size(change.size_b)
+            );
+        }
+    }
+    if let Some(path) = &args.report {
+        fs::write(path, serde_json::to_string_pretty(&comparison)?)?;
+    }
+    Ok(())
+}
 
-        /// Directory with firmware files.
-        #[arg(long, default_value = "/lib/firmware")]
-        firmware_dir: PathBuf,
-    },
+fn run_forecast(args: &ForecastArgs, context: &Context) -> Result<()> {
+    let options = ForecastOptions {
+        root: context.root.clone(),
+        config_paths: args.config_files.split(',').map(String::from).collect(),
+        scan: args.scan.to_options(),
+    };
+    let forecasts = forecast::forecast(&options, context.runner);
+    println!("{:<12} {:>8} {:>14} {:>10}", "SUBSYSTEM", "FILES", "BYTES", "MIB");
+    for f in &forecasts {
+        match &f.error {
+            Some(e) => println!("{:<12} failed: {}", f.subsystem, e),
+            None => println!(
+                "{:<12} {:>8} {:>14} {:>10}",
+                f.subsystem,
+                f.files,
+                f.bytes,
+                f.bytes >> 20
+            ),
+        }
+    }
+    let files: usize = forecasts.iter().map(|f| f.files).sum();
+    let bytes: u64 = forecasts.iter().map(|f| f.bytes).sum();
+    println!("{:<12} {:>8} {:>14} {:>10}", "TOTAL", files, bytes, bytes >> 20);
+    if let Some(path) = &args.report {
+        fs::write(path, serde_json::to_string_pretty(&forecasts)?)?;
+    }
+    Ok(())
 }
 
+/// Exit code of the check command when a check fails, errors exit with 1.
+const EXIT_CHECK_FAILED: i32 = 2;
+
 fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let registry = registry()?;
+    // The subcommands come first, like a derived subcommand field, so the global options are
+    // listed after the options of each subcommand in its help.
+    let command = <Cli as clap::Args>::augment_args(registry.augment(clap::Command::new(env!("CARGO_PKG_NAME"))));
+    let mut matches = command.clone().get_matches();
+    if let Some(root) = matches.get_one::<PathBuf>("root") {
+        matches = root_defaults(command, root).get_matches();
+    }
+    let cli = Cli::from_arg_matches(&matches)?;
+
+    init_logging(&cli, &matches);
+
+    // The other commands do not poll for a graceful stop, a signal terminates them.
+    if deletes_files(&matches) {
+        interrupt::install_handlers()?;
+    }
+
+    if let Some(jobs) = cli.jobs {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()?;
+    }
+
+    if let Err(e) = run(&cli, &matches, &registry) {
+        if let Some(JanitorError::Interrupted { deleted, bytes }) = e.downcast_ref() {
+            report_interrupted(deleted, *bytes);
+            std::process::exit(interrupt::EXIT_INTERRUPTED);
+        }
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Tells whether the subcommand selected in `matches` deletes or rewrites files, the runs stopping
+/// gracefully on a termination signal.
+fn deletes_files(matches: &ArgMatches) -> bool {
+    match matches.subcommand() {
+        Some(("driver-cleanup" | "fw-cleanup" | "cleanup-all" | "apply" | "mkosi", m)) => m.get_flag("delete"),
+        Some(("squashfs" | "oci", _)) => true,
+        Some(("fw-dedupe", m)) => m.get_flag("link"),
+        Some(("module-compress", m)) => m.get_flag("convert"),
+        _ => false,
+    }
+}
+
+/// Prints the partial report of a run stopped by a termination signal.
+fn report_interrupted(deleted: &[PathBuf], bytes: u64) {
+    error!("Interrupted: cleanup stopped before completion");
+    for path in deleted {
+        info!("Deleted before interruption: {}", path.display());
+    }
+    info!(
+        "Partial report (interrupted): {} files deleted, {} bytes ({} MiB) freed",
+        deleted.len(),
+        bytes,
+        bytes >> 20
+    );
+}
 
+/// Sets up the logs of the library, and of the log records of the dependencies, on the standard
+/// error in the requested format, filtered by $RUST_LOG if set.
+fn init_logging(cli: &Cli, matches: &ArgMatches) {
     let log_level = if cli.verbose { "debug" } else { "info" };
-    env_logger::Builder::from_env(Env::default().default_filter_or(log_level)).init();
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(log_level));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    let log_format = match cli.log_format {
+        LogFormat::Text if matches.subcommand_name() == Some("mkosi") && mkosi::Hook::from_env().is_some() => {
+            LogFormat::Mkosi
+        }
+        log_format => log_format,
+    };
+    match log_format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_span_list(true).init(),
+        LogFormat::Mkosi => builder.event_format(MkosiFormat).init(),
+    }
+}
 
-    let runner = SystemCommandRunner;
+fn run(cli: &Cli, matches: &ArgMatches, registry: &Registry) -> Result<()> {
+    let runner = cli.runner();
+    let graph = cli.kernel_graph();
+    let context = Context {
+        root: cli.root.clone().unwrap_or_else(|| PathBuf::from("/")),
+        runner: &runner,
+        graph: &graph,
+        matches,
+    };
+    registry.dispatch(matches, &context)?;
+    Ok(())
+}
 
-    match &cli.command {
-        Commands::DriverCleanup {
-            delete,
-            module_dir,
-            config_files,
-        } => {
-            info!(
-                "Driver cleanup running. Delete: {}, Module Dir: {}",
-                delete,
-                module_dir.display()
+fn run_driver_cleanup(args: &DriverCleanupArgs, cli: &Cli, context: &Context) -> Result<()> {
+    let DriverCleanupArgs {
+        delete,
+        module_dir,
+        config_files,
+        changed_report,
+        backup,
+        journal,
+        quarantine,
+        drop_binary_indexes,
+        strip_debug,
+        remove_devel_files,
+        regenerate_initramfs,
+        regenerate_stale_initramfs,
+        min_size,
+        target_size,
+        delete_blacklisted,
+        graph,
+        decisions,
+        write_state,
+        scan,
+        modalias_file,
+        hardware_ids,
+        dtb,
+        kiwi,
+        kiwi_profile,
+        emit_kiwi_drivers,
+        profile,
+    } = args;
+    info!(
+        "Driver cleanup running. Delete: {}, Module Dir: {}",
+        delete,
+        module_dir.display()
+    );
+    check_live(cli, *delete, &[module_dir])?;
+    let snapshot = take_snapshot(cli, *delete, "driver-cleanup", context.runner)?;
+    anyhow::ensure!(
+        decisions.output != OutputFormat::Sbom,
+        "--output sbom lists the firmware left, use it with fw-cleanup or cleanup-all"
+    );
+    let mut config_paths: Vec<String> = config_files.split(',').map(String::from).collect();
+    let mut extra_rules = Vec::new();
+    if let Some(path) = kiwi {
+        let description = kiwi::Description::read(path)?;
+        let drivers = description.drivers(kiwi_profile)?;
+        info!("Keeping the {} drivers of the KIWI description {}", drivers.len(), path.display());
+        extra_rules = drivers.into_iter().map(kiwi::keep_rule).collect();
+        let default_config = context
+        .matches
+            .subcommand_matches("driver-cleanup")
+            .and_then(|m| m.value_source("config_files"))
+            == Some(ValueSource::DefaultValue);
+        if default_config {
+            config_paths.retain(|path| Path::new(path).exists());
+        }
+    }
+    let options = DriverOptions {
+        config_paths,
+        extra_rules,
+        module_dir: module_dir.clone(),
+        root: context.root.clone(),
+        delete: *delete,
+        scan: scan.to_options(),
+        modaliases: modalias_file
+            .as_deref()
+            .map(util::read_list_file)
+            .transpose()?,
+        hardware_ids: hardware_ids
+            .as_deref()
+            .map(hardware::read_hardware_ids)
+            .transpose()?,
+        device_tree: device_tree_modaliases(dtb)?,
+        profile: profile.as_deref().map(Profile::read).transpose()?,
+        backup: backup_path(backup, *delete)?,
+        journal: journal_path(journal, *delete)?,
+        clock: None,
+        quarantine: quarantine.clone(),
+        explain: decisions.decisions_path()?,
+        dot: graph.clone(),
+        drop_binary_indexes: *drop_binary_indexes,
+        strip_debug: *strip_debug,
+        remove_devel_files: *remove_devel_files,
+        min_size: *min_size,
+        target_size: *target_size,
+        delete_blacklisted: *delete_blacklisted,
+        kiwi_drivers: emit_kiwi_drivers.clone(),
+        fs: None,
+        interrupted: None,
+    };
+    let before = snapshot_for_report(changed_report, *delete, module_dir)?;
+    let summary = driver::cleanup_drivers(&options, context.graph, context.runner)?;
+    log_summary(&summary, *delete);
+    let deleted = summary.deleted;
+    decisions.print(cli, &options.explain, &[module_dir], context.runner)?;
+    finish_changed_report(changed_report, before, module_dir, &deleted)?;
+    if *regenerate_initramfs && *delete {
+        update_initramfs(cli, module_dir, &options.scan, context.runner)?;
+    } else {
+        check_initramfs(cli, module_dir, &deleted, *delete, *regenerate_stale_initramfs, context.runner)?;
+    }
+    if *write_state {
+        // Every file the rules were read from: fragments of drop-in directories and includes too.
+        let mut inputs = Vec::new();
+        for path in &options.config_paths {
+            for source in config::read_with_includes(Path::new(path))? {
+                inputs.push(Input::from_file("config", &source.path)?);
+            }
+        }
+        if let Some(path) = modalias_file {
+            inputs.push(Input::from_file("modalias-file", path)?);
+        }
+        if let Some(path) = hardware_ids {
+            inputs.push(Input::from_file("hardware-ids", path)?);
+        }
+        if let Some(path) = dtb {
+            inputs.push(Input::from_file("dtb", path)?);
+        }
+        if let Some(path) = kiwi {
+            inputs.push(Input::from_file("kiwi", path)?);
+        }
+        if let Some(path) = profile {
+            inputs.push(Input::from_file("profile", path)?);
+        }
+        let mut described = scan.describe();
+        if !kiwi_profile.is_empty() {
+            described.push(format!("--kiwi-profile={}", kiwi_profile.join(",")));
+        }
+        if *strip_debug {
+            described.push("--strip-debug".to_string());
+        }
+        if *min_size > 0 {
+            described.push(format!("--min-size={}", min_size));
+        }
+        if let Some(target_size) = target_size {
+            described.push(format!("--target-size={}", target_size));
+        }
+        if *delete_blacklisted {
+            described.push("--delete-blacklisted".to_string());
+        }
+        let run = Run {
+            inputs,
+            options: described,
+            depmod_required: *drop_binary_indexes,
+            snapshot,
+            ..Default::default()
+        };
+        record_state(cli, "driver-cleanup", *delete, &options.module_dir, &options.scan, run, &deleted)?;
+    }
+    Ok(())
+}
+
+fn run_fw_cleanup(args: &FwCleanupArgs, cli: &Cli, context: &Context) -> Result<()> {
+    let FwCleanupArgs {
+        delete,
+        module_dir,
+        firmware_dir,
+        changed_report,
+        backup,
+        journal,
+        quarantine,
+        decisions,
+        write_state,
+        scan,
+        firmware_overlays,
+        extra_module_dirs,
+        profile,
+        firmware_logs,
+        protect,
+        keep,
+        firmware_config_files,
+        follow_external_symlinks,
+        min_size,
+        target_size,
+        emit_required,
+        emit_format,
+        amdgpu_generations,
+        iwlwifi_fallback_versions,
+        sof_platforms,
+        microcode,
+    } = args;
+    info!(
+        "Firmware cleanup running. Delete: {}, Module Dir: {}, Firmware Dir: {}",
+        delete,
+        module_dir.display(),
+        firmware_dir.display()
+    );
+    check_live(cli, *delete, &[firmware_dir])?;
+    let snapshot = take_snapshot(cli, *delete, "fw-cleanup", context.runner)?;
+    let before = snapshot_for_report(changed_report, *delete, firmware_dir)?;
+    let options = FirmwareOptions {
+        module_dir: module_dir.clone(),
+        extra_module_dirs: extra_module_dirs.clone(),
+        firmware_dir: firmware_dir.clone(),
+        delete: *delete,
+        scan: scan.to_options(),
+        overlays: firmware_overlays.clone(),
+        profile: profile.as_deref().map(Profile::read).transpose()?,
+        failed_firmware: read_firmware_failures(firmware_logs)?,
+        protect: protect.clone(),
+        keep: keep.clone(),
+        rules: firmware_rules(firmware_config_files, context.runner)?,
+        follow_external_symlinks: *follow_external_symlinks,
+        root: cli.root.clone(),
+        min_size: *min_size,
+        target_size: *target_size,
+        amdgpu_generations: amdgpu_generations.clone(),
+        iwlwifi_fallback_versions: *iwlwifi_fallback_versions,
+        sof_platforms: sof_platforms.clone(),
+        microcode: microcode.policy()?,
+        backup: backup_path(backup, *delete)?,
+        journal: journal_path(journal, *delete)?,
+        clock: None,
+        quarantine: quarantine.clone(),
+        explain: decisions.decisions_path()?,
+        emit_required: emit_required.clone(),
+        emit_format: *emit_format,
+        install_dir: install_dir(cli, firmware_dir),
+        fs: None,
+    };
+    let summary = firmware::cleanup_firmware(&options, context.graph)?;
+    log_summary(&summary, *delete);
+    let deleted = summary.deleted;
+    decisions.print(cli, &options.explain, &[firmware_dir], context.runner)?;
+    print_sbom(decisions.output, firmware_dir, &deleted)?;
+    finish_changed_report(changed_report, before, firmware_dir, &deleted)?;
+    if *write_state {
+        let mut inputs = profile
+            .iter()
+            .map(|path| Input::from_file("profile", path))
+            .collect::<Result<Vec<_>, _>>()?;
+        for path in firmware_logs {
+            inputs.push(Input::from_file("firmware-log", path)?);
+        }
+        for path in firmware_config_files.iter().flat_map(|files| files.split(',')) {
+            for source in config::read_with_includes(Path::new(path))? {
+                inputs.push(Input::from_file("firmware-config", &source.path)?);
+            }
+        }
+        let mut described = scan.describe();
+        described.extend(
+            firmware_overlays
+                .iter()
+                .map(|o| format!("--firmware-overlay={}", o.display())),
+        );
+        described.extend(
+            extra_module_dirs
+                .iter()
+                .map(|d| format!("--extra-module-dir={}", d.display())),
+        );
+        described.extend(protect.iter().map(|p| format!("--protect={}", p)));
+        described.extend(keep.iter().map(|p| format!("--keep={}", p)));
+        if *follow_external_symlinks {
+            described.push("--follow-external-symlinks".to_string());
+        }
+        if *min_size > 0 {
+            described.push(format!("--min-size={}", min_size));
+        }
+        if let Some(target_size) = target_size {
+            described.push(format!("--target-size={}", target_size));
+        }
+        if !amdgpu_generations.is_empty() {
+            described.push(format!("--amdgpu-generations={}", amdgpu_generations.join(",")));
+        }
+        if *iwlwifi_fallback_versions > 0 {
+            described.push(format!("--iwlwifi-fallback-versions={}", iwlwifi_fallback_versions));
+        }
+        if !sof_platforms.is_empty() {
+            described.push(format!("--sof-platforms={}", sof_platforms.join(",")));
+        }
+        if *microcode != MicrocodeMode::Keep {
+            let mode = microcode.to_possible_value().expect("no skipped microcode mode");
+            described.push(format!("--microcode={}", mode.get_name()));
+        }
+        let run = Run {
+            inputs,
+            options: described,
+            snapshot,
+            ..Default::default()
+        };
+        record_state(cli, "fw-cleanup", *delete, &options.module_dir, &options.scan, run, &deleted)?;
+    }
+    Ok(())
+}
+
+fn run_cleanup_all(args: &CleanupAllArgs, cli: &Cli, context: &Context) -> Result<()> {
+    let CleanupAllArgs { delete, backup, journal, quarantine, decisions, cleanup } = args;
+    let report = decisions.decisions_path()?;
+    let backup = backup_path(backup, *delete)?;
+    let journal = journal_path(journal, *delete)?;
+    let deleted = cleanup.run(cli, *delete, backup, journal, quarantine.clone(), report.clone(), context)?;
+    decisions.print(cli, &report, &[&cleanup.module_dir, &cleanup.firmware_dir], context.runner)?;
+    print_sbom(decisions.output, &cleanup.firmware_dir, &deleted)?;
+    Ok(())
+}
+
+fn run_plan(args: &PlanArgs, cli: &Cli, context: &Context) -> Result<()> {
+    let PlanArgs { output, upload, cleanup } = args;
+    let root = context.root.clone();
+    let removed = cleanup.run(cli, false, None, None, None, None, context)?;
+    let plan = Plan::from_paths(&root, &removed)?;
+    if let Some(path) = output {
+        plan.write(path)?;
+        info!("Plan of {} files written to {}", plan.files.len(), path.display());
+    }
+    if let Some(url) = upload {
+        upload_plan(url, &plan)?;
+        info!("Plan of {} files uploaded to {}", plan.files.len(), url);
+    }
+    Ok(())
+}
+
+fn run_check(args: &CheckArgs, cli: &Cli, context: &Context) -> Result<()> {
+    let CheckArgs { max_unused_bytes, expected, cleanup } = args;
+    let root = context.root.clone();
+    let removed = cleanup.run(cli, false, None, None, None, None, context)?;
+    let plan = Plan::from_paths(&root, &removed)?;
+    let mut failures = Vec::new();
+    println!(
+        "{} unused files, {} bytes ({} MiB)",
+        plan.files.len(),
+        plan.size(),
+        plan.size() >> 20
+    );
+    if let Some(max) = max_unused_bytes {
+        if plan.size() > *max {
+            failures.push(format!("unused files weigh {} bytes, more than {}", plan.size(), max));
+        }
+    }
+    if let Some(path) = expected {
+        let expected = Plan::read(path)?;
+        for file in plan.unexpected(&expected) {
+            failures.push(format!("{} would be deleted, it is not in {}", file.path, path.display()));
+        }
+    }
+    for failure in &failures {
+        println!("FAILED: {}", failure);
+    }
+    if !failures.is_empty() {
+        std::process::exit(EXIT_CHECK_FAILED);
+    }
+    Ok(())
+}
+
+fn run_why_keep(args: &WhyKeepArgs, cli: &Cli, context: &Context) -> Result<()> {
+    let WhyKeepArgs { path, report, cleanup } = args;
+    let decisions = read_decisions(cli, report, cleanup, context)?;
+    let found = trace::find(&decisions, path);
+    if found.is_empty() {
+        anyhow::bail!("No module or firmware file matches {}", path);
+    }
+    for decision in found {
+        if decision.action == explain::Action::Delete {
+            println!("{} is deleted: {}", decision.path.display(), decision.reason);
+            continue;
+        }
+        for (depth, link) in trace::keep_chain(&decisions, decision).iter().enumerate() {
+            println!("{:indent$}{}: {}", "", link.path.display(), link.reason, indent = 2 * depth);
+        }
+    }
+    Ok(())
+}
+
+fn run_why_delete(args: &WhyDeleteArgs, cli: &Cli, context: &Context) -> Result<()> {
+    let WhyDeleteArgs { path, report, cleanup } = args;
+    let decisions = read_decisions(cli, report, cleanup, context)?;
+    let found = trace::find(&decisions, path);
+    if found.is_empty() {
+        anyhow::bail!("No module or firmware file matches {}", path);
+    }
+    for decision in found {
+        if decision.action == explain::Action::Keep {
+            println!("{} is kept: {}, see why-keep", decision.path.display(), decision.reason);
+            continue;
+        }
+        println!("{}: {}", decision.path.display(), decision.reason);
+        if decision.reason.starts_with("matched delete rule") {
+            continue;
+        }
+        let closest = closest_keep_rules(cleanup, decision, context.runner)?;
+        if !closest.is_empty() {
+            println!("  closest keep rules:");
+            for rule in closest {
+                println!("    {}", rule);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_apply(args: &ApplyArgs, cli: &Cli, context: &Context) -> Result<()> {
+    let ApplyArgs { delete, plan, plan_url, backup, journal, quarantine } = args;
+    let plan = match (plan, plan_url) {
+        (Some(path), _) => Plan::read(path)?,
+        (None, Some(url)) => fetch_plan(url)?,
+        (None, None) => unreachable!("clap requires --plan or --plan-url"),
+    };
+    let root = context.root.clone();
+    check_live(cli, *delete, &[&root])?;
+    take_snapshot(cli, *delete, "apply", context.runner)?;
+    let options = ApplyOptions {
+        root,
+        delete: *delete,
+        backup: backup_path(backup, *delete)?,
+        journal: journal_path(journal, *delete)?,
+        clock: None,
+        quarantine: quarantine.clone(),
+    };
+    let deleted = plan::apply_plan(&plan, &options)?;
+    info!(
+        "{} {} files",
+        if *delete { "Deleted" } else { "Would delete" },
+        deleted.len()
+    );
+    Ok(())
+}
+
+fn run_doctor(args: &DoctorArgs, cli: &Cli, context: &Context) -> Result<()> {
+    let DoctorArgs { module_dir, firmware_dir, config_files, scan } = args;
+    let options = DoctorOptions {
+        config_paths: config_files.split(',').map(String::from).collect(),
+        module_dir: module_dir.clone(),
+        firmware_dir: firmware_dir.clone(),
+        scan: scan.to_options(),
+        root: cli.root.clone(),
+    };
+    let findings = doctor::diagnose(&options, context.runner)?;
+    for finding in &findings {
+        match finding.severity {
+            Severity::Error => error!("[{}] {}", finding.check, finding.message),
+            Severity::Warning => warn!("[{}] {}", finding.check, finding.message),
+        }
+        info!("  hint: {}", finding.hint);
+    }
+    let errors = findings
+        .iter()
+        .filter(|f| f.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        anyhow::bail!("doctor found {} problems", errors);
+    }
+    info!("No blocking problem found");
+    Ok(())
+}
+
+fn run_inspect_erofs(args: &InspectErofsArgs, _cli: &Cli, context: &Context) -> Result<()> {
+    let InspectErofsArgs { image, config_files, work_dir, report, exclude_list, scan } = args;
+    let options = InspectOptions {
+        image: image.clone(),
+        work_dir: work_dir.clone(),
+        config_paths: config_files.split(',').map(String::from).collect(),
+        scan: scan.to_options(),
+    };
+    let savings = erofs::inspect_erofs(&options, context.runner)?;
+    info!(
+        "Cleaning {} would remove {} files, {} bytes ({} MiB)",
+        image.display(),
+        savings.files.len(),
+        savings.bytes,
+        savings.bytes >> 20
+    );
+    if let Some(path) = report {
+        fs::write(path, serde_json::to_string_pretty(&savings)?)?;
+    }
+    if let Some(path) = exclude_list {
+        savings.write_exclude_list(path)?;
+    }
+    Ok(())
+}
+
+fn run_mkosi(args: &MkosiArgs, cli: &Cli, context: &Context) -> Result<()> {
+    let MkosiArgs { delete, directory, profiles, config_files, scan } = args;
+    let hook = mkosi::Hook::from_env();
+    let directory = directory
+        .clone()
+        .or_else(|| hook.as_ref().and_then(|h| h.srcdir.clone()))
+        .unwrap_or_else(|| PathBuf::from("."));
+    let profiles = match (profiles.is_empty(), &hook) {
+        (true, Some(hook)) => hook.profiles.clone(),
+        _ => profiles.clone(),
+    };
+    let config = MkosiConfig::read(&directory, &profiles)?;
+    let root = match (&cli.root, &hook) {
+        (Some(root), _) => root.clone(),
+        (None, Some(hook)) => hook.buildroot.clone(),
+        (None, None) => config.image_root()?,
+    };
+    let config_paths: Vec<String> = config_files.iter().flat_map(|f| f.split(',')).map(String::from).collect();
+    let options = MkosiOptions {
+        root,
+        rules: config.module_rules(config_paths.is_empty()),
+        config_paths,
+        delete: *delete,
+        scan: scan.to_options(),
+    };
+    let summary = mkosi::clean_image(&options, context.runner)?;
+    log_summary(&summary, *delete);
+    Ok(())
+}
+
+fn run_squashfs(args: &SquashfsArgs, _cli: &Cli, context: &Context) -> Result<()> {
+    let SquashfsArgs { input, output, config_files, work_dir, report, scan } = args;
+    let options = SquashfsOptions {
+        input: input.clone(),
+        output: output.clone(),
+        work_dir: work_dir.clone(),
+        config_paths: config_files.split(',').map(String::from).collect(),
+        scan: scan.to_options(),
+    };
+    let result = squashfs::clean_squashfs(&options, context.runner)?;
+    let saved = result.size_before.saturating_sub(result.size_after);
+    info!(
+        "Removed {} files, {} -> {} bytes ({} MiB saved)",
+        result.files_removed,
+        result.size_before,
+        result.size_after,
+        saved >> 20
+    );
+    if let Some(path) = report {
+        fs::write(path, serde_json::to_string_pretty(&result)?)?;
+    }
+    Ok(())
+}
+
+fn run_oci(args: &OciArgs, _cli: &Cli, context: &Context) -> Result<()> {
+    let OciArgs { input, output, config_files, work_dir, report, scan } = args;
+    let options = OciOptions {
+        input: input.clone(),
+        output: output.clone(),
+        work_dir: work_dir.clone(),
+        config_paths: config_files.split(',').map(String::from).collect(),
+        scan: scan.to_options(),
+        clock: None,
+    };
+    let result = oci::clean_image(&options, context.runner)?;
+    info!(
+        "Hid {} files ({} bytes) in a new layer, config {}",
+        result.files.len(),
+        result.bytes,
+        result.config_digest
+    );
+    if let Some(path) = report {
+        fs::write(path, serde_json::to_string_pretty(&result)?)?;
+    }
+    Ok(())
+}
+
+fn run_restore(args: &RestoreArgs, _cli: &Cli, context: &Context) -> Result<()> {
+    let RestoreArgs { backup } = args;
+    let root = context.root.clone();
+    let restored = backup::restore(backup, &root)?;
+    info!("Restored {} entries from {}", restored.len(), backup.display());
+    Ok(())
+}
+
+fn run_undo(args: &UndoArgs, _cli: &Cli, context: &Context) -> Result<()> {
+    let UndoArgs { journal, backup } = args;
+    let root = context.root.clone();
+    let restored = journal::undo(journal, backup.as_deref(), &root)?;
+    info!("Undid {} deletions recorded in {}", restored.len(), journal.display());
+    Ok(())
+}
+
+fn run_purge_quarantine(args: &PurgeQuarantineArgs, _cli: &Cli, _context: &Context) -> Result<()> {
+    let PurgeQuarantineArgs { delete, quarantine, older_than } = args;
+    let purged = quarantine::purge(quarantine, (*older_than).into(), &SystemClock, *delete)?;
+    info!(
+        "{} {} quarantined runs older than {}",
+        if *delete { "Purged" } else { "Would purge" },
+        purged.len(),
+        older_than
+    );
+    Ok(())
+}
+
+fn run_capture_profile(args: &CaptureProfileArgs, _cli: &Cli, context: &Context) -> Result<()> {
+    let CaptureProfileArgs { output } = args;
+    let root = context.root.clone();
+    let profile = profile::capture_profile(&root, context.runner)?;
+    profile.write(output)?;
+    info!(
+        "Profile written to {}: {} modules, {} modaliases, {} firmware files",
+        output.display(),
+        profile.modules.len(),
+        profile.modaliases.len(),
+        profile.firmware.len()
+    );
+    Ok(())
+}
+
+/// Returns the modaliases of the nodes of the device tree blob `dtb`, if any.
+fn device_tree_modaliases(dtb: &Option<PathBuf>) -> Result<Vec<String>> {
+    let Some(dtb) = dtb else {
+        return Ok(Vec::new());
+    };
+    let nodes = devicetree::read_dtb(dtb)?;
+    info!("Read {} device tree nodes from {}", nodes.len(), dtb.display());
+    Ok(devicetree::modaliases(&nodes))
+}
+
+/// Token authenticating the plan transfers, if any.
+#[cfg(feature = "remote")]
+fn remote_token() -> Option<String> {
+    std::env::var("IMAGE_JANITOR_TOKEN").ok().filter(|t| !t.is_empty())
+}
+
+#[cfg(feature = "remote")]
+fn upload_plan(url: &str, plan: &Plan) -> Result<()> {
+    Ok(remote::upload(url, remote_token().as_deref(), &plan.to_json()?)?)
+}
+
+#[cfg(feature = "remote")]
+fn fetch_plan(url: &str) -> Result<Plan> {
+    Ok(Plan::parse(&remote::fetch(url, remote_token().as_deref())?)?)
+}
+
+#[cfg(not(feature = "remote"))]
+fn upload_plan(_url: &str, _plan: &Plan) -> Result<()> {
+    anyhow::bail!("image-janitor was built without the remote feature, --upload is not available")
+}
+
+#[cfg(not(feature = "remote"))]
+fn fetch_plan(_url: &str) -> Result<Plan> {
+    anyhow::bail!("image-janitor was built without the remote feature, --plan-url is not available")
+}
+
+/// Returns the decisions recorded in the explanation `report`, or taken by a dry run of the
+/// cleanup described by `cleanup`.
+fn read_decisions(
+    cli: &Cli,
+    report: &Option<PathBuf>,
+    cleanup: &CleanupArgs,
+    context: &Context,
+) -> Result<Vec<explain::Decision>> {
+    if let Some(report) = report {
+        return Ok(explain::read_explanation(report)?);
+    }
+    let report = tempfile::Builder::new().prefix("image-janitor-").suffix(".jsonl").tempfile()?;
+    cleanup.run(cli, false, None, None, None, Some(report.path().to_path_buf()), context)?;
+    Ok(explain::read_explanation(report.path())?)
+}
+
+/// Number of keep rules why-delete suggests.
+const CLOSEST_RULES: usize = 3;
+
+/// Returns the keep rules of the configuration of `cleanup` closest to matching the file of
+/// `decision`: the module config rules for the kernel flavor of a module, the firmware config
+/// rules for a firmware file.
+fn closest_keep_rules(
+    cleanup: &CleanupArgs,
+    decision: &explain::Decision,
+    runner: &dyn CommandRunner,
+) -> Result<Vec<String>> {
+    let (rules, relative) = match decision.file_type {
+        explain::FileType::Module => {
+            let Ok(relative) = decision.path.strip_prefix(&cleanup.module_dir) else {
+                return Ok(Vec::new());
+            };
+            let mut components = relative.components();
+            let version = components.next().map(|c| c.as_os_str().to_string_lossy().into_owned());
+            let config_paths: Vec<&str> = cleanup.config_files.split(',').collect();
+            let rules = config::read_config(&config_paths, runner)?.for_kernel(&version.unwrap_or_default())?;
+            (rules, components.as_path().to_path_buf())
+        }
+        _ => {
+            let (Some(files), Ok(relative)) = (
+                &cleanup.firmware_config_files,
+                decision.path.strip_prefix(&cleanup.firmware_dir),
+            ) else {
+                return Ok(Vec::new());
+            };
+            let config_paths: Vec<&str> = files.split(',').collect();
+            (config::read_config(&config_paths, runner)?, relative.to_path_buf())
+        }
+    };
+    Ok(rules
+        .closest_keep_rules(&relative.to_string_lossy(), CLOSEST_RULES)
+        .iter()
+        .map(|rule| rule.as_str().to_string())
+        .collect())
+}
+
+/// Starts a new explanation report at `explain`, if requested, replacing an existing one.
+fn explain_path(explain: &Option<PathBuf>) -> Result<Option<PathBuf>> {
+    if let Some(path) = explain {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(explain.clone())
+}
+
+/// Regenerates the initramfs of the kernels of `module_dir` selected by `scan`, with the tool of
+/// the distribution of the image.
+fn update_initramfs(
+    cli: &Cli,
+    module_dir: &Path,
+    scan: &ScanOptions,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    let root = cli.root.clone().unwrap_or_else(|| PathBuf::from("/"));
+    for kernel_dir in util::find_kernel_dirs(module_dir, &scan.kernels)? {
+        let version = kernel_dir.file_name().unwrap_or_default().to_string_lossy();
+        distro::regenerate_initramfs(&root, &version, runner)?;
+    }
+    Ok(())
+}
+
+/// Warns about the initramfs images containing modules of `deleted`, and with `regenerate`,
+/// regenerates them once the modules are deleted.
+fn check_initramfs(
+    cli: &Cli,
+    module_dir: &Path,
+    deleted: &[PathBuf],
+    delete: bool,
+    regenerate: bool,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    let root = cli.root.clone().unwrap_or_else(|| PathBuf::from("/"));
+    let stale = initramfs::check_initramfs(&root, module_dir, deleted, runner)?;
+    for image in &stale {
+        let names: Vec<String> = image.modules.iter().map(|m| util::module_name(m)).collect();
+        warn!(
+            "The initramfs {} contains {} deleted modules ({}), they {} once it is regenerated",
+            image.image.display(),
+            names.len(),
+            names.join(", "),
+            if delete { "will be missing from it" } else { "would be missing from it" }
+        );
+    }
+    if !delete || stale.is_empty() {
+        return Ok(());
+    }
+    let versions: std::collections::BTreeSet<&str> = stale.iter().map(|image| image.version.as_str()).collect();
+    for version in versions {
+        if regenerate {
+            distro::regenerate_initramfs(&root, version, runner)?;
+        } else {
+            warn!(
+                "Regenerate it now to check the image still boots: {}",
+                distro::Distro::detect(&root).initramfs_command(version).join(" ")
             );
-            let config_paths: Vec<&str> = config_files.split(',').collect();
-            driver::cleanup_drivers(&config_paths, module_dir, *delete, &runner)?;
         }
-        Commands::FwCleanup {
-            delete,
-            module_dir,
-            firmware_dir,
-        } => {
+    }
+    Ok(())
+}
+
+/// Logs what a cleanup examined, kept and deleted, by deletion reason.
+fn log_summary(summary: &CleanupSummary, delete: bool) {
+    info!(
+        "Examined {} files: kept {}, {} {} ({} bytes)",
+        summary.examined,
+        summary.kept,
+        if delete { "deleted" } else { "would delete" },
+        summary.deleted.len(),
+        summary.bytes_reclaimed
+    );
+    for (reason, count) in &summary.reasons {
+        info!("  {}: {}", reason, count);
+    }
+    if !summary.firmware_groups.is_empty() {
+        info!("Firmware by vendor directory:");
+        let mut groups: Vec<_> = summary.firmware_groups.iter().collect();
+        groups.sort_by_key(|(_, group)| std::cmp::Reverse(group.deleted_bytes));
+        for (name, group) in groups {
             info!(
-                "Firmware cleanup running. Delete: {}, Module Dir: {}, Firmware Dir: {}",
-                delete,
-                module_dir.display(),
-                firmware_dir.display()
+                "  {}: kept {} ({} bytes), {} {} ({} bytes)",
+                name,
+                group.kept,
+                group.kept_bytes,
+                if delete { "deleted" } else { "would delete" },
+                group.deleted,
+                group.deleted_bytes
+            );
+        }
+    }
+    if !summary.errors.is_empty() {
+        warn!("{} errors did not stop the cleanup, see above", summary.errors.len());
+    }
+}
+
+/// Prints the SBOM of the firmware left in `firmware_dir` once the `deleted` files are gone, if
+/// requested by `output`.
+fn print_sbom(output: OutputFormat, firmware_dir: &Path, deleted: &[PathBuf]) -> Result<()> {
+    if output != OutputFormat::Sbom {
+        return Ok(());
+    }
+    let document = sbom::firmware_sbom(firmware_dir, deleted, &SystemClock)?;
+    println!("{}", serde_json::to_string_pretty(&document)?);
+    Ok(())
+}
+
+/// Reads the names of the firmware files the kernel logs at `paths` report as failing to load.
+fn read_firmware_failures(paths: &[PathBuf]) -> Result<BTreeSet<String>> {
+    let mut names = BTreeSet::new();
+    for path in paths {
+        names.extend(profile::read_firmware_failures(path)?);
+    }
+    Ok(names)
+}
+
+/// Reads the rules of the comma separated firmware config files, if any.
+fn firmware_rules(files: &Option<String>, runner: &dyn CommandRunner) -> Result<Option<Rules>> {
+    let Some(files) = files else {
+        return Ok(None);
+    };
+    let paths: Vec<&str> = files.split(',').collect();
+    let rules = config::read_config(&paths, runner)?;
+    // The firmware is shared by all kernels.
+    if let Some(template) = rules.templates.first() {
+        anyhow::bail!("Firmware config rule '{}' uses a kernel variable, only ${{ARCH}} is available", template);
+    }
+    Ok(Some(rules))
+}
+
+/// Takes the snapshot requested with --snapshot before `command` deletes files, returning its ID.
+fn take_snapshot(cli: &Cli, delete: bool, command: &str, runner: &dyn CommandRunner) -> Result<Option<String>> {
+    let (Some(kind), true) = (cli.snapshot, delete) else {
+        return Ok(None);
+    };
+    let provider: Box<dyn SnapshotProvider> = match kind {
+        SnapshotKind::Snapper => Box::new(Snapper),
+        SnapshotKind::Btrfs => Box::new(Btrfs::default()),
+    };
+    let root = cli.root.clone().unwrap_or_else(|| PathBuf::from("/"));
+    let id = provider.create(&root, &format!("before image-janitor {}", command), runner)?;
+    info!("Created snapshot {}, to roll back: {}", id, provider.rollback_hint(&root, &id));
+    Ok(Some(id))
+}
+
+/// Module and firmware directories of the running system.
+const LIVE_DIRS: &[&str] = &["/lib/modules", "/usr/lib/modules", "/lib/firmware", "/usr/lib/firmware"];
+
+/// Makes the module and firmware directories of the subcommands of `command` default to the ones
+/// below the image `root`, instead of the ones of the running system.
+fn root_defaults(mut command: clap::Command, root: &Path) -> clap::Command {
+    let defaults = [
+        ("module_dir", util::find_in_root(root, util::MODULE_DIRS)),
+        ("firmware_dir", util::find_in_root(root, util::FIRMWARE_DIRS)),
+    ];
+    let names: Vec<String> = command.get_subcommands().map(|s| s.get_name().to_string()).collect();
+    for name in names {
+        command = command.mut_subcommand(name, |mut subcommand| {
+            for (id, dir) in &defaults {
+                if subcommand.get_arguments().any(|arg| arg.get_id() == *id) {
+                    subcommand = subcommand.mut_arg(*id, |arg| arg.default_value(dir.clone().into_os_string()));
+                }
+            }
+            subcommand
+        });
+    }
+    command
+}
+
+/// Refuses to delete below `dirs` when one of them is, contains or is inside a module or firmware
+/// directory of the running system, unless it is below the image root or --force-live is set.
+fn check_live(cli: &Cli, delete: bool, dirs: &[&Path]) -> Result<()> {
+    if !delete || cli.force_live {
+        return Ok(());
+    }
+    // /lib is a symlink to /usr/lib on usrmerged systems, the directories are compared resolved.
+    let live_dirs: Vec<PathBuf> = LIVE_DIRS.iter().filter_map(|d| fs::canonicalize(d).ok()).collect();
+    let root = cli.root.as_ref().and_then(|root| fs::canonicalize(root).ok());
+    for dir in dirs {
+        let Ok(resolved) = fs::canonicalize(dir) else {
+            continue;
+        };
+        if root.as_ref().is_some_and(|root| resolved.starts_with(root)) {
+            continue;
+        }
+        if live_dirs.iter().any(|live| live.starts_with(&resolved) || resolved.starts_with(live)) {
+            anyhow::bail!(
+                "{} belongs to the running system: clean an image below --root, or pass --force-live to delete there anyway",
+                dir.display()
             );
-            firmware::cleanup_firmware(module_dir, firmware_dir, *delete, &runner)?;
         }
     }
+    Ok(())
+}
+
+/// Returns where `firmware_dir` is installed in the image: its path below --root, if it is in it.
+fn install_dir(cli: &Cli, firmware_dir: &Path) -> Option<PathBuf> {
+    let relative = firmware_dir.strip_prefix(cli.root.as_ref()?).ok()?;
+    Some(Path::new("/").join(relative))
+}
+
+/// Returns the backup archive to write, which is only done when deleting, replacing an existing
+/// one: the passes of the run append to it.
+fn backup_path(backup: &Option<PathBuf>, delete: bool) -> Result<Option<PathBuf>> {
+    if backup.is_some() && !delete {
+        warn!("--backup has no effect without --delete");
+    }
+    let backup = backup.clone().filter(|_| delete);
+    if let Some(path) = &backup {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(backup)
+}
+
+/// Returns the journal to write, replacing an existing one when deleting so that it only records
+/// the deletions of this run: the passes of the run append to it.
+fn journal_path(journal: &Option<PathBuf>, delete: bool) -> Result<Option<PathBuf>> {
+    if let Some(path) = journal.as_ref().filter(|_| delete) {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(journal.clone())
+}
+
+/// Captures the state of `root` before a run when a changed report was requested.
+fn snapshot_for_report(
+    changed_report: &Option<PathBuf>,
+    delete: bool,
+    root: &Path,
+) -> Result<Option<TreeSnapshot>> {
+    if changed_report.is_none() {
+        return Ok(None);
+    }
+    if !delete {
+        warn!("--changed-report has no effect without --delete");
+        return Ok(None);
+    }
+    Ok(Some(TreeSnapshot::capture(root)?))
+}
+
+/// Records the run, whose inputs and options are already filled, in the state manifest of the image.
+fn record_state(
+    cli: &Cli,
+    command: &str,
+    delete: bool,
+    module_dir: &Path,
+    scan: &ScanOptions,
+    run: Run,
+    deleted: &[PathBuf],
+) -> Result<()> {
+    if !delete {
+        warn!("--write-state has no effect without --delete");
+        return Ok(());
+    }
+    let kernels = util::find_kernel_dirs(module_dir, &scan.kernels)?
+        .iter()
+        .filter_map(|dir| dir.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect();
+    let run = Run {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        kernels,
+        files_removed: deleted.len(),
+        ..run
+    };
+    let root = cli.root.clone().unwrap_or_else(|| PathBuf::from("/"));
+    let path = root.join(state::DEFAULT_STATE_PATH);
+    State::record(&path, command, run)?;
+    info!("State recorded in {}", path.display());
+    Ok(())
+}
 
+fn finish_changed_report(
+    changed_report: &Option<PathBuf>,
+    before: Option<TreeSnapshot>,
+    root: &Path,
+    deleted: &[PathBuf],
+) -> Result<()> {
+    if let (Some(path), Some(before)) = (changed_report, before) {
+        let report = changes::write_changed_report(path, root, &before, deleted)?;
+        info!(
+            "Changed report written to {}: {} files and {} directories removed, {} bytes freed",
+            path.display(),
+            report.files_removed.len(),
+            report.dirs_removed.len(),
+            report.bytes_freed
+        );
+    }
     Ok(())
-}
\ No newline at end of file
+}
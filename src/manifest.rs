@@ -0,0 +1,136 @@
+//! File lists of the firmware kept by a cleanup.
+//!
+//! Rather than shipping the whole linux-firmware package and deleting from it, a distribution can
+//! build a trimmed subpackage from the keep set of a janitor run: an rpm spec `%files` fragment,
+//! to pass to `%files -f`, or an install manifest for the other packaging tools. Both use the
+//! absolute paths the firmware is installed at in the image, and list the directories holding
+//! kept files so the package owns them.
+
+use crate::atomic;
+use crate::error::JanitorError;
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Format of the list of the firmware files kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ListFormat {
+    /// One path relative to the firmware directory per line, symlinks included.
+    #[default]
+    Paths,
+    /// An rpm spec `%files` fragment: a `%dir` line per directory, then the files and symlinks.
+    RpmFiles,
+    /// Tab separated `dir`, `file` and `symlink` lines with the path, and the target of symlinks.
+    Install,
+}
+
+/// A firmware file or symlink kept by the cleanup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeptEntry {
+    /// Path relative to the firmware directory.
+    pub relative: PathBuf,
+    /// Target of a symlink, as stored in the link, `None` for a file.
+    pub target: Option<PathBuf>,
+}
+
+/// Quotes `path` for an rpm `%files` line: macros are escaped, and paths with whitespace are put
+/// in double quotes.
+fn rpm_path(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('%', "%%");
+    if path.contains(char::is_whitespace) {
+        format!("\"{}\"", path)
+    } else {
+        path
+    }
+}
+
+/// Formats `entries`, sorted by path, in `format`, the firmware directory being installed at
+/// `install_dir`.
+pub fn format_list(entries: &[KeptEntry], install_dir: &Path, format: ListFormat) -> String {
+    let mut out = String::new();
+    if format == ListFormat::Paths {
+        for entry in entries {
+            let _ = writeln!(out, "{}", entry.relative.display());
+        }
+        return out;
+    }
+    let dirs: BTreeSet<&Path> = entries
+        .iter()
+        .flat_map(|entry| entry.relative.ancestors().skip(1))
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect();
+    for dir in dirs {
+        let path = install_dir.join(dir);
+        let _ = match format {
+            ListFormat::RpmFiles => writeln!(out, "%dir {}", rpm_path(&path)),
+            _ => writeln!(out, "dir\t{}", path.display()),
+        };
+    }
+    for entry in entries {
+        let path = install_dir.join(&entry.relative);
+        let _ = match (format, &entry.target) {
+            (ListFormat::RpmFiles, _) => writeln!(out, "{}", rpm_path(&path)),
+            (_, Some(target)) => writeln!(out, "symlink\t{}\t{}", path.display(), target.display()),
+            (_, None) => writeln!(out, "file\t{}", path.display()),
+        };
+    }
+    out
+}
+
+/// Writes `entries` in `format` to `path`, atomically.
+pub fn write_list(path: &Path, entries: &[KeptEntry], install_dir: &Path, format: ListFormat) -> Result<(), JanitorError> {
+    let content = format_list(entries, install_dir, format);
+    atomic::write_atomic(path, |file| file.write_all(content.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<KeptEntry> {
+        vec![
+            KeptEntry {
+                relative: PathBuf::from("brcm/brcmfmac43455-sdio.bin"),
+                target: None,
+            },
+            KeptEntry {
+                relative: PathBuf::from("brcm/brcmfmac43455-sdio.raspberrypi,4-model-b.txt"),
+                target: Some(PathBuf::from("brcmfmac43455-sdio.txt")),
+            },
+            KeptEntry {
+                relative: PathBuf::from("regulatory.db"),
+                target: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_format_list() {
+        let install_dir = Path::new("/usr/lib/firmware");
+        assert_eq!(
+            format_list(&entries(), install_dir, ListFormat::Paths),
+            "brcm/brcmfmac43455-sdio.bin\nbrcm/brcmfmac43455-sdio.raspberrypi,4-model-b.txt\nregulatory.db\n"
+        );
+        assert_eq!(
+            format_list(&entries(), install_dir, ListFormat::RpmFiles),
+            "%dir /usr/lib/firmware/brcm\n\
+             /usr/lib/firmware/brcm/brcmfmac43455-sdio.bin\n\
+             /usr/lib/firmware/brcm/brcmfmac43455-sdio.raspberrypi,4-model-b.txt\n\
+             /usr/lib/firmware/regulatory.db\n"
+        );
+        assert_eq!(
+            format_list(&entries(), install_dir, ListFormat::Install),
+            "dir\t/usr/lib/firmware/brcm\n\
+             file\t/usr/lib/firmware/brcm/brcmfmac43455-sdio.bin\n\
+             symlink\t/usr/lib/firmware/brcm/brcmfmac43455-sdio.raspberrypi,4-model-b.txt\tbrcmfmac43455-sdio.txt\n\
+             file\t/usr/lib/firmware/regulatory.db\n"
+        );
+    }
+
+    #[test]
+    fn test_rpm_path() {
+        assert_eq!(rpm_path(Path::new("/lib/firmware/a b.bin")), "\"/lib/firmware/a b.bin\"");
+        assert_eq!(rpm_path(Path::new("/lib/firmware/100%.bin")), "/lib/firmware/100%%.bin");
+    }
+}
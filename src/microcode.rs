@@ -0,0 +1,139 @@
+//! CPU microcode of the firmware directory.
+//!
+//! The microcode updates of `amd-ucode/` and `intel-ucode/` are not requested by any module: the
+//! kernel loads them from the early initramfs, where dracut or initramfs-tools copy them. The
+//! firmware cleanup applies a [`MicrocodePolicy`] to them instead of the module requirements.
+
+use crate::error::JanitorError;
+use std::fs;
+use std::io;
+use std::path::{Component, Path};
+
+/// Directories of the microcode updates, relative to the firmware directory.
+pub const MICROCODE_DIRS: &[&str] = &["amd-ucode", "intel-ucode"];
+
+/// What the firmware cleanup does with the CPU microcode.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MicrocodePolicy {
+    /// Keep all of it.
+    #[default]
+    Keep,
+    /// Delete all of it, e.g. for images running in virtual machines, where the host loads the
+    /// microcode.
+    Delete,
+    /// Keep only the microcode of this CPU.
+    MatchCpu(Cpu),
+}
+
+/// Identification of a CPU, as printed in `/proc/cpuinfo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cpu {
+    /// Vendor, e.g. `GenuineIntel` or `AuthenticAMD`.
+    pub vendor: String,
+    /// Family, extended family included.
+    pub family: u32,
+    /// Model, extended model included.
+    pub model: u32,
+    pub stepping: u32,
+}
+
+impl Cpu {
+    /// Reads the first CPU of `/proc/cpuinfo` of the running system.
+    pub fn read() -> Result<Self, JanitorError> {
+        let cpuinfo = fs::read_to_string("/proc/cpuinfo")?;
+        Cpu::parse_cpuinfo(&cpuinfo).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "no CPU identification in /proc/cpuinfo").into()
+        })
+    }
+
+    /// Parses the first CPU of the content of `/proc/cpuinfo`.
+    pub fn parse_cpuinfo(cpuinfo: &str) -> Option<Self> {
+        let field = |name: &str| {
+            cpuinfo.lines().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                (key.trim() == name).then(|| value.trim().to_string())
+            })
+        };
+        Some(Cpu {
+            vendor: field("vendor_id")?,
+            family: field("cpu family")?.parse().ok()?,
+            model: field("model")?.parse().ok()?,
+            stepping: field("stepping")?.parse().ok()?,
+        })
+    }
+
+    /// Returns whether the microcode file at `relative_path`, relative to the firmware directory,
+    /// is loaded for this CPU: `intel-ucode/<family>-<model>-<stepping>` in hexadecimal for
+    /// Intel, `amd-ucode/microcode_amd_fam<family>h.bin` for AMD, the families before 15h sharing
+    /// `microcode_amd.bin`.
+    pub fn loads(&self, relative_path: &Path) -> bool {
+        let name = crate::firmware::firmware_name(relative_path);
+        // The AMD microcode comes with detached signatures.
+        let name = name.strip_suffix(".asc").unwrap_or(&name);
+        match self.vendor.as_str() {
+            "GenuineIntel" => name == format!("intel-ucode/{:02x}-{:02x}-{:02x}", self.family, self.model, self.stepping),
+            "AuthenticAMD" if self.family < 0x15 => name == "amd-ucode/microcode_amd.bin",
+            "AuthenticAMD" => name == format!("amd-ucode/microcode_amd_fam{:x}h.bin", self.family),
+            _ => false,
+        }
+    }
+}
+
+/// Returns whether the file at `relative_path`, relative to the firmware directory, is in a
+/// microcode directory.
+pub fn is_microcode(relative_path: &Path) -> bool {
+    match relative_path.components().next() {
+        Some(Component::Normal(dir)) => relative_path.components().count() > 1 && MICROCODE_DIRS.iter().any(|d| dir == *d),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpuinfo() {
+        let cpuinfo = "processor\t: 0\nvendor_id\t: GenuineIntel\ncpu family\t: 6\nmodel\t\t: 140\n\
+                       model name\t: 11th Gen Intel(R) Core(TM) i7-1165G7 @ 2.80GHz\nstepping\t: 1\n\n\
+                       processor\t: 1\nvendor_id\t: GenuineIntel\n";
+        let cpu = Cpu::parse_cpuinfo(cpuinfo).unwrap();
+        assert_eq!(
+            cpu,
+            Cpu {
+                vendor: "GenuineIntel".to_string(),
+                family: 6,
+                model: 140,
+                stepping: 1
+            }
+        );
+        assert!(cpu.loads(Path::new("intel-ucode/06-8c-01")));
+        assert!(cpu.loads(Path::new("intel-ucode/06-8c-01.xz")));
+        assert!(!cpu.loads(Path::new("intel-ucode/06-8c-02")));
+        assert!(!cpu.loads(Path::new("amd-ucode/microcode_amd_fam19h.bin")));
+        assert_eq!(Cpu::parse_cpuinfo("processor\t: 0\n"), None);
+    }
+
+    #[test]
+    fn test_amd_loads() {
+        let cpu = |family| Cpu {
+            vendor: "AuthenticAMD".to_string(),
+            family,
+            model: 1,
+            stepping: 1,
+        };
+        assert!(cpu(0x19).loads(Path::new("amd-ucode/microcode_amd_fam19h.bin")));
+        assert!(cpu(0x1a).loads(Path::new("amd-ucode/microcode_amd_fam1ah.bin")));
+        assert!(!cpu(0x19).loads(Path::new("amd-ucode/microcode_amd_fam17h.bin")));
+        assert!(cpu(0x19).loads(Path::new("amd-ucode/microcode_amd_fam19h.bin.asc")));
+        assert!(cpu(0x10).loads(Path::new("amd-ucode/microcode_amd.bin")));
+    }
+
+    #[test]
+    fn test_is_microcode() {
+        assert!(is_microcode(Path::new("intel-ucode/06-8c-01")));
+        assert!(is_microcode(Path::new("amd-ucode/microcode_amd_fam19h.bin.xz")));
+        assert!(!is_microcode(Path::new("amd-ucode")));
+        assert!(!is_microcode(Path::new("amdgpu/vcn_4_0_0.bin")));
+    }
+}
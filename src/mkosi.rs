@@ -0,0 +1,396 @@
+//! Cleanup of the images built by mkosi.
+//!
+//! An mkosi configuration directory tells where the image is written and which kernel modules
+//! it keeps: the `KernelModulesInclude=` and `KernelModulesExclude=` regular expressions of its
+//! `[Content]` section, matched against the module paths like the rules of the configuration.
+//! The cleanup runs either on the directory image mkosi wrote, or on `$BUILDROOT` as an mkosi
+//! postinst or finalize script, where mkosi passes the build through the environment.
+//!
+//! Only the `Profiles=` conditions of the `[Match]` sections are evaluated: a file matching on
+//! something else, e.g. the distribution, is read as if it matched.
+
+use crate::command::CommandRunner;
+use crate::driver::{self, DriverOptions};
+use crate::error::JanitorError;
+use crate::firmware::{self, FirmwareOptions};
+use crate::kernel_graph::KernelGraph;
+use crate::summary::CleanupSummary;
+use crate::util::{self, ScanOptions};
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Main configuration file of a configuration directory.
+const MKOSI_CONF: &str = "mkosi.conf";
+
+/// Drop-in directory of a configuration directory.
+const MKOSI_CONF_D: &str = "mkosi.conf.d";
+
+/// Directory of the profiles of a configuration directory.
+const MKOSI_PROFILES: &str = "mkosi.profiles";
+
+/// Special values of the module lists naming module sets mkosi computes itself.
+const MODULE_SETS: &[&str] = &["default", "host"];
+
+/// Settings of an mkosi configuration, by section and key, in reading order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MkosiConfig {
+    /// Directory the configuration was read from, relative paths are relative to it.
+    pub directory: PathBuf,
+    settings: BTreeMap<(String, String), Vec<String>>,
+}
+
+/// Parses an mkosi configuration file into its sections, each with its `(key, value)` pairs.
+fn parse_sections(content: &str) -> Vec<(String, Vec<(String, String)>)> {
+    let mut sections: Vec<(String, Vec<(String, String)>)> = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push((name.trim().to_string(), Vec::new()));
+        } else if let (Some((key, value)), Some((_, settings))) = (line.split_once('='), sections.last_mut()) {
+            settings.push((key.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    sections
+}
+
+/// Splits a list setting, whose values are separated by whitespace or commas.
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(|c: char| c.is_whitespace() || c == ',').filter(|v| !v.is_empty())
+}
+
+impl MkosiConfig {
+    /// Reads the configuration of `directory` for a build of `profiles`: `mkosi.conf`, the
+    /// `*.conf` files and `mkosi.conf` of the subdirectories of `mkosi.conf.d`, in sorted order,
+    /// then the files of the profiles in `mkosi.profiles`. The profiles default to the
+    /// `Profiles=` of the `[Config]` section.
+    pub fn read(directory: &Path, profiles: &[String]) -> Result<Self, JanitorError> {
+        let mut files = vec![directory.join(MKOSI_CONF)];
+        files.extend(drop_ins(&directory.join(MKOSI_CONF_D))?);
+        let mut config = MkosiConfig {
+            directory: directory.to_path_buf(),
+            ..Default::default()
+        };
+        let mut contents = Vec::new();
+        for file in files.iter().filter(|f| f.is_file()) {
+            let content = fs::read_to_string(file).map_err(|e| JanitorError::ConfigRead(file.display().to_string(), e))?;
+            contents.push((file.clone(), content));
+        }
+        if contents.is_empty() {
+            return Err(JanitorError::InvalidConfig(format!(
+                "no {} nor {} in {}",
+                MKOSI_CONF,
+                MKOSI_CONF_D,
+                directory.display()
+            )));
+        }
+        // The profiles select the files, they are known before the rest is applied.
+        for (_, content) in &contents {
+            config.apply(content, &[], true);
+        }
+        let profiles = match profiles.is_empty() {
+            true => config.list("Config", "Profiles"),
+            false => profiles.to_vec(),
+        };
+        config.settings.clear();
+        for profile in &profiles {
+            let candidates = [
+                directory.join(MKOSI_PROFILES).join(format!("{}.conf", profile)),
+                directory.join(MKOSI_PROFILES).join(profile).join(MKOSI_CONF),
+            ];
+            let Some(file) = candidates.into_iter().find(|f| f.is_file()) else {
+                debug!("No configuration file for the mkosi profile {}", profile);
+                continue;
+            };
+            let content = fs::read_to_string(&file).map_err(|e| JanitorError::ConfigRead(file.display().to_string(), e))?;
+            contents.push((file, content));
+        }
+        for (file, content) in &contents {
+            if !config.apply(content, &profiles, false) {
+                debug!("Skipping {}, its [Match] section does not match the profiles", file.display());
+            }
+        }
+        config.settings.insert(("Config".to_string(), "Profiles".to_string()), profiles);
+        Ok(config)
+    }
+
+    /// Applies the settings of `content`, unless its `[Match]` sections require other profiles
+    /// than `profiles`, which is only checked when `all` is unset. Returns whether it applied.
+    fn apply(&mut self, content: &str, profiles: &[String], all: bool) -> bool {
+        let sections = parse_sections(content);
+        let matches = sections
+            .iter()
+            .filter(|(name, _)| name == "Match")
+            .flat_map(|(_, settings)| settings)
+            .filter(|(key, _)| key == "Profiles" || key == "Profile")
+            .all(|(_, value)| split_list(value).any(|p| profiles.iter().any(|selected| selected == p)));
+        if !all && !matches {
+            return false;
+        }
+        for (section, settings) in sections.into_iter().filter(|(name, _)| name != "Match") {
+            for (key, value) in settings {
+                self.settings.entry((section.clone(), key)).or_default().push(value);
+            }
+        }
+        true
+    }
+
+    /// Returns the last value of the setting `key` of `section`.
+    pub fn value(&self, section: &str, key: &str) -> Option<&str> {
+        let values = self.settings.get(&(section.to_string(), key.to_string()))?;
+        values.last().map(String::as_str).filter(|v| !v.is_empty())
+    }
+
+    /// Returns the values of the list setting `key` of `section`, an empty assignment clearing
+    /// the ones before.
+    pub fn list(&self, section: &str, key: &str) -> Vec<String> {
+        let mut list = Vec::new();
+        for value in self.settings.get(&(section.to_string(), key.to_string())).into_iter().flatten() {
+            if value.is_empty() {
+                list.clear();
+            }
+            list.extend(split_list(value).map(String::from));
+        }
+        list
+    }
+
+    /// Returns the directory image mkosi writes: `Output=` (by default the `ImageId=`, or
+    /// `image`, with the `ImageVersion=`) in the `OutputDirectory=`, by default `mkosi.output`
+    /// when it exists.
+    pub fn image_root(&self) -> Result<PathBuf, JanitorError> {
+        let format = self.value("Output", "Format").unwrap_or("disk");
+        if format != "directory" {
+            return Err(JanitorError::InvalidConfig(format!(
+                "{}: the image format is {}, only directory images can be cleaned after the build, \
+                 run image-janitor as an mkosi postinst or finalize script instead",
+                self.directory.display(),
+                format
+            )));
+        }
+        let output_dir = match self.value("Output", "OutputDirectory") {
+            Some(dir) => self.directory.join(dir),
+            None if self.directory.join("mkosi.output").is_dir() => self.directory.join("mkosi.output"),
+            None => self.directory.clone(),
+        };
+        let name = match (self.value("Output", "Output"), self.value("Output", "ImageVersion")) {
+            (Some(output), _) => output.to_string(),
+            (None, version) => {
+                let id = self.value("Output", "ImageId").unwrap_or("image");
+                version.map_or_else(|| id.to_string(), |v| format!("{}_{}", id, v))
+            }
+        };
+        Ok(output_dir.join(name))
+    }
+
+    /// Returns the rules keeping the modules of the `[Content]` section: every module but the
+    /// ones of `KernelModulesExclude=` when `keep_all` is set, and the ones of
+    /// `KernelModulesInclude=` whatever excludes them, as mkosi includes them after excluding.
+    pub fn module_rules(&self, keep_all: bool) -> Vec<String> {
+        let patterns = |key: &str| {
+            let mut patterns = self.list("Content", key);
+            patterns.retain(|p| {
+                let set = MODULE_SETS.contains(&p.as_str());
+                if set {
+                    warn!("Ignoring {} in {}=, image-janitor does not compute this module set", p, key);
+                }
+                !set
+            });
+            patterns
+        };
+        let mut rules: Vec<String> = keep_all.then(|| ".*".to_string()).into_iter().collect();
+        rules.extend(patterns("KernelModulesExclude").into_iter().map(|p| format!("-{}", p)));
+        rules.extend(patterns("KernelModulesInclude").into_iter().map(|p| format!("keep@1 {}", p)));
+        rules
+    }
+}
+
+/// Returns the files of the drop-in directory `dir`, in sorted order.
+fn drop_ins(dir: &Path) -> Result<Vec<PathBuf>, JanitorError> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut entries = fs::read_dir(dir)?.map(|e| e.map(|e| e.path())).collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    Ok(entries
+        .into_iter()
+        .filter_map(|entry| match entry.is_dir() {
+            true => Some(entry.join(MKOSI_CONF)),
+            false => entry.extension().is_some_and(|e| e == "conf").then_some(entry),
+        })
+        .collect())
+}
+
+/// The build of an mkosi postinst or finalize script, from the environment mkosi runs it in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hook {
+    /// The image being built, `$BUILDROOT`.
+    pub buildroot: PathBuf,
+    /// The configuration directory, `$SRCDIR`.
+    pub srcdir: Option<PathBuf>,
+    /// The profiles built, the space separated `$PROFILES`.
+    pub profiles: Vec<String>,
+}
+
+impl Hook {
+    /// Returns the build of the script running, if run by mkosi, reading the variables with `var`.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        Some(Hook {
+            buildroot: PathBuf::from(var("BUILDROOT")?),
+            srcdir: var("SRCDIR").map(PathBuf::from),
+            profiles: var("PROFILES").map(|p| split_list(&p).map(String::from).collect()).unwrap_or_default(),
+        })
+    }
+
+    /// Returns the build of the script running, if run by mkosi.
+    pub fn from_env() -> Option<Self> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+}
+
+/// Options of the cleanup of an mkosi image.
+#[derive(Debug, Clone, Default)]
+pub struct MkosiOptions {
+    /// Root of the image.
+    pub root: PathBuf,
+    /// Module list configuration files, read after the rules of the mkosi configuration.
+    pub config_paths: Vec<String>,
+    /// Rules of the mkosi configuration, see [`MkosiConfig::module_rules`].
+    pub rules: Vec<String>,
+    /// Really delete the files.
+    pub delete: bool,
+    pub scan: ScanOptions,
+}
+
+/// Runs the driver cleanup on the image, then the firmware cleanup on the modules it keeps.
+pub fn clean_image(options: &MkosiOptions, runner: &dyn CommandRunner) -> Result<CleanupSummary, JanitorError> {
+    let module_dir = util::find_in_root(&options.root, util::MODULE_DIRS);
+    let firmware_dir = util::find_in_root(&options.root, util::FIRMWARE_DIRS);
+    info!("Cleaning the mkosi image {}", options.root.display());
+    let graph = KernelGraph::new();
+    let mut summary = driver::cleanup_drivers(
+        &DriverOptions {
+            config_paths: options.config_paths.clone(),
+            extra_rules: options.rules.clone(),
+            module_dir: module_dir.clone(),
+            root: options.root.clone(),
+            delete: options.delete,
+            scan: options.scan.clone(),
+            ..Default::default()
+        },
+        &graph,
+        runner,
+    )?;
+    if firmware_dir.is_dir() {
+        summary.merge(firmware::cleanup_firmware(
+            &FirmwareOptions {
+                module_dir,
+                firmware_dir,
+                root: Some(options.root.clone()),
+                delete: options.delete,
+                scan: options.scan.clone(),
+                ..Default::default()
+            },
+            &graph,
+        )?);
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modinfo;
+    use tempfile::tempdir;
+
+    struct ArchRunner;
+
+    impl CommandRunner for ArchRunner {
+        fn run(&self, command: &str, _args: &[&str]) -> Result<String, JanitorError> {
+            match command {
+                "arch" => Ok("x86_64".to_string()),
+                _ => Err(JanitorError::Command(format!("Command not found: {}", command))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_config() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        fs::write(
+            dir.join(MKOSI_CONF),
+            "[Config]\nProfiles=desktop\n\n[Output]\nFormat=directory\nImageId=fedora\nImageVersion=41\n\n\
+             [Content]\nKernelModulesExclude=.*\nKernelModulesInclude=drivers/ata/ fs/ext4\n",
+        )
+        .unwrap();
+        fs::create_dir_all(dir.join(MKOSI_CONF_D)).unwrap();
+        fs::write(dir.join(MKOSI_CONF_D).join("10-gpu.conf"), "[Match]\nProfiles=desktop\n\n[Content]\nKernelModulesInclude=drivers/gpu/drm/i915/\n").unwrap();
+        fs::write(dir.join(MKOSI_CONF_D).join("20-server.conf"), "[Match]\nProfiles=server\n\n[Content]\nKernelModulesInclude=drivers/net/\n").unwrap();
+        fs::create_dir_all(dir.join(MKOSI_PROFILES)).unwrap();
+        fs::write(dir.join(MKOSI_PROFILES).join("server.conf"), "[Output]\nOutput=server\n").unwrap();
+        fs::create_dir(dir.join("mkosi.output")).unwrap();
+
+        let config = MkosiConfig::read(dir, &[]).unwrap();
+        assert_eq!(config.image_root().unwrap(), dir.join("mkosi.output/fedora_41"));
+        assert_eq!(config.module_rules(false), ["-.*", "keep@1 drivers/ata/", "keep@1 fs/ext4", "keep@1 drivers/gpu/drm/i915/"]);
+
+        let config = MkosiConfig::read(dir, &["server".to_string()]).unwrap();
+        assert_eq!(config.image_root().unwrap(), dir.join("mkosi.output/server"));
+        assert_eq!(config.module_rules(true), [".*", "-.*", "keep@1 drivers/ata/", "keep@1 fs/ext4", "keep@1 drivers/net/"]);
+
+        fs::write(dir.join(MKOSI_CONF), "[Output]\nFormat=disk\n").unwrap();
+        assert!(MkosiConfig::read(dir, &[]).unwrap().image_root().is_err());
+    }
+
+    #[test]
+    fn test_list() {
+        let mut config = MkosiConfig::default();
+        config.apply("[Content]\nKernelModulesInclude=a b,c\nKernelModulesInclude=\nKernelModulesInclude=d\n", &[], true);
+        assert_eq!(config.list("Content", "KernelModulesInclude"), ["d"]);
+        assert_eq!(config.list("Content", "Missing"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_hook() {
+        let vars = |name: &str| match name {
+            "BUILDROOT" => Some("/work/buildroot".to_string()),
+            "PROFILES" => Some("desktop gpu".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            Hook::from_vars(vars),
+            Some(Hook {
+                buildroot: PathBuf::from("/work/buildroot"),
+                srcdir: None,
+                profiles: vec!["desktop".to_string(), "gpu".to_string()],
+            })
+        );
+        assert_eq!(Hook::from_vars(|_| None), None);
+    }
+
+    #[test]
+    fn test_clean_image() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join("image");
+        let kernel_dir = root.join("usr/lib/modules/6.1.0-test");
+        for name in ["kernel/fs/ext4.ko", "kernel/sound/snd.ko", "kernel/sound/snd-hda.ko"] {
+            fs::create_dir_all(kernel_dir.join(name).parent().unwrap()).unwrap();
+            fs::write(kernel_dir.join(name), modinfo::build_test_module(&["depends="])).unwrap();
+        }
+        let mut config = MkosiConfig::default();
+        config.apply("[Content]\nKernelModulesExclude=/sound/\nKernelModulesInclude=snd-hda\n", &[], true);
+
+        let options = MkosiOptions {
+            root,
+            rules: config.module_rules(true),
+            delete: true,
+            ..Default::default()
+        };
+        let summary = clean_image(&options, &ArchRunner).unwrap();
+        assert_eq!(summary.deleted, vec![kernel_dir.join("kernel/sound/snd.ko")]);
+        assert!(kernel_dir.join("kernel/sound/snd-hda.ko").exists());
+    }
+}
@@ -0,0 +1,475 @@
+use crate::error::JanitorError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+/// Metadata extracted from the `.modinfo` section of a kernel module.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModInfo {
+    fields: Vec<(String, String)>,
+}
+
+impl ModInfo {
+    /// Parses the raw content of a `.modinfo` section, a list of NUL separated `key=value` strings.
+    pub fn parse(section: &[u8]) -> Self {
+        let fields = section
+            .split(|b| *b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| {
+                let entry = String::from_utf8_lossy(s);
+                entry
+                    .split_once('=')
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+            })
+            .collect();
+        ModInfo { fields }
+    }
+
+    /// Returns all values recorded for `key`, in section order.
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns the first value recorded for `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Names of the modules this module depends on.
+    pub fn depends(&self) -> Vec<String> {
+        self.get_all("depends")
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    /// Firmware files requested by this module.
+    pub fn firmware(&self) -> Vec<String> {
+        self.get_all("firmware").map(String::from).collect()
+    }
+
+    /// Returns the metadata restricted to the fields called one of `keys`.
+    pub fn only(&self, keys: &[&str]) -> ModInfo {
+        let fields = self
+            .fields
+            .iter()
+            .filter(|(k, _)| keys.contains(&k.as_str()))
+            .cloned()
+            .collect();
+        ModInfo { fields }
+    }
+}
+
+/// Reads the `.modinfo` section of a kernel module, decompressing `.ko.xz`, `.ko.zst` and `.ko.gz`
+/// files.
+pub fn read_modinfo(path: &Path) -> Result<ModInfo, JanitorError> {
+    let data = read_module(path)?;
+    let section = find_section(&data, ".modinfo")
+        .map_err(|e| JanitorError::ModuleParse(path.to_path_buf(), e))?;
+    Ok(ModInfo::parse(section))
+}
+
+/// Reads `modules.builtin.modinfo` in `kernel_dir`, the metadata of the drivers built into the
+/// kernel, keyed by module name. Returns an empty map if the file does not exist.
+pub fn read_builtin_modinfo(kernel_dir: &Path) -> Result<BTreeMap<String, ModInfo>, JanitorError> {
+    let path = kernel_dir.join("modules.builtin.modinfo");
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    Ok(parse_builtin_modinfo(&fs::read(&path)?))
+}
+
+/// Parses `modules.builtin.modinfo`, NUL separated `module.key=value` strings.
+fn parse_builtin_modinfo(data: &[u8]) -> BTreeMap<String, ModInfo> {
+    let mut builtin: BTreeMap<String, ModInfo> = BTreeMap::new();
+    for (key, value) in ModInfo::parse(data).fields {
+        if let Some((module, key)) = key.split_once('.') {
+            builtin
+                .entry(module.replace('-', "_"))
+                .or_default()
+                .fields
+                .push((key.to_string(), value));
+        }
+    }
+    builtin
+}
+
+/// Marker the kernel appends, after the signature, to signed modules.
+const MODULE_SIG_MAGIC: &[u8] = b"~Module signature appended~\n";
+
+/// Returns true if the uncompressed module `data` ends with an appended signature.
+pub fn is_signed(data: &[u8]) -> bool {
+    data.ends_with(MODULE_SIG_MAGIC)
+}
+
+/// Reads the content of the module at `path`, decompressed.
+pub(crate) fn read_module(path: &Path) -> Result<Vec<u8>, JanitorError> {
+    let file = fs::File::open(path)?;
+    let name = path.to_string_lossy();
+    let mut data = Vec::new();
+    if name.ends_with(".xz") {
+        xz2::read::XzDecoder::new(file)
+            .read_to_end(&mut data)
+            .map_err(|e| JanitorError::ModuleParse(path.to_path_buf(), e.to_string()))?;
+    } else if name.ends_with(".zst") {
+        data = zstd::stream::decode_all(file)
+            .map_err(|e| JanitorError::ModuleParse(path.to_path_buf(), e.to_string()))?;
+    } else if name.ends_with(".gz") {
+        flate2::read::GzDecoder::new(file)
+            .read_to_end(&mut data)
+            .map_err(|e| JanitorError::ModuleParse(path.to_path_buf(), e.to_string()))?;
+    } else {
+        let mut file = file;
+        file.read_to_end(&mut data)?;
+    }
+    Ok(data)
+}
+
+/// Reads an unsigned integer of `size` bytes at `offset` with the given endianness.
+fn read_uint(data: &[u8], offset: usize, size: usize, big_endian: bool) -> Result<u64, String> {
+    let bytes = offset
+        .checked_add(size)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| format!("truncated ELF file at offset {}", offset))?;
+    let value = if big_endian {
+        bytes.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b))
+    } else {
+        bytes
+            .iter()
+            .rev()
+            .fold(0u64, |acc, b| (acc << 8) | u64::from(*b))
+    };
+    Ok(value)
+}
+
+/// Returns the content of the section called `name` in the ELF image `data`.
+fn find_section<'a>(data: &'a [u8], name: &str) -> Result<&'a [u8], String> {
+    sections(data)?
+        .into_iter()
+        .find(|(section_name, _)| *section_name == name.as_bytes())
+        .map(|(_, content)| content)
+        .ok_or_else(|| format!("no {} section", name))
+}
+
+/// Returns the total size of the DWARF debug sections of the ELF image `data`, with their
+/// relocations: what `strip --strip-debug` removes.
+pub fn debug_size(data: &[u8]) -> Result<u64, String> {
+    let is_debug = |name: &[u8]| {
+        [&b".debug_"[..], b".rela.debug_", b".rel.debug_"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+    };
+    Ok(sections(data)?
+        .into_iter()
+        .filter(|(name, _)| is_debug(name))
+        .map(|(_, content)| content.len() as u64)
+        .sum())
+}
+
+/// Section index telling that the real index is in the header of section 0.
+const SHN_XINDEX: u64 = 0xffff;
+
+/// Type of the sections occupying no space in the file.
+const SHT_NOBITS: u64 = 8;
+
+/// Name and content of an ELF section.
+type Section<'a> = (&'a [u8], &'a [u8]);
+
+/// Returns every section of the ELF image `data`.
+fn sections(data: &[u8]) -> Result<Vec<Section<'_>>, String> {
+    if data.len() < 16 || &data[..4] != b"\x7fELF" {
+        return Err("not an ELF file".to_string());
+    }
+    let is_64 = match data[4] {
+        1 => false,
+        2 => true,
+        c => return Err(format!("unknown ELF class {}", c)),
+    };
+    let be = match data[5] {
+        1 => false,
+        2 => true,
+        e => return Err(format!("unknown ELF data encoding {}", e)),
+    };
+
+    // Offsets of the section header fields in the ELF header and in each section header.
+    let (shoff, shentsize, shnum, shstrndx) = if is_64 {
+        (
+            read_uint(data, 0x28, 8, be)?,
+            read_uint(data, 0x3A, 2, be)?,
+            read_uint(data, 0x3C, 2, be)?,
+            read_uint(data, 0x3E, 2, be)?,
+        )
+    } else {
+        (
+            read_uint(data, 0x20, 4, be)?,
+            read_uint(data, 0x2E, 2, be)?,
+            read_uint(data, 0x30, 2, be)?,
+            read_uint(data, 0x32, 2, be)?,
+        )
+    };
+    let (link_field, off_field, size_field, word) = if is_64 { (40, 24, 32, 8) } else { (24, 16, 20, 4) };
+    let to_usize = |value: u64| usize::try_from(value).map_err(|_| format!("ELF offset {} is too large", value));
+
+    // Start of the header of section `index`, checked as the header fields come from the file.
+    let header = |index: u64| -> Result<usize, String> {
+        index
+            .checked_mul(shentsize)
+            .and_then(|offset| offset.checked_add(shoff))
+            .ok_or_else(|| format!("section header {} is out of bounds", index))
+            .and_then(to_usize)
+    };
+    let field = |index: u64, field: usize, size: usize| -> Result<u64, String> {
+        let offset = header(index)?
+            .checked_add(field)
+            .ok_or_else(|| format!("section header {} is out of bounds", index))?;
+        read_uint(data, offset, size, be)
+    };
+    let section = |index: u64| -> Result<(u64, &[u8]), String> {
+        let name_off = field(index, 0, 4)?;
+        // A section without content in the file, e.g. .bss, may have any size.
+        if field(index, 4, 4)? == SHT_NOBITS {
+            return Ok((name_off, &[][..]));
+        }
+        let offset = to_usize(field(index, off_field, word)?)?;
+        let size = to_usize(field(index, size_field, word)?)?;
+        let content = offset
+            .checked_add(size)
+            .and_then(|end| data.get(offset..end))
+            .ok_or_else(|| format!("section {} is out of bounds", index))?;
+        Ok((name_off, content))
+    };
+
+    // With SHN_XINDEX, or more sections than the ELF header holds, the count and the index of
+    // the name table are in the sh_size and sh_link of section 0.
+    let shnum = match shnum {
+        0 if shoff != 0 => field(0, size_field, word)?,
+        n => n,
+    };
+    let shstrndx = match shstrndx {
+        SHN_XINDEX => field(0, link_field, 4)?,
+        n => n,
+    };
+    if shnum == 0 {
+        return Ok(Vec::new());
+    }
+    if shstrndx >= shnum {
+        return Err(format!("section name table {} is out of bounds", shstrndx));
+    }
+
+    let (_, names) = section(shstrndx)?;
+    (0..shnum)
+        .map(|index| {
+            let (name_off, content) = section(index)?;
+            let name = names
+                .get(name_off as usize..)
+                .and_then(|s| s.split(|b| *b == 0).next())
+                .unwrap_or_default();
+            Ok((name, content))
+        })
+        .collect()
+}
+
+/// Builds a minimal little-endian ELF64 image whose `.modinfo` section holds `fields`.
+#[cfg(any(test, feature = "testbed"))]
+pub(crate) fn build_test_module(fields: &[&str]) -> Vec<u8> {
+    let mut modinfo = Vec::new();
+    for field in fields {
+        modinfo.extend_from_slice(field.as_bytes());
+        modinfo.push(0);
+    }
+    build_test_elf(&[(".modinfo", &modinfo)])
+}
+
+/// Builds a minimal little-endian ELF64 image with `sections`, given by name and content.
+#[cfg(any(test, feature = "testbed"))]
+pub(crate) fn build_test_elf(sections: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut shstrtab = vec![0u8];
+    let mut name_offsets = Vec::new();
+    for (name, _) in sections {
+        name_offsets.push(shstrtab.len() as u32);
+        shstrtab.extend_from_slice(name.as_bytes());
+        shstrtab.push(0);
+    }
+    let shstrtab_name = shstrtab.len() as u32;
+    shstrtab.extend_from_slice(b".shstrtab\0");
+
+    let mut offsets = Vec::new();
+    let mut offset = 64u64;
+    for (_, content) in sections {
+        offsets.push(offset);
+        offset += content.len() as u64;
+    }
+    let shstrtab_off = offset;
+    let shoff = shstrtab_off + shstrtab.len() as u64;
+    let shnum = sections.len() as u16 + 2;
+
+    let mut elf = vec![0u8; 64];
+    elf[..4].copy_from_slice(b"\x7fELF");
+    elf[4] = 2; // ELFCLASS64
+    elf[5] = 1; // ELFDATA2LSB
+    elf[6] = 1; // EV_CURRENT
+    elf[0x28..0x30].copy_from_slice(&shoff.to_le_bytes());
+    elf[0x3A..0x3C].copy_from_slice(&64u16.to_le_bytes());
+    elf[0x3C..0x3E].copy_from_slice(&shnum.to_le_bytes());
+    elf[0x3E..0x40].copy_from_slice(&(shnum - 1).to_le_bytes());
+    for (_, content) in sections {
+        elf.extend_from_slice(content);
+    }
+    elf.extend_from_slice(&shstrtab);
+
+    let mut section_header = |name: u32, offset: u64, size: u64| {
+        let mut header = vec![0u8; 64];
+        header[..4].copy_from_slice(&name.to_le_bytes());
+        header[24..32].copy_from_slice(&offset.to_le_bytes());
+        header[32..40].copy_from_slice(&size.to_le_bytes());
+        elf.extend_from_slice(&header);
+    };
+    section_header(0, 0, 0);
+    for ((name, (_, content)), offset) in name_offsets.iter().zip(sections).zip(&offsets) {
+        section_header(*name, *offset, content.len() as u64);
+    }
+    section_header(shstrtab_name, shstrtab_off, shstrtab.len() as u64);
+    elf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_modinfo_section() {
+        let info = ModInfo::parse(b"license=GPL\0depends=a,b\0\0firmware=x.bin\0firmware=y.bin\0");
+        assert_eq!(info.get("license"), Some("GPL"));
+        assert_eq!(info.depends(), vec!["a", "b"]);
+        assert_eq!(info.firmware(), vec!["x.bin", "y.bin"]);
+    }
+
+    #[test]
+    fn test_parse_empty_depends() {
+        let info = ModInfo::parse(b"depends=\0");
+        assert!(info.depends().is_empty());
+    }
+
+    #[test]
+    fn test_read_modinfo_plain() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("a.ko");
+        fs::write(&path, build_test_module(&["depends=b", "firmware=a.bin"])).unwrap();
+
+        let info = read_modinfo(&path).unwrap();
+        assert_eq!(info.depends(), vec!["b"]);
+        assert_eq!(info.firmware(), vec!["a.bin"]);
+    }
+
+    #[test]
+    fn test_read_modinfo_compressed() {
+        let temp_dir = tempdir().unwrap();
+        let module = build_test_module(&["firmware=c.bin"]);
+
+        let xz_path = temp_dir.path().join("c.ko.xz");
+        let mut encoder = xz2::write::XzEncoder::new(fs::File::create(&xz_path).unwrap(), 6);
+        encoder.write_all(&module).unwrap();
+        encoder.finish().unwrap();
+
+        let zst_path = temp_dir.path().join("c.ko.zst");
+        fs::write(&zst_path, zstd::stream::encode_all(&module[..], 3).unwrap()).unwrap();
+
+        assert_eq!(read_modinfo(&xz_path).unwrap().firmware(), vec!["c.bin"]);
+        assert_eq!(read_modinfo(&zst_path).unwrap().firmware(), vec!["c.bin"]);
+
+        let gz_path = temp_dir.path().join("c.ko.gz");
+        let mut encoder = flate2::write::GzEncoder::new(fs::File::create(&gz_path).unwrap(), flate2::Compression::best());
+        encoder.write_all(&module).unwrap();
+        encoder.finish().unwrap();
+        assert_eq!(read_modinfo(&gz_path).unwrap().firmware(), vec!["c.bin"]);
+    }
+
+    #[test]
+    fn test_read_modinfo_not_elf() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("bogus.ko");
+        fs::write(&path, "not an elf").unwrap();
+
+        let result = read_modinfo(&path);
+        assert!(matches!(result, Err(JanitorError::ModuleParse(_, _))));
+    }
+
+    #[test]
+    fn test_sections_truncated() {
+        let module = build_test_module(&["firmware=a.bin"]);
+        assert_eq!(find_section(&module, ".modinfo").unwrap(), b"firmware=a.bin\0");
+        // The last 24 bytes of a section header, sh_link to sh_entsize, are not read.
+        for len in 0..module.len() - 24 {
+            assert!(find_section(&module[..len], ".modinfo").is_err(), "truncated at {}", len);
+        }
+
+        // Header fields overflowing the offset computations are errors, not panics.
+        let fields: [(usize, &[u8]); 4] = [
+            (0x28, &[0xff; 8]),
+            (0x3A, &[0xff; 2]),
+            (0x3C, &[0xff; 2]),
+            (0x3E, &[0xfe, 0xff]),
+        ];
+        for (offset, value) in fields {
+            let mut corrupted = module.clone();
+            corrupted[offset..offset + value.len()].copy_from_slice(value);
+            assert!(sections(&corrupted).is_err(), "corrupted at {:#x}", offset);
+        }
+        let shoff = read_uint(&module, 0x28, 8, false).unwrap() as usize;
+        let mut corrupted = module.clone();
+        corrupted[shoff + 64 + 24..shoff + 64 + 40].fill(0xff);
+        assert!(sections(&corrupted).is_err());
+    }
+
+    #[test]
+    fn test_sections_xindex() {
+        let mut module = build_test_module(&["firmware=a.bin"]);
+        let shoff = read_uint(&module, 0x28, 8, false).unwrap() as usize;
+        let shnum = read_uint(&module, 0x3C, 2, false).unwrap();
+        module[0x3C..0x3E].copy_from_slice(&0u16.to_le_bytes());
+        module[0x3E..0x40].copy_from_slice(&0xffffu16.to_le_bytes());
+        module[shoff + 32..shoff + 40].copy_from_slice(&shnum.to_le_bytes());
+        module[shoff + 40..shoff + 44].copy_from_slice(&(shnum as u32 - 1).to_le_bytes());
+        assert_eq!(find_section(&module, ".modinfo").unwrap(), b"firmware=a.bin\0");
+    }
+
+    #[test]
+    fn test_sections_nobits() {
+        let mut module = build_test_elf(&[(".bss", b""), (".modinfo", b"firmware=a.bin\0")]);
+        // Make .bss a 1 MiB NOBITS section, its size past the end of the file.
+        let shoff = read_uint(&module, 0x28, 8, false).unwrap() as usize;
+        let bss = shoff + 64;
+        module[bss + 4..bss + 8].copy_from_slice(&SHT_NOBITS.to_le_bytes()[..4]);
+        module[bss + 32..bss + 40].copy_from_slice(&(1u64 << 20).to_le_bytes());
+        assert_eq!(find_section(&module, ".bss").unwrap(), b"");
+        assert_eq!(find_section(&module, ".modinfo").unwrap(), b"firmware=a.bin\0");
+    }
+
+    #[test]
+    fn test_read_builtin_modinfo() {
+        let temp_dir = tempdir().unwrap();
+        assert!(read_builtin_modinfo(temp_dir.path()).unwrap().is_empty());
+
+        fs::write(
+            temp_dir.path().join("modules.builtin.modinfo"),
+            "i915.license=GPL\0i915.firmware=i915/kbl_dmc_ver1_04.bin\0snd-hda.firmware=hda.fw\0",
+        )
+        .unwrap();
+        let builtin = read_builtin_modinfo(temp_dir.path()).unwrap();
+        assert_eq!(builtin.len(), 2);
+        assert_eq!(builtin["i915"].firmware(), vec!["i915/kbl_dmc_ver1_04.bin"]);
+        assert_eq!(builtin["snd_hda"].firmware(), vec!["hda.fw"]);
+    }
+}
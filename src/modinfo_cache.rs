@@ -0,0 +1,167 @@
+//! On-disk cache of module metadata.
+//!
+//! Tuning a configuration means running dry runs over and over on the same tree, each extracting
+//! the `.modinfo` section of thousands of modules. The fields the cleanups use are stored per
+//! module path along with the size and modification time of the file: a module rebuilt or
+//! replaced since is read again. A cache written by another version of image-janitor is ignored.
+
+use crate::atomic;
+use crate::error::JanitorError;
+use crate::modinfo::ModInfo;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The `.modinfo` fields read by the cleanups, the only ones stored.
+const CACHED_FIELDS: &[&str] = &["depends", "softdep", "firmware"];
+
+/// Identity of a module file: its size and modification time, in seconds and nanoseconds.
+type Stamp = (u64, i64, i64);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    stamp: Stamp,
+    modinfo: ModInfo,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Content {
+    /// Version of image-janitor which wrote the cache.
+    version: String,
+    entries: HashMap<PathBuf, Entry>,
+}
+
+/// Metadata of the modules read by earlier runs, stored in a JSON file.
+#[derive(Debug)]
+pub struct ModinfoCache {
+    path: PathBuf,
+    content: Mutex<Content>,
+    dirty: Mutex<bool>,
+}
+
+impl ModinfoCache {
+    /// Returns the default location of the cache, below `$XDG_CACHE_HOME` or `~/.cache`, or none
+    /// if neither is set.
+    pub fn default_path() -> Option<PathBuf> {
+        let var = |name| std::env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+        let cache_dir = var("XDG_CACHE_HOME").or_else(|| var("HOME").map(|h| h.join(".cache")))?;
+        Some(cache_dir.join("image-janitor/modinfo.json"))
+    }
+
+    /// Opens the cache at `path`. A missing, unreadable or outdated cache starts empty.
+    pub fn open(path: &Path) -> Self {
+        let content = match fs::read_to_string(path) {
+            Ok(data) => match serde_json::from_str::<Content>(&data) {
+                Ok(content) if content.version == env!("CARGO_PKG_VERSION") => content,
+                Ok(_) => {
+                    debug!("Ignoring {}, written by another version", path.display());
+                    Content::default()
+                }
+                Err(e) => {
+                    warn!("Ignoring the modinfo cache {}: {}", path.display(), e);
+                    Content::default()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Content::default(),
+            Err(e) => {
+                warn!("Ignoring the modinfo cache {}: {}", path.display(), e);
+                Content::default()
+            }
+        };
+        ModinfoCache {
+            path: path.to_path_buf(),
+            content: Mutex::new(content),
+            dirty: Mutex::new(false),
+        }
+    }
+
+    /// Returns the cached metadata of the module at `path`, unless the file changed since.
+    pub fn get(&self, path: &Path, metadata: &fs::Metadata) -> Option<ModInfo> {
+        let content = self.content.lock().unwrap();
+        content
+            .entries
+            .get(path)
+            .filter(|entry| entry.stamp == stamp(metadata))
+            .map(|entry| entry.modinfo.clone())
+    }
+
+    /// Records the metadata of the module at `path`.
+    pub fn insert(&self, path: &Path, metadata: &fs::Metadata, modinfo: &ModInfo) {
+        let entry = Entry {
+            stamp: stamp(metadata),
+            modinfo: modinfo.only(CACHED_FIELDS),
+        };
+        self.content
+            .lock()
+            .unwrap()
+            .entries
+            .insert(path.to_path_buf(), entry);
+        *self.dirty.lock().unwrap() = true;
+    }
+
+    /// Writes the cache back if it changed, dropping the modules which no longer exist.
+    pub fn save(&self) -> Result<(), JanitorError> {
+        let mut dirty = self.dirty.lock().unwrap();
+        if !*dirty {
+            return Ok(());
+        }
+        let mut content = self.content.lock().unwrap();
+        content.version = env!("CARGO_PKG_VERSION").to_string();
+        content.entries.retain(|path, _| path.exists());
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_string(&*content)?;
+        atomic::write_atomic(&self.path, |f| f.write_all(data.as_bytes()))?;
+        *dirty = false;
+        Ok(())
+    }
+}
+
+fn stamp(metadata: &fs::Metadata) -> Stamp {
+    (metadata.len(), metadata.mtime(), metadata.mtime_nsec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_modinfo_cache() {
+        let temp_dir = tempdir().unwrap();
+        let cache_path = temp_dir.path().join("cache/modinfo.json");
+        let module = temp_dir.path().join("a.ko");
+        fs::write(&module, "module").unwrap();
+        let modinfo = ModInfo::parse(b"depends=b\0firmware=a.bin\0description=A\0");
+
+        let cache = ModinfoCache::open(&cache_path);
+        let metadata = fs::metadata(&module).unwrap();
+        assert_eq!(cache.get(&module, &metadata), None);
+        cache.insert(&module, &metadata, &modinfo);
+        cache.insert(&temp_dir.path().join("gone.ko"), &metadata, &modinfo);
+        cache.save().unwrap();
+
+        let cache = ModinfoCache::open(&cache_path);
+        let cached = cache.get(&module, &metadata).unwrap();
+        assert_eq!(cached.depends(), vec!["b"]);
+        assert_eq!(cached.firmware(), vec!["a.bin"]);
+        assert_eq!(cached.get("description"), None);
+        assert!(!cache.content.lock().unwrap().entries.contains_key(&temp_dir.path().join("gone.ko")));
+
+        // A module of another size is read again.
+        fs::write(&module, "rebuilt module").unwrap();
+        assert_eq!(cache.get(&module, &fs::metadata(&module).unwrap()), None);
+
+        // So is every module after an upgrade.
+        fs::write(&cache_path, fs::read_to_string(&cache_path).unwrap().replace(env!("CARGO_PKG_VERSION"), "0.0.0")).unwrap();
+        assert_eq!(ModinfoCache::open(&cache_path).get(&module, &metadata), None);
+        fs::write(&cache_path, "garbage").unwrap();
+        assert_eq!(ModinfoCache::open(&cache_path).get(&module, &metadata), None);
+    }
+}
@@ -0,0 +1,233 @@
+use crate::error::JanitorError;
+use glob::Pattern;
+use log::debug;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directories holding modprobe configuration, relative to the image root, by decreasing priority.
+const MODPROBE_DIRS: &[&str] = &[
+    "etc/modprobe.d",
+    "run/modprobe.d",
+    "usr/local/lib/modprobe.d",
+    "usr/lib/modprobe.d",
+    "lib/modprobe.d",
+];
+
+/// Directories listing the modules loaded at boot by systemd-modules-load, relative to the image
+/// root, by decreasing priority.
+const MODULES_LOAD_DIRS: &[&str] = &[
+    "etc/modules-load.d",
+    "run/modules-load.d",
+    "usr/local/lib/modules-load.d",
+    "usr/lib/modules-load.d",
+    "lib/modules-load.d",
+];
+
+/// Soft dependencies of a module: modules loaded before and after it when available.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SoftDeps {
+    pub pre: Vec<String>,
+    pub post: Vec<String>,
+}
+
+impl SoftDeps {
+    /// Parses a `pre: a b post: c` specification, as found in modinfo and modprobe.d.
+    pub fn parse(spec: &str) -> Self {
+        let mut softdeps = SoftDeps::default();
+        let mut current = None;
+        for token in spec.split_whitespace() {
+            match token {
+                "pre:" => current = Some(&mut softdeps.pre),
+                "post:" => current = Some(&mut softdeps.post),
+                name => {
+                    if let Some(list) = current.as_deref_mut() {
+                        list.push(name.replace('-', "_"));
+                    }
+                }
+            }
+        }
+        softdeps
+    }
+
+    /// Adds the dependencies of `other` to this one.
+    pub fn merge(&mut self, other: SoftDeps) {
+        self.pre.extend(other.pre);
+        self.post.extend(other.post);
+    }
+
+    /// All soft dependencies, pre first.
+    pub fn all(&self) -> impl Iterator<Item = &String> {
+        self.pre.iter().chain(self.post.iter())
+    }
+}
+
+/// The subset of the modprobe configuration of an image relevant for cleanup.
+#[derive(Debug, Default)]
+pub struct ModprobeConfig {
+    /// Soft dependencies declared with `softdep` lines, keyed by module name.
+    pub softdeps: HashMap<String, SoftDeps>,
+    /// Aliases declared with `alias` lines, as wildcard patterns with the module they name.
+    pub aliases: Vec<(String, String)>,
+    /// Modules or aliases listed in modules-load.d, loaded at boot.
+    pub boot: BTreeSet<String>,
+    /// Modules declared with `blacklist` lines, whose aliases modprobe ignores.
+    pub blacklist: BTreeSet<String>,
+}
+
+impl ModprobeConfig {
+    /// Loads the modprobe.d and modules-load.d configuration found below `root`.
+    pub fn load(root: &Path) -> Result<Self, JanitorError> {
+        let mut config = ModprobeConfig::default();
+        for path in config_files(root, MODPROBE_DIRS)? {
+            debug!("Reading modprobe configuration {}", path.display());
+            config.parse(&fs::read_to_string(&path)?);
+        }
+        for path in config_files(root, MODULES_LOAD_DIRS)? {
+            debug!("Reading modules-load configuration {}", path.display());
+            config.parse_modules_load(&fs::read_to_string(&path)?);
+        }
+        Ok(config)
+    }
+
+    /// Parses the content of a modules-load.d file: one module or alias per line.
+    pub fn parse_modules_load(&mut self, content: &str) {
+        let names = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with(['#', ';']));
+        self.boot.extend(names.map(String::from));
+    }
+
+    /// Returns the names of the modules loaded at boot: the ones listed in modules-load.d, and the
+    /// ones the modprobe.d aliases they list name. Entries matching no alias are returned as
+    /// written, they may be module names or aliases of the modules themselves.
+    pub fn boot_modules(&self) -> BTreeSet<String> {
+        let mut modules = BTreeSet::new();
+        for name in &self.boot {
+            let mut aliased = self
+                .aliases
+                .iter()
+                .filter(|(pattern, _)| Pattern::new(pattern).is_ok_and(|p| p.matches(name)))
+                .map(|(_, module)| module.replace('-', "_"))
+                .peekable();
+            if aliased.peek().is_none() {
+                modules.insert(name.clone());
+            }
+            modules.extend(aliased);
+        }
+        modules
+    }
+
+    /// Parses the content of a modprobe configuration file (also used for `modules.softdep`).
+    pub fn parse(&mut self, content: &str) {
+        for line in content.lines() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.splitn(3, char::is_whitespace);
+            match (words.next(), words.next(), words.next()) {
+                (Some("softdep"), Some(module), Some(spec)) => {
+                    self.softdeps
+                        .entry(module.replace('-', "_"))
+                        .or_default()
+                        .merge(SoftDeps::parse(spec));
+                }
+                (Some("alias"), Some(pattern), Some(module)) => {
+                    self.aliases.push((pattern.to_string(), module.trim().to_string()));
+                }
+                (Some("blacklist"), Some(module), _) => {
+                    self.blacklist.insert(module.replace('-', "_"));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Returns the `*.conf` files found in `dirs` below `root`, sorted by file name. A file in a
+/// directory listed earlier overrides a file with the same name in a later one.
+pub fn config_files(root: &Path, dirs: &[&str]) -> Result<Vec<PathBuf>, JanitorError> {
+    let mut files = BTreeMap::new();
+    for dir in dirs {
+        let dir = root.join(dir);
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "conf") {
+                if let Some(name) = path.file_name() {
+                    files.entry(name.to_os_string()).or_insert(path);
+                }
+            }
+        }
+    }
+    Ok(files.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_softdep_spec() {
+        let softdeps = SoftDeps::parse("pre: crc32c-intel crc32c post: led-class");
+        assert_eq!(softdeps.pre, vec!["crc32c_intel", "crc32c"]);
+        assert_eq!(softdeps.post, vec!["led_class"]);
+    }
+
+    #[test]
+    fn test_load_modprobe_config() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("etc/modprobe.d")).unwrap();
+        fs::create_dir_all(root.join("usr/lib/modprobe.d")).unwrap();
+        fs::write(
+            root.join("usr/lib/modprobe.d/10-crypto.conf"),
+            "# comment\nsoftdep libcrc32c pre: crc32c\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("usr/lib/modprobe.d/50-overridden.conf"),
+            "softdep foo pre: bar\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("etc/modprobe.d/50-overridden.conf"),
+            "softdep foo post: baz\n",
+        )
+        .unwrap();
+
+        let config = ModprobeConfig::load(root).unwrap();
+        assert_eq!(config.softdeps["libcrc32c"].pre, vec!["crc32c"]);
+        assert!(config.softdeps["foo"].pre.is_empty());
+        assert_eq!(config.softdeps["foo"].post, vec!["baz"]);
+    }
+
+    #[test]
+    fn test_boot_modules() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("etc/modules-load.d")).unwrap();
+        fs::create_dir_all(root.join("usr/lib/modules-load.d")).unwrap();
+        fs::create_dir_all(root.join("etc/modprobe.d")).unwrap();
+        fs::write(root.join("etc/modules-load.d/net.conf"), "# bonding for the uplinks\nbonding\n; legacy comment\nmy-nic\n").unwrap();
+        fs::write(root.join("usr/lib/modules-load.d/fs.conf"), "fs-btrfs\n").unwrap();
+        fs::write(
+            root.join("etc/modprobe.d/nic.conf"),
+            "alias my-* e1000e\noptions bonding max_bonds=2\nblacklist e100\nblacklist snd-pcsp\n",
+        )
+        .unwrap();
+
+        let config = ModprobeConfig::load(root).unwrap();
+        assert_eq!(config.aliases, vec![("my-*".to_string(), "e1000e".to_string())]);
+        assert_eq!(config.blacklist, BTreeSet::from(["e100".to_string(), "snd_pcsp".to_string()]));
+        assert_eq!(
+            config.boot_modules(),
+            BTreeSet::from(["bonding".to_string(), "e1000e".to_string(), "fs-btrfs".to_string()])
+        );
+    }
+}
@@ -0,0 +1,649 @@
+//! Cleanup of container images.
+//!
+//! Container base images for VMs ship full firmware trees which are better trimmed in the build
+//! pipeline. The layers of the image, an OCI layout directory or a `docker save` archive, are
+//! extracted to a work directory and the driver and firmware cleanups are run there as dry runs.
+//! The files they would remove are hidden by a new layer made of whiteout entries, and the
+//! config, manifest and index are rewritten with the digests of the new blobs. The existing
+//! layers are left untouched, so registries only need to store the small new one.
+
+use crate::clock::{Clock, SystemClock};
+use crate::command::CommandRunner;
+use crate::driver::{self, DriverOptions};
+use crate::error::JanitorError;
+use crate::firmware::{self, FirmwareOptions};
+use crate::kernel_graph::KernelGraph;
+use crate::util::{self, ScanOptions};
+use log::info;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use walkdir::WalkDir;
+
+/// Media type of the layer added to OCI images.
+const GZIP_LAYER: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+
+/// Prefix of the whiteout entries hiding a file of a lower layer.
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Whiteout entry hiding all the content of its directory in the lower layers.
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Options of a container image cleanup.
+#[derive(Debug, Clone, Default)]
+pub struct OciOptions {
+    /// The image: an OCI layout directory, or a tarball written by `docker save`.
+    pub input: PathBuf,
+    /// The cleaned image written, in the format of the input.
+    pub output: PathBuf,
+    /// Empty or missing directory the image is extracted to, removed afterwards, a new temporary
+    /// directory if unset.
+    pub work_dir: Option<PathBuf>,
+    /// Module list configuration files.
+    pub config_paths: Vec<String>,
+    pub scan: ScanOptions,
+    /// Clock dating the new layer in the image history, the system clock if unset.
+    pub clock: Option<Arc<dyn Clock>>,
+}
+
+/// Result of a container image cleanup.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct OciReport {
+    /// Absolute paths inside the image of the files hidden by the new layer.
+    pub files: Vec<String>,
+    /// Total size of these files.
+    pub bytes: u64,
+    /// Digest of the added layer, none if nothing was removed.
+    pub layer_digest: Option<String>,
+    /// Digest of the config of the new image.
+    pub config_digest: String,
+}
+
+/// Cleans the image `options.input` into `options.output`.
+pub fn clean_image(
+    options: &OciOptions,
+    runner: &dyn CommandRunner,
+) -> Result<OciReport, JanitorError> {
+    util::with_work_dir(options.work_dir.as_deref(), "image-janitor-oci-", |work_dir| {
+        if options.input.is_dir() {
+            clean_oci_layout(options, work_dir, runner)
+        } else {
+            clean_docker_archive(options, work_dir, runner)
+        }
+    })
+}
+
+/// Returns the `sha256:` digest of `data`.
+fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+fn invalid(message: impl Into<String>) -> JanitorError {
+    JanitorError::InvalidImage(message.into())
+}
+
+/// Returns the string field `key` of the JSON object `value`.
+fn string_field<'a>(value: &'a Value, key: &str) -> Result<&'a str, JanitorError> {
+    value[key]
+        .as_str()
+        .ok_or_else(|| invalid(format!("missing '{}' field", key)))
+}
+
+fn read_json(path: &Path) -> Result<Value, JanitorError> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Path of the blob `digest` in the OCI layout at `layout`.
+fn blob_path(layout: &Path, digest: &str) -> Result<PathBuf, JanitorError> {
+    let (algorithm, hex) = digest
+        .split_once(':')
+        .ok_or_else(|| invalid(format!("invalid digest '{}'", digest)))?;
+    Ok(layout.join("blobs").join(algorithm).join(hex))
+}
+
+/// Writes `data` as a blob of the OCI layout at `layout`, returning its descriptor.
+fn write_blob(layout: &Path, media_type: &str, data: &[u8]) -> Result<Value, JanitorError> {
+    let digest = sha256_digest(data);
+    let path = blob_path(layout, &digest)?;
+    fs::create_dir_all(path.parent().unwrap_or(layout))?;
+    fs::write(path, data)?;
+    Ok(json!({ "mediaType": media_type, "digest": digest, "size": data.len() }))
+}
+
+/// Opens the layer tarball at `path`, uncompressed, gzip or zstd compressed as told by its
+/// magic number: media types are not available in `docker save` archives.
+fn open_layer(path: &Path) -> Result<Box<dyn Read>, JanitorError> {
+    let mut file = fs::File::open(path)?;
+    let mut magic = [0; 4];
+    let read = file.read(&mut magic)?;
+    drop(file);
+    let file = BufReader::new(fs::File::open(path)?);
+    Ok(match &magic[..read] {
+        [0x1f, 0x8b, ..] => Box::new(flate2::read::GzDecoder::new(file)),
+        [0x28, 0xb5, 0x2f, 0xfd] => Box::new(zstd::Decoder::with_buffer(file)?),
+        _ => Box::new(file),
+    })
+}
+
+/// Extracts the layer tarball at `path` over `rootfs`, applying its whiteouts.
+fn apply_layer(path: &Path, rootfs: &Path) -> Result<(), JanitorError> {
+    let mut archive = tar::Archive::new(open_layer(path)?);
+    archive.set_preserve_permissions(true);
+    archive.set_overwrite(true);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        let parent = rootfs.join(name.parent().unwrap_or(Path::new("")));
+        let file_name = name.file_name().unwrap_or_default().to_string_lossy();
+        if file_name == OPAQUE_WHITEOUT {
+            if parent.is_dir() {
+                for child in fs::read_dir(&parent)? {
+                    remove_all(&child?.path())?;
+                }
+            }
+            continue;
+        }
+        if let Some(hidden) = file_name.strip_prefix(WHITEOUT_PREFIX) {
+            remove_all(&parent.join(hidden))?;
+            continue;
+        }
+        // A directory of a lower layer replaced by a file, or the other way around.
+        let target = rootfs.join(&name);
+        if let Ok(metadata) = target.symlink_metadata() {
+            if !(metadata.is_dir() && entry.header().entry_type().is_dir()) {
+                remove_all(&target)?;
+            }
+        }
+        entry.unpack_in(rootfs)?;
+    }
+    Ok(())
+}
+
+/// Removes the file, symlink or directory tree at `path`, if any.
+fn remove_all(path: &Path) -> Result<(), JanitorError> {
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+/// Extracts the `layers` to `rootfs` and runs the cleanups there as dry runs, returning the
+/// report of the files they would remove.
+fn analyze_rootfs(
+    options: &OciOptions,
+    layers: &[PathBuf],
+    rootfs: &Path,
+    runner: &dyn CommandRunner,
+) -> Result<OciReport, JanitorError> {
+    fs::create_dir_all(rootfs)?;
+    for layer in layers {
+        info!("Extracting layer {}", layer.display());
+        apply_layer(layer, rootfs)?;
+    }
+
+    let module_dir = util::find_in_root(rootfs, util::MODULE_DIRS);
+    let firmware_dir = util::find_in_root(rootfs, util::FIRMWARE_DIRS);
+    // Both cleanups scan the same module trees.
+    let graph = KernelGraph::new();
+    let mut removed = driver::cleanup_drivers(
+        &DriverOptions {
+            config_paths: options.config_paths.clone(),
+            module_dir: module_dir.clone(),
+            root: rootfs.to_path_buf(),
+            scan: options.scan.clone(),
+            ..Default::default()
+        },
+        &graph,
+        runner,
+    )?;
+    if firmware_dir.is_dir() {
+        removed.merge(firmware::cleanup_firmware(
+            &FirmwareOptions {
+                module_dir,
+                firmware_dir,
+                root: Some(rootfs.to_path_buf()),
+                scan: options.scan.clone(),
+                ..Default::default()
+            },
+            &graph,
+        )?);
+    }
+
+    let mut report = OciReport::default();
+    for path in removed.deleted {
+        report.bytes += fs::symlink_metadata(&path)?.len();
+        let relative = path
+            .strip_prefix(rootfs)
+            .map_err(|_| JanitorError::InvalidPath(path.clone()))?;
+        report.files.push(format!("/{}", relative.display()));
+    }
+    report.files.sort();
+    Ok(report)
+}
+
+/// Builds the uncompressed layer tarball hiding the `files` (absolute paths in the image),
+/// dated `mtime`.
+fn whiteout_layer(files: &[String], mtime: u64) -> Result<Vec<u8>, JanitorError> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for file in files {
+        let path = Path::new(file.trim_start_matches('/'));
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let whiteout = path
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(format!("{}{}", WHITEOUT_PREFIX, name));
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(0);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        builder.append_data(&mut header, whiteout, std::io::empty())?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// Records the layer of uncompressed digest `diff_id` hiding `count` files in the image
+/// `config`.
+fn add_layer_to_config(config: &mut Value, diff_id: &str, count: usize, clock: &dyn Clock) {
+    config["rootfs"]["type"] = json!("layers");
+    match config["rootfs"]["diff_ids"].as_array_mut() {
+        Some(diff_ids) => diff_ids.push(json!(diff_id)),
+        None => config["rootfs"]["diff_ids"] = json!([diff_id]),
+    }
+    let history = json!({
+        "created": humantime::format_rfc3339_seconds(clock.now()).to_string(),
+        "created_by": format!("image-janitor {}", env!("CARGO_PKG_VERSION")),
+        "comment": format!("remove {} unused drivers and firmware files", count),
+    });
+    match config["history"].as_array_mut() {
+        Some(entries) => entries.push(history),
+        None => config["history"] = json!([history]),
+    }
+}
+
+/// Copies the directory tree `from` to `to`.
+fn copy_tree(from: &Path, to: &Path) -> Result<(), JanitorError> {
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(from).unwrap_or(entry.path());
+        let target = to.join(relative);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Cleans an OCI image layout: `index.json` points to the manifest of a single-platform image,
+/// the blobs are stored by digest in `blobs/`.
+fn clean_oci_layout(
+    options: &OciOptions,
+    work_dir: &Path,
+    runner: &dyn CommandRunner,
+) -> Result<OciReport, JanitorError> {
+    let input = &options.input;
+    let mut index = read_json(&input.join("index.json"))?;
+    let descriptors = index["manifests"]
+        .as_array()
+        .ok_or_else(|| invalid("missing 'manifests' in index.json"))?;
+    if descriptors.len() != 1 {
+        return Err(invalid(format!(
+            "index.json lists {} manifests, only single-platform images are supported",
+            descriptors.len()
+        )));
+    }
+    let manifest_media_type = string_field(&descriptors[0], "mediaType")?.to_string();
+    let mut manifest = read_json(&blob_path(input, string_field(&descriptors[0], "digest")?)?)?;
+    let mut config = read_json(&blob_path(
+        input,
+        string_field(&manifest["config"], "digest")?,
+    )?)?;
+    let layers = manifest["layers"]
+        .as_array()
+        .ok_or_else(|| invalid("missing 'layers' in the manifest"))?
+        .iter()
+        .map(|layer| blob_path(input, string_field(layer, "digest")?))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut report = analyze_rootfs(options, &layers, &work_dir.join("rootfs"), runner)?;
+    let output = &options.output;
+    copy_tree(input, output)?;
+    if report.files.is_empty() {
+        info!("Nothing to remove, the image is copied unchanged");
+        report.config_digest = string_field(&manifest["config"], "digest")?.to_string();
+        return Ok(report);
+    }
+
+    let clock = options
+        .clock
+        .clone()
+        .unwrap_or_else(|| Arc::new(SystemClock));
+    let layer = whiteout_layer(&report.files, clock.unix_seconds())?;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&layer)?;
+    let layer_descriptor = write_blob(output, GZIP_LAYER, &encoder.finish()?)?;
+    add_layer_to_config(
+        &mut config,
+        &sha256_digest(&layer),
+        report.files.len(),
+        &*clock,
+    );
+    let config_media_type = string_field(&manifest["config"], "mediaType")?.to_string();
+    let config_descriptor = write_blob(output, &config_media_type, &serde_json::to_vec(&config)?)?;
+
+    report.layer_digest = Some(string_field(&layer_descriptor, "digest")?.to_string());
+    report.config_digest = string_field(&config_descriptor, "digest")?.to_string();
+    manifest["config"] = config_descriptor;
+    if let Some(layers) = manifest["layers"].as_array_mut() {
+        layers.push(layer_descriptor);
+    }
+    let manifest_descriptor = write_blob(
+        output,
+        &manifest_media_type,
+        &serde_json::to_vec(&manifest)?,
+    )?;
+    // Annotations of the descriptor, such as the reference name, are kept.
+    let descriptor = &mut index["manifests"][0];
+    descriptor["digest"] = manifest_descriptor["digest"].clone();
+    descriptor["size"] = manifest_descriptor["size"].clone();
+    fs::write(output.join("index.json"), serde_json::to_vec(&index)?)?;
+    Ok(report)
+}
+
+/// Cleans a `docker save` archive: `manifest.json` lists the config and layer files of the
+/// image, by path in the archive.
+fn clean_docker_archive(
+    options: &OciOptions,
+    work_dir: &Path,
+    runner: &dyn CommandRunner,
+) -> Result<OciReport, JanitorError> {
+    let archive_dir = work_dir.join("archive");
+    tar::Archive::new(fs::File::open(&options.input)?).unpack(&archive_dir)?;
+    let mut manifest = read_json(&archive_dir.join("manifest.json"))?;
+    let images = manifest
+        .as_array_mut()
+        .ok_or_else(|| invalid("manifest.json is not a list of images"))?;
+    if images.len() != 1 {
+        return Err(invalid(format!(
+            "manifest.json lists {} images, only archives of a single image are supported",
+            images.len()
+        )));
+    }
+    let image = &mut images[0];
+    let config_path = archive_dir.join(string_field(image, "Config")?);
+    let mut config = read_json(&config_path)?;
+    let layers = image["Layers"]
+        .as_array()
+        .ok_or_else(|| invalid("missing 'Layers' in manifest.json"))?
+        .iter()
+        .map(|layer| Ok(archive_dir.join(layer.as_str().ok_or_else(|| invalid("invalid layer"))?)))
+        .collect::<Result<Vec<_>, JanitorError>>()?;
+
+    let mut report = analyze_rootfs(options, &layers, &work_dir.join("rootfs"), runner)?;
+    if report.files.is_empty() {
+        info!("Nothing to remove, the image is copied unchanged");
+        report.config_digest = sha256_digest(&fs::read(&config_path)?);
+        fs::copy(&options.input, &options.output)?;
+        return Ok(report);
+    }
+
+    // Layers are stored uncompressed, named after their digest as docker does.
+    let clock = options
+        .clock
+        .clone()
+        .unwrap_or_else(|| Arc::new(SystemClock));
+    let layer = whiteout_layer(&report.files, clock.unix_seconds())?;
+    let diff_id = sha256_digest(&layer);
+    let hex = diff_id.trim_start_matches("sha256:");
+    fs::create_dir_all(archive_dir.join(hex))?;
+    fs::write(archive_dir.join(hex).join("layer.tar"), &layer)?;
+    add_layer_to_config(&mut config, &diff_id, report.files.len(), &*clock);
+    let config_data = serde_json::to_vec(&config)?;
+    report.layer_digest = Some(diff_id.clone());
+    report.config_digest = sha256_digest(&config_data);
+    let config_name = format!(
+        "{}.json",
+        report.config_digest.trim_start_matches("sha256:")
+    );
+    fs::remove_file(&config_path)?;
+    fs::write(archive_dir.join(&config_name), config_data)?;
+
+    image["Config"] = json!(config_name);
+    if let Some(layers) = image["Layers"].as_array_mut() {
+        layers.push(json!(format!("{}/layer.tar", hex)));
+    }
+    fs::write(
+        archive_dir.join("manifest.json"),
+        serde_json::to_vec(&manifest)?,
+    )?;
+
+    let mut builder = tar::Builder::new(fs::File::create(&options.output)?);
+    builder.follow_symlinks(false);
+    builder.append_dir_all(".", &archive_dir)?;
+    builder.into_inner()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::modinfo;
+    use tempfile::tempdir;
+
+    struct MockCommandRunner;
+
+    impl CommandRunner for MockCommandRunner {
+        fn run(&self, command: &str, args: &[&str]) -> Result<String, JanitorError> {
+            match command {
+                "arch" => Ok("x86_64".to_string()),
+                _ => Err(JanitorError::Command(format!(
+                    "Not mocked: {} {:?}",
+                    command, args
+                ))),
+            }
+        }
+    }
+
+    /// Builds an uncompressed layer tarball of the `files`, with their content.
+    fn layer(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, &data[..]).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// The layers of a small image: the second one removes a firmware file of the first.
+    fn image_layers() -> Vec<Vec<u8>> {
+        vec![
+            layer(&[
+                (
+                    "usr/lib/modules/6.1.0-test/a.ko",
+                    modinfo::build_test_module(&["firmware=a.bin"]),
+                ),
+                (
+                    "usr/lib/modules/6.1.0-test/b.ko",
+                    modinfo::build_test_module(&["firmware=b.bin"]),
+                ),
+                ("usr/lib/firmware/a.bin", b"a".to_vec()),
+                ("usr/lib/firmware/b.bin", b"b".to_vec()),
+                ("usr/lib/firmware/c.bin", b"c".to_vec()),
+            ]),
+            layer(&[("usr/lib/firmware/.wh.c.bin", Vec::new())]),
+        ]
+    }
+
+    fn options(dir: &Path, input: PathBuf, output: PathBuf) -> OciOptions {
+        let config_path = dir.join("module.list");
+        fs::write(&config_path, "a.ko").unwrap();
+        OciOptions {
+            input,
+            output,
+            work_dir: Some(dir.join("work")),
+            config_paths: vec![config_path.to_str().unwrap().to_string()],
+            clock: Some(Arc::new(FixedClock::from_unix_seconds(86400))),
+            ..Default::default()
+        }
+    }
+
+    /// Names of the entries of the uncompressed or compressed layer tarball at `path`.
+    fn entries(path: &Path) -> Vec<String> {
+        let mut archive = tar::Archive::new(open_layer(path).unwrap());
+        archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_clean_oci_layout() {
+        let temp_dir = tempdir().unwrap();
+        let input = temp_dir.path().join("image");
+        fs::create_dir_all(&input).unwrap();
+        fs::write(
+            input.join("oci-layout"),
+            r#"{"imageLayoutVersion":"1.0.0"}"#,
+        )
+        .unwrap();
+        let mut layers = Vec::new();
+        let mut diff_ids = Vec::new();
+        for data in image_layers() {
+            diff_ids.push(sha256_digest(&data));
+            layers
+                .push(write_blob(&input, "application/vnd.oci.image.layer.v1.tar", &data).unwrap());
+        }
+        let config = json!({ "architecture": "amd64", "rootfs": { "type": "layers", "diff_ids": diff_ids } });
+        let config = write_blob(
+            &input,
+            "application/vnd.oci.image.config.v1+json",
+            &serde_json::to_vec(&config).unwrap(),
+        )
+        .unwrap();
+        let manifest = json!({ "schemaVersion": 2, "config": config, "layers": layers });
+        let manifest = write_blob(
+            &input,
+            "application/vnd.oci.image.manifest.v1+json",
+            &serde_json::to_vec(&manifest).unwrap(),
+        )
+        .unwrap();
+        let mut descriptor = manifest.clone();
+        descriptor["annotations"] = json!({ "org.opencontainers.image.ref.name": "latest" });
+        let index = json!({ "schemaVersion": 2, "manifests": [descriptor] });
+        fs::write(
+            input.join("index.json"),
+            serde_json::to_vec(&index).unwrap(),
+        )
+        .unwrap();
+
+        let output = temp_dir.path().join("clean");
+        let options = options(temp_dir.path(), input, output.clone());
+        let report = clean_image(&options, &MockCommandRunner).unwrap();
+        assert_eq!(
+            report.files,
+            vec![
+                "/usr/lib/firmware/b.bin",
+                "/usr/lib/modules/6.1.0-test/b.ko"
+            ]
+        );
+        assert!(!temp_dir.path().join("work").exists());
+
+        let index = read_json(&output.join("index.json")).unwrap();
+        let descriptor = &index["manifests"][0];
+        assert_eq!(
+            descriptor["annotations"]["org.opencontainers.image.ref.name"],
+            "latest"
+        );
+        let manifest_data =
+            fs::read(blob_path(&output, descriptor["digest"].as_str().unwrap()).unwrap()).unwrap();
+        assert_eq!(sha256_digest(&manifest_data), descriptor["digest"]);
+        let manifest: Value = serde_json::from_slice(&manifest_data).unwrap();
+        assert_eq!(manifest["config"]["digest"], report.config_digest.as_str());
+        let layers = manifest["layers"].as_array().unwrap();
+        assert_eq!(layers.len(), 3);
+        assert_eq!(layers[2]["mediaType"], GZIP_LAYER);
+        assert_eq!(layers[2]["digest"], report.layer_digest.unwrap().as_str());
+        let layer_path = blob_path(&output, layers[2]["digest"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            entries(&layer_path),
+            vec![
+                "usr/lib/firmware/.wh.b.bin",
+                "usr/lib/modules/6.1.0-test/.wh.b.ko"
+            ]
+        );
+
+        let config = read_json(&blob_path(&output, &report.config_digest).unwrap()).unwrap();
+        let diff_ids = config["rootfs"]["diff_ids"].as_array().unwrap();
+        assert_eq!(diff_ids.len(), 3);
+        let mut uncompressed = Vec::new();
+        open_layer(&layer_path)
+            .unwrap()
+            .read_to_end(&mut uncompressed)
+            .unwrap();
+        assert_eq!(diff_ids[2], sha256_digest(&uncompressed).as_str());
+        assert_eq!(config["history"][0]["created"], "1970-01-02T00:00:00Z");
+    }
+
+    #[test]
+    fn test_clean_docker_archive() {
+        let temp_dir = tempdir().unwrap();
+        let layers = image_layers();
+        let config = json!({ "rootfs": { "type": "layers", "diff_ids": [] } });
+        let manifest = json!([{
+            "Config": "config.json",
+            "RepoTags": ["vm-base:latest"],
+            "Layers": ["1/layer.tar", "2/layer.tar"],
+        }]);
+        let input = temp_dir.path().join("image.tar");
+        let mut builder = tar::Builder::new(fs::File::create(&input).unwrap());
+        for (name, data) in [
+            ("manifest.json", serde_json::to_vec(&manifest).unwrap()),
+            ("config.json", serde_json::to_vec(&config).unwrap()),
+            ("1/layer.tar", layers[0].clone()),
+            ("2/layer.tar", layers[1].clone()),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, &data[..]).unwrap();
+        }
+        builder.into_inner().unwrap();
+
+        let output = temp_dir.path().join("clean.tar");
+        let options = options(temp_dir.path(), input, output.clone());
+        let report = clean_image(&options, &MockCommandRunner).unwrap();
+        assert_eq!(report.files.len(), 2);
+
+        let extracted = temp_dir.path().join("extracted");
+        tar::Archive::new(fs::File::open(&output).unwrap())
+            .unpack(&extracted)
+            .unwrap();
+        let manifest = read_json(&extracted.join("manifest.json")).unwrap();
+        assert_eq!(manifest[0]["RepoTags"][0], "vm-base:latest");
+        let layer_name = manifest[0]["Layers"][2].as_str().unwrap();
+        assert_eq!(
+            entries(&extracted.join(layer_name)),
+            vec![
+                "usr/lib/firmware/.wh.b.bin",
+                "usr/lib/modules/6.1.0-test/.wh.b.ko"
+            ]
+        );
+        let config_data =
+            fs::read(extracted.join(manifest[0]["Config"].as_str().unwrap())).unwrap();
+        assert_eq!(sha256_digest(&config_data), report.config_digest);
+        assert!(!extracted.join("config.json").exists());
+    }
+}
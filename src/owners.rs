@@ -0,0 +1,389 @@
+//! Package ownership of the files a cleanup deletes.
+//!
+//! When most of the deletions come from a few packages, the image is better trimmed by not
+//! installing those packages, or by splitting them, than by deleting their payload afterwards.
+//! The owners come from the rpm or dpkg database of the image, dumped once with the package
+//! manager instead of querying it file by file, or from the apk database, read directly as
+//! Alpine images do not necessarily ship apk.
+
+use crate::command::CommandRunner;
+use crate::error::JanitorError;
+use crate::explain::{Action, Decision};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// The apk database of installed packages, relative to the image root.
+const APK_INSTALLED: &str = "lib/apk/db/installed";
+
+/// A package database found in an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageDb {
+    Rpm,
+    Dpkg,
+    Apk,
+}
+
+impl PackageDb {
+    /// Returns the package database of the image at `root`, if any.
+    pub fn detect(root: &Path) -> Option<PackageDb> {
+        if root.join("usr/lib/sysimage/rpm").is_dir() || root.join("var/lib/rpm").is_dir() {
+            Some(PackageDb::Rpm)
+        } else if root.join("var/lib/dpkg/info").is_dir() {
+            Some(PackageDb::Dpkg)
+        } else if root.join(APK_INSTALLED).is_file() {
+            Some(PackageDb::Apk)
+        } else {
+            None
+        }
+    }
+}
+
+/// The packages owning the files of an image, keyed by absolute path within the image.
+#[derive(Debug, Clone, Default)]
+pub struct Owners {
+    root: PathBuf,
+    files: HashMap<PathBuf, String>,
+}
+
+impl Owners {
+    /// Lists the files of every package installed in the image at `root` with `db`.
+    pub fn read(root: &Path, db: PackageDb, runner: &dyn CommandRunner) -> Result<Self, JanitorError> {
+        let files = match db {
+            PackageDb::Rpm => {
+                let root_str = root
+                    .to_str()
+                    .ok_or_else(|| JanitorError::InvalidPath(root.to_path_buf()))?;
+                parse_rpm(&runner.run(
+                    "rpm",
+                    &["--root", root_str, "-qa", "--queryformat", "[%{FILENAMES}\t%{NAME}\n]"],
+                )?)
+            }
+            PackageDb::Dpkg => {
+                let admin_dir = root.join("var/lib/dpkg");
+                let admin_dir = admin_dir.to_string_lossy();
+                parse_dpkg(&runner.run("dpkg-query", &["--admindir", &admin_dir, "-S", "*"])?)
+            }
+            PackageDb::Apk => parse_apk(&fs::read_to_string(root.join(APK_INSTALLED))?),
+        };
+        Ok(Owners {
+            root: root.to_path_buf(),
+            files,
+        })
+    }
+
+    /// Returns the package owning `path`, a path on the host below the image root. A file is
+    /// looked up below `/lib` and `/usr/lib` both, the database may name either on a usrmerged
+    /// image.
+    pub fn owner(&self, path: &Path) -> Option<&str> {
+        let in_image = Path::new("/").join(path.strip_prefix(&self.root).unwrap_or(path));
+        let [path, merged] = merged_paths(&in_image);
+        self.files
+            .get(&path)
+            .or_else(|| self.files.get(&merged))
+            .map(String::as_str)
+    }
+
+    /// Returns the packages owning deleted files in `decisions` whose other files below `dirs`
+    /// are all deleted too, so the whole package can be removed instead. Directories and files
+    /// missing from the image are ignored, the files elsewhere (documentation, licences) go with
+    /// the package.
+    pub fn removable_packages(&self, decisions: &[Decision], dirs: &[&Path]) -> Vec<String> {
+        let deleted: HashSet<&Path> = decisions
+            .iter()
+            .filter(|d| d.action == Action::Delete)
+            .map(|d| d.path.as_path())
+            .collect();
+        let candidates: BTreeSet<&str> = decisions
+            .iter()
+            .filter_map(|d| d.package.as_deref())
+            .collect();
+        let mut used: HashSet<&str> = HashSet::new();
+        for (path, package) in &self.files {
+            if !candidates.contains(package.as_str()) || used.contains(package.as_str()) {
+                continue;
+            }
+            let on_host = merged_paths(path).map(|p| self.root.join(p.strip_prefix("/").unwrap_or(&p)));
+            if !on_host.iter().any(|p| dirs.iter().any(|dir| p.starts_with(dir))) {
+                continue;
+            }
+            let is_file = on_host
+                .iter()
+                .filter_map(|p| p.symlink_metadata().ok())
+                .any(|m| !m.is_dir());
+            if is_file && !on_host.iter().any(|p| deleted.contains(p.as_path())) {
+                used.insert(package);
+            }
+        }
+        candidates
+            .into_iter()
+            .filter(|package| !used.contains(package))
+            .map(String::from)
+            .collect()
+    }
+
+    /// Records the owner of each deleted file in `decisions`.
+    pub fn annotate(&self, decisions: &mut [Decision]) {
+        for decision in decisions.iter_mut().filter(|d| d.action == Action::Delete) {
+            decision.package = self.owner(&decision.path).map(String::from);
+        }
+    }
+}
+
+/// Returns `path`, an absolute path in the image, and its counterpart below `/lib` or `/usr/lib`
+/// on a usrmerged image, or `path` again.
+fn merged_paths(path: &Path) -> [PathBuf; 2] {
+    let merged = if let Ok(rest) = path.strip_prefix("/usr/lib") {
+        Path::new("/lib").join(rest)
+    } else if let Ok(rest) = path.strip_prefix("/lib") {
+        Path::new("/usr/lib").join(rest)
+    } else {
+        path.to_path_buf()
+    };
+    [path.to_path_buf(), merged]
+}
+
+/// Writes to `path` a shell script removing `packages` from the image at `root` with its package
+/// manager: zypper or dnf for rpm, dpkg itself for dpkg, which refuses to break dependencies, and
+/// apk.
+pub fn write_removal_script(
+    path: &Path,
+    root: &Path,
+    db: PackageDb,
+    packages: &[String],
+) -> Result<(), JanitorError> {
+    let mut script = String::from("#!/bin/sh\n# Packages whose files image-janitor found all unused.\nset -e\n");
+    if packages.is_empty() {
+        script.push_str("# No package to remove.\n");
+    } else {
+        let root = root.display();
+        let command = match db {
+            PackageDb::Rpm if Path::new(&root.to_string()).join("usr/bin/zypper").exists() => {
+                format!("zypper --root '{}' --non-interactive remove", root)
+            }
+            PackageDb::Rpm => format!("dnf --installroot '{}' --assumeyes remove", root),
+            PackageDb::Dpkg => format!("dpkg --root '{}' --remove", root),
+            PackageDb::Apk => format!("apk --root '{}' del", root),
+        };
+        script.push_str(&format!("{} {}\n", command, packages.join(" ")));
+    }
+    fs::write(path, script)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+/// Parses the apk database of installed packages: a `P:` line names a package, and the files it
+/// owns are given by `R:` lines, relative to the last `F:` directory.
+fn parse_apk(content: &str) -> HashMap<PathBuf, String> {
+    let mut files = HashMap::new();
+    let mut package = "";
+    let mut dir = PathBuf::from("/");
+    for line in content.lines() {
+        match line.split_once(':') {
+            Some(("P", name)) => {
+                package = name;
+                dir = PathBuf::from("/");
+            }
+            Some(("F", path)) => dir = Path::new("/").join(path),
+            Some(("R", name)) => {
+                files.insert(dir.join(name), package.to_string());
+            }
+            _ => {}
+        }
+    }
+    files
+}
+
+/// Parses `rpm -qa` output listing `path<TAB>package` lines.
+fn parse_rpm(output: &str) -> HashMap<PathBuf, String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(path, package)| (PathBuf::from(path), package.to_string()))
+        .collect()
+}
+
+/// Parses `dpkg-query -S` output listing `package[, package...]: path` lines. Diversions are
+/// skipped and the first of several owners is kept.
+fn parse_dpkg(output: &str) -> HashMap<PathBuf, String> {
+    output
+        .lines()
+        .filter(|line| !line.starts_with("diversion "))
+        .filter_map(|line| line.split_once(": "))
+        .map(|(packages, path)| {
+            let package = packages.split(", ").next().unwrap_or(packages);
+            // Multiarch packages are listed as name:arch.
+            let package = package.split(':').next().unwrap_or(package);
+            (PathBuf::from(path), package.to_string())
+        })
+        .collect()
+}
+
+/// Number of deleted files and their total size, per owning package, the most space first.
+/// Files owned by no package are grouped under `None`.
+pub fn summarize(decisions: &[Decision]) -> Vec<(Option<String>, usize, u64)> {
+    let mut packages: BTreeMap<Option<String>, (usize, u64)> = BTreeMap::new();
+    for decision in decisions.iter().filter(|d| d.action == Action::Delete) {
+        let entry = packages.entry(decision.package.clone()).or_default();
+        entry.0 += 1;
+        entry.1 += decision.size;
+    }
+    let mut summary: Vec<_> = packages
+        .into_iter()
+        .map(|(package, (files, size))| (package, files, size))
+        .collect();
+    summary.sort_by_key(|(_, _, size)| std::cmp::Reverse(*size));
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::explain::FileType;
+    use std::fs;
+    use tempfile::tempdir;
+
+    struct MockCommandRunner;
+
+    impl CommandRunner for MockCommandRunner {
+        fn run(&self, command: &str, args: &[&str]) -> Result<String, JanitorError> {
+            match command {
+                "rpm" => {
+                    assert_eq!(args[2], "-qa");
+                    Ok("/usr/lib/modules/6.1/a.ko.zst\tkernel-default\n\
+                        /usr/lib/firmware/b.bin\tkernel-firmware-b\n\
+                        /usr/lib/firmware\tfilesystem"
+                        .to_string())
+                }
+                "dpkg-query" => Ok("diversion by dash from: /bin/sh\n\
+                                    firmware-misc-nonfree: /lib/firmware/b.bin\n\
+                                    linux-image-6.1:amd64, linux-modules: /lib/modules/6.1/a.ko"
+                    .to_string()),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    fn decision(path: PathBuf, action: Action, size: u64) -> Decision {
+        Decision {
+            path,
+            file_type: FileType::Firmware,
+            size,
+            action,
+            reason: String::new(),
+            package: None,
+        }
+    }
+
+    #[test]
+    fn test_owners_rpm() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        assert_eq!(PackageDb::detect(root), None);
+        fs::create_dir_all(root.join("usr/lib/sysimage/rpm")).unwrap();
+        assert_eq!(PackageDb::detect(root), Some(PackageDb::Rpm));
+
+        let owners = Owners::read(root, PackageDb::Rpm, &MockCommandRunner).unwrap();
+        let mut decisions = vec![
+            decision(root.join("usr/lib/modules/6.1/a.ko.zst"), Action::Delete, 10),
+            decision(root.join("lib/firmware/b.bin"), Action::Delete, 5),
+            decision(root.join("usr/lib/firmware/c.bin"), Action::Delete, 1),
+            decision(root.join("usr/lib/firmware/d.bin"), Action::Delete, 2),
+            decision(root.join("usr/lib/firmware/kept.bin"), Action::Keep, 100),
+        ];
+        owners.annotate(&mut decisions);
+        let packages: Vec<_> = decisions.iter().map(|d| d.package.as_deref()).collect();
+        assert_eq!(
+            packages,
+            vec![Some("kernel-default"), Some("kernel-firmware-b"), None, None, None]
+        );
+        assert_eq!(
+            summarize(&decisions),
+            vec![
+                (Some("kernel-default".to_string()), 1, 10),
+                (Some("kernel-firmware-b".to_string()), 1, 5),
+                (None, 2, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_owners_dpkg() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("var/lib/dpkg/info")).unwrap();
+        assert_eq!(PackageDb::detect(root), Some(PackageDb::Dpkg));
+
+        let owners = Owners::read(root, PackageDb::Dpkg, &MockCommandRunner).unwrap();
+        assert_eq!(owners.owner(&root.join("usr/lib/firmware/b.bin")), Some("firmware-misc-nonfree"));
+        assert_eq!(owners.owner(&root.join("lib/modules/6.1/a.ko")), Some("linux-image-6.1"));
+        assert_eq!(owners.owner(&root.join("bin/sh")), None);
+    }
+
+    #[test]
+    fn test_removable_packages() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let fw_dir = root.join("usr/lib/firmware");
+        fs::create_dir_all(fw_dir.join("nvidia")).unwrap();
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::write(root.join("usr/bin/zypper"), "").unwrap();
+        for name in ["nvidia/a.bin", "nvidia/b.bin", "amd.bin", "amd2.bin"] {
+            fs::write(fw_dir.join(name), "fw").unwrap();
+        }
+        let owners = Owners {
+            root: root.to_path_buf(),
+            files: parse_rpm(
+                "/usr/lib/firmware/nvidia\tkernel-firmware-nvidia\n\
+                 /usr/lib/firmware/nvidia/a.bin\tkernel-firmware-nvidia\n\
+                 /usr/lib/firmware/nvidia/b.bin\tkernel-firmware-nvidia\n\
+                 /usr/lib/firmware/nvidia/gone.bin\tkernel-firmware-nvidia\n\
+                 /usr/share/doc/nvidia/README\tkernel-firmware-nvidia\n\
+                 /usr/lib/firmware/amd.bin\tkernel-firmware-amd\n\
+                 /usr/lib/firmware/amd2.bin\tkernel-firmware-amd",
+            ),
+        };
+        let mut decisions = vec![
+            decision(fw_dir.join("nvidia/a.bin"), Action::Delete, 2),
+            decision(fw_dir.join("nvidia/b.bin"), Action::Delete, 2),
+            decision(fw_dir.join("amd.bin"), Action::Delete, 2),
+            decision(fw_dir.join("amd2.bin"), Action::Keep, 2),
+        ];
+        owners.annotate(&mut decisions);
+        let removable = owners.removable_packages(&decisions, &[&fw_dir]);
+        assert_eq!(removable, vec!["kernel-firmware-nvidia"]);
+
+        let script = root.join("remove.sh");
+        write_removal_script(&script, root, PackageDb::Rpm, &removable).unwrap();
+        assert_eq!(
+            fs::read_to_string(&script).unwrap().lines().last().unwrap(),
+            format!("zypper --root '{}' --non-interactive remove kernel-firmware-nvidia", root.display())
+        );
+        assert_eq!(fs::metadata(&script).unwrap().permissions().mode() & 0o777, 0o755);
+    }
+
+    #[test]
+    fn test_owners_apk() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("lib/apk/db")).unwrap();
+        fs::write(
+            root.join(APK_INSTALLED),
+            "C:Q1abc=\nP:linux-lts\nV:6.6.31-r0\nF:lib/modules/6.6.31-0-lts/kernel\nR:a.ko.gz\n\n\
+             P:linux-firmware-amdgpu\nF:lib/firmware/amdgpu\nR:navi10_gpu_info.bin\n",
+        )
+        .unwrap();
+        assert_eq!(PackageDb::detect(root), Some(PackageDb::Apk));
+
+        let owners = Owners::read(root, PackageDb::Apk, &MockCommandRunner).unwrap();
+        assert_eq!(
+            owners.owner(&root.join("lib/modules/6.6.31-0-lts/kernel/a.ko.gz")),
+            Some("linux-lts")
+        );
+        assert_eq!(
+            owners.owner(&root.join("lib/firmware/amdgpu/navi10_gpu_info.bin")),
+            Some("linux-firmware-amdgpu")
+        );
+    }
+}
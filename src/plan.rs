@@ -0,0 +1,359 @@
+//! Cleanup plans: the files a cleanup would remove from an image, computed on one machine and
+//! applied on others.
+//!
+//! A build controller runs the scanners once and ships the plan, devices only need to apply it.
+//! Before anything is deleted, every planned file is checked against the image, so a plan is never
+//! applied to an image it was not computed for.
+
+use crate::atomic;
+use crate::clock::Clock;
+use crate::compare::FileChange;
+use crate::deleter::Deleter;
+use crate::error::JanitorError;
+use crate::explain::{Action, Decision};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A file to remove, relative to the image root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedFile {
+    pub path: String,
+    pub size: u64,
+}
+
+/// The files a cleanup run would remove.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Plan {
+    /// Version of image-janitor which computed the plan.
+    pub version: String,
+    pub files: Vec<PlannedFile>,
+}
+
+impl Plan {
+    /// Builds the plan removing `paths`, which are below `root`.
+    pub fn from_paths(root: &Path, paths: &[PathBuf]) -> Result<Self, JanitorError> {
+        let mut files = Vec::new();
+        for path in paths {
+            let metadata = fs::symlink_metadata(path)?;
+            if metadata.is_dir() {
+                continue;
+            }
+            let relative = path
+                .strip_prefix(root)
+                .ok()
+                .and_then(|p| p.to_str())
+                .ok_or_else(|| JanitorError::InvalidPath(path.clone()))?;
+            files.push(PlannedFile {
+                path: relative.to_string(),
+                size: metadata.len(),
+            });
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Plan {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            files,
+        })
+    }
+
+    /// Builds the plan removing the files deleted by the `decisions` of an explanation report,
+    /// with their absolute paths.
+    pub fn from_decisions(decisions: &[Decision]) -> Self {
+        let mut files: Vec<PlannedFile> = decisions
+            .iter()
+            .filter(|d| d.action == Action::Delete)
+            .map(|d| PlannedFile {
+                path: d.path.to_string_lossy().into_owned(),
+                size: d.size,
+            })
+            .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Plan {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            files,
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<Self, JanitorError> {
+        Ok(serde_json::from_str(content)?)
+    }
+
+    pub fn to_json(&self) -> Result<String, JanitorError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn read(path: &Path) -> Result<Self, JanitorError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), JanitorError> {
+        let content = self.to_json()?;
+        atomic::write_atomic(path, |file| file.write_all(content.as_bytes()))?;
+        Ok(())
+    }
+
+    /// Total size of the planned files, in bytes.
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+
+    /// Returns the files of the plan missing from `expected`, e.g. an approved plan.
+    pub fn unexpected(&self, expected: &Plan) -> Vec<&PlannedFile> {
+        let expected: std::collections::BTreeSet<&str> =
+            expected.files.iter().map(|f| f.path.as_str()).collect();
+        self.files
+            .iter()
+            .filter(|f| !expected.contains(f.path.as_str()))
+            .collect()
+    }
+
+    /// Compares the plan with the `newer` one, e.g. of the next build of the image.
+    pub fn diff(&self, newer: &Plan) -> PlanDiff {
+        let mut sizes: BTreeMap<&str, (Option<u64>, Option<u64>)> = BTreeMap::new();
+        for file in &self.files {
+            sizes.entry(&file.path).or_default().0 = Some(file.size);
+        }
+        for file in &newer.files {
+            sizes.entry(&file.path).or_default().1 = Some(file.size);
+        }
+        let mut diff = PlanDiff {
+            bytes_old: self.size(),
+            bytes_new: newer.size(),
+            ..PlanDiff::default()
+        };
+        for (path, (size_a, size_b)) in sizes {
+            let change = FileChange {
+                path: path.to_string(),
+                size_a,
+                size_b,
+            };
+            match (size_a, size_b) {
+                (None, _) => diff.added.push(change),
+                (_, None) => diff.removed.push(change),
+                (a, b) if a != b => diff.changed.push(change),
+                _ => {}
+            }
+        }
+        diff
+    }
+}
+
+/// Differences between two plans, sizes being the ones of the older plan as A and of the newer
+/// one as B.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PlanDiff {
+    /// Files newly unused.
+    pub added: Vec<FileChange>,
+    /// Files no longer unused: used again, or gone from the image.
+    pub removed: Vec<FileChange>,
+    /// Files unused in both plans, with different sizes.
+    pub changed: Vec<FileChange>,
+    /// Total size of the files of each plan.
+    pub bytes_old: u64,
+    pub bytes_new: u64,
+}
+
+impl PlanDiff {
+    /// Growth of the unused files from the older plan to the newer one, in bytes.
+    pub fn delta(&self) -> i64 {
+        self.bytes_new as i64 - self.bytes_old as i64
+    }
+}
+
+/// Options of a plan application.
+#[derive(Debug, Clone, Default)]
+pub struct ApplyOptions {
+    /// Root of the image the plan is applied to.
+    pub root: PathBuf,
+    /// Really delete the files.
+    pub delete: bool,
+    /// Archive the deleted files are saved to before being deleted.
+    pub backup: Option<PathBuf>,
+    /// Journal the deletions are recorded in.
+    pub journal: Option<PathBuf>,
+    /// Clock timestamping the journal entries, the system clock if unset.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Quarantine directory the deleted files are moved to instead of being unlinked, see
+    /// [`crate::quarantine`].
+    pub quarantine: Option<PathBuf>,
+}
+
+/// Removes the files of `plan` below `options.root`, returning the deleted paths (or the ones that
+/// would be deleted in a dry run). Files already gone are skipped, a file whose size differs from
+/// the plan aborts the run before anything is deleted.
+pub fn apply_plan(plan: &Plan, options: &ApplyOptions) -> Result<Vec<PathBuf>, JanitorError> {
+    let mut present = Vec::new();
+    for file in &plan.files {
+        let path = options.root.join(&file.path);
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.len() == file.size && !metadata.is_dir() => {
+                present.push((path, file.size))
+            }
+            Ok(_) => return Err(JanitorError::PlanMismatch(path)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!("{} is already gone", path.display())
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    info!(
+        "Applying a plan of {} files, {} already gone",
+        plan.files.len(),
+        plan.files.len() - present.len()
+    );
+
+    let mut deleter = Deleter::new(options.delete);
+    if let Some(backup) = &options.backup {
+        deleter = deleter.with_backup(backup)?;
+    }
+    if let Some(journal) = &options.journal {
+        deleter = deleter.with_journal(journal)?;
+    }
+    if let Some(clock) = &options.clock {
+        deleter = deleter.with_clock(Arc::clone(clock));
+    }
+    if let Some(quarantine) = &options.quarantine {
+        deleter = deleter.with_quarantine(quarantine)?;
+    }
+    for (path, size) in present {
+        deleter.remove_file(&path, size, "listed in the cleanup plan")?;
+    }
+    deleter.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::explain::FileType;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_plan_round_trip_and_apply() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("lib/firmware")).unwrap();
+        fs::write(root.join("lib/firmware/a.bin"), "aaaa").unwrap();
+        fs::write(root.join("lib/firmware/b.bin"), "bb").unwrap();
+        fs::write(root.join("lib/firmware/c.bin"), "c").unwrap();
+
+        let paths = vec![
+            root.join("lib/firmware/b.bin"),
+            root.join("lib/firmware/a.bin"),
+        ];
+        let plan = Plan::from_paths(root, &paths).unwrap();
+        assert_eq!(plan.files[0].path, "lib/firmware/a.bin");
+        assert_eq!(plan.files[0].size, 4);
+        let plan_path = root.join("plan.json");
+        plan.write(&plan_path).unwrap();
+        let plan = Plan::read(&plan_path).unwrap();
+
+        // The plan is applied to another copy of the image, where b.bin is already gone.
+        let device = root.join("device");
+        fs::create_dir_all(device.join("lib/firmware")).unwrap();
+        fs::write(device.join("lib/firmware/a.bin"), "aaaa").unwrap();
+        fs::write(device.join("lib/firmware/c.bin"), "c").unwrap();
+        let options = ApplyOptions {
+            root: device.clone(),
+            delete: true,
+            ..Default::default()
+        };
+        let deleted = apply_plan(&plan, &options).unwrap();
+        assert_eq!(deleted, vec![device.join("lib/firmware/a.bin")]);
+        assert!(device.join("lib/firmware/c.bin").exists());
+    }
+
+    #[test]
+    fn test_apply_plan_mismatch() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::write(root.join("a.bin"), "aaaa").unwrap();
+        fs::write(root.join("b.bin"), "changed").unwrap();
+        let plan = Plan {
+            files: vec![
+                PlannedFile {
+                    path: "a.bin".to_string(),
+                    size: 4,
+                },
+                PlannedFile {
+                    path: "b.bin".to_string(),
+                    size: 2,
+                },
+            ],
+            ..Default::default()
+        };
+        let options = ApplyOptions {
+            root: root.to_path_buf(),
+            delete: true,
+            ..Default::default()
+        };
+        let result = apply_plan(&plan, &options);
+        assert!(matches!(result, Err(JanitorError::PlanMismatch(_))));
+        assert!(root.join("a.bin").exists());
+    }
+
+    #[test]
+    fn test_unexpected() {
+        let file = |path: &str, size| PlannedFile {
+            path: path.to_string(),
+            size,
+        };
+        let approved = Plan {
+            version: "0.1.0".to_string(),
+            files: vec![file("lib/firmware/a.bin", 4), file("lib/firmware/b.bin", 2)],
+        };
+        let plan = Plan {
+            version: "0.2.0".to_string(),
+            files: vec![file("lib/firmware/a.bin", 4), file("lib/modules/6.1/nvme.ko", 100)],
+        };
+        assert_eq!(plan.size(), 104);
+        assert_eq!(plan.unexpected(&approved), vec![&file("lib/modules/6.1/nvme.ko", 100)]);
+        assert!(approved.unexpected(&approved).is_empty());
+    }
+
+    #[test]
+    fn test_diff() {
+        let file = |path: &str, size| PlannedFile {
+            path: path.to_string(),
+            size,
+        };
+        let old = Plan {
+            version: "0.2.0".to_string(),
+            files: vec![file("lib/firmware/a.bin", 4), file("lib/firmware/b.bin", 10), file("lib/firmware/c.bin", 1)],
+        };
+        let new = Plan {
+            version: "0.2.0".to_string(),
+            files: vec![file("lib/firmware/a.bin", 4), file("lib/firmware/b.bin", 12), file("lib/modules/6.1/nvme.ko", 100)],
+        };
+        let diff = old.diff(&new);
+        let paths = |changes: &[FileChange]| changes.iter().map(|c| c.path.clone()).collect::<Vec<_>>();
+        assert_eq!(paths(&diff.added), vec!["lib/modules/6.1/nvme.ko"]);
+        assert_eq!(paths(&diff.removed), vec!["lib/firmware/c.bin"]);
+        assert_eq!(paths(&diff.changed), vec!["lib/firmware/b.bin"]);
+        assert_eq!(diff.changed[0].delta(), 2);
+        assert_eq!(diff.delta(), 101);
+    }
+
+    #[test]
+    fn test_from_decisions() {
+        let decision = |path: &str, action| Decision {
+            path: PathBuf::from(path),
+            file_type: FileType::Firmware,
+            size: 3,
+            action,
+            reason: String::new(),
+            package: None,
+        };
+        let plan = Plan::from_decisions(&[
+            decision("/lib/firmware/b.bin", Action::Delete),
+            decision("/lib/firmware/c.bin", Action::Keep),
+            decision("/lib/firmware/a.bin", Action::Delete),
+        ]);
+        let paths: Vec<&str> = plan.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, vec!["/lib/firmware/a.bin", "/lib/firmware/b.bin"]);
+        assert_eq!(plan.size(), 6);
+    }
+}
@@ -0,0 +1,1681 @@
+//! Pure policy evaluation: configuration parsing, rule matching and dependency closure.
+//!
+//! Nothing in this module touches the filesystem or spawns processes, so it builds without
+//! the `native` feature (e.g. for WebAssembly) and can be fed with module metadata gathered
+//! elsewhere, such as a manifest uploaded to a web based image configurator.
+
+use crate::error::JanitorError;
+use log::{debug, info};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+/// Keep and delete rules read from module list configuration files.
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    pub keep: Vec<Regex>,
+    pub delete: Vec<Regex>,
+    /// Rule lines using the `${KVER}` or `${FLAVOR}` variables, delete rules with their `-`
+    /// prefix, compiled for each kernel by [`Rules::for_kernel`].
+    pub templates: Vec<String>,
+    /// Rules of the `<flavor:NAME>` sections, by kernel flavor, see [`Rules::for_flavor`].
+    pub flavors: BTreeMap<String, Rules>,
+    /// How a path matched by several rules is decided.
+    pub precedence: Precedence,
+    /// Positions of the keep rules, delete rules and templates in the configuration, for the
+    /// precedence modes depending on the order of the rules.
+    keep_ranks: Vec<usize>,
+    delete_ranks: Vec<usize>,
+    template_ranks: Vec<usize>,
+    /// Priorities of the keep and delete rules, see [`parse_rule`].
+    keep_priorities: Vec<u32>,
+    delete_priorities: Vec<u32>,
+}
+
+/// How the rules matching a path decide it, declared by a `precedence MODE` line in the header
+/// of a configuration file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Precedence {
+    /// Delete rules win over keep rules, whatever their order (`delete-wins`).
+    #[default]
+    DeleteWins,
+    /// Keep rules win over delete rules, whatever their order (`keep-overrides-delete`).
+    KeepOverridesDelete,
+    /// The first matching rule decides (`first-match-wins`).
+    FirstMatchWins,
+    /// The last matching rule decides (`last-match-wins`), so later rules add exceptions to
+    /// earlier ones.
+    LastMatchWins,
+}
+
+impl Precedence {
+    /// The modes, as named in the configuration files.
+    pub const NAMES: &'static [(&'static str, Precedence)] = &[
+        ("delete-wins", Precedence::DeleteWins),
+        ("keep-overrides-delete", Precedence::KeepOverridesDelete),
+        ("first-match-wins", Precedence::FirstMatchWins),
+        ("last-match-wins", Precedence::LastMatchWins),
+    ];
+
+    /// Returns the mode called `name` in the configuration files.
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::NAMES.iter().find(|(n, _)| *n == name).map(|(_, mode)| *mode)
+    }
+
+    fn names() -> String {
+        Self::NAMES.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// Outcome of matching a path against the configured rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleMatch {
+    Keep,
+    Delete,
+    Unmatched,
+}
+
+impl Rules {
+    /// Parses configuration content for the architecture `arch`.
+    pub fn parse(content: &str, arch: &str) -> Result<Self, JanitorError> {
+        Self::from_lines(content.lines().map(String::from).collect(), arch)
+    }
+
+    /// Builds the rules from configuration lines, skipping comments and other architectures.
+    /// `${ARCH}` is replaced by `arch` right away. The lines of several files may declare the
+    /// same precedence, but not different ones.
+    pub fn from_lines(lines: Vec<String>, arch: &str) -> Result<Self, JanitorError> {
+        let mut precedence = None;
+        for name in lines.iter().filter_map(|l| precedence_directive(l)) {
+            let mode = Precedence::parse(name).ok_or_else(|| {
+                JanitorError::InvalidConfig(format!(
+                    "unknown precedence '{}', expected one of {}",
+                    name,
+                    Precedence::names()
+                ))
+            })?;
+            if precedence.is_some_and(|declared| declared != mode) {
+                return Err(JanitorError::InvalidConfig(format!(
+                    "conflicting precedence '{}', another configuration file declares a different one",
+                    name
+                )));
+            }
+            precedence = Some(mode);
+        }
+        let lines = lines
+            .into_iter()
+            .filter(|l| {
+                !l.is_empty()
+                    && !l.starts_with('#')
+                    && include_target(l).is_none()
+                    && precedence_directive(l).is_none()
+            })
+            .map(|l| expand_variable(&l, "ARCH", arch))
+            .collect();
+        let (lines, flavor_sections) = split_flavor_sections(lines);
+        let mut rules = Self::compile(arch_filter(lines, arch))?;
+        for (flavor, lines) in flavor_sections {
+            rules.flavors.insert(flavor, Self::compile(lines)?);
+        }
+        rules.precedence = precedence.unwrap_or_default();
+        Ok(rules)
+    }
+
+    /// The rules applying to the kernels of `flavor`: the common ones and the ones of its
+    /// section, if any, which come after the common ones for the precedence modes depending on
+    /// the order of the rules.
+    pub fn for_flavor(&self, flavor: Option<&str>) -> Rules {
+        let mut rules = Rules {
+            keep: self.keep.clone(),
+            delete: self.delete.clone(),
+            templates: self.templates.clone(),
+            flavors: BTreeMap::new(),
+            precedence: self.precedence,
+            keep_ranks: self.keep_ranks.clone(),
+            delete_ranks: self.delete_ranks.clone(),
+            template_ranks: self.template_ranks.clone(),
+            keep_priorities: self.keep_priorities.clone(),
+            delete_priorities: self.delete_priorities.clone(),
+        };
+        if let Some(specific) = flavor.and_then(|f| self.flavors.get(f)) {
+            let offset = self.keep.len() + self.delete.len() + self.templates.len();
+            let shift = |ranks: &[usize]| ranks.iter().map(|rank| rank + offset).collect::<Vec<_>>();
+            rules.keep.extend(specific.keep.iter().cloned());
+            rules.keep_ranks.extend(shift(&specific.keep_ranks));
+            rules.keep_priorities.extend(specific.keep_priorities.iter().copied());
+            rules.delete.extend(specific.delete.iter().cloned());
+            rules.delete_ranks.extend(shift(&specific.delete_ranks));
+            rules.delete_priorities.extend(specific.delete_priorities.iter().copied());
+            rules.templates.extend(specific.templates.iter().cloned());
+            rules.template_ranks.extend(shift(&specific.template_ranks));
+        }
+        rules
+    }
+
+    /// The rules applying to the kernel `release`: the ones of its flavor, see
+    /// [`Rules::for_flavor`], with the templates compiled for it. Fails if a template uses
+    /// `${FLAVOR}` while the kernel has no flavor.
+    pub fn for_kernel(&self, release: &str) -> Result<Rules, JanitorError> {
+        let flavor = kernel_flavor(release);
+        let mut rules = self.for_flavor(flavor);
+        let mut lines = Vec::new();
+        for template in std::mem::take(&mut rules.templates) {
+            let line = expand_variable(&template, "KVER", release);
+            let line = match flavor {
+                Some(flavor) => expand_variable(&line, "FLAVOR", flavor),
+                None if line.contains("${FLAVOR}") => {
+                    return Err(JanitorError::InvalidConfig(format!(
+                        "rule '{}' uses ${{FLAVOR}}, but kernel {} has no flavor",
+                        template, release
+                    )))
+                }
+                None => line,
+            };
+            lines.push(line);
+        }
+        let template_ranks = std::mem::take(&mut rules.template_ranks);
+        let compiled = Self::compile(lines)?;
+        rules.keep.extend(compiled.keep);
+        rules.keep_ranks.extend(compiled.keep_ranks.iter().map(|i| template_ranks[*i]));
+        rules.keep_priorities.extend(compiled.keep_priorities);
+        rules.delete.extend(compiled.delete);
+        rules.delete_ranks.extend(compiled.delete_ranks.iter().map(|i| template_ranks[*i]));
+        rules.delete_priorities.extend(compiled.delete_priorities);
+        Ok(rules)
+    }
+
+    /// Compiles rule lines, without sections. The lines using kernel variables are kept as
+    /// templates.
+    fn compile(lines: Vec<String>) -> Result<Self, JanitorError> {
+        let mut rules = Rules::default();
+        for (rank, line) in lines.into_iter().enumerate() {
+            let variables = rule_variables(&line);
+            if let Some((_, unknown)) = variables.iter().find(|(_, name)| !RULE_VARIABLES.contains(name)) {
+                return Err(JanitorError::InvalidConfig(format!(
+                    "unknown variable ${{{}}} in rule '{}', expected ${{KVER}}, ${{ARCH}} or ${{FLAVOR}}",
+                    unknown, line
+                )));
+            }
+            if !variables.is_empty() {
+                rules.templates.push(line);
+                rules.template_ranks.push(rank);
+                continue;
+            }
+            match parse_rule(&line).map_err(JanitorError::InvalidConfig)? {
+                (RuleMatch::Delete, priority, pattern) => {
+                    rules.delete.push(rule_regex(pattern).map_err(JanitorError::Regex)?);
+                    rules.delete_ranks.push(rank);
+                    rules.delete_priorities.push(priority);
+                }
+                (_, priority, pattern) => {
+                    rules.keep.push(rule_regex(pattern).map_err(JanitorError::Regex)?);
+                    rules.keep_ranks.push(rank);
+                    rules.keep_priorities.push(priority);
+                }
+            }
+        }
+        Ok(rules)
+    }
+
+    /// Matches `path`, relative to the kernel directory, deciding between the matching rules
+    /// according to the precedence.
+    pub fn matches(&self, path: &str) -> RuleMatch {
+        self.matching_rule(path).0
+    }
+
+    /// Like [`Rules::matches`], also returning the rule which decided.
+    pub fn matching_rule(&self, path: &str) -> (RuleMatch, Option<&Regex>) {
+        match self.decisive_rule(path) {
+            Some((kind, rule, _)) => (kind, Some(rule)),
+            None => (RuleMatch::Unmatched, None),
+        }
+    }
+
+    /// Like [`Rules::matches`], also returning the rule which decided as reported in the
+    /// reasons: its pattern, in the annotated syntax if it has a priority.
+    pub fn matching_rule_text(&self, path: &str) -> (RuleMatch, Option<String>) {
+        match self.decisive_rule(path) {
+            Some((kind, rule, 0)) => (kind, Some(rule.as_str().to_string())),
+            Some((RuleMatch::Delete, rule, priority)) => {
+                (RuleMatch::Delete, Some(format!("{}{} {}", DELETE_ANNOTATION, priority, rule.as_str())))
+            }
+            Some((kind, rule, priority)) => (kind, Some(format!("{}{} {}", KEEP_ANNOTATION, priority, rule.as_str()))),
+            None => (RuleMatch::Unmatched, None),
+        }
+    }
+
+    /// Returns the priority of the delete rule deciding `path`, 0 if it is not deleted by a rule
+    /// or by one without priority.
+    pub fn delete_priority(&self, path: &str) -> u32 {
+        match self.decisive_rule(path) {
+            Some((RuleMatch::Delete, _, priority)) => priority,
+            _ => 0,
+        }
+    }
+
+    /// Returns the rule deciding `path` with its kind and priority: among the matching rules of
+    /// the highest priority, the one the precedence selects.
+    fn decisive_rule(&self, path: &str) -> Option<(RuleMatch, &Regex, u32)> {
+        // Rules added without a position come after the configured ones.
+        let rank = |ranks: &[usize], index: usize| ranks.get(index).copied().unwrap_or(usize::MAX);
+        let priority = |priorities: &[u32], index: usize| priorities.get(index).copied().unwrap_or_default();
+        let mut matching = Vec::new();
+        for (index, rule) in self.keep.iter().enumerate().filter(|(_, r)| r.is_match(path)) {
+            let priority = priority(&self.keep_priorities, index);
+            matching.push((rank(&self.keep_ranks, index), RuleMatch::Keep, rule, priority));
+        }
+        for (index, rule) in self.delete.iter().enumerate().filter(|(_, r)| r.is_match(path)) {
+            let priority = priority(&self.delete_priorities, index);
+            matching.push((rank(&self.delete_ranks, index), RuleMatch::Delete, rule, priority));
+        }
+        let highest = matching.iter().map(|(_, _, _, priority)| *priority).max()?;
+        matching.retain(|(_, _, _, priority)| *priority == highest);
+        matching.sort_by_key(|(rank, _, _, _)| *rank);
+        let first = |kind| matching.iter().find(|(_, k, _, _)| *k == kind);
+        let decisive = match self.precedence {
+            Precedence::DeleteWins => first(RuleMatch::Delete).or(first(RuleMatch::Keep)),
+            Precedence::KeepOverridesDelete => first(RuleMatch::Keep).or(first(RuleMatch::Delete)),
+            Precedence::FirstMatchWins => matching.first(),
+            Precedence::LastMatchWins => matching.last(),
+        };
+        decisive.map(|(_, kind, rule, priority)| (*kind, *rule, *priority))
+    }
+
+    /// Records in `usage` whether each rule matches one of `paths`. Rules are keyed as written in
+    /// a configuration file, delete rules with their `-` prefix; a rule already used stays so, to
+    /// accumulate the usage over several module trees.
+    pub fn record_usage(&self, paths: &[&str], usage: &mut BTreeMap<String, bool>) {
+        let rules = self
+            .keep
+            .iter()
+            .map(|r| (r.as_str().to_string(), r))
+            .chain(self.delete.iter().map(|r| (format!("-{}", r.as_str()), r)));
+        for (key, rule) in rules {
+            let used = paths.iter().any(|path| rule.is_match(path));
+            *usage.entry(key).or_default() |= used;
+        }
+    }
+
+    /// Returns at most `count` keep rules closest to matching `path`, the closest first, to spot
+    /// misspelt rules. The distance of a rule is the edit distance between its literal text,
+    /// without the operators, and the part of `path` it resembles most.
+    pub fn closest_keep_rules(&self, path: &str, count: usize) -> Vec<&Regex> {
+        let mut rules: Vec<(usize, &Regex)> = self
+            .keep
+            .iter()
+            .map(|rule| (substring_distance(&rule_literal(rule.as_str()), path), rule))
+            .collect();
+        // The sort is stable, so rules as close keep their order in the configuration.
+        rules.sort_by_key(|(distance, _)| *distance);
+        rules.into_iter().take(count).map(|(_, rule)| rule).collect()
+    }
+}
+
+/// Returns the literal characters of the regular expression `rule`, dropping its operators and
+/// character classes.
+fn rule_literal(rule: &str) -> String {
+    let mut literal = String::new();
+    let mut chars = rule.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => literal.extend(chars.next()),
+            '[' => {
+                // A `]` right after the opening bracket is part of the class.
+                let mut first = true;
+                for c in chars.by_ref() {
+                    if c == ']' && !first {
+                        break;
+                    }
+                    first = c == '^' && first;
+                }
+            }
+            '^' | '$' | '.' | '*' | '+' | '?' | '(' | ')' | '{' | '}' | '|' => {}
+            c => literal.push(c),
+        }
+    }
+    literal
+}
+
+/// Returns the smallest edit distance between `pattern` and a substring of `text`.
+fn substring_distance(pattern: &str, text: &str) -> usize {
+    let text: Vec<char> = text.chars().collect();
+    // Starting anywhere in the text is free.
+    let mut previous = vec![0; text.len() + 1];
+    for (i, p) in pattern.chars().enumerate() {
+        let mut current = vec![i + 1; text.len() + 1];
+        for (j, t) in text.iter().enumerate() {
+            let substitution = previous[j] + usize::from(p != *t);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous.into_iter().min().unwrap_or(0)
+}
+
+/// Prefix of the tags of the sections applying to a kernel flavor, e.g. `<flavor:rt>`.
+const FLAVOR_PREFIX: &str = "flavor:";
+
+/// Moves the lines of the `<flavor:NAME>` sections out of `lines`, returning the remaining
+/// lines and the ones of each flavor.
+fn split_flavor_sections(lines: Vec<String>) -> (Vec<String>, BTreeMap<String, Vec<String>>) {
+    let start_tag_re = Regex::new(r"^\s*<flavor:([\w.-]+)\s*>\s*$").unwrap();
+    let end_tag_re = Regex::new(r"^\s*</flavor:[\w.-]+\s*>\s*$").unwrap();
+    let mut common = Vec::new();
+    let mut flavors: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut flavor: Option<String> = None;
+    for line in lines {
+        if let Some(captures) = start_tag_re.captures(&line) {
+            let name = captures[1].to_string();
+            flavors.entry(name.clone()).or_default();
+            flavor = Some(name);
+        } else if end_tag_re.is_match(&line) {
+            flavor = None;
+        } else {
+            match &flavor {
+                Some(name) => flavors.get_mut(name).unwrap().push(line),
+                None => common.push(line),
+            }
+        }
+    }
+    (common, flavors)
+}
+
+/// Returns the flavor of the kernel `release`, the last dash separated part of it if it holds a
+/// letter: `default` for `6.4.0-150600.23-default`, `64kb` for `6.4.0-150600.23-64kb`.
+pub fn kernel_flavor(release: &str) -> Option<&str> {
+    release
+        .rsplit_once('-')
+        .map(|(_, flavor)| flavor)
+        .filter(|flavor| flavor.chars().any(|c| c.is_ascii_alphabetic()))
+}
+
+/// Keeps the lines outside of architecture sections and inside the sections for `arch`.
+pub fn arch_filter(lines: Vec<String>, arch: &str) -> Vec<String> {
+    let mut filtered = Vec::new();
+    let mut skipping = false;
+    let mut arch_tag: Option<String> = None;
+
+    let start_tag_re = Regex::new(r"^\s*<(\w+)\s*>\s*$").unwrap();
+    let end_tag_re = Regex::new(r"^\s*</\w+\s*>\s*$").unwrap();
+
+    for line in lines {
+        if let Some(captures) = start_tag_re.captures(&line) {
+            let tag = captures.get(1).unwrap().as_str().to_string();
+            skipping = tag != arch;
+            arch_tag = Some(tag);
+            continue;
+        }
+
+        if end_tag_re.is_match(&line) {
+            skipping = false;
+            arch_tag = None;
+            continue;
+        }
+
+        if skipping {
+            debug!(
+                "Ignoring {} specific line: {}",
+                arch_tag.as_deref().unwrap_or(""),
+                line
+            );
+        } else {
+            filtered.push(line);
+        }
+    }
+
+    filtered
+}
+
+/// Returns the tags of the architecture sections opened in `lines`.
+pub fn arch_tags(lines: &[String]) -> BTreeSet<String> {
+    let start_tag_re = Regex::new(r"^\s*<(\w+)\s*>\s*$").unwrap();
+    lines
+        .iter()
+        .filter_map(|line| start_tag_re.captures(line))
+        .map(|captures| captures[1].to_string())
+        .collect()
+}
+
+/// Architectures expected in the section tags of the configuration files.
+pub const KNOWN_ARCHES: &[&str] = &[
+    "aarch64",
+    "armv7l",
+    "i586",
+    "i686",
+    "loongarch64",
+    "ppc64",
+    "ppc64le",
+    "riscv64",
+    "s390x",
+    "x86_64",
+];
+
+/// A problem found in configuration content, at a 1-based line and column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    /// The content is still usable, but probably not as intended.
+    pub warning: bool,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = if self.warning { "warning" } else { "error" };
+        write!(f, "{}:{}: {}: {}", self.line, self.column, level, self.message)
+    }
+}
+
+/// Checks configuration content, returning its problems in line order: malformed or unbalanced
+/// architecture sections, unknown architectures (with a suggestion when one is close) and
+/// invalid regular expressions.
+pub fn validate(content: &str) -> Vec<Diagnostic> {
+    // Broader than the tags accepted by `arch_filter`, to diagnose misspelled ones.
+    let start_tag_re = Regex::new(r"^(\s*<\s*)([\w.:-]+)\s*>\s*$").unwrap();
+    let end_tag_re = Regex::new(r"^(\s*</\s*)([\w.:-]+)\s*>\s*$").unwrap();
+    let mut diagnostics = Vec::new();
+    let mut error = |line: usize, column: usize, message: String| {
+        diagnostics.push(Diagnostic {
+            line,
+            column,
+            message,
+            warning: false,
+        })
+    };
+    let mut open: Option<(String, usize)> = None;
+    let mut unknown_arches = Vec::new();
+    // Only comments and includes may come before a precedence declaration.
+    let mut in_header = true;
+
+    for (index, text) in content.lines().enumerate() {
+        let line = index + 1;
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+
+        if let Some(mode) = precedence_directive(text) {
+            if Precedence::parse(mode).is_none() {
+                let message = format!("unknown precedence '{}', expected one of {}", mode, Precedence::names());
+                error(line, "precedence ".len() + 1, message);
+            }
+            if !in_header {
+                error(line, 1, "precedence declared after rules or sections, declare it in the header".to_string());
+            }
+            continue;
+        }
+        in_header &= include_target(text).is_some();
+
+        if let Some(captures) = start_tag_re.captures(text) {
+            let column = captures[1].len() + 1;
+            let tag = captures[2].to_string();
+            if let Some((outer, _)) = &open {
+                error(
+                    line,
+                    column,
+                    format!("section <{}> opened inside section <{}>, close it with </{}> first", tag, outer, outer),
+                );
+            }
+            if let Some(flavor) = tag.strip_prefix(FLAVOR_PREFIX) {
+                if flavor.is_empty() || flavor.contains(':') {
+                    error(line, column, format!("invalid kernel flavor '{}'", flavor));
+                }
+            } else if tag.contains(':') {
+                error(
+                    line,
+                    column,
+                    format!("unknown condition '{}', expected an architecture or flavor:NAME", tag),
+                );
+            } else if !KNOWN_ARCHES.contains(&tag.as_str()) {
+                match suggest_arch(&tag) {
+                    Some(arch) => error(
+                        line,
+                        column,
+                        format!("unknown architecture '{}', did you mean '{}'?", tag, arch),
+                    ),
+                    None if tag.contains(['.', '-']) => error(
+                        line,
+                        column,
+                        format!("invalid architecture '{}', only letters, digits and '_' are allowed", tag),
+                    ),
+                    None => unknown_arches.push((line, column, tag.clone())),
+                }
+            }
+            open = Some((tag, line));
+        } else if let Some(captures) = end_tag_re.captures(text) {
+            let column = captures[1].len() + 1;
+            let tag = &captures[2];
+            match &open {
+                None => error(line, column, format!("closing tag </{}> without opening tag", tag)),
+                Some((opened, opened_line)) if opened != tag => error(
+                    line,
+                    column,
+                    format!(
+                        "closing tag </{}> does not match <{}> opened at line {}",
+                        tag, opened, opened_line
+                    ),
+                ),
+                Some(_) => {}
+            }
+            open = None;
+        } else if include_target(text).is_some() {
+            if let Some((tag, _)) = &open {
+                error(
+                    line,
+                    1,
+                    format!("include inside section <{}>, includes apply to every architecture", tag),
+                );
+            }
+        } else if text.trim_start().starts_with('<') {
+            let column = text.len() - text.trim_start().len() + 1;
+            error(line, column, "malformed section tag, expected <arch> or </arch>".to_string());
+        } else {
+            let pattern = match parse_rule(text) {
+                Ok((_, _, pattern)) => pattern,
+                Err(message) => {
+                    error(line, 1, message);
+                    continue;
+                }
+            };
+            let column = text.len() - pattern.len() + 1;
+            let variables = rule_variables(text);
+            let unknown: Vec<_> = variables.iter().filter(|(_, name)| !RULE_VARIABLES.contains(name)).collect();
+            for (offset, name) in &unknown {
+                error(
+                    line,
+                    offset + 1,
+                    format!("unknown variable ${{{}}}, expected ${{KVER}}, ${{ARCH}} or ${{FLAVOR}}", name),
+                );
+            }
+            // The variables are checked with their names as values.
+            let pattern = variables
+                .iter()
+                .fold(pattern.to_string(), |pattern, (_, name)| expand_variable(&pattern, name, name));
+            if !unknown.is_empty() {
+                continue;
+            }
+            if let Err(e) = rule_regex(&pattern) {
+                error(line, column, format!("invalid regular expression: {}", regex_error_summary(&e)));
+            }
+        }
+    }
+    if let Some((tag, line)) = open {
+        error(line, 1, format!("section <{}> is never closed", tag));
+    }
+
+    diagnostics.extend(unknown_arches.into_iter().map(|(line, column, tag)| Diagnostic {
+        line,
+        column,
+        message: format!("unknown architecture '{}', this section is only used on it", tag),
+        warning: true,
+    }));
+    diagnostics.sort_by_key(|d| (d.line, d.column));
+    diagnostics
+}
+
+/// Variables the rules may use: the release and the flavor of the kernel whose modules are
+/// matched, and the architecture.
+pub const RULE_VARIABLES: &[&str] = &["KVER", "ARCH", "FLAVOR"];
+
+/// Returns the `${NAME}` variables used by a rule line, with their byte offsets.
+fn rule_variables(line: &str) -> Vec<(usize, &str)> {
+    let mut variables = Vec::new();
+    let mut rest = line;
+    while let Some(start) = rest.find("${") {
+        let offset = line.len() - rest.len() + start;
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        variables.push((offset, &rest[start + 2..start + end]));
+        rest = &rest[start + end + 1..];
+    }
+    variables
+}
+
+/// Replaces the `${name}` variable of a rule line with `value`, escaped for a regular expression
+/// unless the rule is a glob.
+fn expand_variable(line: &str, name: &str, value: &str) -> String {
+    let is_glob = parse_rule(line).is_ok_and(|(_, _, pattern)| pattern.starts_with("glob:"));
+    let value = match is_glob {
+        true => value.to_string(),
+        false => regex::escape(value),
+    };
+    line.replace(&format!("${{{}}}", name), &value)
+}
+
+/// Compiles the pattern of a rule: a regular expression, or a glob if prefixed with `glob:`.
+fn rule_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    match pattern.strip_prefix("glob:") {
+        Some(glob) => Regex::new(&glob_to_regex(glob)),
+        None => Regex::new(pattern),
+    }
+}
+
+/// Prefixes of the keep and delete rules annotated with a priority.
+const KEEP_ANNOTATION: &str = "keep@";
+const DELETE_ANNOTATION: &str = "delete@";
+
+/// Splits a rule line into its kind, priority and pattern. Rules are written `PATTERN` or
+/// `-PATTERN`, with priority 0, or with a priority annotation `keep@N PATTERN` or
+/// `delete@N PATTERN`. Among the rules matching a path, only the ones of the highest priority
+/// are decided between by the precedence.
+pub fn parse_rule(line: &str) -> Result<(RuleMatch, u32, &str), String> {
+    let (kind, annotated) = match (line.strip_prefix(KEEP_ANNOTATION), line.strip_prefix(DELETE_ANNOTATION)) {
+        (Some(rest), _) => (RuleMatch::Keep, rest),
+        (_, Some(rest)) => (RuleMatch::Delete, rest),
+        _ => {
+            return Ok(match line.strip_prefix('-') {
+                Some(pattern) => (RuleMatch::Delete, 0, pattern),
+                None => (RuleMatch::Keep, 0, line),
+            })
+        }
+    };
+    let (priority, pattern) = annotated.split_once(' ').unwrap_or((annotated, ""));
+    let priority = priority
+        .parse()
+        .map_err(|_| format!("invalid priority '{}', expected a non-negative integer", priority))?;
+    match pattern.trim_start() {
+        "" => Err(format!("missing pattern after the priority of rule '{}'", line)),
+        pattern => Ok((kind, priority, pattern)),
+    }
+}
+
+/// Translates `glob` to a regular expression matching whole paths: `*` and `?` do not match
+/// `/`, `**` matches across directories and `[...]` (or `[!...]`) is a character class.
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` also matches no directory at all.
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let class: String = chars.clone().take_while(|c| *c != ']').collect();
+                if class.is_empty() || class.chars().count() == chars.clone().count() {
+                    // No closing bracket, a literal one.
+                    regex.push_str(r"\[");
+                } else {
+                    chars.nth(class.chars().count());
+                    let (negated, class) = match class.strip_prefix('!') {
+                        Some(class) => ("^", class),
+                        None => ("", class.as_str()),
+                    };
+                    regex.push('[');
+                    regex.push_str(negated);
+                    regex.push_str(&class.replace('\\', r"\\").replace('[', r"\["));
+                    regex.push(']');
+                }
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// Returns the mode named by `line` if it is a `precedence MODE` directive.
+pub fn precedence_directive(line: &str) -> Option<&str> {
+    line.strip_prefix("precedence ").map(str::trim)
+}
+
+/// Returns the file named by `line` if it is an `include FILE` directive.
+pub fn include_target(line: &str) -> Option<&str> {
+    line.strip_prefix("include ")
+        .map(str::trim)
+        .filter(|target| !target.is_empty())
+}
+
+/// A rule line of configuration content, with the section it is in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionRule {
+    /// 1-based line number.
+    pub line: usize,
+    /// Tag of the enclosing section, e.g. `x86_64` or `flavor:rt`.
+    pub section: Option<String>,
+    /// The rule as written, `-` prefix included.
+    pub text: String,
+}
+
+impl SectionRule {
+    fn is_delete(&self) -> bool {
+        parse_rule(&self.text).is_ok_and(|(kind, _, _)| kind == RuleMatch::Delete)
+    }
+
+    /// The pattern, without the `-` prefix of delete rules nor priority annotation.
+    fn pattern(&self) -> &str {
+        parse_rule(&self.text).map_or(&self.text, |(_, _, pattern)| pattern)
+    }
+
+    fn priority(&self) -> u32 {
+        parse_rule(&self.text).map_or(0, |(_, priority, _)| priority)
+    }
+
+    /// The path the rule matches literally, if its pattern has no wildcard nor regular
+    /// expression syntax other than `.`.
+    fn literal(&self) -> Option<&str> {
+        match self.pattern().strip_prefix("glob:") {
+            Some(glob) => (!glob.contains(['*', '?', '['])).then_some(glob),
+            None => {
+                let pattern = self.pattern();
+                let syntax = ['\\', '^', '$', '*', '+', '?', '(', ')', '[', ']', '{', '}', '|'];
+                (!pattern.contains(syntax)).then_some(pattern)
+            }
+        }
+    }
+}
+
+/// Lists the rules of configuration content with their line and section, skipping comments,
+/// includes and section tags.
+pub fn section_rules(content: &str) -> Vec<SectionRule> {
+    let start_tag_re = Regex::new(r"^\s*<\s*([\w.:-]+)\s*>\s*$").unwrap();
+    let end_tag_re = Regex::new(r"^\s*</\s*[\w.:-]+\s*>\s*$").unwrap();
+    let mut rules = Vec::new();
+    let mut section = None;
+    for (index, text) in content.lines().enumerate() {
+        if text.is_empty()
+            || text.starts_with('#')
+            || include_target(text).is_some()
+            || precedence_directive(text).is_some()
+        {
+            continue;
+        }
+        if let Some(captures) = start_tag_re.captures(text) {
+            section = Some(captures[1].to_string());
+        } else if end_tag_re.is_match(text) {
+            section = None;
+        } else {
+            rules.push(SectionRule {
+                line: index + 1,
+                section: section.clone(),
+                text: text.to_string(),
+            });
+        }
+    }
+    rules
+}
+
+/// Finds the rules which can never decide anything with the `precedence` of the configuration,
+/// `rules` being in reading order. A rule is shadowed by a rule with the same pattern or matching
+/// the path the rule names literally, which wins over it: with the default precedence, an
+/// earlier rule of the same kind or, for keep rules, a delete rule anywhere since delete rules
+/// win. The shadowing rule must apply wherever the shadowed one does: outside of any section or
+/// in the same one.
+///
+/// Returns the index of each shadowed rule with the index of the rule shadowing it.
+pub fn shadowed_rules(rules: &[SectionRule], precedence: Precedence) -> Vec<(usize, usize)> {
+    let regexes: Vec<Option<Regex>> = rules.iter().map(|r| rule_regex(r.pattern()).ok()).collect();
+    let shadows = |i: usize, j: usize| {
+        let (shadowing, shadowed) = (&rules[i], &rules[j]);
+        let applies = shadowing.section.is_none() || shadowing.section == shadowed.section;
+        let kind_wins = match (precedence, shadowing.is_delete(), shadowed.is_delete()) {
+            _ if shadowing.priority() != shadowed.priority() => shadowing.priority() > shadowed.priority(),
+            (Precedence::DeleteWins, true, false) | (Precedence::KeepOverridesDelete, false, true) => true,
+            (Precedence::DeleteWins | Precedence::KeepOverridesDelete, a, b) => a == b && i < j,
+            (Precedence::FirstMatchWins, _, _) => i < j,
+            (Precedence::LastMatchWins, _, _) => i > j,
+        };
+        let covers = shadowing.pattern() == shadowed.pattern()
+            || matches!(
+                (&regexes[i], shadowed.literal()),
+                (Some(regex), Some(literal)) if regex.is_match(literal)
+            );
+        i != j && applies && kind_wins && covers && regexes[j].is_some()
+    };
+    (0..rules.len())
+        .filter_map(|j| (0..rules.len()).find(|&i| shadows(i, j)).map(|i| (j, i)))
+        .collect()
+}
+
+/// Returns the known architecture closest to `tag`, if it is likely a misspelling of it.
+fn suggest_arch(tag: &str) -> Option<&'static str> {
+    let tag = tag.to_lowercase();
+    KNOWN_ARCHES
+        .iter()
+        .map(|arch| (edit_distance(&tag, arch), *arch))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, arch)| arch)
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Returns the one line description of a regex syntax error, without the pattern excerpt.
+fn regex_error_summary(e: &regex::Error) -> String {
+    let text = e.to_string();
+    text.lines()
+        .find_map(|l| l.strip_prefix("error: "))
+        .unwrap_or(&text)
+        .to_string()
+}
+
+/// Metadata of a kernel module, as needed to evaluate the policy.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Module {
+    /// Module name, with dashes normalized to underscores.
+    pub name: String,
+    /// Path relative to the kernel directory.
+    pub path: String,
+    /// Names of the modules this one depends on.
+    #[serde(default)]
+    pub deps: Vec<String>,
+    /// Names of the modules loaded before or after this one through `softdep`.
+    #[serde(default)]
+    pub softdeps: Vec<String>,
+}
+
+/// Why a module is kept or deleted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "detail", rename_all = "kebab-case")]
+pub enum Reason {
+    /// Matched this keep rule.
+    KeepRule(String),
+    /// Matched this delete rule.
+    DeleteRule(String),
+    /// Kept by name, e.g. matching a device modalias, loaded on a profiled machine or listed in
+    /// modules-load.d.
+    Name,
+    /// Needed by the named kept module.
+    Dependency(String),
+    /// Loaded along with the named kept module through `softdep`.
+    SoftDependency(String),
+    /// Loaded on the running system the cleanup runs on.
+    Loaded,
+    /// Smaller than this minimum size, in bytes, of the files worth deleting.
+    BelowMinSize(u64),
+    /// Unused, but the tree fits this target size, in bytes, without deleting it, see
+    /// [`select_within_budget`].
+    WithinSizeBudget(u64),
+    /// Target of this `weak-updates` link of another kernel.
+    WeakUpdateTarget(String),
+    /// Blacklisted in modprobe.d, see [`drop_blacklisted`].
+    Blacklisted,
+    /// Only needed by blacklisted modules.
+    NeededByBlacklisted,
+    /// Neither kept by a rule or by name, nor needed by a kept module.
+    Unmatched,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reason::KeepRule(rule) => write!(f, "matched keep rule '{}'", rule),
+            // Rules with a priority are reported in their annotated syntax.
+            Reason::DeleteRule(rule) if rule.starts_with(DELETE_ANNOTATION) => write!(f, "matched delete rule '{}'", rule),
+            Reason::DeleteRule(rule) => write!(f, "matched delete rule '-{}'", rule),
+            Reason::Name => write!(f, "kept by name (modalias, hardware profile or modules-load.d)"),
+            Reason::Dependency(module) => write!(f, "dependency of {}", module),
+            Reason::SoftDependency(module) => write!(f, "soft dependency of {}", module),
+            Reason::Loaded => write!(f, "loaded on the running system"),
+            Reason::BelowMinSize(size) => write!(f, "smaller than the minimum size of {} bytes", size),
+            Reason::WithinSizeBudget(size) => write!(f, "unused, kept within the target size of {} bytes", size),
+            Reason::WeakUpdateTarget(link) => write!(f, "target of the weak-updates link {}", link),
+            Reason::Blacklisted => write!(f, "blacklisted in modprobe.d"),
+            Reason::NeededByBlacklisted => write!(f, "only needed by blacklisted modules"),
+            Reason::Unmatched => write!(f, "not kept by any rule nor needed by a kept module"),
+        }
+    }
+}
+
+/// Result of evaluating the policy over a set of modules.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Evaluation {
+    /// Paths of the modules to keep.
+    pub keep: BTreeSet<String>,
+    /// Paths of the modules to delete.
+    pub delete: BTreeSet<String>,
+    /// Why each module is kept or deleted, keyed by path.
+    pub reasons: BTreeMap<String, Reason>,
+}
+
+/// Applies `rules` to `modules` and closes the keep set over hard and soft dependencies.
+pub fn evaluate(modules: &[Module], rules: &Rules) -> Evaluation {
+    evaluate_with_names(modules, rules, &BTreeSet::new())
+}
+
+/// Like [`evaluate`], also keeping the modules called one of `names` unless a delete rule
+/// matches them.
+pub fn evaluate_with_names(
+    modules: &[Module],
+    rules: &Rules,
+    names: &BTreeSet<String>,
+) -> Evaluation {
+    let mut rejected = Vec::new();
+    let seeds = modules
+        .iter()
+        .filter_map(|module| match rules.matching_rule_text(&module.path) {
+            (RuleMatch::Delete, rule) => {
+                debug!("Marked for deletion by config: {}", module.path);
+                rejected.push((module.path.clone(), Reason::DeleteRule(rule.unwrap_or_default())));
+                None
+            }
+            (RuleMatch::Keep, rule) => {
+                debug!("Marked for keeping by config: {}", module.path);
+                Some((module, Reason::KeepRule(rule.unwrap_or_default())))
+            }
+            (RuleMatch::Unmatched, _) if names.contains(&module.name) => {
+                debug!("Marked for keeping by name: {}", module.path);
+                Some((module, Reason::Name))
+            }
+            (RuleMatch::Unmatched, _) => None,
+        })
+        .collect();
+    let mut evaluation = keep_closure(modules, seeds);
+    // Modules matching a delete rule may still be kept as dependencies.
+    for (path, reason) in rejected {
+        if evaluation.delete.contains(&path) {
+            evaluation.reasons.insert(path, reason);
+        }
+    }
+    evaluation
+}
+
+/// Keeps the modules whose aliases match one of the `modaliases` of the target hardware,
+/// and closes the keep set over hard and soft dependencies.
+pub fn evaluate_modaliases(
+    modules: &[Module],
+    aliases: &[Alias],
+    modaliases: &[String],
+) -> Evaluation {
+    evaluate_names(modules, &match_modaliases(aliases, modaliases))
+}
+
+/// Keeps the modules called one of `names`, and closes the keep set over hard and soft
+/// dependencies.
+pub fn evaluate_names(modules: &[Module], names: &BTreeSet<String>) -> Evaluation {
+    let seeds = modules
+        .iter()
+        .filter(|m| names.contains(&m.name))
+        .inspect(|m| debug!("Marked for keeping by name: {}", m.path))
+        .map(|m| (m, Reason::Name))
+        .collect();
+    keep_closure(modules, seeds)
+}
+
+/// Deletes the modules called one of `blacklist` which `evaluation` keeps by rule or by name,
+/// along with the modules only kept for them. The blacklisted modules a kept module depends on
+/// are still kept, modprobe loads them as dependencies whatever the blacklist says.
+pub fn drop_blacklisted(
+    modules: &[Module],
+    evaluation: Evaluation,
+    blacklist: &BTreeSet<String>,
+) -> Evaluation {
+    let seeds = modules
+        .iter()
+        .filter(|m| !blacklist.contains(&m.name))
+        .filter_map(|m| match evaluation.reasons.get(&m.path) {
+            Some(reason @ (Reason::KeepRule(_) | Reason::Name)) => Some((m, reason.clone())),
+            _ => None,
+        })
+        .collect();
+    let mut dropped = keep_closure(modules, seeds);
+    for module in modules.iter().filter(|m| dropped.delete.contains(&m.path)) {
+        let reason = match &evaluation.reasons[&module.path] {
+            reason @ (Reason::DeleteRule(_) | Reason::Unmatched) => reason.clone(),
+            _ if blacklist.contains(&module.name) => Reason::Blacklisted,
+            _ => Reason::NeededByBlacklisted,
+        };
+        dropped.reasons.insert(module.path.clone(), reason);
+    }
+    dropped
+}
+
+/// Moves the modules to delete at the paths of `rescued` to the ones `evaluation` keeps, for the
+/// reason given with each, along with the modules to delete they depend on, hard or soft. The
+/// modules kept before and the ones still deleted keep their reason. Returns the paths of the
+/// modules moved.
+pub fn keep_also(modules: &[Module], evaluation: &mut Evaluation, rescued: Vec<(String, Reason)>) -> Vec<String> {
+    let mut rescued: HashMap<String, Reason> = rescued.into_iter().collect();
+    let seeds = modules
+        .iter()
+        .filter_map(|m| match rescued.remove(&m.path) {
+            Some(reason) => Some((m, reason)),
+            None if evaluation.keep.contains(&m.path) => Some((m, evaluation.reasons.get(&m.path)?.clone())),
+            None => None,
+        })
+        .collect();
+    let closure = keep_closure(modules, seeds);
+    let moved: Vec<String> = closure.keep.difference(&evaluation.keep).cloned().collect();
+    for path in &moved {
+        evaluation.delete.remove(path);
+        evaluation.keep.insert(path.clone());
+        evaluation.reasons.insert(path.clone(), closure.reasons[path].clone());
+    }
+    moved
+}
+
+/// Keeps `seeds` and every module they depend on, deleting the rest.
+fn keep_closure<'a>(modules: &'a [Module], seeds: Vec<(&'a Module, Reason)>) -> Evaluation {
+    let mut by_name: HashMap<&str, Vec<&Module>> = HashMap::new();
+    for module in modules {
+        by_name
+            .entry(module.name.as_str())
+            .or_default()
+            .push(module);
+    }
+
+    let mut keep = BTreeSet::new();
+    let mut reasons = BTreeMap::new();
+    let mut worklist = Vec::new();
+    for (module, reason) in seeds {
+        if keep.insert(module.path.clone()) {
+            reasons.insert(module.path.clone(), reason);
+            worklist.push(module);
+        }
+    }
+
+    while let Some(module) = worklist.pop() {
+        let deps = module.deps.iter().map(|d| (d, false));
+        let softdeps = module.softdeps.iter().map(|d| (d, true));
+        for (dep_name, soft) in deps.chain(softdeps) {
+            for dep in by_name.get(dep_name.as_str()).into_iter().flatten() {
+                // If the dependency was not already kept, keep it and
+                // put it on the worklist to process its dependencies.
+                if keep.insert(dep.path.clone()) {
+                    let reason = if soft {
+                        info!("Keep soft dependency {} of {}", dep.path, module.name);
+                        Reason::SoftDependency(module.name.clone())
+                    } else {
+                        info!("Keep dependant driver {}", dep.path);
+                        Reason::Dependency(module.name.clone())
+                    };
+                    reasons.insert(dep.path.clone(), reason);
+                    worklist.push(dep);
+                }
+            }
+        }
+    }
+
+    let delete: BTreeSet<String> = modules
+        .iter()
+        .filter(|m| !keep.contains(&m.path))
+        .map(|m| m.path.clone())
+        .collect();
+    for path in &delete {
+        reasons.insert(path.clone(), Reason::Unmatched);
+    }
+    Evaluation {
+        keep,
+        delete,
+        reasons,
+    }
+}
+
+/// Returns the files to delete for a tree of `total` bytes to fit in `target` bytes, among
+/// `candidates` given with their size and the priority of the delete rule dropping them, 0 for
+/// the unused ones: the ones of the highest priority first, then the largest, until enough space
+/// is reclaimed, so that as few files as possible go. The others can be kept. All of them are
+/// returned when they do not reclaim enough.
+pub fn select_within_budget<T: Clone + Ord>(candidates: &[(T, u64, u32)], total: u64, target: u64) -> BTreeSet<T> {
+    let mut sorted: Vec<&(T, u64, u32)> = candidates.iter().collect();
+    sorted.sort_by(|(a, a_size, a_priority), (b, b_size, b_priority)| {
+        b_priority.cmp(a_priority).then(b_size.cmp(a_size)).then_with(|| a.cmp(b))
+    });
+    let mut remaining = total;
+    let mut selected = BTreeSet::new();
+    for (candidate, size, _) in sorted {
+        if remaining <= target {
+            break;
+        }
+        remaining = remaining.saturating_sub(*size);
+        selected.insert(candidate.clone());
+    }
+    selected
+}
+
+/// Renders the dependency graph of `modules` in the DOT language, as a digraph called `name`.
+///
+/// Kept modules are green and deleted ones grey. The modules anchoring the keep set, kept by a
+/// rule or by name, have a bold border and the reason in their label. Hard dependencies are
+/// solid edges, soft dependencies dashed ones.
+pub fn to_dot(name: &str, modules: &[Module], evaluation: &Evaluation) -> String {
+    let quote = |s: &str| {
+        let escaped = s.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("\"{}\"", escaped.replace('\n', "\\n"))
+    };
+    let names: BTreeSet<&str> = modules.iter().map(|m| m.name.as_str()).collect();
+
+    let mut dot = format!("digraph {} {{\n", quote(name));
+    dot.push_str("    node [shape=box, style=filled];\n");
+    for module in modules {
+        let kept = evaluation.keep.contains(&module.path);
+        let color = if kept { "palegreen" } else { "lightgrey" };
+        let mut attributes = format!("fillcolor={}, tooltip={}", color, quote(&module.path));
+        if let Some(reason @ (Reason::KeepRule(_) | Reason::Name)) =
+            evaluation.reasons.get(&module.path)
+        {
+            let label = format!("{}\n{}", module.name, reason);
+            attributes.push_str(&format!(", penwidth=3, label={}", quote(&label)));
+        }
+        dot.push_str(&format!("    {} [{}];\n", quote(&module.name), attributes));
+    }
+    for module in modules {
+        let deps = module.deps.iter().map(|d| (d, ""));
+        let softdeps = module.softdeps.iter().map(|d| (d, " [style=dashed]"));
+        for (dep, style) in deps.chain(softdeps) {
+            // Dependencies on modules built into the kernel have no node.
+            if names.contains(dep.as_str()) {
+                dot.push_str(&format!("    {} -> {}{};\n", quote(&module.name), quote(dep), style));
+            }
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// A module alias declared through `MODULE_DEVICE_TABLE`, as listed in `modules.alias`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Alias {
+    /// Shell wildcard pattern matched against device modaliases.
+    pub pattern: String,
+    /// Name of the module handling the matching devices.
+    pub module: String,
+}
+
+/// Returns the names of the modules having an alias matching one of `modaliases`, using the
+/// same wildcard semantics as modprobe.
+pub fn match_modaliases(aliases: &[Alias], modaliases: &[String]) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    for alias in aliases {
+        let Ok(pattern) = glob::Pattern::new(&alias.pattern) else {
+            debug!("Ignoring invalid alias pattern {}", alias.pattern);
+            continue;
+        };
+        if modaliases.iter().any(|m| pattern.matches(m)) {
+            names.insert(alias.module.replace('-', "_"));
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(name: &str, deps: &[&str], softdeps: &[&str]) -> Module {
+        Module {
+            name: name.to_string(),
+            path: format!("kernel/{}.ko", name),
+            deps: deps.iter().map(|d| d.to_string()).collect(),
+            softdeps: softdeps.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_arch_filter() {
+        let lines = vec![
+            "<x86_64>".to_string(),
+            "intel_driver".to_string(),
+            "</x86_64>".to_string(),
+            "<aarch64>".to_string(),
+            "arm_driver".to_string(),
+            "</aarch64>".to_string(),
+            "<ppc64le>".to_string(),
+            "power_driver".to_string(),
+            "</ppc64le>".to_string(),
+            "<s390x>".to_string(),
+            "ibm_driver".to_string(),
+            "</s390x>".to_string(),
+            "common_driver".to_string(),
+        ];
+
+        let x86_64_lines = arch_filter(lines.clone(), "x86_64");
+        assert_eq!(x86_64_lines, vec!["intel_driver", "common_driver"]);
+
+        let aarch64_lines = arch_filter(lines.clone(), "aarch64");
+        assert_eq!(aarch64_lines, vec!["arm_driver", "common_driver"]);
+
+        let ppc64le_lines = arch_filter(lines.clone(), "ppc64le");
+        assert_eq!(ppc64le_lines, vec!["power_driver", "common_driver"]);
+
+        let s390x_lines = arch_filter(lines.clone(), "s390x");
+        assert_eq!(s390x_lines, vec!["ibm_driver", "common_driver"]);
+    }
+
+    #[test]
+    fn test_rules_delete_wins() {
+        let rules = Rules::parse(
+            "# comment\nkernel/net/.*\n-kernel/net/wireless/.*\n",
+            "x86_64",
+        )
+        .unwrap();
+        assert_eq!(rules.matches("kernel/net/e1000e.ko"), RuleMatch::Keep);
+        assert_eq!(
+            rules.matches("kernel/net/wireless/iwlwifi.ko"),
+            RuleMatch::Delete
+        );
+        assert_eq!(rules.matches("kernel/sound/snd.ko"), RuleMatch::Unmatched);
+    }
+
+    #[test]
+    fn test_evaluate_dependency_closure() {
+        let modules = vec![
+            module("a", &["b"], &[]),
+            module("b", &["c"], &["soft"]),
+            module("c", &[], &[]),
+            module("soft", &[], &[]),
+            module("d", &[], &[]),
+        ];
+        let rules = Rules::parse("kernel/a.ko", "x86_64").unwrap();
+
+        let evaluation = evaluate(&modules, &rules);
+        assert_eq!(
+            evaluation
+                .keep
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>(),
+            vec![
+                "kernel/a.ko",
+                "kernel/b.ko",
+                "kernel/c.ko",
+                "kernel/soft.ko"
+            ]
+        );
+        assert_eq!(
+            evaluation
+                .delete
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>(),
+            vec!["kernel/d.ko"]
+        );
+    }
+
+    #[test]
+    fn test_keep_also() {
+        let modules = vec![
+            module("a", &[], &[]),
+            module("small", &["helper"], &["soft"]),
+            module("helper", &[], &[]),
+            module("soft", &[], &[]),
+            module("d", &[], &[]),
+        ];
+        let rules = Rules::parse("kernel/a.ko\n-kernel/helper.ko", "x86_64").unwrap();
+        let mut evaluation = evaluate(&modules, &rules);
+
+        let moved = keep_also(
+            &modules,
+            &mut evaluation,
+            vec![("kernel/small.ko".to_string(), Reason::BelowMinSize(100))],
+        );
+        assert_eq!(moved, vec!["kernel/helper.ko", "kernel/small.ko", "kernel/soft.ko"]);
+        assert_eq!(evaluation.delete.iter().collect::<Vec<_>>(), vec!["kernel/d.ko"]);
+        assert_eq!(evaluation.reasons["kernel/a.ko"], Reason::KeepRule("kernel/a.ko".to_string()));
+        assert_eq!(evaluation.reasons["kernel/small.ko"], Reason::BelowMinSize(100));
+        assert_eq!(evaluation.reasons["kernel/helper.ko"], Reason::Dependency("small".to_string()));
+        assert_eq!(evaluation.reasons["kernel/soft.ko"], Reason::SoftDependency("small".to_string()));
+        assert_eq!(evaluation.reasons["kernel/d.ko"], Reason::Unmatched);
+    }
+
+    #[test]
+    fn test_evaluate_modaliases() {
+        let modules = vec![
+            module("e1000e", &["ptp"], &[]),
+            module("ptp", &[], &[]),
+            module("igb", &[], &[]),
+        ];
+        let aliases = vec![
+            Alias {
+                pattern: "pci:v00008086d000010D3sv*sd*bc*sc*i*".to_string(),
+                module: "e1000e".to_string(),
+            },
+            Alias {
+                pattern: "pci:v00008086d000010C9sv*sd*bc*sc*i*".to_string(),
+                module: "igb".to_string(),
+            },
+        ];
+        let modaliases = vec!["pci:v00008086d000010D3sv00008086sd0000A01Fbc02sc00i00".to_string()];
+
+        let evaluation = evaluate_modaliases(&modules, &aliases, &modaliases);
+        assert_eq!(
+            evaluation
+                .keep
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>(),
+            vec!["kernel/e1000e.ko", "kernel/ptp.ko"]
+        );
+        assert_eq!(
+            evaluation
+                .delete
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>(),
+            vec!["kernel/igb.ko"]
+        );
+    }
+
+    #[test]
+    fn test_rule_variables() {
+        let content = "kernel/arch/${ARCH}/.*\n-updates/${KVER}/.*\n<flavor:rt>\nglob:extra/${FLAVOR}-${KVER}/*.ko\n</flavor:rt>\n";
+        let rules = Rules::parse(content, "x86_64").unwrap();
+        assert_eq!(rules.keep[0].as_str(), "kernel/arch/x86_64/.*");
+        assert_eq!(rules.templates, vec!["-updates/${KVER}/.*"]);
+
+        let rt = rules.for_kernel("6.4.0-rt").unwrap();
+        assert_eq!(rt.delete[0].as_str(), r"updates/6\.4\.0\-rt/.*");
+        assert_eq!(rt.matches("updates/6.4.0-rt/a.ko"), RuleMatch::Delete);
+        assert_eq!(rt.matches("updates/6.4.0-default/a.ko"), RuleMatch::Unmatched);
+        assert_eq!(rt.matches("extra/rt-6.4.0-rt/b.ko"), RuleMatch::Keep);
+        assert!(rules.for_kernel("6.4.0").unwrap().templates.is_empty());
+
+        // ${FLAVOR} cannot be substituted for a kernel without flavor.
+        let rules = Rules::parse("extra/${FLAVOR}/.*", "x86_64").unwrap();
+        assert!(matches!(rules.for_kernel("6.4.0"), Err(JanitorError::InvalidConfig(_))));
+        assert!(matches!(Rules::parse("extra/${KVERSION}/.*", "x86_64"), Err(JanitorError::InvalidConfig(_))));
+
+        let diagnostics: Vec<String> = validate("updates/${KVER}/.*\n-extra/${kver}/(.*\n").iter().map(|d| d.to_string()).collect();
+        assert_eq!(diagnostics, vec!["2:8: error: unknown variable ${kver}, expected ${KVER}, ${ARCH} or ${FLAVOR}"]);
+    }
+
+    #[test]
+    fn test_precedence() {
+        let rules = "kernel/drivers/net/wireless/intel/iwlwifi/.*\n-kernel/drivers/net/wireless/.*\nkernel/drivers/net/wireless/ath/ath9k/.*\n";
+        let iwlwifi = "kernel/drivers/net/wireless/intel/iwlwifi/iwlwifi.ko";
+        let ath9k = "kernel/drivers/net/wireless/ath/ath9k/ath9k.ko";
+        let rtw88 = "kernel/drivers/net/wireless/realtek/rtw88/rtw88_core.ko";
+        let decisions = |header: &str| {
+            let rules = Rules::parse(&format!("{}{}", header, rules), "x86_64").unwrap();
+            [iwlwifi, ath9k, rtw88].map(|path| rules.matches(path))
+        };
+        use RuleMatch::{Delete, Keep};
+        assert_eq!(decisions(""), [Delete, Delete, Delete]);
+        assert_eq!(decisions("precedence delete-wins\n"), [Delete, Delete, Delete]);
+        assert_eq!(decisions("precedence keep-overrides-delete\n"), [Keep, Keep, Delete]);
+        assert_eq!(decisions("# Wireless exceptions\nprecedence first-match-wins\n"), [Keep, Delete, Delete]);
+        assert_eq!(decisions("precedence last-match-wins\n"), [Delete, Keep, Delete]);
+
+        // The flavor sections come after the common rules.
+        let rules = Rules::parse("precedence last-match-wins\n<flavor:rt>\nkernel/a.ko\n</flavor:rt>\n-kernel/.*\n", "x86_64").unwrap();
+        assert_eq!(rules.for_kernel("6.4.0-rt").unwrap().matches("kernel/a.ko"), Keep);
+        assert_eq!(rules.for_kernel("6.4.0-default").unwrap().matches("kernel/a.ko"), Delete);
+
+        assert!(matches!(Rules::parse("precedence newest-wins\n", "x86_64"), Err(JanitorError::InvalidConfig(_))));
+        let conflicting = vec!["precedence first-match-wins".to_string(), "precedence last-match-wins".to_string()];
+        assert!(matches!(Rules::from_lines(conflicting, "x86_64"), Err(JanitorError::InvalidConfig(_))));
+
+        let diagnostics: Vec<String> = validate("include base.list\nprecedence first-match-wins\nkernel/a.ko\nprecedence last-wins\n")
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                "4:1: error: precedence declared after rules or sections, declare it in the header".to_string(),
+                "4:12: error: unknown precedence 'last-wins', expected one of delete-wins, keep-overrides-delete, first-match-wins, last-match-wins".to_string(),
+            ]
+        );
+
+        // Only the rules naming a path literally can be shadowed by a broader one.
+        let rules = section_rules("kernel/a.ko\n-kernel/.*\nkernel/a.ko\n-kernel/b.ko\n");
+        assert_eq!(shadowed_rules(&rules, Precedence::FirstMatchWins), vec![(2, 0), (3, 1)]);
+        assert_eq!(shadowed_rules(&rules, Precedence::LastMatchWins), vec![(0, 1)]);
+        assert_eq!(shadowed_rules(&rules, Precedence::KeepOverridesDelete), vec![(2, 0), (3, 1)]);
+    }
+
+    #[test]
+    fn test_validate() {
+        let content = "# comment\n<x86-64>\nkernel/net/.*\n</x86-64>\n<foo>\n-kernel/(sound\n</bar>\n<aarch64\n<s390x>\n";
+        let diagnostics: Vec<String> = validate(content).iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                "2:2: error: unknown architecture 'x86-64', did you mean 'x86_64'?".to_string(),
+                "5:2: warning: unknown architecture 'foo', this section is only used on it".to_string(),
+                "6:2: error: invalid regular expression: unclosed group".to_string(),
+                "7:3: error: closing tag </bar> does not match <foo> opened at line 5".to_string(),
+                "8:1: error: malformed section tag, expected <arch> or </arch>".to_string(),
+                "9:1: error: section <s390x> is never closed".to_string(),
+            ]
+        );
+        assert!(validate("<x86_64>\nkernel/.*\n</x86_64>\n-kernel/sound/.*\n").is_empty());
+    }
+
+    #[test]
+    fn test_include_directive() {
+        assert_eq!(include_target("include base.list"), Some("base.list"));
+        assert_eq!(include_target("kernel/include/.*"), None);
+        assert!(validate("include base.list\nkernel/a.ko\n").is_empty());
+        let diagnostics: Vec<String> = validate("<x86_64>\ninclude x86.list\n</x86_64>\n")
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec!["2:1: error: include inside section <x86_64>, includes apply to every architecture".to_string()]
+        );
+
+        let rules = Rules::parse("include base.list\nkernel/a.ko", "x86_64").unwrap();
+        assert_eq!(rules.keep.len(), 1);
+    }
+
+    #[test]
+    fn test_flavor_sections() {
+        let content = "kernel/a.ko\n<flavor:rt>\nkernel/rt.ko\n-kernel/a.ko\n</flavor:rt>\n<x86_64>\nkernel/x86.ko\n</x86_64>\n";
+        assert!(validate(content).is_empty());
+        let rules = Rules::parse(content, "x86_64").unwrap();
+        assert_eq!(rules.keep.len(), 2);
+        assert_eq!(rules.matches("kernel/rt.ko"), RuleMatch::Unmatched);
+
+        let rt = rules.for_flavor(Some("rt"));
+        assert_eq!(rt.matches("kernel/rt.ko"), RuleMatch::Keep);
+        assert_eq!(rt.matches("kernel/a.ko"), RuleMatch::Delete);
+        assert_eq!(rt.matches("kernel/x86.ko"), RuleMatch::Keep);
+        assert_eq!(rules.for_flavor(Some("64kb")).matches("kernel/a.ko"), RuleMatch::Keep);
+
+        assert_eq!(kernel_flavor("6.4.0-150600.23-64kb"), Some("64kb"));
+        assert_eq!(kernel_flavor("6.11.5-1-default"), Some("default"));
+        assert_eq!(kernel_flavor("6.11.5-1"), None);
+        let diagnostics: Vec<String> = validate("<flavor:>\n</flavor:>\n<arch:x86_64>\n</arch:x86_64>\n")
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                "1:2: error: invalid kernel flavor ''".to_string(),
+                "3:2: error: unknown condition 'arch:x86_64', expected an architecture or flavor:NAME".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shadowed_rules() {
+        let rules = section_rules(
+            "kernel/a.ko\n<x86_64>\nkernel/net/.*\nkernel/net/e1000e.ko\n</x86_64>\n\
+             <aarch64>\nkernel/net/igb.ko\n</aarch64>\n-glob:kernel/**\nglob:kernel/b.ko\nkernel/a.ko\n",
+        );
+        assert_eq!(rules[1].section.as_deref(), Some("x86_64"));
+        assert_eq!(rules[2].line, 4);
+        // The x86_64 rule does not apply on aarch64, a keep rule is shadowed by a later delete
+        // rule, a delete rule is not shadowed by an earlier keep rule.
+        assert_eq!(shadowed_rules(&rules, Precedence::DeleteWins), vec![(0, 4), (2, 1), (3, 4), (5, 4), (6, 0)]);
+    }
+
+    #[test]
+    fn test_rule_priorities() {
+        let content = "keep@10 kernel/a.*\n-kernel/.*\ndelete@20 kernel/ab\\.ko\n";
+        assert!(validate(content).is_empty());
+        let rules = Rules::parse(content, "x86_64").unwrap();
+        assert_eq!(rules.matches("kernel/a.ko"), RuleMatch::Keep);
+        assert_eq!(rules.matches("kernel/ab.ko"), RuleMatch::Delete);
+        assert_eq!(rules.matches("kernel/b.ko"), RuleMatch::Delete);
+        assert_eq!(rules.delete_priority("kernel/ab.ko"), 20);
+        assert_eq!(rules.delete_priority("kernel/b.ko"), 0);
+        let (_, rule) = rules.matching_rule_text("kernel/ab.ko");
+        assert_eq!(Reason::DeleteRule(rule.unwrap()).to_string(), r"matched delete rule 'delete@20 kernel/ab\.ko'");
+        let (_, rule) = rules.matching_rule_text("kernel/a.ko");
+        assert_eq!(Reason::KeepRule(rule.unwrap()).to_string(), "matched keep rule 'keep@10 kernel/a.*'");
+
+        assert!(matches!(Rules::parse("keep@high kernel/a.ko", "x86_64"), Err(JanitorError::InvalidConfig(_))));
+        let diagnostics: Vec<String> = validate("keep@x kernel/a.ko\ndelete@3\ndelete@3 kernel/(a\n")
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                "1:1: error: invalid priority 'x', expected a non-negative integer".to_string(),
+                "2:1: error: missing pattern after the priority of rule 'delete@3'".to_string(),
+                "3:10: error: invalid regular expression: unclosed group".to_string(),
+            ]
+        );
+        // A rule of higher priority is not shadowed by the delete rules winning otherwise.
+        let rules = section_rules("keep@1 kernel/a.ko\n-kernel/a.ko\nkernel/a.ko\n");
+        assert_eq!(shadowed_rules(&rules, Precedence::DeleteWins), vec![(1, 0), (2, 0)]);
+    }
+
+    #[test]
+    fn test_record_usage() {
+        let rules = Rules::parse("kernel/a.ko\nkernel/renamed.ko\n-kernel/b.ko\n-kernel/gone/.*", "x86_64").unwrap();
+        let mut usage = BTreeMap::new();
+        rules.record_usage(&["kernel/a.ko"], &mut usage);
+        rules.record_usage(&["kernel/b.ko"], &mut usage);
+        let unused: Vec<_> = usage.iter().filter(|(_, used)| !**used).map(|(rule, _)| rule.as_str()).collect();
+        assert_eq!(unused, vec!["-kernel/gone/.*", "kernel/renamed.ko"]);
+    }
+
+    #[test]
+    fn test_closest_keep_rules() {
+        let rules = Rules::parse(
+            "kernel/drivers/nvme/host/nvme-cor\\.ko\nkernel/fs/ext4/.*\nglob:kernel/drivers/nvme/**/nvme.ko",
+            "x86_64",
+        )
+        .unwrap();
+        let closest = rules.closest_keep_rules("kernel/drivers/nvme/host/nvme-core.ko.zst", 2);
+        let closest: Vec<&str> = closest.iter().map(|r| r.as_str()).collect();
+        assert_eq!(closest, vec![r"kernel/drivers/nvme/host/nvme-cor\.ko", r"^kernel/drivers/nvme/(?:.*/)?nvme\.ko$"]);
+        assert_eq!(rule_literal(r"^kernel/[^/]*\.ko$"), "kernel/.ko");
+        assert_eq!(rule_literal(r"snd-hda-[]a-c]\.ko"), "snd-hda-.ko");
+        assert_eq!(substring_distance("nvme-cor.ko", "host/nvme-core.ko.zst"), 1);
+    }
+
+    #[test]
+    fn test_glob_rules() {
+        assert_eq!(glob_to_regex("kernel/*.ko"), r"^kernel/[^/]*\.ko$");
+        let rules = Rules::parse(
+            "glob:kernel/drivers/net/wireless/**\nglob:**/snd-hda-?.ko\n-glob:kernel/drivers/net/wireless/[!i]*/**",
+            "x86_64",
+        )
+        .unwrap();
+        assert_eq!(rules.matches("kernel/drivers/net/wireless/intel/iwlwifi.ko"), RuleMatch::Keep);
+        assert_eq!(rules.matches("kernel/drivers/net/wireless/ath/ath9k.ko"), RuleMatch::Delete);
+        assert_eq!(rules.matches("kernel/sound/snd-hda-a.ko"), RuleMatch::Keep);
+        assert_eq!(rules.matches("snd-hda-a.ko"), RuleMatch::Keep);
+        // Unlike regular expressions, globs are anchored and '.' is literal.
+        assert_eq!(rules.matches("extra/kernel/drivers/net/wireless/x.ko"), RuleMatch::Unmatched);
+        assert_eq!(rules.matches("kernel/sound/snd-hda-ab.ko"), RuleMatch::Unmatched);
+        assert!(validate("glob:kernel/[net/*.ko\n").is_empty());
+    }
+
+    #[test]
+    fn test_evaluate_reasons() {
+        let modules = vec![
+            module("a", &["b"], &["soft"]),
+            module("b", &[], &[]),
+            module("soft", &[], &[]),
+            module("named", &[], &[]),
+            module("dropped", &[], &[]),
+            module("other", &[], &[]),
+        ];
+        let rules = Rules::parse("kernel/a.ko\n-kernel/(b|dropped).ko", "x86_64").unwrap();
+        let names = ["named".to_string()].into();
+
+        let reasons = evaluate_with_names(&modules, &rules, &names).reasons;
+        let reason = |name: &str| reasons[&format!("kernel/{}.ko", name)].clone();
+        assert_eq!(reason("a"), Reason::KeepRule("kernel/a.ko".to_string()));
+        assert_eq!(reason("b"), Reason::Dependency("a".to_string()));
+        assert_eq!(reason("soft"), Reason::SoftDependency("a".to_string()));
+        assert_eq!(reason("named"), Reason::Name);
+        assert_eq!(reason("dropped"), Reason::DeleteRule("kernel/(b|dropped).ko".to_string()));
+        assert_eq!(reason("other"), Reason::Unmatched);
+        assert_eq!(
+            reason("dropped").to_string(),
+            "matched delete rule '-kernel/(b|dropped).ko'"
+        );
+    }
+
+    #[test]
+    fn test_drop_blacklisted() {
+        let modules = vec![
+            module("pcspkr", &["input"], &[]),
+            module("input", &[], &[]),
+            module("evdev", &["input"], &[]),
+            module("floppy", &["block"], &[]),
+            module("block", &[], &[]),
+            module("ahci", &["libahci"], &[]),
+            module("libahci", &[], &[]),
+            module("other", &[], &[]),
+        ];
+        let rules = Rules::parse("kernel/(pcspkr|evdev|floppy).ko
+-kernel/other.ko", "x86_64").unwrap();
+        let names = ["ahci".to_string()].into();
+        let blacklist = ["pcspkr", "floppy", "libahci", "other"].map(String::from).into();
+
+        let evaluation = drop_blacklisted(&modules, evaluate_with_names(&modules, &rules, &names), &blacklist);
+        let reason = |name: &str| evaluation.reasons[&format!("kernel/{}.ko", name)].clone();
+        assert_eq!(reason("pcspkr"), Reason::Blacklisted);
+        assert_eq!(reason("floppy"), Reason::Blacklisted);
+        assert_eq!(reason("block"), Reason::NeededByBlacklisted);
+        assert_eq!(reason("other"), Reason::DeleteRule("kernel/other.ko".to_string()));
+        // Still needed by evdev and ahci.
+        assert_eq!(reason("input"), Reason::Dependency("evdev".to_string()));
+        assert_eq!(reason("libahci"), Reason::Dependency("ahci".to_string()));
+        assert_eq!(evaluation.keep.len(), 4);
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let modules = vec![
+            module("a", &["b", "builtin"], &["soft"]),
+            module("b", &[], &[]),
+            module("soft", &[], &[]),
+            module("d", &[], &[]),
+        ];
+        let rules = Rules::parse("kernel/a.ko", "x86_64").unwrap();
+        let dot = to_dot("6.1.0", &modules, &evaluate(&modules, &rules));
+
+        assert!(dot.starts_with("digraph \"6.1.0\" {"));
+        assert!(dot.contains("\"a\" [fillcolor=palegreen, tooltip=\"kernel/a.ko\", penwidth=3, label=\"a\\nmatched keep rule 'kernel/a.ko'\"];"));
+        assert!(dot.contains("\"d\" [fillcolor=lightgrey, tooltip=\"kernel/d.ko\"];"));
+        assert!(dot.contains("\"a\" -> \"b\";"));
+        assert!(dot.contains("\"a\" -> \"soft\" [style=dashed];"));
+        assert!(!dot.contains("builtin"));
+    }
+
+    #[test]
+    fn test_select_within_budget() {
+        let candidates = [("a", 100, 0), ("b", 300, 0), ("c", 200, 0), ("d", 300, 0)];
+        assert_eq!(select_within_budget(&candidates, 1000, 500), BTreeSet::from(["b", "d"]));
+        assert_eq!(select_within_budget(&candidates, 1000, 300), BTreeSet::from(["b", "c", "d"]));
+        assert_eq!(select_within_budget(&candidates, 400, 500), BTreeSet::new());
+        assert_eq!(select_within_budget(&candidates, 1000, 0).len(), 4);
+        // The files of the delete rules of highest priority go first, whatever their size.
+        let candidates = [("a", 100, 5), ("b", 300, 0), ("c", 200, 1)];
+        assert_eq!(select_within_budget(&candidates, 600, 350), BTreeSet::from(["a", "c"]));
+    }
+}
@@ -0,0 +1,236 @@
+//! Hardware profiles recorded on a reference machine.
+//!
+//! A profile lists what a running system actually uses: the loaded modules, the modaliases of
+//! its devices and the firmware files its drivers requested. The cleanup commands use it as an
+//! additional keep source when trimming an image for the same hardware.
+
+use crate::command::CommandRunner;
+use crate::devicetree;
+use crate::error::JanitorError;
+use log::{debug, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Hardware profile of a reference machine.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    /// Release of the kernel running when the profile was captured.
+    #[serde(default)]
+    pub kernel: Option<String>,
+    /// Names of the loaded modules.
+    #[serde(default)]
+    pub modules: BTreeSet<String>,
+    /// Modaliases of the devices.
+    #[serde(default)]
+    pub modaliases: BTreeSet<String>,
+    /// Names of the firmware files loaded by the drivers, relative to the firmware directory.
+    #[serde(default)]
+    pub firmware: BTreeSet<String>,
+}
+
+impl Profile {
+    /// Reads a profile written by [`Profile::write`].
+    pub fn read(path: &Path) -> Result<Self, JanitorError> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| JanitorError::ConfigRead(path.display().to_string(), e))?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Writes the profile as JSON.
+    pub fn write(&self, path: &Path) -> Result<(), JanitorError> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Captures the profile of the system whose `/proc` and `/sys` are mounted below `root`.
+///
+/// Firmware names are collected from `/sys/class/firmware` and from the kernel log; a kernel
+/// log which cannot be read (e.g. without privileges) is skipped with a warning.
+pub fn capture_profile(root: &Path, runner: &dyn CommandRunner) -> Result<Profile, JanitorError> {
+    let mut profile = Profile {
+        kernel: running_kernel(root),
+        ..Default::default()
+    };
+
+    profile.modules = loaded_modules(root)?;
+
+    // Device directories are reached through their real paths below /sys/devices, the
+    // symlinks of /sys/bus and /sys/class are not followed.
+    for entry in WalkDir::new(root.join("sys/devices"))
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_file() && e.file_name() == "modalias")
+    {
+        match fs::read_to_string(entry.path()) {
+            Ok(modalias) if !modalias.trim().is_empty() => {
+                profile.modaliases.insert(modalias.trim().to_string());
+            }
+            Ok(_) => {}
+            Err(e) => debug!("Reading {} failed: {}", entry.path().display(), e),
+        }
+    }
+
+    // The devices behind a controller whose driver is not loaded, e.g. on an I2C or SPI bus, are
+    // not created yet: on boards described by a device tree, its nodes list them.
+    let device_tree = root.join("proc/device-tree");
+    if device_tree.is_dir() {
+        let nodes = devicetree::read_device_tree_dir(&device_tree)?;
+        profile.modaliases.extend(devicetree::modaliases(&nodes));
+    }
+
+    // Pending firmware requests are listed with '/' replaced by '!'.
+    if let Ok(entries) = fs::read_dir(root.join("sys/class/firmware")) {
+        for entry in entries.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().replace('!', "/");
+            if name != "timeout" {
+                profile.firmware.insert(name);
+            }
+        }
+    }
+
+    match runner.run("dmesg", &[]) {
+        Ok(log) => profile.firmware.extend(parse_firmware_log(&log)),
+        Err(e) => warn!(
+            "Reading the kernel log failed, firmware loads are not recorded: {}",
+            e
+        ),
+    }
+
+    Ok(profile)
+}
+
+/// Returns the names of the modules loaded on the system whose `/proc` is mounted below `root`.
+pub fn loaded_modules(root: &Path) -> Result<BTreeSet<String>, JanitorError> {
+    let proc_modules = fs::read_to_string(root.join("proc/modules"))?;
+    Ok(proc_modules
+        .lines()
+        .filter_map(|l| l.split_whitespace().next())
+        .map(|name| name.replace('-', "_"))
+        .collect())
+}
+
+/// Returns the release of the kernel running the system whose `/proc` is mounted below `root`.
+pub fn running_kernel(root: &Path) -> Option<String> {
+    fs::read_to_string(root.join("proc/sys/kernel/osrelease"))
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+/// Extracts the names of the firmware files loaded according to a kernel log.
+fn parse_firmware_log(log: &str) -> BTreeSet<String> {
+    let firmware_re = Regex::new(
+        r"(?:direct-loading firmware|[Ll]oaded firmware file|[Ll]oading firmware:?) '?([\w.+/-]+)",
+    )
+    .unwrap();
+    log.lines()
+        .filter_map(|line| firmware_re.captures(line))
+        .map(|captures| captures[1].to_string())
+        .collect()
+}
+
+/// Reads the kernel log of a reference system, saved e.g. with `dmesg` or `journalctl -k`, at
+/// `path`, and returns the names of the firmware files its drivers failed to load: requests made
+/// at runtime which the modules do not declare.
+pub fn read_firmware_failures(path: &Path) -> Result<BTreeSet<String>, JanitorError> {
+    let log = fs::read_to_string(path)
+        .map_err(|e| JanitorError::ConfigRead(path.display().to_string(), e))?;
+    Ok(parse_firmware_failures(&log))
+}
+
+/// Extracts the names of the firmware files which failed to load according to a kernel log.
+fn parse_firmware_failures(log: &str) -> BTreeSet<String> {
+    let failure_re = Regex::new(
+        r"(?:[Dd]irect firmware load for|firmware: failed to load) ([\w.+/-]+)",
+    )
+    .unwrap();
+    log.lines()
+        .filter_map(|line| failure_re.captures(line))
+        .map(|captures| captures[1].to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+
+    struct MockCommandRunner {
+        responses: HashMap<String, String>,
+    }
+
+    impl CommandRunner for MockCommandRunner {
+        fn run(&self, command: &str, _args: &[&str]) -> Result<String, JanitorError> {
+            self.responses
+                .get(command)
+                .cloned()
+                .ok_or_else(|| JanitorError::Command(format!("Not mocked: {}", command)))
+        }
+    }
+
+    #[test]
+    fn test_capture_profile() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("proc/sys/kernel")).unwrap();
+        fs::write(root.join("proc/sys/kernel/osrelease"), "6.1.0-test\n").unwrap();
+        fs::write(
+            root.join("proc/modules"),
+            "e1000e 286720 0 - Live 0x0000000000000000\nsnd-hda-intel 61440 0 - Live 0x0000000000000000\n",
+        )
+        .unwrap();
+        let device = root.join("sys/devices/pci0000:00/0000:00:1f.6");
+        fs::create_dir_all(&device).unwrap();
+        fs::write(device.join("modalias"), "pci:v00008086d000015BCsv*\n").unwrap();
+        fs::create_dir_all(root.join("sys/class/firmware/i915!kbl_dmc_ver1_04.bin")).unwrap();
+        let node = root.join("proc/device-tree/soc/i2c@7e804000/rtc@51");
+        fs::create_dir_all(&node).unwrap();
+        fs::write(node.join("compatible"), "nxp,pcf85063\0").unwrap();
+        fs::write(root.join("sys/class/firmware/timeout"), "60").unwrap();
+
+        let mut responses = HashMap::new();
+        responses.insert(
+            "dmesg".to_string(),
+            "[    2.1] iwlwifi 0000:00:14.3: loaded firmware version 77 op_mode iwlmvm\n\
+             [    2.2] firmware_class: __allocate_fw_priv: fw-rtl_nic/rtl8168h-2.fw\n\
+             [    2.3] firmware_class:_request_firmware: direct-loading firmware rtl_nic/rtl8168h-2.fw\n"
+                .to_string(),
+        );
+        let runner = MockCommandRunner { responses };
+
+        let profile = capture_profile(root, &runner).unwrap();
+        assert_eq!(profile.kernel.as_deref(), Some("6.1.0-test"));
+        assert_eq!(
+            profile.modules.iter().collect::<Vec<_>>(),
+            vec!["e1000e", "snd_hda_intel"]
+        );
+        assert_eq!(
+            profile.modaliases.iter().collect::<Vec<_>>(),
+            vec!["of:NrtcT(null)Cnxp,pcf85063", "pci:v00008086d000015BCsv*"]
+        );
+        assert_eq!(
+            profile.firmware.iter().collect::<Vec<_>>(),
+            vec!["i915/kbl_dmc_ver1_04.bin", "rtl_nic/rtl8168h-2.fw"]
+        );
+
+        let path = root.join("profile.json");
+        profile.write(&path).unwrap();
+        assert_eq!(Profile::read(&path).unwrap(), profile);
+    }
+
+    #[test]
+    fn test_parse_firmware_failures() {
+        let log = "[    3.1] iwlwifi 0000:00:14.3: Direct firmware load for iwlwifi-cc-a0-78.ucode failed with error -2\n\
+                   Oct 16 09:12:01 host kernel: r8169 0000:02:00.0: firmware: failed to load rtl_nic/rtl8168h-2.fw (-2)\n\
+                   [    3.3] firmware_class: direct-loading firmware i915/kbl_dmc_ver1_04.bin\n";
+        assert_eq!(
+            parse_firmware_failures(log).into_iter().collect::<Vec<_>>(),
+            vec!["iwlwifi-cc-a0-78.ucode", "rtl_nic/rtl8168h-2.fw"]
+        );
+    }
+}
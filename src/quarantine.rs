@@ -0,0 +1,145 @@
+//! Quarantine of the deleted files.
+//!
+//! Instead of unlinking them, the cleanups can move the files they delete to a quarantine
+//! directory, below a directory named after the start of the run in seconds since the Unix epoch,
+//! at their absolute path: `/lib/firmware/foo.bin` deleted by the run started at 1700000000 goes to
+//! `<quarantine>/1700000000/lib/firmware/foo.bin`. Moving them back undoes the run, and
+//! [`purge`] reclaims the space of the runs old enough to be trusted.
+
+use crate::attributes;
+use crate::clock::Clock;
+use crate::error::JanitorError;
+use log::{debug, info};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The directory the files deleted by one run are moved to.
+#[derive(Debug, Clone)]
+pub struct Quarantine {
+    run_dir: PathBuf,
+}
+
+impl Quarantine {
+    /// Prepares the quarantine of a run started now according to `clock`, below `dir`. The runs
+    /// started in the same second, e.g. the driver and firmware passes of cleanup-all, share their
+    /// directory.
+    pub fn create(dir: &Path, clock: &dyn Clock) -> Result<Self, JanitorError> {
+        let run_dir = dir.join(clock.unix_seconds().to_string());
+        fs::create_dir_all(&run_dir)?;
+        Ok(Quarantine { run_dir })
+    }
+
+    /// Moves the file or symlink at `path` to the quarantine, copying it when the quarantine is
+    /// on another filesystem. Returns where it was moved to.
+    pub fn move_file(&self, path: &Path) -> Result<PathBuf, JanitorError> {
+        let absolute = std::path::absolute(path)?;
+        let target = self.run_dir.join(absolute.strip_prefix("/").unwrap_or(&absolute));
+        move_path(path, &target)?;
+        debug!("Moved {} to {}", path.display(), target.display());
+        Ok(target)
+    }
+}
+
+/// Moves the file or symlink `quarantined` by a run back to `path`.
+pub fn restore_file(quarantined: &Path, path: &Path) -> Result<(), JanitorError> {
+    move_path(quarantined, path)?;
+    info!("Restored {}", path.display());
+    Ok(())
+}
+
+/// Moves the file or symlink at `from` to `to`, creating its directory, and copying it with its
+/// attributes when they are on different filesystems.
+fn move_path(from: &Path, to: &Path) -> Result<(), JanitorError> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(from, to) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            if fs::symlink_metadata(from)?.is_symlink() {
+                std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+            } else {
+                fs::copy(from, to)?;
+                attributes::copy_attributes(from, to)?;
+            }
+            fs::remove_file(from)?;
+        }
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+/// Removes the runs of the quarantine directory `dir` started at least `older_than` ago according
+/// to `clock`, or only lists them unless `delete` is set. Returns the directories of the runs.
+pub fn purge(dir: &Path, older_than: Duration, clock: &dyn Clock, delete: bool) -> Result<Vec<PathBuf>, JanitorError> {
+    let now = clock.unix_seconds();
+    let mut purged = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        // Anything not named after a run was not put there by a cleanup.
+        let Some(started) = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.parse::<u64>().ok()) else {
+            continue;
+        };
+        if now.saturating_sub(started) < older_than.as_secs() || !path.is_dir() {
+            continue;
+        }
+        if delete {
+            info!("Purging quarantined run {}", path.display());
+            fs::remove_dir_all(&path)?;
+        } else {
+            info!("Would purge quarantined run {}", path.display());
+        }
+        purged.push(path);
+    }
+    purged.sort();
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_move_file() {
+        let temp_dir = tempdir().unwrap();
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        fs::create_dir_all(&fw_dir).unwrap();
+        fs::write(fw_dir.join("foo.bin"), "foo").unwrap();
+        symlink("foo.bin", fw_dir.join("link.bin")).unwrap();
+
+        let dir = temp_dir.path().join("quarantine");
+        let quarantine = Quarantine::create(&dir, &FixedClock::from_unix_seconds(1_700_000_000)).unwrap();
+        quarantine.move_file(&fw_dir.join("foo.bin")).unwrap();
+        quarantine.move_file(&fw_dir.join("link.bin")).unwrap();
+
+        let mirror = dir.join("1700000000").join(fw_dir.strip_prefix("/").unwrap());
+        assert!(!fw_dir.join("foo.bin").exists());
+        assert_eq!(fs::read_to_string(mirror.join("foo.bin")).unwrap(), "foo");
+        assert_eq!(fs::read_link(mirror.join("link.bin")).unwrap(), Path::new("foo.bin"));
+    }
+
+    #[test]
+    fn test_purge() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        for run in ["1000", "2000", "3000"] {
+            fs::create_dir_all(dir.join(run).join("lib")).unwrap();
+        }
+        fs::create_dir(dir.join("notes")).unwrap();
+        let clock = FixedClock::from_unix_seconds(3500);
+
+        let old = [dir.join("1000"), dir.join("2000")];
+        assert_eq!(purge(dir, Duration::from_secs(1500), &clock, false).unwrap(), old);
+        assert!(dir.join("1000").exists());
+        assert_eq!(purge(dir, Duration::from_secs(1500), &clock, true).unwrap(), old);
+        assert!(!dir.join("1000").exists());
+        assert!(!dir.join("2000").exists());
+        assert!(dir.join("3000").exists());
+        assert!(dir.join("notes").exists());
+    }
+}
@@ -0,0 +1,110 @@
+//! Transfer of cleanup plans over HTTP(S).
+//!
+//! Requests carry an optional bearer token, so plans can be kept on an authenticated server.
+
+use crate::error::JanitorError;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+fn request(method: &str, url: &str, token: Option<&str>) -> ureq::Request {
+    let request = ureq::request(method, url).timeout(TIMEOUT);
+    match token {
+        Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+        None => request,
+    }
+}
+
+fn remote_error(url: &str, error: ureq::Error) -> JanitorError {
+    JanitorError::Remote(format!("{}: {}", url, error))
+}
+
+/// Downloads the document at `url`.
+pub fn fetch(url: &str, token: Option<&str>) -> Result<String, JanitorError> {
+    request("GET", url, token)
+        .call()
+        .map_err(|e| remote_error(url, e))?
+        .into_string()
+        .map_err(JanitorError::Io)
+}
+
+/// Uploads `body` as JSON to `url`.
+pub fn upload(url: &str, token: Option<&str>, body: &str) -> Result<(), JanitorError> {
+    request("PUT", url, token)
+        .set("Content-Type", "application/json")
+        .send_string(body)
+        .map_err(|e| remote_error(url, e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serves one request with `status` and `body`, returning the request line, headers and body.
+    fn serve_once(
+        status: &'static str,
+        body: &'static str,
+    ) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/plan.json", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut content = vec![0; length];
+            reader.read_exact(&mut content).unwrap();
+            request.push_str(&String::from_utf8(content).unwrap());
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            request
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_fetch() {
+        let (url, server) = serve_once("200 OK", "{\"files\":[]}");
+        assert_eq!(fetch(&url, Some("secret")).unwrap(), "{\"files\":[]}");
+        let request = server.join().unwrap();
+        assert!(request.starts_with("GET /plan.json"));
+        assert!(request.contains("Authorization: Bearer secret"));
+    }
+
+    #[test]
+    fn test_upload() {
+        let (url, server) = serve_once("201 Created", "");
+        upload(&url, None, "{\"files\":[]}").unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("PUT /plan.json"));
+        assert!(!request.contains("Authorization"));
+        assert!(request.ends_with("{\"files\":[]}"));
+    }
+
+    #[test]
+    fn test_fetch_http_error() {
+        let (url, server) = serve_once("403 Forbidden", "");
+        assert!(matches!(fetch(&url, None), Err(JanitorError::Remote(_))));
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+//! SPDX software bill of materials of the shipped firmware.
+//!
+//! Compliance teams need the list of blobs left in the image with their licenses. The licenses
+//! come from the linux-firmware `WHENCE` file, which groups the files of each driver with the
+//! licence they are distributed under, usually a reference to one of the `LICENSE.*` files.
+
+use crate::clock::Clock;
+use crate::error::JanitorError;
+use crate::firmware;
+use crate::journal;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Parses a linux-firmware `WHENCE` file, returning the licence of each file and link it lists,
+/// keyed by path relative to the firmware directory.
+pub fn parse_whence(content: &str) -> HashMap<String, String> {
+    let mut licenses = HashMap::new();
+    let mut files = Vec::new();
+    let mut license: Option<String> = None;
+    let mut flush = |files: &mut Vec<String>, license: &mut Option<String>| {
+        if let Some(license) = license.take() {
+            for file in files.iter() {
+                licenses.insert(file.clone(), license.clone());
+            }
+        }
+        files.clear();
+    };
+    for line in content.lines() {
+        if line.starts_with("----") {
+            flush(&mut files, &mut license);
+            continue;
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match key {
+            "File" | "RawFile" => files.push(value.trim_matches('"').to_string()),
+            "Link" => {
+                let name = value.split_once("->").map_or(value, |(name, _)| name);
+                files.push(name.trim().trim_matches('"').to_string());
+            }
+            "Licence" | "License" if license.is_none() => license = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    flush(&mut files, &mut license);
+    licenses
+}
+
+/// An SPDX 2.3 document, in its JSON serialization.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpdxDocument {
+    pub spdx_version: String,
+    pub data_license: String,
+    #[serde(rename = "SPDXID")]
+    pub spdx_id: String,
+    pub name: String,
+    pub document_namespace: String,
+    pub creation_info: CreationInfo,
+    pub files: Vec<SpdxFile>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub has_extracted_licensing_infos: Vec<ExtractedLicense>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CreationInfo {
+    pub created: String,
+    pub creators: Vec<String>,
+}
+
+/// A firmware file of the image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpdxFile {
+    /// Path relative to the firmware directory, prefixed with `./`.
+    pub file_name: String,
+    #[serde(rename = "SPDXID")]
+    pub spdx_id: String,
+    pub checksums: Vec<Checksum>,
+    pub license_concluded: String,
+    pub license_info_in_files: Vec<String>,
+    pub copyright_text: String,
+    /// The licence as written in `WHENCE`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Checksum {
+    pub algorithm: String,
+    pub checksum_value: String,
+}
+
+/// A licence file of the firmware directory referenced by `WHENCE`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtractedLicense {
+    pub license_id: String,
+    pub name: String,
+    pub extracted_text: String,
+}
+
+const NOASSERTION: &str = "NOASSERTION";
+
+/// SPDX identifiers of the licences `WHENCE` names directly.
+const SPDX_IDENTIFIERS: &[&str] = &[
+    "Apache-2.0",
+    "BSD-2-Clause",
+    "BSD-3-Clause",
+    "GPL-2.0",
+    "GPL-2.0-only",
+    "GPL-2.0-or-later",
+    "GPL-3.0-only",
+    "GPL-3.0-or-later",
+    "ISC",
+    "MIT",
+];
+
+/// Builds the SBOM of the files of `fw_dir` left once the `removed` ones are gone, in a dry run
+/// too, created at the time given by `clock`.
+pub fn firmware_sbom(
+    fw_dir: &Path,
+    removed: &[PathBuf],
+    clock: &dyn Clock,
+) -> Result<SpdxDocument, JanitorError> {
+    let whence = match fs::read_to_string(fw_dir.join("WHENCE")) {
+        Ok(content) => parse_whence(&content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let removed: HashSet<&PathBuf> = removed.iter().collect();
+
+    let mut files = Vec::new();
+    let mut extracted = BTreeMap::new();
+    let mut namespace = Sha256::new();
+    for entry in WalkDir::new(fw_dir).sort_by_file_name().into_iter().filter_map(Result::ok) {
+        if !entry.file_type().is_file() || removed.contains(&entry.path().to_path_buf()) {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(fw_dir).unwrap_or(entry.path());
+        let sha256 = journal::hash_file(entry.path())?;
+        namespace.update(format!("{} {}\n", relative.display(), sha256));
+        let license = whence.get(&firmware::firmware_name(relative));
+        let license_concluded = match license {
+            Some(text) => spdx_license(text, fw_dir, &mut extracted)?,
+            None => NOASSERTION.to_string(),
+        };
+        files.push(SpdxFile {
+            file_name: format!("./{}", relative.display()),
+            spdx_id: format!("SPDXRef-File-{}", files.len() + 1),
+            checksums: vec![Checksum {
+                algorithm: "SHA256".to_string(),
+                checksum_value: sha256,
+            }],
+            license_concluded,
+            license_info_in_files: vec![NOASSERTION.to_string()],
+            copyright_text: NOASSERTION.to_string(),
+            comment: license.map(|text| format!("WHENCE licence: {}", text)),
+        });
+    }
+
+    let digest: String = namespace.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(SpdxDocument {
+        spdx_version: "SPDX-2.3".to_string(),
+        data_license: "CC0-1.0".to_string(),
+        spdx_id: "SPDXRef-DOCUMENT".to_string(),
+        name: format!("firmware of {}", fw_dir.display()),
+        document_namespace: format!("https://spdx.org/spdxdocs/image-janitor-firmware-{}", digest),
+        creation_info: CreationInfo {
+            created: humantime::format_rfc3339_seconds(clock.now()).to_string(),
+            creators: vec![format!("Tool: image-janitor-{}", env!("CARGO_PKG_VERSION"))],
+        },
+        files,
+        has_extracted_licensing_infos: extracted.into_values().collect(),
+    })
+}
+
+/// Returns the SPDX licence expression of the `WHENCE` licence `text`: a `LicenseRef-` to the
+/// `LICENSE.*` or `LICENCE.*` file it refers to, recorded in `extracted`, the text itself if it
+/// only combines known SPDX identifiers, or `NOASSERTION`.
+fn spdx_license(
+    text: &str,
+    fw_dir: &Path,
+    extracted: &mut BTreeMap<String, ExtractedLicense>,
+) -> Result<String, JanitorError> {
+    let reference = text
+        .split_whitespace()
+        .map(|word| word.trim_end_matches(['.', ',', ';', ')']))
+        .find(|word| word.starts_with("LICENSE.") || word.starts_with("LICENCE."));
+    if let Some(file) = reference {
+        let license_id = format!(
+            "LicenseRef-{}",
+            file.replace(|c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '-', "-")
+        );
+        if !extracted.contains_key(&license_id) {
+            let extracted_text = match fs::read(fw_dir.join(file)) {
+                Ok(data) => String::from_utf8_lossy(&data).into_owned(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    format!("See {} in linux-firmware.", file)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            extracted.insert(
+                license_id.clone(),
+                ExtractedLicense {
+                    license_id: license_id.clone(),
+                    name: file.to_string(),
+                    extracted_text,
+                },
+            );
+        }
+        return Ok(license_id);
+    }
+    if text
+        .split(" OR ")
+        .flat_map(|part| part.split(" AND "))
+        .all(|id| SPDX_IDENTIFIERS.contains(&id))
+    {
+        return Ok(text.to_string());
+    }
+    Ok(NOASSERTION.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use tempfile::tempdir;
+
+    const WHENCE: &str = "\
+--------------------------------------------------------------------------
+
+Driver: amdgpu - AMD Radeon
+
+File: amdgpu/navi10_gpu_info.bin
+Link: amdgpu/navi10_link.bin -> navi10_gpu_info.bin
+
+Licence: Redistributable. See LICENSE.amdgpu for details.
+
+--------------------------------------------------------------------------
+
+Driver: foo
+
+File: \"foo fw.bin\"
+Licence: GPL-2.0-only OR MIT
+
+--------------------------------------------------------------------------
+
+Driver: bar
+
+File: bar.bin
+
+Licence: Redistributable.
+";
+
+    #[test]
+    fn test_parse_whence() {
+        let licenses = parse_whence(WHENCE);
+        assert_eq!(licenses.len(), 4);
+        assert_eq!(
+            licenses["amdgpu/navi10_link.bin"],
+            "Redistributable. See LICENSE.amdgpu for details."
+        );
+        assert_eq!(licenses["foo fw.bin"], "GPL-2.0-only OR MIT");
+    }
+
+    #[test]
+    fn test_firmware_sbom() {
+        let temp_dir = tempdir().unwrap();
+        let fw_dir = temp_dir.path();
+        fs::create_dir_all(fw_dir.join("amdgpu")).unwrap();
+        fs::write(fw_dir.join("WHENCE"), WHENCE).unwrap();
+        fs::write(fw_dir.join("LICENSE.amdgpu"), "AMD licence").unwrap();
+        fs::write(fw_dir.join("amdgpu/navi10_gpu_info.bin.xz"), "blob").unwrap();
+        fs::write(fw_dir.join("foo fw.bin"), "foo").unwrap();
+        fs::write(fw_dir.join("bar.bin"), "bar").unwrap();
+        fs::write(fw_dir.join("unlisted.bin"), "unlisted").unwrap();
+
+        let clock = FixedClock::from_unix_seconds(86400);
+        let sbom = firmware_sbom(fw_dir, &[fw_dir.join("unlisted.bin")], &clock).unwrap();
+        let licenses: Vec<(&str, &str)> = sbom
+            .files
+            .iter()
+            .map(|f| (f.file_name.as_str(), f.license_concluded.as_str()))
+            .collect();
+        assert_eq!(
+            licenses,
+            vec![
+                ("./LICENSE.amdgpu", NOASSERTION),
+                ("./WHENCE", NOASSERTION),
+                ("./amdgpu/navi10_gpu_info.bin.xz", "LicenseRef-LICENSE.amdgpu"),
+                ("./bar.bin", NOASSERTION),
+                ("./foo fw.bin", "GPL-2.0-only OR MIT"),
+            ]
+        );
+        assert_eq!(sbom.has_extracted_licensing_infos.len(), 1);
+        assert_eq!(sbom.has_extracted_licensing_infos[0].extracted_text, "AMD licence");
+        assert_eq!(sbom.creation_info.created, "1970-01-02T00:00:00Z");
+
+        let json = serde_json::to_value(&sbom).unwrap();
+        assert_eq!(json["spdxVersion"], "SPDX-2.3");
+        assert_eq!(json["files"][2]["SPDXID"], "SPDXRef-File-3");
+        assert_eq!(json["files"][2]["checksums"][0]["algorithm"], "SHA256");
+    }
+}
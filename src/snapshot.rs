@@ -0,0 +1,179 @@
+//! Filesystem snapshots taken before destructive runs.
+//!
+//! On btrfs systems, e.g. with snapper or transactional-update, a snapshot taken right before
+//! `--delete` is the cheapest way back from a wrong keep list. Providers implement
+//! [`SnapshotProvider`]; the snapshot ID they return is logged and recorded in the state manifest.
+
+use crate::clock::{Clock, SystemClock};
+use crate::command::CommandRunner;
+use crate::error::JanitorError;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Creates snapshots of an image or of the running system.
+pub trait SnapshotProvider {
+    /// Creates a snapshot of the filesystem at `root`, described by `description`, and returns
+    /// its ID.
+    fn create(
+        &self,
+        root: &Path,
+        description: &str,
+        runner: &dyn CommandRunner,
+    ) -> Result<String, JanitorError>;
+
+    /// Returns how to roll `root` back to the snapshot `id`.
+    fn rollback_hint(&self, root: &Path, id: &str) -> String;
+}
+
+/// Returns `path` as a string argument.
+fn path_arg(path: &Path) -> Result<&str, JanitorError> {
+    path.to_str()
+        .ok_or_else(|| JanitorError::InvalidPath(path.to_path_buf()))
+}
+
+/// Snapshots managed by snapper, numbered and cleaned up by its number algorithm.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Snapper;
+
+impl Snapper {
+    /// The options selecting the root snapper operates on: without D-Bus for an image.
+    fn root_args(root: &Path) -> Result<Vec<&str>, JanitorError> {
+        if root == Path::new("/") {
+            Ok(Vec::new())
+        } else {
+            Ok(vec!["--no-dbus", "--root", path_arg(root)?])
+        }
+    }
+}
+
+impl SnapshotProvider for Snapper {
+    fn create(
+        &self,
+        root: &Path,
+        description: &str,
+        runner: &dyn CommandRunner,
+    ) -> Result<String, JanitorError> {
+        let mut args = Snapper::root_args(root)?;
+        args.extend([
+            "create",
+            "--type",
+            "single",
+            "--cleanup-algorithm",
+            "number",
+            "--print-number",
+            "--description",
+            description,
+        ]);
+        Ok(runner.run("snapper", &args)?.trim().to_string())
+    }
+
+    fn rollback_hint(&self, root: &Path, id: &str) -> String {
+        if root == Path::new("/") {
+            format!("snapper rollback {}", id)
+        } else {
+            format!("snapper --no-dbus --root {} undochange {}..0", root.display(), id)
+        }
+    }
+}
+
+/// Read-only btrfs snapshots of the subvolume at the root, stored in its `.snapshots`
+/// directory.
+#[derive(Debug, Clone)]
+pub struct Btrfs {
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Btrfs {
+    fn default() -> Self {
+        Btrfs {
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl Btrfs {
+    /// Names the snapshots after the time given by `clock` instead of the system time.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Btrfs { clock }
+    }
+}
+
+impl SnapshotProvider for Btrfs {
+    fn create(
+        &self,
+        root: &Path,
+        _description: &str,
+        runner: &dyn CommandRunner,
+    ) -> Result<String, JanitorError> {
+        let dir = root.join(".snapshots");
+        fs::create_dir_all(&dir)?;
+        let snapshot = dir.join(format!("image-janitor-{}", self.clock.unix_seconds()));
+        runner.run(
+            "btrfs",
+            &["subvolume", "snapshot", "-r", path_arg(root)?, path_arg(&snapshot)?],
+        )?;
+        Ok(snapshot.display().to_string())
+    }
+
+    fn rollback_hint(&self, _root: &Path, id: &str) -> String {
+        format!("copy the files back from {}, or make a writable snapshot of it the default subvolume", id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use std::cell::RefCell;
+    use tempfile::tempdir;
+
+    struct MockCommandRunner {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for MockCommandRunner {
+        fn run(&self, command: &str, args: &[&str]) -> Result<String, JanitorError> {
+            self.calls
+                .borrow_mut()
+                .push(format!("{} {}", command, args.join(" ")));
+            Ok("42\n".to_string())
+        }
+    }
+
+    #[test]
+    fn test_snapper() {
+        let runner = MockCommandRunner {
+            calls: RefCell::new(Vec::new()),
+        };
+        let id = Snapper.create(Path::new("/"), "before cleanup", &runner).unwrap();
+        assert_eq!(id, "42");
+        Snapper.create(Path::new("/image"), "before cleanup", &runner).unwrap();
+        assert_eq!(
+            *runner.calls.borrow(),
+            vec![
+                "snapper create --type single --cleanup-algorithm number --print-number --description before cleanup",
+                "snapper --no-dbus --root /image create --type single --cleanup-algorithm number --print-number --description before cleanup",
+            ]
+        );
+        assert_eq!(Snapper.rollback_hint(Path::new("/"), &id), "snapper rollback 42");
+    }
+
+    #[test]
+    fn test_btrfs() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let runner = MockCommandRunner {
+            calls: RefCell::new(Vec::new()),
+        };
+        let btrfs = Btrfs::with_clock(Arc::new(FixedClock::from_unix_seconds(1700000000)));
+        let id = btrfs.create(root, "before cleanup", &runner).unwrap();
+        let snapshot = root.join(".snapshots/image-janitor-1700000000");
+        assert_eq!(id, snapshot.display().to_string());
+        assert!(root.join(".snapshots").is_dir());
+        assert_eq!(
+            *runner.calls.borrow(),
+            vec![format!("btrfs subvolume snapshot -r {} {}", root.display(), snapshot.display())]
+        );
+    }
+}
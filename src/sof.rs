@@ -0,0 +1,91 @@
+//! Selection of the Sound Open Firmware by Intel platform.
+//!
+//! The snd-sof drivers list the firmware and topologies of every Intel platform they support,
+//! in `intel/sof/`, `intel/sof-tplg/`, `intel/sof-ipc4/` and the like. Their files are named
+//! after the platform, such as `sof-tgl.ri` or `sof-adl-rt711.tplg`, or stored in a directory
+//! named after it, such as `sof-ipc4/mtl/`, which is enough to only keep the files of the
+//! platforms an image targets.
+
+use std::path::{Component, Path};
+
+/// Intel platforms with Sound Open Firmware, by their short name.
+pub const SOF_PLATFORMS: &[&str] = &[
+    "byt", "cht", "bdw", "apl", "glk", "cnl", "cfl", "cml", "icl", "jsl", "ehl", "tgl", "adl", "rpl",
+    "mtl", "lnl", "arl", "ptl",
+];
+
+/// Returns whether the firmware file at `path`, relative to a firmware layer, belongs to Sound
+/// Open Firmware: it is below an `intel/sof*` directory.
+pub fn is_sof_firmware(path: &Path) -> bool {
+    let mut components = path.components().skip_while(|c| *c == Component::Normal("updates".as_ref()));
+    components.next() == Some(Component::Normal("intel".as_ref()))
+        && components
+            .next()
+            .is_some_and(|c| c.as_os_str().to_string_lossy().starts_with("sof"))
+}
+
+/// Returns the platforms named by the Sound Open Firmware file at `path`: the directories below
+/// `intel/sof*` and the file name without its `sof-` prefix and extensions, such as `tgl-h` for
+/// `sof-tgl-h.ri`. Only the names starting with a platform of [`SOF_PLATFORMS`] are returned, so
+/// generic files such as `sof-hda-generic.tplg` name none.
+fn platform_names(path: &Path) -> Vec<String> {
+    let mut names: Vec<String> = path
+        .parent()
+        .into_iter()
+        .flat_map(Path::components)
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    if let Some(file_name) = path.file_name() {
+        let file_name = file_name.to_string_lossy();
+        if let Some(name) = file_name.strip_prefix("sof-") {
+            names.push(name.split('.').next().unwrap_or_default().to_string());
+        }
+    }
+    names.retain(|name| {
+        let platform = name.split('-').next().unwrap_or_default();
+        SOF_PLATFORMS.contains(&platform)
+    });
+    names
+}
+
+/// Returns whether the Sound Open Firmware file at `path` is only used by platforms other than
+/// `platforms`. Files of no particular platform, such as the generic HDA topologies, are used
+/// by all of them. A platform selects its variants too, `tgl` selecting `tgl-h`.
+pub fn is_other_platform(path: &Path, platforms: &[String]) -> bool {
+    let names = platform_names(path);
+    !names.is_empty()
+        && !names.iter().any(|name| {
+            platforms.iter().any(|platform| {
+                let platform = platform.trim().to_ascii_lowercase();
+                *name == platform || name.starts_with(&format!("{}-", platform))
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_sof_firmware() {
+        assert!(is_sof_firmware(Path::new("intel/sof/sof-tgl.ri")));
+        assert!(is_sof_firmware(Path::new("intel/sof-tplg/sof-adl-rt711.tplg.xz")));
+        assert!(is_sof_firmware(Path::new("updates/intel/sof-ipc4/mtl/sof-mtl.ri")));
+        assert!(!is_sof_firmware(Path::new("intel/ibt-20-1-3.sfi")));
+        assert!(!is_sof_firmware(Path::new("sof/sof-tgl.ri")));
+    }
+
+    #[test]
+    fn test_is_other_platform() {
+        let platforms = ["tgl".to_string(), "ADL".to_string()];
+        assert!(!is_other_platform(Path::new("intel/sof/sof-tgl.ri"), &platforms));
+        assert!(!is_other_platform(Path::new("intel/sof/community/sof-tgl-h.ri"), &platforms));
+        assert!(!is_other_platform(Path::new("intel/sof-tplg/sof-adl-rt711.tplg"), &platforms));
+        assert!(!is_other_platform(Path::new("intel/sof-ipc4/adl-n/intel-signed/sof-adl-n.ri"), &platforms));
+        assert!(!is_other_platform(Path::new("intel/sof-tplg/sof-hda-generic-2ch.tplg"), &platforms));
+        assert!(!is_other_platform(Path::new("intel/sof/LICENCE.Intel"), &platforms));
+        assert!(is_other_platform(Path::new("intel/sof/sof-cml.ri"), &platforms));
+        assert!(is_other_platform(Path::new("intel/sof-ipc4/mtl/sof-mtl.ri.zst"), &platforms));
+        assert!(is_other_platform(Path::new("intel/sof-tplg/sof-apl-pcm512x.tplg"), &platforms));
+    }
+}
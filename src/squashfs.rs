@@ -0,0 +1,241 @@
+//! End-to-end cleanup of squashfs images, as found on live ISOs.
+//!
+//! The image is unpacked with `unsquashfs` into a work directory, the driver and firmware
+//! cleanups delete what is unused there, and `mksquashfs` packs the result again with the
+//! compression of the original image.
+
+use crate::command::CommandRunner;
+use crate::driver::{self, DriverOptions};
+use crate::error::JanitorError;
+use crate::firmware::{self, FirmwareOptions};
+use crate::kernel_graph::KernelGraph;
+use crate::util::{self, ScanOptions};
+use log::info;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Options of a squashfs image cleanup.
+#[derive(Debug, Clone, Default)]
+pub struct SquashfsOptions {
+    /// The squashfs image to clean.
+    pub input: PathBuf,
+    /// The cleaned image written, replaced if it exists.
+    pub output: PathBuf,
+    /// Empty or missing directory the image is unpacked to, removed afterwards, a new temporary
+    /// directory if unset.
+    pub work_dir: Option<PathBuf>,
+    /// Module list configuration files.
+    pub config_paths: Vec<String>,
+    pub scan: ScanOptions,
+}
+
+/// Result of a squashfs image cleanup.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SquashfsReport {
+    /// Number of files removed from the image.
+    pub files_removed: usize,
+    /// Compression of both images, as named by mksquashfs.
+    pub compression: String,
+    /// Size of the input image.
+    pub size_before: u64,
+    /// Size of the cleaned image.
+    pub size_after: u64,
+}
+
+/// Unpacks the image, cleans it and packs it again.
+pub fn clean_squashfs(
+    options: &SquashfsOptions,
+    runner: &dyn CommandRunner,
+) -> Result<SquashfsReport, JanitorError> {
+    util::with_work_dir(options.work_dir.as_deref(), "image-janitor-squashfs-", |work_dir| {
+        unpack_clean_repack(options, work_dir, runner)
+    })
+}
+
+fn unpack_clean_repack(
+    options: &SquashfsOptions,
+    work_dir: &Path,
+    runner: &dyn CommandRunner,
+) -> Result<SquashfsReport, JanitorError> {
+    let input = path_arg(&options.input)?;
+    let compression = image_compression(input, runner)?;
+    // unsquashfs creates the destination itself.
+    let root = work_dir.join("root");
+    info!(
+        "Unpacking {} to {}",
+        options.input.display(),
+        root.display()
+    );
+    runner.run(
+        "unsquashfs",
+        &["-no-progress", "-d", path_arg(&root)?, input],
+    )?;
+
+    let module_dir = util::find_in_root(&root, util::MODULE_DIRS);
+    let firmware_dir = util::find_in_root(&root, util::FIRMWARE_DIRS);
+    // Both cleanups scan the same module trees, the firmware pass only sees the modules kept.
+    let graph = KernelGraph::new();
+    let mut removed = driver::cleanup_drivers(
+        &DriverOptions {
+            config_paths: options.config_paths.clone(),
+            module_dir: module_dir.clone(),
+            root: root.clone(),
+            delete: true,
+            scan: options.scan.clone(),
+            ..Default::default()
+        },
+        &graph,
+        runner,
+    )?;
+    if firmware_dir.is_dir() {
+        removed.merge(firmware::cleanup_firmware(
+            &FirmwareOptions {
+                module_dir,
+                firmware_dir,
+                root: Some(root.clone()),
+                delete: true,
+                scan: options.scan.clone(),
+                ..Default::default()
+            },
+            &graph,
+        )?);
+    }
+
+    info!("Packing {} with {}", options.output.display(), compression);
+    runner.run(
+        "mksquashfs",
+        &[
+            path_arg(&root)?,
+            path_arg(&options.output)?,
+            "-noappend",
+            "-no-progress",
+            "-comp",
+            &compression,
+        ],
+    )?;
+    Ok(SquashfsReport {
+        files_removed: removed.deleted.len(),
+        compression,
+        size_before: fs::metadata(&options.input)?.len(),
+        size_after: fs::metadata(&options.output)?.len(),
+    })
+}
+
+/// Returns the compression of the squashfs image `image`, from the superblock printed by
+/// `unsquashfs -s`.
+fn image_compression(image: &str, runner: &dyn CommandRunner) -> Result<String, JanitorError> {
+    let superblock = runner.run("unsquashfs", &["-s", image])?;
+    superblock
+        .lines()
+        .find_map(|line| line.strip_prefix("Compression "))
+        .map(|c| c.trim().to_string())
+        .ok_or_else(|| {
+            JanitorError::Command(format!(
+                "cannot find the compression of {} in its superblock",
+                image
+            ))
+        })
+}
+
+fn path_arg(path: &Path) -> Result<&str, JanitorError> {
+    path.to_str()
+        .ok_or_else(|| JanitorError::InvalidPath(path.to_path_buf()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modinfo;
+    use std::cell::RefCell;
+    use tempfile::tempdir;
+
+    /// Simulates squashfs-tools: unsquashfs writes a small image tree, mksquashfs an image
+    /// whose size is the number of files packed.
+    struct SquashfsRunner {
+        packed: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for SquashfsRunner {
+        fn run(&self, command: &str, args: &[&str]) -> Result<String, JanitorError> {
+            match (command, args) {
+                ("arch", []) => Ok("x86_64".to_string()),
+                ("unsquashfs", ["-s", _]) => {
+                    Ok("Found a valid SQUASHFS 4:0 superblock on root.squashfs.\n\
+                                                 Compression xz\nBlock size 131072"
+                        .to_string())
+                }
+                ("unsquashfs", ["-no-progress", "-d", root, _]) => {
+                    let root = Path::new(root);
+                    let kernel_dir = root.join("usr/lib/modules/6.1.0-test");
+                    let fw_dir = root.join("usr/lib/firmware");
+                    fs::create_dir_all(&kernel_dir).unwrap();
+                    fs::create_dir_all(&fw_dir).unwrap();
+                    fs::write(
+                        kernel_dir.join("a.ko"),
+                        modinfo::build_test_module(&["firmware=a.bin"]),
+                    )
+                    .unwrap();
+                    fs::write(
+                        kernel_dir.join("b.ko"),
+                        modinfo::build_test_module(&["firmware=b.bin"]),
+                    )
+                    .unwrap();
+                    fs::write(fw_dir.join("a.bin"), "a").unwrap();
+                    fs::write(fw_dir.join("b.bin"), "b").unwrap();
+                    Ok(String::new())
+                }
+                ("mksquashfs", [root, output, .., comp]) => {
+                    assert_eq!(*comp, "xz");
+                    let files: Vec<String> = walkdir::WalkDir::new(root)
+                        .sort_by_file_name()
+                        .into_iter()
+                        .filter_map(Result::ok)
+                        .filter(|e| e.file_type().is_file())
+                        .map(|e| e.file_name().to_string_lossy().into_owned())
+                        .collect();
+                    fs::write(output, vec![0; files.len()]).unwrap();
+                    *self.packed.borrow_mut() = files;
+                    Ok(String::new())
+                }
+                _ => Err(JanitorError::Command(format!(
+                    "Not mocked: {} {:?}",
+                    command, args
+                ))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_clean_squashfs() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("module.list");
+        fs::write(&config_path, "a.ko").unwrap();
+        let input = temp_dir.path().join("root.squashfs");
+        fs::write(&input, vec![0; 100]).unwrap();
+        let work_dir = temp_dir.path().join("work");
+
+        let options = SquashfsOptions {
+            input,
+            output: temp_dir.path().join("root-clean.squashfs"),
+            work_dir: Some(work_dir.clone()),
+            config_paths: vec![config_path.to_str().unwrap().to_string()],
+            ..Default::default()
+        };
+        let runner = SquashfsRunner {
+            packed: RefCell::new(Vec::new()),
+        };
+        let report = clean_squashfs(&options, &runner).unwrap();
+        assert_eq!(
+            report,
+            SquashfsReport {
+                files_removed: 2,
+                compression: "xz".to_string(),
+                size_before: 100,
+                size_after: 2,
+            }
+        );
+        assert_eq!(*runner.packed.borrow(), vec!["a.bin", "a.ko"]);
+        assert!(!work_dir.exists());
+    }
+}
@@ -0,0 +1,124 @@
+//! Manifest recording how an image was trimmed.
+//!
+//! Written into the cleaned image, it tells later runs and support engineers which kernel and
+//! which inputs shaped the shipped module and firmware trees.
+
+use crate::atomic;
+use crate::error::JanitorError;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Default location of the manifest, relative to the image root.
+pub const DEFAULT_STATE_PATH: &str = "usr/lib/image-janitor/state.json";
+
+/// An input file of a run, identified by its content hash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Input {
+    /// What the file is used for, e.g. `config` or `profile`.
+    pub kind: String,
+    pub path: String,
+    pub sha256: String,
+}
+
+impl Input {
+    /// Hashes the file at `path`.
+    pub fn from_file(kind: &str, path: &Path) -> Result<Self, JanitorError> {
+        let content =
+            fs::read(path).map_err(|e| JanitorError::ConfigRead(path.display().to_string(), e))?;
+        let sha256 = Sha256::digest(&content)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Ok(Input {
+            kind: kind.to_string(),
+            path: path.display().to_string(),
+            sha256,
+        })
+    }
+}
+
+/// Record of the last run of a cleanup command.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Run {
+    /// Version of image-janitor which did the run.
+    pub version: String,
+    /// Releases of the kernels whose modules were scanned.
+    pub kernels: Vec<String>,
+    pub inputs: Vec<Input>,
+    /// Options which changed the result, as given on the command line.
+    #[serde(default)]
+    pub options: Vec<String>,
+    pub files_removed: usize,
+    /// Whether the binary module indexes were removed, so depmod must run before modules are loaded.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub depmod_required: bool,
+    /// ID of the snapshot taken before the run, to roll back to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<String>,
+}
+
+/// Content of the manifest: the last run of each command, keyed by command name.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    pub runs: BTreeMap<String, Run>,
+}
+
+impl State {
+    /// Reads the manifest at `path`, or returns an empty one if there is none yet.
+    pub fn read(path: &Path) -> Result<Self, JanitorError> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Records `run` for `command` in the manifest at `path`, keeping the runs of the other
+    /// commands.
+    pub fn record(path: &Path, command: &str, run: Run) -> Result<Self, JanitorError> {
+        let mut state = State::read(path)?;
+        state.runs.insert(command.to_string(), run);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let content = serde_json::to_string_pretty(&state)?;
+        atomic::write_atomic(path, |f| f.write_all(content.as_bytes()))?;
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_record_keeps_other_commands() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join("module.list");
+        fs::write(&config_path, "abc").unwrap();
+        let state_path = temp_dir.path().join(DEFAULT_STATE_PATH);
+
+        let input = Input::from_file("config", &config_path).unwrap();
+        assert_eq!(
+            input.sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let driver_run = Run {
+            kernels: vec!["6.1.0-test".to_string()],
+            inputs: vec![input],
+            files_removed: 3,
+            ..Default::default()
+        };
+        State::record(&state_path, "driver-cleanup", driver_run.clone()).unwrap();
+        State::record(&state_path, "fw-cleanup", Run::default()).unwrap();
+
+        let state = State::read(&state_path).unwrap();
+        assert_eq!(state.runs.len(), 2);
+        assert_eq!(state.runs["driver-cleanup"], driver_run);
+    }
+}
@@ -0,0 +1,278 @@
+//! Inventory of the module and firmware trees.
+//!
+//! Unlike the cleanups, nothing is evaluated: the trees are only counted, the modules per
+//! kernel, the firmware per vendor directory, both per compression format, with their symlinks
+//! and the dangling ones among them. [`list_kernels`] tells which of the installed kernels the
+//! cleanups select.
+
+use crate::compress::Compression;
+use crate::error::JanitorError;
+use crate::policy;
+use crate::util::{self, ScanOptions};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Name of the vendor of the firmware files at the top of the firmware directory.
+const TOP_LEVEL_VENDOR: &str = "(top level)";
+
+/// Number and size of files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Count {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+impl Count {
+    fn add(&mut self, size: u64) {
+        self.files += 1;
+        self.bytes += size;
+    }
+}
+
+/// Symlinks of a tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Symlinks {
+    pub total: usize,
+    /// The ones whose target does not exist.
+    pub dangling: usize,
+}
+
+impl Symlinks {
+    /// Counts the symlinks below `dir`.
+    fn count(dir: &Path) -> Result<Self, JanitorError> {
+        let mut symlinks = Symlinks::default();
+        for entry in WalkDir::new(dir) {
+            let entry = entry?;
+            if entry.path_is_symlink() {
+                symlinks.total += 1;
+                if !entry.path().exists() {
+                    symlinks.dangling += 1;
+                }
+            }
+        }
+        Ok(symlinks)
+    }
+}
+
+/// Inventory of the module tree of a kernel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KernelStats {
+    /// Release of the kernel, the name of its directory.
+    pub release: String,
+    pub modules: Count,
+    /// The modules by compression format: `none`, `xz`, `zstd` or `gzip`.
+    pub compression: BTreeMap<String, Count>,
+    pub symlinks: Symlinks,
+}
+
+/// Inventory of the firmware directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FirmwareStats {
+    /// The regular files, symlinks excepted.
+    pub files: Count,
+    /// The files by top level directory, usually named after the vendor or the driver.
+    pub vendors: BTreeMap<String, Count>,
+    /// The files by compression format: `none`, `xz`, `zstd` or `gzip`.
+    pub compression: BTreeMap<String, Count>,
+    pub symlinks: Symlinks,
+}
+
+/// Inventory of the module and firmware trees of an image.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    pub kernels: Vec<KernelStats>,
+    pub firmware: FirmwareStats,
+}
+
+/// A kernel installed in the module directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InstalledKernel {
+    /// Release of the kernel, the name of its directory.
+    pub release: String,
+    /// Flavor, the last part of the release, see [`policy::kernel_flavor`].
+    pub flavor: Option<String>,
+    pub modules: usize,
+    /// Size of all the files of the kernel directory, modules or not.
+    pub bytes: u64,
+    /// Whether depmod generated the `modules.dep` the cleanups read the dependencies from.
+    pub modules_dep: bool,
+    /// Whether the cleanups run with the same kernel selection process this kernel.
+    pub selected: bool,
+}
+
+/// Lists every kernel directory of `module_dir`, in the order the cleanups process them, marking
+/// the ones selected by `scan`.
+pub fn list_kernels(module_dir: &Path, scan: &ScanOptions) -> Result<Vec<InstalledKernel>, JanitorError> {
+    let selected = util::find_kernel_dirs(module_dir, &scan.kernels)?;
+    let mut kernels = Vec::new();
+    for kernel_dir in util::find_kernel_dirs(module_dir, &util::KernelSelection::All)? {
+        let mut bytes = 0;
+        for entry in WalkDir::new(&kernel_dir) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                bytes += entry.metadata()?.len();
+            }
+        }
+        let release = kernel_dir.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        kernels.push(InstalledKernel {
+            flavor: policy::kernel_flavor(&release).map(String::from),
+            modules: util::find_kernel_modules(&kernel_dir, scan)?.len(),
+            bytes,
+            modules_dep: kernel_dir.join("modules.dep").is_file(),
+            selected: selected.contains(&kernel_dir),
+            release,
+        });
+    }
+    Ok(kernels)
+}
+
+/// Returns the name of `compression` in the inventory.
+fn compression_name(compression: Compression) -> String {
+    let value = compression.to_possible_value().expect("no skipped compression");
+    value.get_name().to_string()
+}
+
+/// Returns the compression of the firmware file at `path`, from its extension.
+fn firmware_compression(path: &Path) -> Compression {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("xz") => Compression::Xz,
+        Some("zst") => Compression::Zstd,
+        Some("gz") => Compression::Gzip,
+        _ => Compression::None,
+    }
+}
+
+/// Takes the inventory of the kernels of `module_dir` selected by `scan`.
+pub fn kernel_stats(module_dir: &Path, scan: &ScanOptions) -> Result<Vec<KernelStats>, JanitorError> {
+    let mut kernels = Vec::new();
+    for kernel_dir in util::find_kernel_dirs(module_dir, &scan.kernels)? {
+        let mut modules = Count::default();
+        let mut compression: BTreeMap<String, Count> = BTreeMap::new();
+        for path in util::find_kernel_modules(&kernel_dir, scan)? {
+            let size = fs::metadata(&path)?.len();
+            modules.add(size);
+            compression.entry(compression_name(Compression::of(&path))).or_default().add(size);
+        }
+        kernels.push(KernelStats {
+            release: kernel_dir.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+            modules,
+            compression,
+            symlinks: Symlinks::count(&kernel_dir)?,
+        });
+    }
+    Ok(kernels)
+}
+
+/// Takes the inventory of the firmware directory `fw_dir`, empty if it does not exist.
+pub fn firmware_stats(fw_dir: &Path) -> Result<FirmwareStats, JanitorError> {
+    let mut stats = FirmwareStats::default();
+    if !fw_dir.is_dir() {
+        return Ok(stats);
+    }
+    for entry in WalkDir::new(fw_dir) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(fw_dir).unwrap();
+        let vendor = match relative.components().count() {
+            1 => TOP_LEVEL_VENDOR.to_string(),
+            _ => relative.components().next().unwrap().as_os_str().to_string_lossy().into_owned(),
+        };
+        let size = entry.metadata()?.len();
+        stats.files.add(size);
+        stats.vendors.entry(vendor).or_default().add(size);
+        let compression = compression_name(firmware_compression(entry.path()));
+        stats.compression.entry(compression).or_default().add(size);
+    }
+    stats.symlinks = Symlinks::count(fw_dir)?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    fn write(path: &Path, size: usize) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, vec![0u8; size]).unwrap();
+    }
+
+    #[test]
+    fn test_stats() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path().join("lib/modules");
+        let kernel_dir = module_dir.join("6.1.0-test");
+        write(&kernel_dir.join("kernel/drivers/net/e1000e.ko.zst"), 300);
+        write(&kernel_dir.join("kernel/fs/ext4.ko.zst"), 200);
+        write(&kernel_dir.join("kernel/fs/vfat.ko"), 50);
+        fs::create_dir_all(kernel_dir.join("weak-updates")).unwrap();
+        symlink("/nowhere/foo.ko", kernel_dir.join("weak-updates/foo.ko")).unwrap();
+
+        let kernels = kernel_stats(&module_dir, &ScanOptions::default()).unwrap();
+        assert_eq!(kernels.len(), 1);
+        assert_eq!(kernels[0].release, "6.1.0-test");
+        assert_eq!(kernels[0].modules, Count { files: 3, bytes: 550 });
+        assert_eq!(kernels[0].compression["zstd"], Count { files: 2, bytes: 500 });
+        assert_eq!(kernels[0].compression["none"], Count { files: 1, bytes: 50 });
+        assert_eq!(kernels[0].symlinks, Symlinks { total: 1, dangling: 1 });
+
+        let fw_dir = temp_dir.path().join("lib/firmware");
+        write(&fw_dir.join("intel/ibt-20-1-3.sfi.xz"), 100);
+        write(&fw_dir.join("intel/ibt-20-1-4.sfi.xz"), 100);
+        write(&fw_dir.join("regulatory.db"), 10);
+        symlink("intel/ibt-20-1-3.sfi.xz", fw_dir.join("ibt.sfi.xz")).unwrap();
+        symlink("missing.bin", fw_dir.join("dangling.bin")).unwrap();
+
+        let firmware = firmware_stats(&fw_dir).unwrap();
+        assert_eq!(firmware.files, Count { files: 3, bytes: 210 });
+        assert_eq!(firmware.vendors["intel"], Count { files: 2, bytes: 200 });
+        assert_eq!(firmware.vendors[TOP_LEVEL_VENDOR], Count { files: 1, bytes: 10 });
+        assert_eq!(firmware.compression["xz"], Count { files: 2, bytes: 200 });
+        assert_eq!(firmware.symlinks, Symlinks { total: 2, dangling: 1 });
+        assert_eq!(firmware_stats(&temp_dir.path().join("missing")).unwrap(), FirmwareStats::default());
+    }
+
+    #[test]
+    fn test_list_kernels() {
+        let temp_dir = tempdir().unwrap();
+        let module_dir = temp_dir.path();
+        write(&module_dir.join("6.4.0-150600.23-default/kernel/fs/ext4.ko.zst"), 300);
+        write(&module_dir.join("6.4.0-150600.23-default/modules.dep"), 20);
+        write(&module_dir.join("6.4.0-150600.25-rt/kernel/fs/ext4.ko.zst"), 400);
+
+        let kernels = list_kernels(module_dir, &ScanOptions::default()).unwrap();
+        assert_eq!(
+            kernels,
+            vec![
+                InstalledKernel {
+                    release: "6.4.0-150600.23-default".to_string(),
+                    flavor: Some("default".to_string()),
+                    modules: 1,
+                    bytes: 320,
+                    modules_dep: true,
+                    selected: false,
+                },
+                InstalledKernel {
+                    release: "6.4.0-150600.25-rt".to_string(),
+                    flavor: Some("rt".to_string()),
+                    modules: 1,
+                    bytes: 400,
+                    modules_dep: false,
+                    selected: true,
+                },
+            ]
+        );
+        let scan = ScanOptions {
+            kernels: util::KernelSelection::All,
+            ..Default::default()
+        };
+        assert!(list_kernels(module_dir, &scan).unwrap().iter().all(|k| k.selected));
+    }
+}
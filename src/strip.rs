@@ -0,0 +1,114 @@
+//! Stripping of the debug sections of kernel modules.
+//!
+//! Some vendor trees ship unstripped modules, whose DWARF sections weigh tens of MiB. They are
+//! removed with `strip --strip-debug`, as `INSTALL_MOD_STRIP` does in the kernel build. Signed
+//! modules are left alone: stripping them would invalidate their appended signature.
+
+use crate::atomic;
+use crate::command::CommandRunner;
+use crate::compress::Compression;
+use crate::error::JanitorError;
+use crate::modinfo;
+use log::{debug, info, warn};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Strips the debug sections of the module at `path` if `strip` is set, keeping its compression.
+/// Returns the size of the debug sections, removed or which would be, 0 for a module without
+/// any or a signed one.
+pub fn strip_module(
+    path: &Path,
+    strip: bool,
+    runner: &dyn CommandRunner,
+) -> Result<u64, JanitorError> {
+    let data = modinfo::read_module(path)?;
+    let size = modinfo::debug_size(&data)
+        .map_err(|e| JanitorError::ModuleParse(path.to_path_buf(), e))?;
+    if size == 0 {
+        return Ok(0);
+    }
+    if modinfo::is_signed(&data) {
+        warn!(
+            "Not stripping the {} bytes of debug sections of {}, it is signed",
+            size,
+            path.display()
+        );
+        return Ok(0);
+    }
+    if !strip {
+        debug!("Found {} bytes of debug sections in {}", size, path.display());
+        return Ok(size);
+    }
+
+    info!("Stripping {} bytes of debug sections from {}", size, path.display());
+    // strip works on an uncompressed file, written next to the module so an interrupted run
+    // leaves an orphan removed by the next one.
+    let temp = atomic::temp_path(path);
+    let stripped = (|| {
+        fs::write(&temp, &data)?;
+        let temp_str = temp
+            .to_str()
+            .ok_or_else(|| JanitorError::InvalidPath(temp.clone()))?;
+        runner.run("strip", &["--strip-debug", temp_str])?;
+        Ok::<_, JanitorError>(fs::read(&temp)?)
+    })();
+    let _ = fs::remove_file(&temp);
+    let compressed = Compression::of(path)
+        .compress(&stripped?)
+        .map_err(|e| JanitorError::ModuleParse(path.to_path_buf(), e.to_string()))?;
+    atomic::write_atomic(path, |file| file.write_all(&compressed))?;
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use tempfile::tempdir;
+
+    /// Runs strip by replacing the file with an image without debug sections.
+    struct MockCommandRunner {
+        calls: RefCell<usize>,
+    }
+
+    impl CommandRunner for MockCommandRunner {
+        fn run(&self, command: &str, args: &[&str]) -> Result<String, JanitorError> {
+            assert_eq!((command, args[0]), ("strip", "--strip-debug"));
+            *self.calls.borrow_mut() += 1;
+            fs::write(args[1], modinfo::build_test_module(&["depends="]))?;
+            Ok(String::new())
+        }
+    }
+
+    #[test]
+    fn test_strip_module() {
+        let temp_dir = tempdir().unwrap();
+        let unstripped = modinfo::build_test_elf(&[
+            (".modinfo", b"depends=\0"),
+            (".debug_info", &[0; 100]),
+            (".rela.debug_info", &[0; 20]),
+        ]);
+        let path = temp_dir.path().join("a.ko.zst");
+        fs::write(&path, Compression::Zstd.compress(&unstripped).unwrap()).unwrap();
+        let mut signed = unstripped.clone();
+        signed.extend_from_slice(b"~Module signature appended~\n");
+        let signed_path = temp_dir.path().join("signed.ko");
+        fs::write(&signed_path, &signed).unwrap();
+
+        let runner = MockCommandRunner { calls: RefCell::new(0) };
+        assert_eq!(strip_module(&path, false, &runner).unwrap(), 120);
+        assert_eq!(*runner.calls.borrow(), 0);
+
+        assert_eq!(strip_module(&path, true, &runner).unwrap(), 120);
+        let data = modinfo::read_module(&path).unwrap();
+        assert_eq!(modinfo::debug_size(&data).unwrap(), 0);
+        assert_eq!(strip_module(&path, true, &runner).unwrap(), 0);
+
+        assert_eq!(strip_module(&signed_path, true, &runner).unwrap(), 0);
+        assert_eq!(fs::read(&signed_path).unwrap(), signed);
+        assert_eq!(*runner.calls.borrow(), 1);
+        // The uncompressed copy given to strip is gone.
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,202 @@
+//! Registry of extra subcommands of the command line tool.
+//!
+//! The tool registers its subcommands in a [`Registry`] and dispatches them through it, so a
+//! downstream distribution can add its own cleanup subsystems, e.g. in a fork or behind a feature
+//! flag, by registering them next to the built-in ones instead of patching the dispatcher.
+
+use crate::command::CommandRunner;
+use crate::error::JanitorError;
+use crate::kernel_graph::KernelGraph;
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+use std::path::PathBuf;
+
+/// What the tool shares with a subcommand run.
+pub struct Context<'a> {
+    /// Root directory of the image being cleaned.
+    pub root: PathBuf,
+    pub runner: &'a dyn CommandRunner,
+    /// Kernel module trees scanned during the run, shared by the passes of the subcommand.
+    pub graph: &'a KernelGraph,
+    /// The whole command line, for the global options of the tool.
+    pub matches: &'a ArgMatches,
+}
+
+/// A subcommand of the command line tool.
+pub trait Subcommand: Send + Sync {
+    /// Name of the subcommand on the command line.
+    fn name(&self) -> &str;
+
+    /// The subcommand with its arguments, named as [`Subcommand::name`].
+    fn command(&self) -> Command;
+
+    /// Runs the subcommand with the arguments parsed from the command line.
+    fn run(&self, matches: &ArgMatches, context: &Context) -> anyhow::Result<()>;
+}
+
+/// A subcommand taking the arguments of a clap derived struct, run by a function.
+pub struct ArgsSubcommand<A> {
+    name: &'static str,
+    about: &'static str,
+    run: fn(&A, &Context) -> anyhow::Result<()>,
+}
+
+impl<A> ArgsSubcommand<A> {
+    /// Creates the subcommand `name`, described by `about` in the help, calling `run` with the
+    /// parsed arguments.
+    pub fn new(
+        name: &'static str,
+        about: &'static str,
+        run: fn(&A, &Context) -> anyhow::Result<()>,
+    ) -> Self {
+        ArgsSubcommand { name, about, run }
+    }
+}
+
+impl<A: Args + FromArgMatches> Subcommand for ArgsSubcommand<A> {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn command(&self) -> Command {
+        A::augment_args(Command::new(self.name)).about(self.about)
+    }
+
+    fn run(&self, matches: &ArgMatches, context: &Context) -> anyhow::Result<()> {
+        let args = A::from_arg_matches(matches)?;
+        (self.run)(&args, context)
+    }
+}
+
+/// Name of the subcommand clap adds to print the help.
+const RESERVED: &str = "help";
+
+/// The subcommands of the tool, in registration order.
+#[derive(Default)]
+pub struct Registry {
+    subcommands: Vec<Box<dyn Subcommand>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `subcommand`, failing if its name is taken: by a subcommand registered before,
+    /// the built-in ones of the tool included, or by the `help` subcommand clap adds.
+    pub fn register(&mut self, subcommand: impl Subcommand + 'static) -> Result<(), JanitorError> {
+        if subcommand.name() == RESERVED || self.get(subcommand.name()).is_some() {
+            return Err(JanitorError::DuplicateSubcommand(subcommand.name().to_string()));
+        }
+        self.subcommands.push(Box::new(subcommand));
+        Ok(())
+    }
+
+    /// Returns the subcommand registered as `name`.
+    pub fn get(&self, name: &str) -> Option<&dyn Subcommand> {
+        self.subcommands
+            .iter()
+            .find(|s| s.name() == name)
+            .map(|s| s.as_ref())
+    }
+
+    /// Adds the registered subcommands to the command line `command`.
+    pub fn augment(&self, command: Command) -> Command {
+        command.subcommands(self.subcommands.iter().map(|s| s.command()))
+    }
+
+    /// Runs the registered subcommand selected in `matches`, the matches of the command line
+    /// built by [`Registry::augment`]. Returns false if no registered subcommand was selected.
+    pub fn dispatch(&self, matches: &ArgMatches, context: &Context) -> anyhow::Result<bool> {
+        let Some((name, sub_matches)) = matches.subcommand() else {
+            return Ok(false);
+        };
+        match self.get(name) {
+            Some(subcommand) => subcommand.run(sub_matches, context).map(|_| true),
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    struct MockCommandRunner {
+        responses: HashMap<String, String>,
+    }
+
+    impl CommandRunner for MockCommandRunner {
+        fn run(&self, command: &str, _args: &[&str]) -> Result<String, JanitorError> {
+            self.responses
+                .get(command)
+                .cloned()
+                .ok_or_else(|| JanitorError::Command(format!("Not mocked: {}", command)))
+        }
+    }
+
+    #[derive(clap::Args)]
+    struct LocaleArgs {
+        #[arg(long)]
+        keep: Vec<String>,
+    }
+
+    fn run_locales(args: &LocaleArgs, context: &Context) -> anyhow::Result<()> {
+        let arch = context.runner.run("arch", &[])?;
+        anyhow::ensure!(args.keep == ["de", "fr"], "unexpected arguments");
+        anyhow::ensure!(context.root == Path::new("/image") && arch == "x86_64");
+        Ok(())
+    }
+
+    #[test]
+    fn test_registry_dispatch() {
+        let mut registry = Registry::new();
+        registry
+            .register(ArgsSubcommand::new("locales", "Removes translations.", run_locales))
+            .unwrap();
+        let matches = registry
+            .augment(Command::new("image-janitor").subcommand(Command::new("doctor")))
+            .try_get_matches_from(["image-janitor", "locales", "--keep", "de", "--keep", "fr"])
+            .unwrap();
+
+        let mut responses = HashMap::new();
+        responses.insert("arch".to_string(), "x86_64".to_string());
+        let runner = MockCommandRunner { responses };
+        let graph = KernelGraph::new();
+        let context = Context {
+            root: PathBuf::from("/image"),
+            runner: &runner,
+            graph: &graph,
+            matches: &matches,
+        };
+        assert!(registry.dispatch(&matches, &context).unwrap());
+
+        let builtin = registry
+            .augment(Command::new("image-janitor").subcommand(Command::new("doctor")))
+            .try_get_matches_from(["image-janitor", "doctor"])
+            .unwrap();
+        assert!(!registry.dispatch(&builtin, &context).unwrap());
+    }
+
+    #[test]
+    fn test_register_rejects_taken_names() {
+        let mut registry = Registry::new();
+        // Registered first like the built-in subcommands of the tool.
+        registry
+            .register(ArgsSubcommand::new("locales", "First.", run_locales))
+            .unwrap();
+        assert!(matches!(
+            registry.register(ArgsSubcommand::new("locales", "Second.", run_locales)),
+            Err(JanitorError::DuplicateSubcommand(name)) if name == "locales"
+        ));
+        assert!(matches!(
+            registry.register(ArgsSubcommand::new("help", "Help.", run_locales)),
+            Err(JanitorError::DuplicateSubcommand(name)) if name == "help"
+        ));
+        let command = registry.augment(Command::new("image-janitor"));
+        let about = command.find_subcommand("locales").unwrap().get_about();
+        assert_eq!(about.unwrap().to_string(), "First.");
+        assert_eq!(command.get_subcommands().count(), 1);
+    }
+}
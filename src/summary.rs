@@ -0,0 +1,127 @@
+//! Outcome of a cleanup, as returned to library consumers.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// What a driver or firmware cleanup examined, kept and deleted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CleanupSummary {
+    /// Number of modules or firmware files the cleanup decided on.
+    pub examined: usize,
+    /// Number of them kept.
+    pub kept: usize,
+    /// Files deleted, or that would be deleted in a dry run. Besides the examined files, they
+    /// include the binary indexes, development files and dangling symlinks removed along.
+    pub deleted: Vec<PathBuf>,
+    /// Size of the deleted files, in bytes.
+    pub bytes_reclaimed: u64,
+    /// Number of deleted files for each deletion reason.
+    pub reasons: BTreeMap<String, usize>,
+    /// Problems which did not stop the cleanup, e.g. modules whose metadata cannot be read.
+    pub errors: Vec<String>,
+    /// Firmware files examined by top-level directory of the firmware directory, i.e. mostly by
+    /// vendor or driver (`iwlwifi`, `amdgpu`, ...). The files right in the firmware directory are
+    /// grouped under [`TOP_LEVEL_GROUP`].
+    pub firmware_groups: BTreeMap<String, GroupSummary>,
+}
+
+/// Group of the files right in the firmware directory.
+pub const TOP_LEVEL_GROUP: &str = "(top level)";
+
+/// Kept and deleted files of a group.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct GroupSummary {
+    pub kept: usize,
+    pub kept_bytes: u64,
+    pub deleted: usize,
+    pub deleted_bytes: u64,
+}
+
+impl GroupSummary {
+    /// Adds a file of `size` bytes to the group.
+    pub fn add(&mut self, size: u64, deleted: bool) {
+        if deleted {
+            self.deleted += 1;
+            self.deleted_bytes += size;
+        } else {
+            self.kept += 1;
+            self.kept_bytes += size;
+        }
+    }
+}
+
+impl CleanupSummary {
+    /// Adds the counts and files of `other`, e.g. of the firmware pass following a driver pass.
+    pub fn merge(&mut self, other: CleanupSummary) {
+        self.examined += other.examined;
+        self.kept += other.kept;
+        self.deleted.extend(other.deleted);
+        self.bytes_reclaimed += other.bytes_reclaimed;
+        for (reason, count) in other.reasons {
+            *self.reasons.entry(reason).or_default() += count;
+        }
+        self.errors.extend(other.errors);
+        for (name, group) in other.firmware_groups {
+            let total = self.firmware_groups.entry(name).or_default();
+            total.kept += group.kept;
+            total.kept_bytes += group.kept_bytes;
+            total.deleted += group.deleted;
+            total.deleted_bytes += group.deleted_bytes;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let mut summary = CleanupSummary {
+            examined: 10,
+            kept: 8,
+            deleted: vec![PathBuf::from("/lib/modules/6.4.0/d.ko")],
+            bytes_reclaimed: 100,
+            reasons: BTreeMap::from([("unused".to_string(), 1)]),
+            errors: vec!["Reading modinfo of e.ko failed".to_string()],
+            firmware_groups: BTreeMap::from([("iwlwifi".to_string(), GroupSummary::default())]),
+        };
+        summary.merge(CleanupSummary {
+            examined: 5,
+            kept: 3,
+            deleted: vec![
+                PathBuf::from("/lib/firmware/a.bin"),
+                PathBuf::from("/lib/firmware/b.bin"),
+            ],
+            bytes_reclaimed: 20,
+            reasons: BTreeMap::from([
+                ("unused".to_string(), 1),
+                ("dangling symlink".to_string(), 1),
+            ]),
+            errors: Vec::new(),
+            firmware_groups: BTreeMap::from([(
+                "iwlwifi".to_string(),
+                GroupSummary {
+                    kept: 2,
+                    kept_bytes: 30,
+                    deleted: 2,
+                    deleted_bytes: 20,
+                },
+            )]),
+        });
+        assert_eq!(summary.examined, 15);
+        assert_eq!(summary.kept, 11);
+        assert_eq!(summary.deleted.len(), 3);
+        assert_eq!(summary.bytes_reclaimed, 120);
+        assert_eq!(
+            summary.reasons,
+            BTreeMap::from([
+                ("dangling symlink".to_string(), 1),
+                ("unused".to_string(), 2)
+            ])
+        );
+        assert_eq!(summary.errors.len(), 1);
+        assert_eq!(summary.firmware_groups["iwlwifi"].deleted_bytes, 20);
+    }
+}
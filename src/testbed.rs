@@ -0,0 +1,218 @@
+//! Fake image roots for integration tests, built with the `testbed` feature.
+//!
+//! A [`Testbed`] is a temporary directory laid out like an image: kernel trees with plain and
+//! compressed modules, a firmware tree with its WHENCE file and symlinks, and module list config
+//! files. Tests run the cleanups or the command line tool against it and check what is left.
+
+use crate::modinfo;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+pub use crate::compress::Compression;
+
+/// A fake image root in a temporary directory, removed when dropped.
+pub struct Testbed {
+    dir: TempDir,
+}
+
+impl Testbed {
+    /// Creates an empty image root, with its usrmerged module and firmware directories.
+    pub fn new() -> Self {
+        let testbed = Testbed {
+            dir: tempfile::tempdir().expect("cannot create the testbed directory"),
+        };
+        fs::create_dir_all(testbed.module_dir()).unwrap();
+        fs::create_dir_all(testbed.firmware_dir()).unwrap();
+        testbed
+    }
+
+    /// Creates the image root of a laptop with two kernels, `6.1.0-1-default` and
+    /// `6.4.0-1-default`, each shipping:
+    ///
+    /// * `kernel/drivers/net/wireless/intel/iwlwifi/iwlwifi.ko.zst`, requiring
+    ///   `iwlwifi-cc-a0-77.ucode` and depending on `cfg80211`;
+    /// * `kernel/net/wireless/cfg80211.ko.xz`;
+    /// * `kernel/drivers/gpu/drm/amd/amdgpu/amdgpu.ko.xz`, requiring
+    ///   `amdgpu/navi10_gpu_info.bin`;
+    /// * `kernel/drivers/net/ethernet/intel/e1000e/e1000e.ko`, uncompressed.
+    ///
+    /// The firmware tree holds `amdgpu/navi10_gpu_info.bin`, `WHENCE`, `LICENSE.iwlwifi`, the
+    /// unused `brcm/` files and symlinks: `iwlwifi-cc-a0-77.ucode` points to the actual file in
+    /// `intel/iwlwifi/`, as in recent linux-firmware trees, and `brcm/brcmfmac-default.bin` to an
+    /// unused file.
+    ///
+    /// The `wireless.list` config keeps the wireless drivers.
+    pub fn laptop() -> Self {
+        let testbed = Testbed::new();
+        for version in ["6.1.0-1-default", "6.4.0-1-default"] {
+            testbed.add_module(
+                version,
+                "kernel/drivers/net/wireless/intel/iwlwifi/iwlwifi",
+                Compression::Zstd,
+                &["depends=cfg80211", "firmware=iwlwifi-cc-a0-77.ucode"],
+            );
+            testbed.add_module(version, "kernel/net/wireless/cfg80211", Compression::Xz, &["depends="]);
+            testbed.add_module(
+                version,
+                "kernel/drivers/gpu/drm/amd/amdgpu/amdgpu",
+                Compression::Xz,
+                &["depends=", "firmware=amdgpu/navi10_gpu_info.bin"],
+            );
+            testbed.add_module(
+                version,
+                "kernel/drivers/net/ethernet/intel/e1000e/e1000e",
+                Compression::None,
+                &["depends="],
+            );
+        }
+        testbed.add_firmware("intel/iwlwifi/iwlwifi-cc-a0-77.ucode", 4096);
+        testbed.add_firmware("amdgpu/navi10_gpu_info.bin", 2048);
+        testbed.add_firmware("brcm/brcmfmac43455-sdio.bin", 1024);
+        testbed.add_firmware("brcm/brcmfmac43455-sdio.txt", 16);
+        testbed.add_firmware("LICENSE.iwlwifi", 64);
+        testbed.add_firmware_link("iwlwifi-cc-a0-77.ucode", "intel/iwlwifi/iwlwifi-cc-a0-77.ucode");
+        testbed.add_firmware_link("brcm/brcmfmac-default.bin", "brcmfmac43455-sdio.bin");
+        testbed.write_whence();
+        testbed.write_config("wireless.list", &["kernel/drivers/net/wireless/.*"]);
+        testbed
+    }
+
+    /// The image root.
+    pub fn root(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Directory with the kernel trees.
+    pub fn module_dir(&self) -> PathBuf {
+        self.root().join("usr/lib/modules")
+    }
+
+    /// Directory with the firmware files.
+    pub fn firmware_dir(&self) -> PathBuf {
+        self.root().join("usr/lib/firmware")
+    }
+
+    /// Directory with the modules of kernel `version`.
+    pub fn kernel_dir(&self, version: &str) -> PathBuf {
+        self.module_dir().join(version)
+    }
+
+    /// Adds the module `relative` (without the `.ko` extension) to kernel `version`, with the
+    /// `key=value` entries of `modinfo` in its `.modinfo` section. Returns its path.
+    pub fn add_module(
+        &self,
+        version: &str,
+        relative: &str,
+        compression: Compression,
+        modinfo: &[&str],
+    ) -> PathBuf {
+        let path = self
+            .kernel_dir(version)
+            .join(format!("{}.ko{}", relative, compression.extension()));
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let module = modinfo::build_test_module(modinfo);
+        fs::write(&path, compression.compress(&module).unwrap()).unwrap();
+        path
+    }
+
+    /// Writes the `modules.dep` of kernel `version`, each entry being a module path relative to
+    /// the kernel directory followed by the paths of its dependencies.
+    pub fn write_modules_dep(&self, version: &str, entries: &[(&str, &[&str])]) -> PathBuf {
+        let content: String = entries
+            .iter()
+            .map(|(module, deps)| format!("{}: {}\n", module, deps.join(" ")))
+            .collect();
+        let path = self.kernel_dir(version).join("modules.dep");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// Adds the firmware file `relative`, of `size` bytes. Returns its path.
+    pub fn add_firmware(&self, relative: &str, size: usize) -> PathBuf {
+        let path = self.firmware_dir().join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, vec![0u8; size]).unwrap();
+        path
+    }
+
+    /// Adds the firmware symlink `relative`, pointing to `target` (relative to the link's
+    /// directory, as linux-firmware does). Returns its path.
+    pub fn add_firmware_link(&self, relative: &str, target: &str) -> PathBuf {
+        let path = self.firmware_dir().join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(target, &path).unwrap();
+        path
+    }
+
+    /// Writes the `WHENCE` file of the firmware tree, listing the files and links it holds.
+    pub fn write_whence(&self) -> PathBuf {
+        let firmware_dir = self.firmware_dir();
+        let mut entries: Vec<String> = walkdir::WalkDir::new(&firmware_dir)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|e| !e.file_type().is_dir())
+            .map(|e| {
+                let relative = e.path().strip_prefix(&firmware_dir).unwrap().display().to_string();
+                match fs::read_link(e.path()) {
+                    Ok(target) => format!("Link: {} -> {}", relative, target.display()),
+                    Err(_) => format!("File: {}", relative),
+                }
+            })
+            .collect();
+        entries.sort();
+        let path = firmware_dir.join("WHENCE");
+        fs::write(&path, entries.join("\n") + "\n").unwrap();
+        path
+    }
+
+    /// Writes the module list config file `name`, outside of the image, with `lines`. Returns
+    /// its path.
+    pub fn write_config(&self, name: &str, lines: &[&str]) -> PathBuf {
+        let path = self.root().join("config").join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, lines.join("\n") + "\n").unwrap();
+        path
+    }
+
+    /// Returns whether `relative` exists in the image, not following a final symlink.
+    pub fn exists(&self, relative: &str) -> bool {
+        self.root().join(relative).symlink_metadata().is_ok()
+    }
+
+    /// Returns the command running `program`, the command line tool, on the image: `--root` is
+    /// set, the subcommand and its arguments have to be added.
+    pub fn command(&self, program: &Path) -> Command {
+        let mut command = Command::new(program);
+        command.arg("--root").arg(self.root());
+        command
+    }
+}
+
+impl Default for Testbed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_laptop_layout() {
+        let testbed = Testbed::laptop();
+        let kernel_dir = testbed.kernel_dir("6.4.0-1-default");
+        let iwlwifi = kernel_dir.join("kernel/drivers/net/wireless/intel/iwlwifi/iwlwifi.ko.zst");
+        let info = modinfo::read_modinfo(&iwlwifi).unwrap();
+        assert_eq!(info.depends(), vec!["cfg80211"]);
+        assert_eq!(info.firmware(), vec!["iwlwifi-cc-a0-77.ucode"]);
+        assert!(testbed.exists("usr/lib/modules/6.1.0-1-default/kernel/net/wireless/cfg80211.ko.xz"));
+
+        let whence = fs::read_to_string(testbed.firmware_dir().join("WHENCE")).unwrap();
+        assert!(whence.contains("File: amdgpu/navi10_gpu_info.bin\n"));
+        assert!(whence.contains("Link: iwlwifi-cc-a0-77.ucode -> intel/iwlwifi/iwlwifi-cc-a0-77.ucode\n"));
+    }
+}
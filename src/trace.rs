@@ -0,0 +1,165 @@
+//! Traceability of the cleanup decisions.
+//!
+//! The explanation report records the decision taken for every module and firmware file with
+//! its direct reason only: a firmware file is required by a module, a module is a dependency of
+//! another one. Following the modules named by these reasons leads back to what really keeps a
+//! file in the image, e.g. a keep rule of the configuration.
+
+use crate::explain::{Action, Decision, FileType};
+use crate::util;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Prefixes of the reasons naming the module a file is kept for.
+const KEEPING_MODULE_PREFIXES: &[&str] = &[
+    "dependency of ",
+    "soft dependency of ",
+    "required by module ",
+];
+
+/// Returns the decisions about `query`: the file at that path, or else the files whose path ends
+/// with it (e.g. `kernel/drivers/nvme/host/nvme.ko.zst` or `iwlwifi-cc-a0-77.ucode`), or else the
+/// modules called so (e.g. `nvme`). There are several when a module is part of several kernels.
+pub fn find<'a>(decisions: &'a [Decision], query: &str) -> Vec<&'a Decision> {
+    let path = Path::new(query);
+    let exact: Vec<&Decision> = decisions.iter().filter(|d| d.path == path).collect();
+    if !exact.is_empty() {
+        return exact;
+    }
+    let suffix: Vec<&Decision> = decisions
+        .iter()
+        .filter(|d| path.is_relative() && d.path.ends_with(path))
+        .collect();
+    if !suffix.is_empty() {
+        return suffix;
+    }
+    let name = query.replace('-', "_");
+    decisions
+        .iter()
+        .filter(|d| d.file_type == FileType::Module && util::module_name(&d.path) == name)
+        .collect()
+}
+
+/// Returns the name of the module `reason` keeps a file for, if any.
+pub fn keeping_module(reason: &str) -> Option<&str> {
+    KEEPING_MODULE_PREFIXES
+        .iter()
+        .find_map(|prefix| reason.strip_prefix(prefix))
+}
+
+/// Returns the chain of decisions keeping the file of `decision`: the decision itself, then the
+/// decision about the module its reason names, and so on up to a file kept for another reason,
+/// e.g. a keep rule. Modules are looked up in the kernel of the previous one first, as the
+/// firmware requirements name modules of any selected kernel.
+pub fn keep_chain<'a>(decisions: &'a [Decision], decision: &'a Decision) -> Vec<&'a Decision> {
+    let mut chain = vec![decision];
+    let mut seen = HashSet::from([&decision.path]);
+    let mut current = decision;
+    while let Some(name) = keeping_module(&current.reason) {
+        let candidates: Vec<&Decision> = decisions
+            .iter()
+            .filter(|d| {
+                d.file_type == FileType::Module
+                    && d.action == Action::Keep
+                    && util::module_name(&d.path) == name
+            })
+            .collect();
+        let kernel_dir = current.path.parent();
+        let next = candidates
+            .iter()
+            .find(|d| kernel_dir.is_some_and(|dir| d.path.starts_with(dir)))
+            .or(candidates.first());
+        // Dependency cycles end the chain too.
+        match next {
+            Some(next) if seen.insert(&next.path) => {
+                chain.push(next);
+                current = next;
+            }
+            _ => break,
+        }
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn decision(path: &str, file_type: FileType, action: Action, reason: &str) -> Decision {
+        Decision {
+            path: PathBuf::from(path),
+            file_type,
+            size: 0,
+            action,
+            reason: reason.to_string(),
+            package: None,
+        }
+    }
+
+    #[test]
+    fn test_keep_chain() {
+        let kernel = "/lib/modules/6.4.0-default/kernel/drivers/net/wireless/intel";
+        let decisions = vec![
+            decision(
+                "/lib/firmware/iwlwifi-cc-a0-77.ucode",
+                FileType::Firmware,
+                Action::Keep,
+                "required by module iwlwifi",
+            ),
+            decision(
+                &format!("{}/iwlwifi/iwlwifi.ko.zst", kernel),
+                FileType::Module,
+                Action::Keep,
+                "dependency of iwlmvm",
+            ),
+            decision(
+                &format!("{}/iwlwifi/mvm/iwlmvm.ko.zst", kernel),
+                FileType::Module,
+                Action::Keep,
+                "matched keep rule 'kernel/drivers/net/wireless/intel/.*'",
+            ),
+            decision(
+                &format!("{}/iwlegacy/iwl4965.ko.zst", kernel),
+                FileType::Module,
+                Action::Delete,
+                "not kept by any rule nor needed by a kept module",
+            ),
+        ];
+
+        let found = find(&decisions, "iwlwifi-cc-a0-77.ucode");
+        assert_eq!(found, vec![&decisions[0]]);
+        assert_eq!(
+            keep_chain(&decisions, found[0]),
+            vec![&decisions[0], &decisions[1], &decisions[2]]
+        );
+        assert_eq!(find(&decisions, "iwlmvm"), vec![&decisions[2]]);
+        assert_eq!(
+            find(&decisions, &format!("{}/iwlegacy/iwl4965.ko.zst", kernel)),
+            vec![&decisions[3]]
+        );
+        assert!(find(&decisions, "iwl3945").is_empty());
+    }
+
+    #[test]
+    fn test_keep_chain_cycle() {
+        let decisions = vec![
+            decision(
+                "/lib/modules/6.4.0/a.ko",
+                FileType::Module,
+                Action::Keep,
+                "dependency of b",
+            ),
+            decision(
+                "/lib/modules/6.4.0/b.ko",
+                FileType::Module,
+                Action::Keep,
+                "soft dependency of a",
+            ),
+        ];
+        assert_eq!(
+            keep_chain(&decisions, &decisions[0]),
+            vec![&decisions[0], &decisions[1]]
+        );
+    }
+}
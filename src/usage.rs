@@ -0,0 +1,184 @@
+//! Size accounting of the module and firmware trees.
+//!
+//! Shows where the space goes before deciding on config rules: every file sorted by size, and
+//! totals per category. Modules are grouped by the directory of their driver class (e.g.
+//! `kernel/drivers/net`), firmware files by their top level directory.
+
+use crate::error::JanitorError;
+use crate::util::{self, ScanOptions};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Number of leading path components of a module, relative to its kernel directory, naming its
+/// category.
+const MODULE_CATEGORY_DEPTH: usize = 3;
+
+/// A file and its size.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SizedFile {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Totals of a category of files.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Category {
+    pub name: String,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Size accounting of a tree, the largest files and categories first.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub files: Vec<SizedFile>,
+    pub categories: Vec<Category>,
+    pub bytes: u64,
+}
+
+impl Usage {
+    /// Builds the accounting of `files`, each given with its category.
+    fn new(files: Vec<(SizedFile, String)>) -> Self {
+        let mut categories: BTreeMap<String, Category> = BTreeMap::new();
+        for (file, name) in &files {
+            let category = categories.entry(name.clone()).or_insert_with(|| Category {
+                name: name.clone(),
+                files: 0,
+                bytes: 0,
+            });
+            category.files += 1;
+            category.bytes += file.size;
+        }
+        let mut categories: Vec<_> = categories.into_values().collect();
+        categories.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.name.cmp(&b.name)));
+        let mut files: Vec<_> = files.into_iter().map(|(file, _)| file).collect();
+        files.sort_by(|a, b| b.size.cmp(&a.size).then(a.path.cmp(&b.path)));
+        Usage {
+            bytes: files.iter().map(|f| f.size).sum(),
+            files,
+            categories,
+        }
+    }
+}
+
+/// Accounts the modules of the kernels of `module_dir` selected by `scan`.
+pub fn module_usage(module_dir: &Path, scan: &ScanOptions) -> Result<Usage, JanitorError> {
+    let mut files = Vec::new();
+    for kernel_dir in util::find_kernel_dirs(module_dir, &scan.kernels)? {
+        for path in util::find_kernel_modules(&kernel_dir, scan)? {
+            let relative = path.strip_prefix(&kernel_dir).unwrap();
+            let category = relative
+                .parent()
+                .map(|dir| {
+                    dir.components()
+                        .take(MODULE_CATEGORY_DEPTH)
+                        .collect::<PathBuf>()
+                })
+                .unwrap_or_default();
+            let size = fs::metadata(&path)?.len();
+            files.push((
+                SizedFile { path, size },
+                category.to_string_lossy().into_owned(),
+            ));
+        }
+    }
+    Ok(Usage::new(files))
+}
+
+/// Accounts the firmware files of `fw_dir`. Symlinks are not counted, their targets are.
+pub fn firmware_usage(fw_dir: &Path) -> Result<Usage, JanitorError> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(fw_dir) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(fw_dir).unwrap();
+        let category = match relative.components().count() {
+            1 => "(top level)".to_string(),
+            _ => relative
+                .components()
+                .next()
+                .unwrap()
+                .as_os_str()
+                .to_string_lossy()
+                .into_owned(),
+        };
+        let size = entry.metadata()?.len();
+        files.push((
+            SizedFile {
+                path: entry.into_path(),
+                size,
+            },
+            category,
+        ));
+    }
+    Ok(Usage::new(files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    fn write(path: &Path, size: usize) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, vec![0u8; size]).unwrap();
+    }
+
+    #[test]
+    fn test_module_usage() {
+        let temp_dir = tempdir().unwrap();
+        let kernel_dir = temp_dir.path().join("6.1.0-test");
+        write(
+            &kernel_dir.join("kernel/drivers/gpu/drm/amd/amdgpu.ko.zst"),
+            300,
+        );
+        write(
+            &kernel_dir.join("kernel/drivers/gpu/drm/i915/i915.ko.zst"),
+            200,
+        );
+        write(&kernel_dir.join("kernel/drivers/net/e1000e.ko.zst"), 50);
+        write(&kernel_dir.join("kernel/fs/ext4.ko.zst"), 400);
+
+        let usage = module_usage(temp_dir.path(), &ScanOptions::default()).unwrap();
+        assert_eq!(usage.bytes, 950);
+        assert_eq!(
+            usage.files[0].path,
+            kernel_dir.join("kernel/fs/ext4.ko.zst")
+        );
+        let categories: Vec<_> = usage
+            .categories
+            .iter()
+            .map(|c| (c.name.as_str(), c.files, c.bytes))
+            .collect();
+        assert_eq!(
+            categories,
+            vec![
+                ("kernel/drivers/gpu", 2, 500),
+                ("kernel/fs", 1, 400),
+                ("kernel/drivers/net", 1, 50),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_firmware_usage() {
+        let temp_dir = tempdir().unwrap();
+        let fw_dir = temp_dir.path();
+        write(&fw_dir.join("amdgpu/navi10_sos.bin"), 100);
+        write(&fw_dir.join("amdgpu/navi10_me.bin"), 20);
+        write(&fw_dir.join("iwlwifi-cc-a0-77.ucode"), 80);
+        symlink("amdgpu/navi10_sos.bin", fw_dir.join("link.bin")).unwrap();
+
+        let usage = firmware_usage(fw_dir).unwrap();
+        assert_eq!(usage.files.len(), 3);
+        assert_eq!(usage.bytes, 200);
+        assert_eq!(usage.categories[0].name, "amdgpu");
+        assert_eq!(usage.categories[1].name, "(top level)");
+    }
+}
@@ -1,6 +1,11 @@
 use crate::error::JanitorError;
+use crate::interrupt;
+use glob::Pattern;
+use log::warn;
+use rayon::prelude::*;
 use std::fs;
 use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
 
 pub fn find_kernel_dir(module_dir: &Path) -> Result<PathBuf, JanitorError> {
     if !module_dir.exists() {
@@ -22,6 +27,222 @@ pub fn find_kernel_dir(module_dir: &Path) -> Result<PathBuf, JanitorError> {
         .ok_or_else(|| JanitorError::NoKernelDir(module_dir.to_path_buf()))
 }
 
+/// Which of the installed kernels are processed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum KernelSelection {
+    /// Only the lexically last kernel directory.
+    #[default]
+    Latest,
+    /// The kernels with these versions.
+    Versions(Vec<String>),
+    /// Every installed kernel.
+    All,
+}
+
+/// Returns the directories of the kernels selected by `selection` in `module_dir`.
+pub fn find_kernel_dirs(
+    module_dir: &Path,
+    selection: &KernelSelection,
+) -> Result<Vec<PathBuf>, JanitorError> {
+    match selection {
+        KernelSelection::Latest => Ok(vec![find_kernel_dir(module_dir)?]),
+        KernelSelection::Versions(versions) => versions
+            .iter()
+            .map(|version| {
+                let kernel_dir = module_dir.join(version);
+                if kernel_dir.is_dir() {
+                    Ok(kernel_dir)
+                } else {
+                    Err(JanitorError::NoKernelDir(kernel_dir))
+                }
+            })
+            .collect(),
+        KernelSelection::All => {
+            if !module_dir.exists() {
+                return Err(JanitorError::NoKernelDir(module_dir.to_path_buf()));
+            }
+            let mut entries = fs::read_dir(module_dir)?
+                .filter_map(Result::ok)
+                .map(|e| e.path())
+                .filter(|p| p.is_dir())
+                .collect::<Vec<_>>();
+            if entries.is_empty() {
+                return Err(JanitorError::NoKernelDir(module_dir.to_path_buf()));
+            }
+            entries.sort();
+            Ok(entries)
+        }
+    }
+}
+
+/// Locations of the kernel module trees in an image, the usrmerged one first.
+pub const MODULE_DIRS: &[&str] = &["usr/lib/modules", "lib/modules"];
+
+/// Locations of the firmware directory in an image, the usrmerged one first.
+pub const FIRMWARE_DIRS: &[&str] = &["usr/lib/firmware", "lib/firmware"];
+
+/// Returns the first of `candidates` which is a directory below `root`, or the first candidate
+/// if none exists.
+pub fn find_in_root(root: &Path, candidates: &[&str]) -> PathBuf {
+    candidates
+        .iter()
+        .map(|c| root.join(c))
+        .find(|p| p.is_dir())
+        .unwrap_or_else(|| root.join(candidates[0]))
+}
+
+/// Returns true if `path` names a kernel module, compressed or not.
+pub fn is_kernel_module(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "ko")
+        || path.to_str().is_some_and(|s| s.ends_with(".ko.xz"))
+        || path.to_str().is_some_and(|s| s.ends_with(".ko.zst"))
+        || path.to_str().is_some_and(|s| s.ends_with(".ko.gz"))
+}
+
+/// Reads a list file with one entry per line, ignoring empty lines and `#` comments.
+pub fn read_list_file(path: &Path) -> Result<Vec<String>, JanitorError> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| JanitorError::ConfigRead(path.display().to_string(), e))?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Returns the name of the module stored at `path`, with dashes normalized to underscores
+/// as the kernel does.
+pub fn module_name(path: &Path) -> String {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| n.split('.').next())
+        .unwrap_or_default()
+        .replace('-', "_")
+}
+
+/// Options controlling how kernel module trees are scanned.
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    /// Patterns, relative to the kernel directory, of subtrees skipped entirely while scanning.
+    pub exclude: Vec<Pattern>,
+    /// Kernels whose module trees are scanned.
+    pub kernels: KernelSelection,
+}
+
+impl ScanOptions {
+    /// Returns true if `path`, relative to the kernel directory, is excluded from scanning.
+    pub fn is_excluded(&self, relative_path: &Path) -> bool {
+        self.exclude.iter().any(|p| p.matches_path(relative_path))
+    }
+}
+
+/// Recursively collects the kernel modules below `kernel_dir`, sorted by path.
+///
+/// The top-level subdirectories are walked in parallel on the rayon thread pool. Top-level
+/// symlinks, i.e. the `build` and `source` links to the kernel sources, are not followed, and
+/// the links of `weak-updates` to the modules of other kernels are not collected.
+pub fn find_kernel_modules(
+    kernel_dir: &Path,
+    options: &ScanOptions,
+) -> Result<Vec<PathBuf>, JanitorError> {
+    let mut roots = fs::read_dir(kernel_dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    roots.retain(|root| !root.is_symlink());
+
+    let is_excluded = |path: &Path| {
+        path.strip_prefix(kernel_dir)
+            .is_ok_and(|p| options.is_excluded(p))
+    };
+
+    let nested = roots
+        .par_iter()
+        .map(|root| {
+            let mut modules = Vec::new();
+            let walker = WalkDir::new(root)
+                .into_iter()
+                .filter_entry(|e| !is_excluded(e.path()));
+            for entry in walker {
+                interrupt::check()?;
+                let entry = entry?;
+                let path = entry.path();
+                if entry.path_is_symlink() && path.starts_with(kernel_dir.join("weak-updates")) {
+                    continue;
+                }
+                if path.is_file() && is_kernel_module(path) {
+                    modules.push(path.to_path_buf());
+                }
+            }
+            Ok(modules)
+        })
+        .collect::<Result<Vec<_>, JanitorError>>()?;
+
+    let mut modules: Vec<PathBuf> = nested.into_iter().flatten().collect();
+    modules.sort();
+    Ok(modules)
+}
+
+/// Parses a size in bytes, optionally followed by a unit: `K`, `M`, `G` or `T`, for powers of 1024
+/// with an optional `iB` suffix (`800MiB`), for powers of 1000 with a `B` suffix (`800MB`).
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("invalid size {:?}", text))?;
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "T" | "TiB" => 1 << 40,
+        "KB" | "kB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        unit => return Err(format!("unknown size unit {:?}", unit)),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size {:?} is too large", text))
+}
+
+/// Runs `f` in a work directory removed afterwards: `work_dir`, created if missing and refused
+/// if it holds anything, or a new directory named after `prefix` in the temporary directory. The
+/// error of `f` is returned even when the work directory cannot be removed.
+pub fn with_work_dir<T>(
+    work_dir: Option<&Path>,
+    prefix: &str,
+    f: impl FnOnce(&Path) -> Result<T, JanitorError>,
+) -> Result<T, JanitorError> {
+    let (dir, temp_dir) = match work_dir {
+        Some(dir) => {
+            if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+                return Err(JanitorError::WorkDirNotEmpty(dir.to_path_buf()));
+            }
+            fs::create_dir_all(dir)?;
+            (dir.to_path_buf(), None)
+        }
+        None => {
+            let temp_dir = tempfile::Builder::new().prefix(prefix).tempdir_in(std::env::temp_dir())?;
+            (temp_dir.path().to_path_buf(), Some(temp_dir))
+        }
+    };
+    let result = f(&dir);
+    let removed = match temp_dir {
+        Some(temp_dir) => temp_dir.close(),
+        None => fs::remove_dir_all(&dir),
+    };
+    match (result, removed) {
+        (Ok(_), Err(e)) => Err(e.into()),
+        (Err(e), Err(removal)) => {
+            warn!("Could not remove the work directory {}: {}", dir.display(), removal);
+            Err(e)
+        }
+        (result, Ok(())) => result,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -67,4 +288,131 @@ mod tests {
 
         assert!(find_kernel_dir(modules_dir).unwrap().ends_with("6.1.0-test"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_find_kernel_modules() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let kernel_dir = temp_dir.path();
+
+        let mod1 = kernel_dir.join("module1.ko");
+        let mod2 = kernel_dir.join("module2.ko.xz");
+        let mod3 = kernel_dir.join("module3.ko.zst");
+        let mod4 = kernel_dir.join("module4.ko.gz");
+        let not_a_mod = kernel_dir.join("not_a_module.txt");
+        let nested_dir = kernel_dir.join("nested");
+        fs::create_dir(&nested_dir).unwrap();
+        let nested_mod = nested_dir.join("nested.ko");
+
+        fs::write(&mod1, "").unwrap();
+        fs::write(&mod2, "").unwrap();
+        fs::write(&mod3, "").unwrap();
+        fs::write(&mod4, "").unwrap();
+        fs::write(&not_a_mod, "").unwrap();
+        fs::write(&nested_mod, "").unwrap();
+
+        let mut found = find_kernel_modules(kernel_dir, &ScanOptions::default()).unwrap();
+        found.sort();
+
+        let mut expected = vec![mod1, mod2, mod3, mod4, nested_mod];
+        expected.sort();
+
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_find_kernel_modules_gz() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let kernel_dir = temp_dir.path();
+        let module_dir = kernel_dir.join("kernel/drivers/net");
+        fs::create_dir_all(&module_dir).unwrap();
+        let path = module_dir.join("e1000.ko.gz");
+        let mut encoder = flate2::write::GzEncoder::new(fs::File::create(&path).unwrap(), flate2::Compression::best());
+        std::io::Write::write_all(&mut encoder, &crate::modinfo::build_test_module(&["firmware=e1000.bin"])).unwrap();
+        encoder.finish().unwrap();
+
+        let found = find_kernel_modules(kernel_dir, &ScanOptions::default()).unwrap();
+        assert_eq!(found, vec![path.clone()]);
+        assert_eq!(module_name(&path), "e1000");
+        let info = crate::modinfo::read_modinfo(&found[0]).unwrap();
+        assert_eq!(info.firmware(), vec!["e1000.bin"]);
+    }
+
+    #[test]
+    fn test_find_kernel_modules_with_exclude() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let kernel_dir = temp_dir.path();
+        let amd_dir = kernel_dir.join("kernel/drivers/gpu/drm/amd");
+        let intel_dir = kernel_dir.join("kernel/drivers/gpu/drm/i915");
+        fs::create_dir_all(amd_dir.join("amdgpu")).unwrap();
+        fs::create_dir_all(&intel_dir).unwrap();
+
+        let amd_mod = amd_dir.join("amdgpu/amdgpu.ko.zst");
+        let intel_mod = intel_dir.join("i915.ko.zst");
+        fs::write(&amd_mod, "").unwrap();
+        fs::write(&intel_mod, "").unwrap();
+
+        let options = ScanOptions {
+            exclude: vec![Pattern::new("kernel/drivers/gpu/drm/amd/*").unwrap()],
+            ..Default::default()
+        };
+        let found = find_kernel_modules(kernel_dir, &options).unwrap();
+        assert_eq!(found, vec![intel_mod]);
+    }
+
+    #[test]
+    fn test_find_kernel_dirs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let module_dir = temp_dir.path();
+        fs::create_dir(module_dir.join("6.1.0-1")).unwrap();
+        fs::create_dir(module_dir.join("6.1.0-2")).unwrap();
+
+        let all = find_kernel_dirs(module_dir, &KernelSelection::All).unwrap();
+        assert_eq!(all, vec![module_dir.join("6.1.0-1"), module_dir.join("6.1.0-2")]);
+
+        let latest = find_kernel_dirs(module_dir, &KernelSelection::Latest).unwrap();
+        assert_eq!(latest, vec![module_dir.join("6.1.0-2")]);
+
+        let selected =
+            find_kernel_dirs(module_dir, &KernelSelection::Versions(vec!["6.1.0-1".to_string()]))
+                .unwrap();
+        assert_eq!(selected, vec![module_dir.join("6.1.0-1")]);
+
+        let missing =
+            find_kernel_dirs(module_dir, &KernelSelection::Versions(vec!["5.0".to_string()]));
+        assert!(matches!(missing, Err(JanitorError::NoKernelDir(_))));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("800MiB"), Ok(800 << 20));
+        assert_eq!(parse_size("800M"), Ok(800 << 20));
+        assert_eq!(parse_size("2 GB"), Ok(2_000_000_000));
+        assert!(parse_size("800 furlongs").is_err());
+        assert!(parse_size("MiB").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_with_work_dir() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let work_dir = temp_dir.path().join("work");
+        fs::create_dir(&work_dir).unwrap();
+        fs::write(work_dir.join("keep.txt"), "data").unwrap();
+        let result = with_work_dir(Some(&work_dir), "test-", |_| Ok(()));
+        assert!(matches!(result, Err(JanitorError::WorkDirNotEmpty(_))));
+        assert!(work_dir.join("keep.txt").exists());
+
+        let empty_dir = temp_dir.path().join("empty");
+        let result: Result<(), _> = with_work_dir(Some(&empty_dir), "test-", |dir| {
+            fs::write(dir.join("extracted"), "x")?;
+            Err(JanitorError::Command("extraction failed".to_string()))
+        });
+        assert!(matches!(result, Err(JanitorError::Command(_))));
+        assert!(!empty_dir.exists());
+
+        let used = with_work_dir(None, "image-janitor-test-", |dir| Ok(dir.to_path_buf())).unwrap();
+        assert!(used.file_name().unwrap().to_str().unwrap().starts_with("image-janitor-test-"));
+        assert!(!used.exists());
+    }
+}
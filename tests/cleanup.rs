@@ -0,0 +1,252 @@
+//! End-to-end runs of the cleanup subcommands on fake image roots.
+
+use image_janitor::testbed::Testbed;
+use std::path::Path;
+use std::process::Output;
+
+const AMDGPU: &str = "kernel/drivers/gpu/drm/amd/amdgpu/amdgpu.ko.xz";
+const E1000E: &str = "kernel/drivers/net/ethernet/intel/e1000e/e1000e.ko";
+const IWLWIFI: &str = "kernel/drivers/net/wireless/intel/iwlwifi/iwlwifi.ko.zst";
+const CFG80211: &str = "kernel/net/wireless/cfg80211.ko.xz";
+
+/// Runs the tool on `testbed` with `args`, checking it succeeds.
+fn run(testbed: &Testbed, args: &[&str]) -> Output {
+    let output = testbed
+        .command(Path::new(env!("CARGO_BIN_EXE_image-janitor")))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+fn module(version: &str, relative: &str) -> String {
+    format!("usr/lib/modules/{}/{}", version, relative)
+}
+
+#[test]
+fn test_cleanup_all_on_every_kernel() {
+    let testbed = Testbed::laptop();
+    let module_dir = testbed.module_dir();
+    let firmware_dir = testbed.firmware_dir();
+    let config = testbed.root().join("config/wireless.list");
+    let backup = testbed.root().join("backup.tar.zst");
+    run(
+        &testbed,
+        &[
+            "cleanup-all",
+            "--delete",
+            "--backup",
+            backup.to_str().unwrap(),
+            "--all-kernels",
+            "--module-dir",
+            module_dir.to_str().unwrap(),
+            "--firmware-dir",
+            firmware_dir.to_str().unwrap(),
+            "--config-files",
+            config.to_str().unwrap(),
+        ],
+    );
+
+    for version in ["6.1.0-1-default", "6.4.0-1-default"] {
+        assert!(testbed.exists(&module(version, IWLWIFI)));
+        assert!(testbed.exists(&module(version, CFG80211)));
+        assert!(!testbed.exists(&module(version, AMDGPU)));
+        assert!(!testbed.exists(&module(version, E1000E)));
+    }
+    for kept in [
+        "iwlwifi-cc-a0-77.ucode",
+        "intel/iwlwifi/iwlwifi-cc-a0-77.ucode",
+        "WHENCE",
+        "LICENSE.iwlwifi",
+    ] {
+        assert!(testbed.exists(&format!("usr/lib/firmware/{}", kept)), "{} was deleted", kept);
+    }
+    for deleted in [
+        "amdgpu/navi10_gpu_info.bin",
+        "brcm/brcmfmac43455-sdio.bin",
+        "brcm/brcmfmac43455-sdio.txt",
+        "brcm/brcmfmac-default.bin",
+    ] {
+        assert!(!testbed.exists(&format!("usr/lib/firmware/{}", deleted)), "{} was kept", deleted);
+    }
+
+    // Both passes saved what they deleted to the backup.
+    let restored = image_janitor::backup::restore(&backup, &testbed.root().join("restored")).unwrap();
+    assert!(restored.iter().any(|path| path.ends_with("amdgpu.ko.xz")));
+    assert!(restored.iter().any(|path| path.ends_with("amdgpu/navi10_gpu_info.bin")));
+}
+
+#[test]
+fn test_driver_cleanup_dry_run_csv() {
+    let testbed = Testbed::laptop();
+    let module_dir = testbed.module_dir();
+    let config = testbed.root().join("config/wireless.list");
+    let output = run(
+        &testbed,
+        &[
+            "driver-cleanup",
+            "--output",
+            "csv",
+            "--module-dir",
+            module_dir.to_str().unwrap(),
+            "--config-files",
+            config.to_str().unwrap(),
+        ],
+    );
+
+    let csv = String::from_utf8(output.stdout).unwrap();
+    let kernel_dir = testbed.kernel_dir("6.4.0-1-default");
+    let row = |relative: &str| {
+        let prefix = format!("{},module,", kernel_dir.join(relative).display());
+        csv.lines()
+            .find(|line| line.starts_with(&prefix))
+            .unwrap_or_else(|| panic!("no row for {} in {}", relative, csv))
+            .to_string()
+    };
+    assert!(csv.starts_with("path,type,size,decision,reason\n"));
+    assert!(row(IWLWIFI).ends_with(",keep,matched keep rule 'kernel/drivers/net/wireless/.*'"));
+    assert!(row(CFG80211).ends_with(",keep,dependency of iwlwifi"));
+    assert!(row(AMDGPU).contains(",delete,"));
+    // Only the latest kernel is processed by default, and a dry run removes nothing.
+    assert!(!csv.contains("6.1.0-1-default"));
+    assert!(testbed.exists(&module("6.4.0-1-default", AMDGPU)));
+}
+
+#[test]
+fn test_root_default_dirs() {
+    let testbed = Testbed::laptop();
+    let config = testbed.root().join("config/wireless.list");
+    // Without --module-dir, the modules of the image are cleaned, not the ones of the host.
+    let output = run(
+        &testbed,
+        &["driver-cleanup", "--output", "csv", "--config-files", config.to_str().unwrap()],
+    );
+    let csv = String::from_utf8(output.stdout).unwrap();
+    let amdgpu = testbed.kernel_dir("6.4.0-1-default").join(AMDGPU);
+    assert!(csv.contains(&format!("{},module,", amdgpu.display())), "{}", csv);
+
+    // Directories of the host are still guarded with --root. The missing module directory stops
+    // the run before anything is deleted on hosts without module or firmware directories.
+    let missing = testbed.root().join("missing");
+    let output = testbed
+        .command(Path::new(env!("CARGO_BIN_EXE_image-janitor")))
+        .args(["fw-cleanup", "--delete", "--firmware-dir", "/", "--module-dir", missing.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    if ["/lib/modules", "/lib/firmware", "/usr/lib/modules", "/usr/lib/firmware"]
+        .iter()
+        .any(|dir| Path::new(dir).exists())
+    {
+        assert!(String::from_utf8_lossy(&output.stderr).contains("belongs to the running system"));
+    }
+}
+
+#[test]
+fn test_fw_cleanup_follows_symlinks() {
+    let testbed = Testbed::laptop();
+    let module_dir = testbed.module_dir();
+    let firmware_dir = testbed.firmware_dir();
+    run(
+        &testbed,
+        &[
+            "fw-cleanup",
+            "--delete",
+            "--module-dir",
+            module_dir.to_str().unwrap(),
+            "--firmware-dir",
+            firmware_dir.to_str().unwrap(),
+        ],
+    );
+
+    // Every module is still there, so only the unused brcm files and link go. The required link
+    // keeps its target.
+    assert!(testbed.exists("usr/lib/firmware/amdgpu/navi10_gpu_info.bin"));
+    assert!(testbed.exists("usr/lib/firmware/iwlwifi-cc-a0-77.ucode"));
+    assert!(testbed.exists("usr/lib/firmware/intel/iwlwifi/iwlwifi-cc-a0-77.ucode"));
+    assert!(!testbed.exists("usr/lib/firmware/brcm/brcmfmac43455-sdio.bin"));
+    assert!(!testbed.exists("usr/lib/firmware/brcm/brcmfmac-default.bin"));
+}
+
+#[test]
+fn test_undo_last_run() {
+    let testbed = Testbed::laptop();
+    let module_dir = testbed.module_dir();
+    let firmware_dir = testbed.firmware_dir();
+    let config = testbed.root().join("config/wireless.list");
+    let journal = testbed.root().join("journal.jsonl");
+    let backup = testbed.root().join("backup.tar.zst");
+    let files = [
+        "--journal",
+        journal.to_str().unwrap(),
+        "--backup",
+        backup.to_str().unwrap(),
+        "--module-dir",
+        module_dir.to_str().unwrap(),
+    ];
+    run(
+        &testbed,
+        &[&["driver-cleanup", "--delete", "--config-files", config.to_str().unwrap()], &files[..]].concat(),
+    );
+    run(
+        &testbed,
+        &[&["fw-cleanup", "--delete", "--firmware-dir", firmware_dir.to_str().unwrap()], &files[..]].concat(),
+    );
+    assert!(!testbed.exists(&module("6.4.0-1-default", AMDGPU)));
+    assert!(!testbed.exists("usr/lib/firmware/amdgpu/navi10_gpu_info.bin"));
+
+    // The second run started a new journal and backup, so only its deletions are undone. The
+    // journal records absolute paths, hence no --root.
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_image-janitor"))
+        .args(["undo", "--journal", journal.to_str().unwrap(), "--backup", backup.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "undo failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(testbed.exists("usr/lib/firmware/amdgpu/navi10_gpu_info.bin"));
+    assert!(!testbed.exists(&module("6.4.0-1-default", AMDGPU)));
+}
+
+#[test]
+fn test_check_exit_codes() {
+    let testbed = Testbed::laptop();
+    let module_dir = testbed.module_dir();
+    let firmware_dir = testbed.firmware_dir();
+    let config = testbed.root().join("config/wireless.list");
+    let check = |extra: &[&str]| {
+        let mut args = vec![
+            "check",
+            "--module-dir",
+            module_dir.to_str().unwrap(),
+            "--firmware-dir",
+            firmware_dir.to_str().unwrap(),
+            "--config-files",
+            config.to_str().unwrap(),
+        ];
+        args.extend(extra);
+        testbed
+            .command(Path::new(env!("CARGO_BIN_EXE_image-janitor")))
+            .args(&args)
+            .output()
+            .unwrap()
+    };
+
+    assert!(check(&["--max-unused-bytes", "1000000000"]).status.success());
+    let output = check(&["--max-unused-bytes", "0"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("FAILED: unused files weigh"));
+
+    // An approved plan missing the amdgpu module makes its deletion unexpected.
+    let plan = testbed.root().join("approved.json");
+    std::fs::write(&plan, r#"{"version": "0.2.0", "files": []}"#).unwrap();
+    let output = check(&["--expected", plan.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stdout).contains(AMDGPU));
+    // Nothing was deleted.
+    assert!(testbed.exists(&module("6.4.0-1-default", AMDGPU)));
+}